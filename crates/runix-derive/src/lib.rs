@@ -28,9 +28,9 @@ fn impl_to_args(ast: &syn::DeriveInput) -> Result<TokenStream, syn::Error> {
         .iter()
         .enumerate()
         .map(|(n, field)| match field.ident {
-            Some(ref i) => quote! { self.#i.to_args() },
+            Some(ref i) => quote! { self.#i.to_cow_args() },
             // Tuple structs
-            None => quote! { self.#n.to_args() },
+            None => quote! { self.#n.to_cow_args() },
         })
         .collect::<Vec<_>>();
 
@@ -38,8 +38,8 @@ fn impl_to_args(ast: &syn::DeriveInput) -> Result<TokenStream, syn::Error> {
 
     let gen = quote! {
         impl #generics ToArgs for #name #generics {
-            fn to_args(&self) -> ::std::vec::Vec<::std::string::String> {
-                let args: [::std::vec::Vec<::std::string::String>; #len] = [
+            fn to_cow_args(&self) -> ::std::vec::Vec<::std::borrow::Cow<'_, str>> {
+                let args: [::std::vec::Vec<::std::borrow::Cow<'_, str>>; #len] = [
                     #(#conversions),*
                 ];
 
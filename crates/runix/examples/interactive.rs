@@ -0,0 +1,54 @@
+//! Manual test for commands sharing the terminal with nix, see [ExecutionMode]
+//!
+//! ```shell
+//! $ cargo run --example interactive -- [inherit|captured|piped] [installable]
+//! ```
+//!
+//! Runs `nix shell` for the installable (`nixpkgs#bashInteractive` by default).
+//! With `inherit`, the default, the shell owns the terminal:
+//! its prompt is shown, input is echoed and Ctrl-C reaches the shell,
+//! a shell exiting with the failure of a command stopped with Ctrl-C reports an interrupt,
+//! exiting it with `exit 0` after Ctrl-C does not.
+//! With `captured` or `piped` the shell reads no input and exits right away.
+
+use std::str::FromStr;
+
+use runix::arguments::NixArgs;
+use runix::command::Shell;
+use runix::command_line::child::ExecutionMode;
+use runix::command_line::{NixCommandLine, NixCommandLineRunError};
+use runix::installable::{FlakeAttribute, Installable};
+use runix::Run;
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let mode = match args.next().as_deref() {
+        None | Some("inherit") => ExecutionMode::Inherit,
+        Some("captured") => ExecutionMode::Captured,
+        Some("piped") => ExecutionMode::Piped,
+        Some(mode) => panic!("unknown mode '{mode}', expected inherit, captured or piped"),
+    };
+    let installable = args
+        .next()
+        .unwrap_or_else(|| "nixpkgs#bashInteractive".to_string());
+    let installable = FlakeAttribute::from_str(&installable).expect("invalid installable");
+
+    let shell = Shell {
+        installables: vec![Installable::from(installable)].into(),
+        ..Default::default()
+    };
+    let result = shell
+        .run(
+            &NixCommandLine::default(),
+            &NixArgs::default().with_execution_mode(mode),
+        )
+        .await;
+
+    match result {
+        Ok(()) => println!("nix exited successfully"),
+        Err(NixCommandLineRunError::Interrupted(status)) => println!("nix was interrupted: {status}"),
+        Err(NixCommandLineRunError::Exit(status)) => println!("nix failed: {status}"),
+        Err(err) => println!("error: {err}"),
+    }
+}
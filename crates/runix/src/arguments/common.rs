@@ -1,10 +1,11 @@
 //! Arguments common for all mix commands
 
-use derive_more::{Deref, From};
+use derive_more::{Deref, Display, From};
 use runix_derive::ToArgs;
 
 use crate::command_line::flag::{Flag, FlagType};
 use crate::command_line::ToArgs;
+use crate::store_uri::StoreUri;
 
 /// These arguments do not depend on the nix subcommand issued
 /// and refer to the options defined in
@@ -13,12 +14,85 @@ use crate::command_line::ToArgs;
 /// - [nix/main.cc](https://github.com/NixOS/nix/blob/b7e8a3bf4cbb2448db860f65ea13ef2c64b6883b/src/nix/main.cc#L66-L110)
 #[derive(Clone, Default, Debug, ToArgs)]
 pub struct NixCommonArgs {
+    pub offline: Offline,
     pub store: Option<Store>,
+    pub log_format: Option<LogFormat>,
 }
 
+/// Flag for offline
+///
+/// Use cached flake inputs and tarballs regardless of their age
+/// and do not query substituters
 #[derive(Clone, From, Debug, Deref, Default)]
-pub struct Store(String);
+pub struct Offline(bool);
+impl Flag for Offline {
+    const FLAG: &'static str = "--offline";
+    const FLAG_TYPE: FlagType<Self> = FlagType::bool();
+}
+
+/// Flag for store
+///
+/// The store to build into and query,
+/// e.g. a chroot store (`/custom/store`) or a remote one (`ssh-ng://host`).
+/// Evaluation may use a different store,
+/// see [EvalStore](crate::arguments::eval::EvalStore).
+#[derive(Clone, From, Debug, Deref, Default)]
+#[from(forward)]
+pub struct Store(StoreUri);
 impl Flag for Store {
     const FLAG: &'static str = "--store";
     const FLAG_TYPE: FlagType<Self> = FlagType::arg();
 }
+
+/// Flag for log-format
+///
+/// How nix prints its progress and logs to stderr.
+/// [LogFormat::InternalJson] output can be parsed with
+/// [internal_log](crate::internal_log), see
+/// [NixCommandLine::run_with_log](crate::command_line::NixCommandLine::run_with_log).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum LogFormat {
+    #[display(fmt = "raw")]
+    Raw,
+    #[display(fmt = "raw-with-logs")]
+    RawWithLogs,
+    #[display(fmt = "internal-json")]
+    InternalJson,
+    #[display(fmt = "bar")]
+    Bar,
+    #[display(fmt = "bar-with-logs")]
+    BarWithLogs,
+}
+impl Flag for LogFormat {
+    const FLAG: &'static str = "--log-format";
+    const FLAG_TYPE: FlagType<Self> = FlagType::Arg(|s| s.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_args(uri: &str) -> Vec<String> {
+        NixCommonArgs {
+            store: Some(uri.parse::<StoreUri>().unwrap().into()),
+            ..Default::default()
+        }
+        .to_args()
+    }
+
+    #[test]
+    fn renders_chroot_store() {
+        assert_eq!(store_args("/custom/store"), vec![
+            "--store",
+            "/custom/store"
+        ]);
+    }
+
+    #[test]
+    fn renders_remote_store() {
+        assert_eq!(store_args("ssh-ng://host"), vec![
+            "--store",
+            "ssh-ng://host"
+        ]);
+    }
+}
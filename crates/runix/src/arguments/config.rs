@@ -1,12 +1,18 @@
 //! Nix config flags and nix.conf generation, see [NixConfigArgs]
 
+use std::num::{NonZeroU32, NonZeroU64};
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use derive_more::{Deref, From};
+use derive_more::{Deref, Display, From};
 use runix_derive::ToArgs;
+use thiserror::Error;
 
 use crate::command_line::flag::{Flag, FlagType};
 use crate::command_line::ToArgs;
+use crate::installable::Installable;
+use crate::store_uri::StoreUri;
 
 /// These arguments correspond to nix config settings as defined in `nix.conf` or overridden on the commandline
 /// and refer to the options defined in
@@ -14,36 +20,95 @@ use crate::command_line::ToArgs;
 #[derive(Clone, Default, Debug, ToArgs)]
 pub struct NixConfigArgs {
     pub accept_flake_config: AcceptFlakeConfig,
-    pub connect_timeout: ConnectTimeout,
+    pub allow_dirty: Option<AllowDirty>,
+    pub allow_import_from_derivation: Option<AllowImportFromDerivation>,
+    pub connect_timeout: Option<ConnectTimeout>,
+    pub download_attempts: Option<DownloadAttempts>,
     pub extra_access_tokens: AccessTokens,
     pub extra_experimental_features: ExperimentalFeatures,
     pub extra_substituters: Substituters,
     pub extra_trusted_public_keys: TrustedPublicKeys,
     pub flake_registry: Option<FlakeRegistry>,
+    pub http_connections: Option<HttpConnections>,
+    pub max_silent_time: Option<MaxSilentTime>,
+    pub narinfo_cache_negative_ttl: Option<NarinfoCacheNegativeTtl>,
+    pub narinfo_cache_positive_ttl: Option<NarinfoCachePositiveTtl>,
     pub netrc_file: Option<NetRCFile>,
+    pub post_build_hook: Option<PostBuildHook>,
+    pub pure_eval: Option<PureEval>,
     pub show_trace: ShowTrace,
-    pub warn_dirty: WarnDirty,
+    pub tarball_ttl: Option<TarballTtl>,
+    pub timeout: Option<Timeout>,
+    pub warn_dirty: Option<WarnDirty>,
 }
 
 impl NixConfigArgs {
     fn config_items(&self) -> Vec<(String, String)> {
         [
             self.accept_flake_config.to_config(),
-            self.connect_timeout.to_config(),
+            self.allow_dirty.as_ref().and_then(ToConfig::to_config),
+            self.allow_import_from_derivation
+                .as_ref()
+                .and_then(ToConfig::to_config),
+            self.connect_timeout.as_ref().and_then(ToConfig::to_config),
+            self.download_attempts
+                .as_ref()
+                .and_then(ToConfig::to_config),
             self.extra_access_tokens.to_config(),
             self.extra_experimental_features.to_config(),
             self.extra_substituters.to_config(),
             self.extra_trusted_public_keys.to_config(),
             self.flake_registry.as_ref().and_then(ToConfig::to_config),
+            self.http_connections.as_ref().and_then(ToConfig::to_config),
+            self.max_silent_time.as_ref().and_then(ToConfig::to_config),
+            self.narinfo_cache_negative_ttl
+                .as_ref()
+                .and_then(ToConfig::to_config),
+            self.narinfo_cache_positive_ttl
+                .as_ref()
+                .and_then(ToConfig::to_config),
             self.netrc_file.as_ref().and_then(ToConfig::to_config),
+            self.post_build_hook.as_ref().and_then(ToConfig::to_config),
+            self.pure_eval.as_ref().and_then(ToConfig::to_config),
             self.show_trace.to_config(),
-            self.warn_dirty.to_config(),
+            self.tarball_ttl.as_ref().and_then(ToConfig::to_config),
+            self.timeout.as_ref().and_then(ToConfig::to_config),
+            self.warn_dirty.as_ref().and_then(ToConfig::to_config),
         ]
         .into_iter()
         .flatten()
         .collect()
     }
 
+    /// Check settings that nix would only reject once it uses them
+    pub fn validate(&self) -> Result<(), NixConfigError> {
+        if let Some(ref hook) = self.post_build_hook {
+            let executable = hook
+                .metadata()
+                .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false);
+            if !executable {
+                return Err(NixConfigError::NotExecutable(hook.to_path_buf()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Find settings that have no effect on the given installables
+    ///
+    /// Unlike [NixConfigArgs::validate] these do not prevent running nix,
+    /// but likely point to a misunderstanding.
+    pub fn warnings(&self, installables: &[&Installable]) -> Vec<NixConfigWarning> {
+        let mut warnings = Vec::new();
+        let flake = installables
+            .iter()
+            .any(|i| matches!(i, Installable::FlakeAttribute(_)));
+        if self.pure_eval.is_some() && flake {
+            warnings.push(NixConfigWarning::PureEvalWithFlake);
+        }
+        warnings
+    }
+
     /// write the config in the `nix.conf` file format
     pub fn to_config_string(&self) -> String {
         self.config_items()
@@ -70,6 +135,7 @@ where
                 (value != default).then_some((name.to_owned(), value.to_string()))
             },
             FlagType::Indicator(f) => f(self).then_some((name.to_owned(), true.to_string())),
+            FlagType::Setting(f) => Some((name.to_owned(), f(self))),
             _ => match self.to_args()[..] {
                 [] | [_] => None,
                 ref args => Some((name.to_owned(), args[1..].join(" "))),
@@ -78,12 +144,36 @@ where
     }
 }
 
-/// flag for warn dirty
+/// Flag for warn-dirty
+///
+/// Whether nix warns about evaluating a dirty git tree
 #[derive(Clone, From, Debug, Deref, Default)]
 pub struct WarnDirty(bool);
 impl Flag for WarnDirty {
     const FLAG: &'static str = "--warn-dirty";
-    const FLAG_TYPE: FlagType<Self> = FlagType::switch(true);
+    const FLAG_TYPE: FlagType<Self> = FlagType::setting();
+}
+
+/// Flag for allow-dirty
+///
+/// Whether nix accepts dirty git trees at all
+#[derive(Clone, From, Debug, Deref, Default)]
+pub struct AllowDirty(bool);
+impl Flag for AllowDirty {
+    const FLAG: &'static str = "--allow-dirty";
+    const FLAG_TYPE: FlagType<Self> = FlagType::setting();
+}
+
+/// Flag for allow-import-from-derivation
+///
+/// Whether evaluations may build derivations to import their outputs (IFD).
+/// If disabled, such evaluations fail with
+/// [ImportFromDerivationBlocked](crate::command_line::NixCommandLineCollectError::ImportFromDerivationBlocked)
+#[derive(Clone, From, Debug, Deref, Default)]
+pub struct AllowImportFromDerivation(bool);
+impl Flag for AllowImportFromDerivation {
+    const FLAG: &'static str = "--allow-import-from-derivation";
+    const FLAG_TYPE: FlagType<Self> = FlagType::setting();
 }
 
 /// Flag for accept-flake-config
@@ -94,15 +184,220 @@ impl Flag for AcceptFlakeConfig {
     const FLAG_TYPE: FlagType<Self> = FlagType::switch(false);
 }
 
-/// Flag for accept-flake-config
-#[derive(Clone, From, Debug, Deref, Default)]
-pub struct ConnectTimeout(u32);
+/// Nix only accepts time limits and TTLs in whole seconds
+#[derive(Debug, Error)]
+#[error("'{0:?}' is not a whole number of seconds")]
+pub struct InvalidSeconds(Duration);
+
+fn whole_seconds(duration: Duration) -> Result<Duration, InvalidSeconds> {
+    if duration.subsec_nanos() != 0 {
+        return Err(InvalidSeconds(duration));
+    }
+    Ok(duration)
+}
+
+/// Flag for connect-timeout
+///
+/// How long nix waits for connections to binary caches to be established.
+/// Constructed from a whole number of seconds using [TryFrom<Duration>],
+/// where nix' special value [Duration::ZERO] becomes [ConnectTimeout::NoLimit].
+#[derive(Clone, Debug, PartialEq, Eq, Display)]
+pub enum ConnectTimeout {
+    /// Leave the timeout to curl
+    #[display(fmt = "0")]
+    NoLimit,
+    /// Give up connecting after the given number of seconds
+    #[display(fmt = "{}", _0)]
+    After(NonZeroU64),
+}
+impl TryFrom<Duration> for ConnectTimeout {
+    type Error = InvalidSeconds;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        match NonZeroU64::new(whole_seconds(duration)?.as_secs()) {
+            Some(seconds) => Ok(ConnectTimeout::After(seconds)),
+            None => Ok(ConnectTimeout::NoLimit),
+        }
+    }
+}
 impl Flag for ConnectTimeout {
     const FLAG: &'static str = "--connect-timeout";
-    const FLAG_TYPE: FlagType<Self> = FlagType::number_arg();
+    const FLAG_TYPE: FlagType<Self> = FlagType::Setting(|s| s.to_string());
+}
+
+/// Flag for http-connections
+///
+/// The maximum number of parallel connections nix opens for downloads.
+/// Nix' special value `0` is represented by [HttpConnections::Unlimited],
+/// which is also what [From<u32>] maps `0` to.
+#[derive(Clone, Debug, PartialEq, Eq, Display)]
+pub enum HttpConnections {
+    #[display(fmt = "0")]
+    Unlimited,
+    #[display(fmt = "{}", _0)]
+    Limit(NonZeroU32),
+}
+impl From<u32> for HttpConnections {
+    fn from(connections: u32) -> Self {
+        match NonZeroU32::new(connections) {
+            Some(limit) => HttpConnections::Limit(limit),
+            None => HttpConnections::Unlimited,
+        }
+    }
+}
+impl Flag for HttpConnections {
+    const FLAG: &'static str = "--http-connections";
+    const FLAG_TYPE: FlagType<Self> = FlagType::Setting(|s| s.to_string());
+}
+
+/// Flag for download-attempts
+///
+/// How often nix tries to download a file before giving up.
+/// Nix makes at least one attempt, hence this is never zero.
+#[derive(Clone, Debug, Deref, From)]
+pub struct DownloadAttempts(NonZeroU32);
+impl Flag for DownloadAttempts {
+    const FLAG: &'static str = "--download-attempts";
+    const FLAG_TYPE: FlagType<Self> = FlagType::Setting(|s| s.to_string());
+}
+
+/// Flag for max-silent-time
+///
+/// Builds that do not produce output for this long are killed,
+/// [Duration::ZERO] disables the limit.
+/// Constructed from a whole number of seconds using [TryFrom<Duration>].
+#[derive(Clone, Debug, Deref)]
+pub struct MaxSilentTime(Duration);
+impl TryFrom<Duration> for MaxSilentTime {
+    type Error = InvalidSeconds;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        whole_seconds(duration).map(MaxSilentTime)
+    }
+}
+impl Flag for MaxSilentTime {
+    const FLAG: &'static str = "--max-silent-time";
+    const FLAG_TYPE: FlagType<Self> = FlagType::seconds_arg();
+}
+
+/// Flag for timeout
+///
+/// Builds that take longer than this are killed,
+/// [Duration::ZERO] disables the limit.
+/// Constructed from a whole number of seconds using [TryFrom<Duration>].
+#[derive(Clone, Debug, Deref)]
+pub struct Timeout(Duration);
+impl TryFrom<Duration> for Timeout {
+    type Error = InvalidSeconds;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        whole_seconds(duration).map(Timeout)
+    }
+}
+impl Flag for Timeout {
+    const FLAG: &'static str = "--timeout";
+    const FLAG_TYPE: FlagType<Self> = FlagType::seconds_arg();
+}
+
+/// Flag for tarball-ttl
+///
+/// How long downloaded tarballs and fetched branches of flake refs
+/// are considered up to date, [Duration::ZERO] always refetches them.
+/// Constructed from a whole number of seconds using [TryFrom<Duration>].
+#[derive(Clone, Debug, Deref)]
+pub struct TarballTtl(Duration);
+impl TryFrom<Duration> for TarballTtl {
+    type Error = InvalidSeconds;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        whole_seconds(duration).map(TarballTtl)
+    }
+}
+impl Flag for TarballTtl {
+    const FLAG: &'static str = "--tarball-ttl";
+    const FLAG_TYPE: FlagType<Self> = FlagType::seconds_arg();
+}
+
+/// Flag for narinfo-cache-negative-ttl
+///
+/// How long the absence of a store path on a binary cache is remembered.
+/// Constructed from a whole number of seconds using [TryFrom<Duration>].
+#[derive(Clone, Debug, Deref)]
+pub struct NarinfoCacheNegativeTtl(Duration);
+impl TryFrom<Duration> for NarinfoCacheNegativeTtl {
+    type Error = InvalidSeconds;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        whole_seconds(duration).map(NarinfoCacheNegativeTtl)
+    }
+}
+impl Flag for NarinfoCacheNegativeTtl {
+    const FLAG: &'static str = "--narinfo-cache-negative-ttl";
+    const FLAG_TYPE: FlagType<Self> = FlagType::seconds_arg();
+}
+
+/// Flag for narinfo-cache-positive-ttl
+///
+/// How long the presence of a store path on a binary cache is remembered.
+/// Constructed from a whole number of seconds using [TryFrom<Duration>].
+#[derive(Clone, Debug, Deref)]
+pub struct NarinfoCachePositiveTtl(Duration);
+impl TryFrom<Duration> for NarinfoCachePositiveTtl {
+    type Error = InvalidSeconds;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        whole_seconds(duration).map(NarinfoCachePositiveTtl)
+    }
+}
+impl Flag for NarinfoCachePositiveTtl {
+    const FLAG: &'static str = "--narinfo-cache-positive-ttl";
+    const FLAG_TYPE: FlagType<Self> = FlagType::seconds_arg();
+}
+
+/// Flag for post-build-hook
+///
+/// Note that this is a restricted setting:
+/// the nix daemon ignores it unless the user is trusted.
+#[derive(Clone, From, Deref, Debug)]
+#[from(forward)]
+pub struct PostBuildHook(PathBuf);
+impl Flag for PostBuildHook {
+    const FLAG: &'static str = "--post-build-hook";
+    const FLAG_TYPE: FlagType<Self> = FlagType::os_str_arg();
+}
+
+/// Flag for pure-eval
+///
+/// Forbids access to the file system and environment
+/// when evaluating `--expr`s and files.
+/// Flakes are always evaluated purely (unless `--impure`),
+/// hence this has no effect on flake installables,
+/// see [NixConfigArgs::warnings].
+#[derive(Clone, From, Debug, Deref, Default)]
+pub struct PureEval(bool);
+impl Flag for PureEval {
+    const FLAG: &'static str = "--pure-eval";
+    const FLAG_TYPE: FlagType<Self> = FlagType::setting();
+}
+
+/// Settings that likely do not do what the user expects,
+/// see [NixConfigArgs::warnings]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NixConfigWarning {
+    #[error("pure-eval has no effect on flakes, which are always evaluated purely")]
+    PureEvalWithFlake,
+}
+
+#[derive(Debug, Error)]
+pub enum NixConfigError {
+    #[error("'{0}' is not an executable file")]
+    NotExecutable(PathBuf),
 }
 
 /// Flag for show-trace
+///
+/// Makes nix print the full trace of evaluation errors,
+/// which collected commands provide as an [EvalTrace](crate::eval_trace::EvalTrace)
 #[derive(Clone, From, Debug, Deref, Default)]
 pub struct ShowTrace(bool);
 impl Flag for ShowTrace {
@@ -121,13 +416,16 @@ impl Flag for ExperimentalFeatures {
 
 /// Flag for extra substituters
 #[derive(Clone, From, Deref, Debug, Default)]
-pub struct Substituters(Vec<String>);
+pub struct Substituters(Vec<StoreUri>);
 impl Flag for Substituters {
     const FLAG: &'static str = "--extra-substituters";
     const FLAG_TYPE: FlagType<Self> = FlagType::list();
 }
 
-/// Flag for extra substituters
+/// Flag for the global flake registry
+///
+/// Indirect flake references are resolved through the registry at this path
+/// instead of the one configured in `nix.conf` or downloaded by nix.
 #[derive(Clone, From, Deref, Debug, Default)]
 pub struct FlakeRegistry(PathBuf);
 impl Flag for FlakeRegistry {
@@ -158,3 +456,213 @@ impl Flag for TrustedPublicKeys {
     const FLAG: &'static str = "--extra-trusted-public-keys";
     const FLAG_TYPE: FlagType<Self> = FlagType::list();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flake_ref::indirect::IndirectRef;
+    use crate::flake_ref::FlakeRef;
+    use crate::installable::FlakeAttribute;
+    use crate::store_path::StorePath;
+
+    #[test]
+    fn renders_time_limits_in_seconds() {
+        let config = NixConfigArgs {
+            max_silent_time: Some(Duration::from_secs(600).try_into().unwrap()),
+            timeout: Some(Duration::from_secs(3600).try_into().unwrap()),
+            ..Default::default()
+        };
+
+        let args = config.to_args();
+        assert!(args.windows(2).any(|w| w == ["--max-silent-time", "600"]));
+        assert!(args.windows(2).any(|w| w == ["--timeout", "3600"]));
+
+        let config_string = config.to_config_string();
+        assert!(config_string.contains("max-silent-time = 600"));
+        assert!(config_string.contains("timeout = 3600"));
+    }
+
+    #[test]
+    fn renders_allow_import_from_derivation() {
+        let config = NixConfigArgs {
+            allow_import_from_derivation: Some(false.into()),
+            ..Default::default()
+        };
+        assert!(config
+            .to_args()
+            .windows(3)
+            .any(|w| w == ["--option", "allow-import-from-derivation", "false"]));
+        assert_eq!(
+            config.to_config_string(),
+            "allow-import-from-derivation = false"
+        );
+    }
+
+    #[test]
+    fn renders_pure_eval() {
+        let config = NixConfigArgs {
+            pure_eval: Some(true.into()),
+            ..Default::default()
+        };
+        assert!(config
+            .to_args()
+            .windows(3)
+            .any(|w| w == ["--option", "pure-eval", "true"]));
+        assert_eq!(config.to_config_string(), "pure-eval = true");
+    }
+
+    #[test]
+    fn renders_flake_registry() {
+        let config = NixConfigArgs {
+            flake_registry: Some(PathBuf::from("/etc/nix/registry.json").into()),
+            ..Default::default()
+        };
+        assert!(config
+            .to_args()
+            .windows(2)
+            .any(|w| w == ["--flake-registry", "/etc/nix/registry.json"]));
+        assert_eq!(
+            config.to_config_string(),
+            "flake-registry = /etc/nix/registry.json"
+        );
+        assert!(!NixConfigArgs::default()
+            .to_args()
+            .contains(&"--flake-registry".to_string()));
+    }
+
+    #[test]
+    fn warns_pure_eval_with_flake() {
+        let config = NixConfigArgs {
+            pure_eval: Some(true.into()),
+            ..Default::default()
+        };
+        let flake = Installable::from(FlakeAttribute {
+            flakeref: FlakeRef::Indirect(IndirectRef::from_pairs("nixpkgs", [])),
+            attr_path: ["hello"].try_into().unwrap(),
+        });
+        let store_path = Installable::StorePath(
+            StorePath::from_path("/nix/store/8vq9f8bq9ayn3ih4hz3ihc2jjq3d5mgl-hello-2.12.1")
+                .unwrap(),
+        );
+
+        assert_eq!(config.warnings(&[&store_path, &flake]), vec![
+            NixConfigWarning::PureEvalWithFlake
+        ]);
+        assert!(config.warnings(&[&store_path]).is_empty());
+        assert!(NixConfigArgs::default().warnings(&[&flake]).is_empty());
+    }
+
+    #[test]
+    fn renders_http_tuning_settings() {
+        let config = NixConfigArgs {
+            connect_timeout: Some(Duration::from_secs(5).try_into().unwrap()),
+            download_attempts: Some(NonZeroU32::new(3).unwrap().into()),
+            http_connections: Some(4.into()),
+            ..Default::default()
+        };
+
+        let args = config.to_args();
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["--option", "connect-timeout", "5"]));
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["--option", "download-attempts", "3"]));
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["--option", "http-connections", "4"]));
+
+        let config_string = config.to_config_string();
+        assert!(config_string.contains("connect-timeout = 5"));
+        assert!(config_string.contains("download-attempts = 3"));
+        assert!(config_string.contains("http-connections = 4"));
+    }
+
+    #[test]
+    fn renders_http_tuning_zero_values() {
+        assert_eq!(HttpConnections::from(0), HttpConnections::Unlimited);
+        assert_eq!(
+            ConnectTimeout::try_from(Duration::ZERO).unwrap(),
+            ConnectTimeout::NoLimit
+        );
+        assert!(ConnectTimeout::try_from(Duration::from_millis(500)).is_err());
+        assert!(ConnectTimeout::try_from(Duration::from_millis(1500)).is_err());
+        assert_eq!(
+            ConnectTimeout::try_from(Duration::from_secs(5)).unwrap(),
+            ConnectTimeout::After(NonZeroU64::new(5).unwrap())
+        );
+
+        let config = NixConfigArgs {
+            connect_timeout: Some(ConnectTimeout::NoLimit),
+            http_connections: Some(HttpConnections::Unlimited),
+            ..Default::default()
+        };
+        let config_string = config.to_config_string();
+        assert!(config_string.contains("connect-timeout = 0"));
+        assert!(config_string.contains("http-connections = 0"));
+
+        // unset settings are left to nix
+        assert!(NixConfigArgs::default().to_config_string().is_empty());
+    }
+
+    #[test]
+    fn renders_ttls_in_seconds() {
+        let config = NixConfigArgs {
+            tarball_ttl: Some(Duration::ZERO.try_into().unwrap()),
+            narinfo_cache_negative_ttl: Some(Duration::from_secs(60).try_into().unwrap()),
+            narinfo_cache_positive_ttl: Some(Duration::from_secs(86400).try_into().unwrap()),
+            ..Default::default()
+        };
+
+        let args = config.to_args();
+        assert!(args.windows(2).any(|w| w == ["--tarball-ttl", "0"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--narinfo-cache-negative-ttl", "60"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--narinfo-cache-positive-ttl", "86400"]));
+
+        let config_string = config.to_config_string();
+        assert!(config_string.contains("tarball-ttl = 0"));
+        assert!(config_string.contains("narinfo-cache-negative-ttl = 60"));
+        assert!(config_string.contains("narinfo-cache-positive-ttl = 86400"));
+
+        assert!(TarballTtl::try_from(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn validates_post_build_hook() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let hook = tempdir.path().join("upload.sh");
+        std::fs::write(&hook, "#!/bin/sh\n").unwrap();
+
+        let config = NixConfigArgs {
+            post_build_hook: Some(hook.clone().into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(NixConfigError::NotExecutable(ref path)) if path == &hook
+        ));
+
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        config.validate().unwrap();
+        assert!(config
+            .to_config_string()
+            .contains(&format!("post-build-hook = {}", hook.to_string_lossy())));
+
+        let config = NixConfigArgs {
+            post_build_hook: Some(tempdir.path().into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_sub_second_time_limits() {
+        assert!(Timeout::try_from(Duration::from_millis(500)).is_err());
+        assert!(MaxSilentTime::try_from(Duration::from_millis(1500)).is_err());
+        assert_eq!(*Timeout::try_from(Duration::ZERO).unwrap(), Duration::ZERO);
+    }
+}
@@ -5,6 +5,7 @@ use runix_derive::ToArgs;
 
 use crate::command_line::ToArgs;
 use crate::default::flag::{Flag, FlagType};
+use crate::store_uri::StoreUri;
 
 /// Evaluation related arguments
 /// Corresponding to the arguments defined in
@@ -13,10 +14,16 @@ use crate::default::flag::{Flag, FlagType};
 pub struct EvaluationArgs {
     pub eval_store: Option<EvalStore>,
     pub impure: Impure,
+    pub refresh: Refresh,
 }
 
+/// Flag for eval-store
+///
+/// The store to evaluate in, i.e. to put `.drv` files and sources in,
+/// if different from the [Store](crate::arguments::common::Store) to build in
 #[derive(Clone, From, Debug, Deref, Default)]
-pub struct EvalStore(String);
+#[from(forward)]
+pub struct EvalStore(StoreUri);
 impl Flag for EvalStore {
     const FLAG: &'static str = "--eval-store";
     const FLAG_TYPE: FlagType<Self> = FlagType::arg();
@@ -28,3 +35,13 @@ impl Flag for Impure {
     const FLAG: &'static str = "--impure";
     const FLAG_TYPE: FlagType<Self> = FlagType::bool();
 }
+
+/// Flag for refresh
+///
+/// Consider all previously downloaded files, e.g. flake inputs, out of date
+#[derive(Clone, From, Debug, Deref, Default)]
+pub struct Refresh(bool);
+impl Flag for Refresh {
+    const FLAG: &'static str = "--refresh";
+    const FLAG_TYPE: FlagType<Self> = FlagType::bool();
+}
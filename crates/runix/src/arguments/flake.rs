@@ -14,6 +14,8 @@ use crate::flake_ref::FlakeRef;
 pub struct FlakeArgs {
     pub override_inputs: Vec<OverrideInput>,
     pub no_write_lock_file: NoWriteLockFile,
+    pub commit_lock_file: CommitLockFile,
+    pub commit_lockfile_summary: Option<CommitLockfileSummary>,
 }
 
 /// Tuple like override inputs flag
@@ -41,3 +43,26 @@ impl Flag for NoWriteLockFile {
     /// There is no `--write-lock-file` equivalent
     const FLAG_TYPE: FlagType<Self> = FlagType::bool();
 }
+
+/// Flag for commit-lock-file
+///
+/// Commit changes to the lock file, which requires writing it,
+/// i.e. conflicts with [NoWriteLockFile]
+#[derive(Clone, From, Debug, Deref, Default)]
+pub struct CommitLockFile(bool);
+impl Flag for CommitLockFile {
+    const FLAG: &'static str = "--commit-lock-file";
+    const FLAG_TYPE: FlagType<Self> = FlagType::bool();
+}
+
+/// Flag for commit-lockfile-summary
+///
+/// The first line of the commit message for [CommitLockFile],
+/// nix uses `flake.lock: Update` if unset
+#[derive(Clone, From, Debug, Deref)]
+#[from(forward)]
+pub struct CommitLockfileSummary(String);
+impl Flag for CommitLockfileSummary {
+    const FLAG: &'static str = "--commit-lockfile-summary";
+    const FLAG_TYPE: FlagType<Self> = FlagType::arg();
+}
@@ -1,15 +1,23 @@
 //! Command's own arguments, Option groups and [InstallableArg]s
 
+use std::borrow::Cow;
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use derive_more::{Deref, From};
+use derive_more::{Deref, Display, From};
 use runix_derive::ToArgs;
 
 use self::common::NixCommonArgs;
 use self::config::NixConfigArgs;
+use crate::command::TemplateFlag;
+use crate::command_line::child::ExecutionMode;
+use crate::command_line::input::InputSource;
 use crate::command_line::ToArgs;
 use crate::default::flag::{Flag, FlagType};
 use crate::installable::{FlakeAttribute, Installable};
+use crate::store_path::StorePath;
+use crate::store_uri::StoreUri;
 
 pub mod common;
 pub mod config;
@@ -23,7 +31,8 @@ pub mod source;
 pub struct NixArgs {
     /// Configure the cwd for nix actions.
     ///
-    /// Relevant for instance for init and relative installables
+    /// Relevant for instance for init, relative installables and out-links.
+    /// The directory has to exist when nix is started.
     pub cwd: Option<PathBuf>,
 
     /// Common arguments to the nix command
@@ -31,11 +40,109 @@ pub struct NixArgs {
 
     /// Nix configuration (overrides nix.conf)
     pub config: NixConfigArgs,
+
+    /// Environment variables set for nix (and the processes it starts),
+    /// on top of the backend's defaults and the environment of this process
+    pub env: Vec<(OsString, OsString)>,
+
+    /// Environment variables nix does not inherit,
+    /// variables also set in [NixArgs::env] are set nonetheless
+    pub env_remove: Vec<OsString>,
+
+    /// Names of variables in [NixArgs::env] whose values are redacted in logs and previews,
+    /// see [NixArgs::with_secret_env]
+    ///
+    /// Nix run through ssh gets them on its command line,
+    /// see [NixSshCommandLine](crate::command_line::ssh::NixSshCommandLine).
+    pub secret_env: Vec<OsString>,
+
+    /// How nix shares the terminal, overriding the
+    /// [NixCliCommand::EXECUTION_MODE](crate::command_line::NixCliCommand::EXECUTION_MODE)
+    /// of the command
+    pub execution_mode: Option<ExecutionMode>,
+
+    /// What nix reads from stdin, instead of what the execution mode connects it to,
+    /// see [NixArgs::with_stdin]
+    pub stdin: Option<InputSource>,
+
+    /// Keep [NixArgs::stdin] out of previews, e.g. for keys,
+    /// see [NixArgs::with_secret_stdin]
+    pub secret_stdin: bool,
+}
+
+impl NixArgs {
+    /// Refetch branch and tarball flake refs instead of using nix' cached copies
+    ///
+    /// Sets `tarball-ttl` to zero for invocations using these args only.
+    /// Unlike nix' `--refresh` flag, which only some subcommands accept,
+    /// this is a setting that applies to any subcommand
+    /// and is carried over into [NixConfigArgs::to_config_string].
+    /// Binary cache lookups are not affected,
+    /// see the `narinfo_cache_*_ttl` settings for those.
+    pub fn fresh(mut self) -> Self {
+        self.config.tarball_ttl = Some(
+            config::TarballTtl::try_from(Duration::ZERO)
+                .expect("zero is a whole number of seconds"),
+        );
+        self
+    }
+
+    /// Set the environment variable `name` for invocations using these args only
+    ///
+    /// Unlike [std::env::set_var], this does not affect other threads
+    /// or other invocations.
+    pub fn with_env(mut self, name: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Like [NixArgs::with_env], but keep the value out of logs and previews,
+    /// e.g. for credentials
+    pub fn with_secret_env(self, name: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        let name = name.into();
+        let mut args = self.with_env(name.clone(), value);
+        args.secret_env.push(name);
+        args
+    }
+
+    /// Do not pass the environment variable `name` on to nix
+    pub fn without_env(mut self, name: impl Into<OsString>) -> Self {
+        self.env_remove.push(name.into());
+        self
+    }
+
+    /// Run nix in `cwd` instead of the working directory of this process,
+    /// see [NixArgs::cwd]
+    pub fn with_cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Feed `input` to nix on stdin, see [InputSource]
+    ///
+    /// E.g. an expression for `nix eval --file -`.
+    pub fn with_stdin(mut self, input: InputSource) -> Self {
+        self.stdin = Some(input);
+        self
+    }
+
+    /// Like [NixArgs::with_stdin], but keep the input out of previews,
+    /// e.g. for keys
+    pub fn with_secret_stdin(mut self, input: InputSource) -> Self {
+        self.secret_stdin = true;
+        self.with_stdin(input)
+    }
+
+    /// Run nix in `mode`, see [NixArgs::execution_mode]
+    pub fn with_execution_mode(mut self, mode: ExecutionMode) -> Self {
+        self.execution_mode = Some(mode);
+        self
+    }
 }
 
 impl ToArgs for NixArgs {
-    fn to_args(&self) -> Vec<String> {
-        [self.config.to_args(), self.common.to_args()]
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        [self.config.to_cow_args(), self.common.to_cow_args()]
             .into_iter()
             .flatten()
             .collect()
@@ -44,12 +151,12 @@ impl ToArgs for NixArgs {
 
 /// Installable argument for commands taking a single Installable
 /// ([approximately](https://github.com/NixOS/nix/search?q=InstallablesCommand)
-#[derive(From, Clone, Default, Debug)]
+#[derive(From, Clone, Default, Debug, Deref)]
 #[from(forward)]
 pub struct InstallableArg(Option<Installable>);
 impl ToArgs for InstallableArg {
-    fn to_args(&self) -> Vec<String> {
-        self.0.iter().map(|i| i.to_string()).collect()
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        self.0.iter().map(|i| i.to_string().into()).collect()
     }
 }
 
@@ -59,14 +166,20 @@ impl From<FlakeAttribute> for InstallableArg {
     }
 }
 
+impl From<StorePath> for InstallableArg {
+    fn from(store_path: StorePath) -> Self {
+        Self(Some(store_path.into()))
+    }
+}
+
 /// Installable argument for commands taking multiple Installables
 /// ([approximately](https://github.com/NixOS/nix/search?q=InstallablesCommand)
-#[derive(Debug, From, Default, Clone)]
+#[derive(Debug, From, Default, Clone, Deref)]
 #[from(forward)]
 pub struct InstallablesArgs(Vec<Installable>);
 impl ToArgs for InstallablesArgs {
-    fn to_args(&self) -> Vec<String> {
-        self.0.iter().map(|i| i.to_string()).collect()
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        self.0.iter().map(|i| i.to_string().into()).collect()
     }
 }
 
@@ -112,6 +225,25 @@ impl Flag for Bundler {
 #[derive(Debug, Default, Clone, ToArgs)]
 pub struct BundleArgs {
     pub bundler: Option<Bundler>,
+    pub out_link: Option<OutLink>,
+}
+
+/// `nix flake new <dest-dir>` argument
+#[derive(Clone, From, Deref, Debug)]
+#[from(forward)]
+pub struct FlakeNewDir(PathBuf);
+impl Flag for FlakeNewDir {
+    const FLAG: &'static str = "";
+    const FLAG_TYPE: FlagType<Self> =
+        FlagType::Custom(|dir| vec![dir.0.to_string_lossy().into_owned()]);
+}
+
+/// `nix flake new` options
+#[derive(Debug, Default, Clone, ToArgs)]
+pub struct FlakeNewArgs {
+    pub template: Option<TemplateFlag>,
+    /// The directory to create the flake in
+    pub dest_dir: Option<FlakeNewDir>,
 }
 
 /// `nix eval --apply <expr>` option
@@ -130,7 +262,7 @@ pub struct EvalArgs {
     pub installable: Option<InstallableArg>,
 }
 
-/// `nix store gc --dry-run` flag
+/// `nix store gc --dry-run` and `nix store add-path --dry-run` flag
 #[derive(Clone, From, Deref, Debug)]
 #[from(forward)]
 pub struct DryRun(bool);
@@ -155,6 +287,33 @@ pub struct StoreGcArgs {
     pub max: Option<Max>,
 }
 
+/// `nix store add-path --name <name>` option, the name of the store path
+#[derive(Clone, From, Deref, Debug)]
+#[from(forward)]
+pub struct AddPathName(String);
+impl Flag for AddPathName {
+    const FLAG: &'static str = "--name";
+    const FLAG_TYPE: FlagType<Self> = FlagType::arg();
+}
+
+/// The file or directory added by `nix store add-path`
+#[derive(Debug, Default, Clone, Deref, From)]
+#[from(forward)]
+pub struct AddPathSource(PathBuf);
+impl ToArgs for AddPathSource {
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        vec![self.0.to_string_lossy()]
+    }
+}
+
+/// `nix store add-path` options
+#[derive(Debug, Default, Clone, ToArgs)]
+pub struct StoreAddPathArgs {
+    pub dry_run: Option<DryRun>,
+    pub name: Option<AddPathName>,
+    pub path: AddPathSource,
+}
+
 /// `nix copy` options
 #[derive(Debug, Default, Clone, ToArgs)]
 pub struct CopyArgs {
@@ -166,7 +325,7 @@ pub struct CopyArgs {
 /// `nix copy --from` option
 #[derive(Debug, Clone, Deref, Default, From)]
 #[from(forward)]
-pub struct CopyFrom(String);
+pub struct CopyFrom(StoreUri);
 impl Flag for CopyFrom {
     const FLAG: &'static str = "--from";
     const FLAG_TYPE: FlagType<Self> = FlagType::arg();
@@ -175,7 +334,7 @@ impl Flag for CopyFrom {
 /// `nix copy --to` option
 #[derive(Debug, Clone, Deref, Default, From)]
 #[from(forward)]
-pub struct CopyTo(String);
+pub struct CopyTo(StoreUri);
 impl Flag for CopyTo {
     const FLAG: &'static str = "--to";
     const FLAG_TYPE: FlagType<Self> = FlagType::arg();
@@ -252,3 +411,90 @@ pub struct StoreSignArgs {
     pub key_file: KeyFile,
     pub recursive: Option<Recursive>,
 }
+
+/// `nix why-depends --all` flag
+#[derive(Clone, From, Deref, Debug)]
+#[from(forward)]
+pub struct All(bool);
+impl Flag for All {
+    const FLAG: &'static str = "--all";
+    const FLAG_TYPE: FlagType<Self> = FlagType::switch(false);
+}
+
+/// `nix why-depends` options
+#[derive(Debug, Default, Clone, ToArgs)]
+pub struct WhyDependsArgs {
+    pub all: Option<All>,
+    /// The package whose closure is inspected
+    pub package: InstallableArg,
+    /// The dependency that is referenced by `package`
+    pub dependency: InstallableArg,
+}
+
+/// `nix profile --profile <path>` option
+#[derive(Clone, From, Deref, Debug)]
+#[from(forward)]
+pub struct ProfilePath(PathBuf);
+impl Flag for ProfilePath {
+    const FLAG: &'static str = "--profile";
+    const FLAG_TYPE: FlagType<Self> = FlagType::os_str_arg();
+}
+
+/// `nix profile install --priority <n>` option
+///
+/// Rendered as `--priority=<n>` so that negative priorities
+/// are not mistaken for a flag
+#[derive(Clone, From, Deref, Debug)]
+#[from(forward)]
+pub struct Priority(i32);
+impl Flag for Priority {
+    const FLAG: &'static str = "--priority";
+    const FLAG_TYPE: FlagType<Self> =
+        FlagType::Custom(|priority| vec![format!("{}={}", Priority::FLAG, priority.0)]);
+}
+
+/// `nix profile install` options
+#[derive(Debug, Default, Clone, ToArgs)]
+pub struct ProfileInstallArgs {
+    pub profile: Option<ProfilePath>,
+    pub priority: Option<Priority>,
+    pub installables: InstallablesArgs,
+}
+
+/// Selects elements of a profile for `nix profile upgrade` and `nix profile remove`
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum ProfileElement {
+    /// The element's index as listed by `nix profile list`
+    Index(usize),
+    /// A regular expression matching the element's attribute path
+    Regex(String),
+    /// A store path of the element
+    StorePath(StorePath),
+    /// Every element in the profile
+    #[display(fmt = "--all")]
+    All,
+}
+
+/// A list of [ProfileElement]s
+#[derive(Debug, Default, Clone, From)]
+#[from(forward)]
+pub struct ProfileElements(Vec<ProfileElement>);
+impl ToArgs for ProfileElements {
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        self.0.iter().map(|e| e.to_string().into()).collect()
+    }
+}
+
+/// `nix profile upgrade` options
+#[derive(Debug, Default, Clone, ToArgs)]
+pub struct ProfileUpgradeArgs {
+    pub profile: Option<ProfilePath>,
+    pub elements: ProfileElements,
+}
+
+/// `nix profile remove` options
+#[derive(Debug, Default, Clone, ToArgs)]
+pub struct ProfileRemoveArgs {
+    pub profile: Option<ProfilePath>,
+    pub elements: ProfileElements,
+}
@@ -1,6 +1,7 @@
 //! Backened independent Command implementations
 
 use std::collections::HashMap;
+use std::str::FromStr;
 
 use derive_more::{Deref, From};
 use serde::Deserialize;
@@ -14,17 +15,31 @@ use crate::arguments::{
     CopyArgs,
     DevelopArgs,
     EvalArgs,
+    FlakeNewArgs,
     InstallableArg,
     InstallablesArgs,
+    NoLink,
+    OutLink,
     PathInfoArgs,
+    ProfileInstallArgs,
+    ProfileRemoveArgs,
+    ProfileUpgradeArgs,
+    StoreAddPathArgs,
     StoreGcArgs,
     StoreSignArgs,
+    WhyDependsArgs,
 };
+use crate::command_line::child::ExecutionMode;
 use crate::command_line::flag::{Flag, FlagType};
-use crate::command_line::{Group, JsonCommand, NixCliCommand, TypedCommand};
+use crate::command_line::limit::InvocationCost;
+use crate::command_line::{ArgConflict, Group, JsonCommand, NixCliCommand, TypedCommand};
 use crate::flake_ref::FlakeRef;
 use crate::installable::Installable;
 use crate::narinfo::Narinfo;
+use crate::store_path::{StorePath, StorePathError};
+
+/// Experimental features required by commands that may evaluate flakes
+const FLAKE_FEATURES: &[&str] = &["nix-command", "flakes"];
 
 /// `nix build` Command
 #[derive(Debug, Default, Clone)]
@@ -39,12 +54,25 @@ pub struct Build {
 impl NixCliCommand for Build {
     type Own = BuildArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| d.build.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.build);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["build"];
+
+    fn validate(&self) -> Result<(), ArgConflict> {
+        if self.build.out_link.is_some() && self.build.no_link.as_deref() == Some(&true) {
+            return Err(ArgConflict {
+                first: OutLink::FLAG,
+                second: NoLink::FLAG,
+                reason: "no result symlink is created to name".to_string(),
+            });
+        }
+        Ok(())
+    }
 }
 impl JsonCommand for Build {}
 #[derive(Deserialize, Clone, Debug)]
@@ -52,8 +80,8 @@ impl JsonCommand for Build {}
 /// Type for an element in the output of `nix build --json`
 pub struct BuildOutEntry {
     #[serde(rename = "drvPath")]
-    pub drv_path: String,
-    pub outputs: HashMap<String, String>,
+    pub drv_path: StorePath,
+    pub outputs: HashMap<String, StorePath>,
 }
 
 /// The output of `nix build --json`
@@ -84,13 +112,35 @@ impl Flag for TemplateFlag {
 impl NixCliCommand for FlakeInit {
     type Own = Option<TemplateFlag>;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-    const OWN_ARGS: Group<Self, Option<TemplateFlag>> = Some(|d| d.template.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const OWN_ARGS: Group<Self, Option<TemplateFlag>> = Some(|d| &d.template);
     const SUBCOMMAND: &'static [&'static str] = &["flake", "init"];
 }
 
+/// `nix flake new` Command
+///
+/// Like [FlakeInit], but creates the flake in [FlakeNewArgs::dest_dir]
+/// instead of the current directory.
+#[derive(Debug, Default, Clone)]
+pub struct FlakeNew {
+    pub flake: FlakeArgs,
+    pub eval: EvaluationArgs,
+    pub flake_new: FlakeNewArgs,
+}
+
+impl NixCliCommand for FlakeNew {
+    type Own = FlakeNewArgs;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.flake_new);
+    const SUBCOMMAND: &'static [&'static str] = &["flake", "new"];
+}
+
 /// `nix flake metadata <FLAKE_REF>` flag
 #[derive(Deref, Debug, Clone, From)]
 #[from(forward)]
@@ -111,10 +161,15 @@ pub struct FlakeMetadata {
 impl NixCliCommand for FlakeMetadata {
     type Own = Option<FlakeRefArg>;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| d.flake_ref.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.flake_ref);
     const SUBCOMMAND: &'static [&'static str] = &["flake", "metadata"];
+
+    fn flake_refs(&self) -> Vec<FlakeRef> {
+        self.flake_ref.iter().map(|arg| arg.0.clone()).collect()
+    }
 }
 impl JsonCommand for FlakeMetadata {}
 impl TypedCommand for FlakeMetadata {
@@ -132,10 +187,62 @@ pub struct FlakeUpdate {
 impl NixCliCommand for FlakeUpdate {
     type Own = Option<FlakeRefArg>;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| d.flake_ref.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.flake_ref);
     const SUBCOMMAND: &'static [&'static str] = &["flake", "update"];
+
+    fn flake_refs(&self) -> Vec<FlakeRef> {
+        self.flake_ref.iter().map(|arg| arg.0.clone()).collect()
+    }
+}
+
+/// `nix flake lock` Command
+///
+/// Like [FlakeUpdate], but only adds missing inputs to the lock file
+#[derive(Debug, Default, Clone)]
+pub struct FlakeLock {
+    pub eval: EvaluationArgs,
+    pub flake: FlakeArgs,
+    pub flake_ref: Option<FlakeRefArg>,
+}
+
+impl NixCliCommand for FlakeLock {
+    type Own = Option<FlakeRefArg>;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.flake_ref);
+    const SUBCOMMAND: &'static [&'static str] = &["flake", "lock"];
+
+    fn flake_refs(&self) -> Vec<FlakeRef> {
+        self.flake_ref.iter().map(|arg| arg.0.clone()).collect()
+    }
+}
+
+/// `nix flake check` Command
+#[derive(Debug, Default, Clone)]
+pub struct FlakeCheck {
+    pub eval: EvaluationArgs,
+    pub flake: FlakeArgs,
+    pub flake_ref: Option<FlakeRefArg>,
+}
+
+impl NixCliCommand for FlakeCheck {
+    type Own = Option<FlakeRefArg>;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.flake_ref);
+    const SUBCOMMAND: &'static [&'static str] = &["flake", "check"];
+
+    fn flake_refs(&self) -> Vec<FlakeRef> {
+        self.flake_ref.iter().map(|arg| arg.0.clone()).collect()
+    }
 }
 
 /// `nix develop` Command
@@ -146,17 +253,23 @@ pub struct Develop {
     pub source: SourceArgs,
     pub installable: InstallableArg,
     pub develop_args: DevelopArgs,
+    /// Passed after `--`, never interpreted as nix options
+    pub args_after_double_dash: Vec<String>,
 }
 
 impl NixCliCommand for Develop {
     type Own = DevelopArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLE: Group<Self, InstallableArg> = Some(|d| d.installable.clone());
-    const OWN_ARGS: Group<Self, DevelopArgs> = Some(|d| d.develop_args.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXECUTION_MODE: ExecutionMode = ExecutionMode::Inherit;
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLE: Group<Self, InstallableArg> = Some(|d| &d.installable);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, DevelopArgs> = Some(|d| &d.develop_args);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["develop"];
+    const TRAILING_ARGS: Group<Self, Vec<String>> = Some(|d| &d.args_after_double_dash);
 }
 
 /// `nix eval` Command
@@ -171,11 +284,24 @@ pub struct Eval {
 impl NixCliCommand for Eval {
     type Own = EvalArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const OWN_ARGS: Group<Self, EvalArgs> = Some(|d| d.eval_args.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const OWN_ARGS: Group<Self, EvalArgs> = Some(|d| &d.eval_args);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["eval"];
+
+    /// `nix eval` takes its installable as part of [EvalArgs]
+    fn installables(&self) -> Vec<Installable> {
+        if self.source.expr.is_some() {
+            return Vec::new();
+        }
+        self.eval_args
+            .installable
+            .iter()
+            .flat_map(|i| i.as_ref().cloned())
+            .collect()
+    }
 }
 impl JsonCommand for Eval {}
 
@@ -186,16 +312,23 @@ pub struct Run {
     pub eval: EvaluationArgs,
     pub source: SourceArgs,
     pub installable: InstallableArg,
+    /// Arguments to the program, passed after `--`
+    /// so they are never interpreted as nix options
+    pub args_after_double_dash: Vec<String>,
 }
 
 impl NixCliCommand for Run {
     type Own = ();
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLE: Group<Self, InstallableArg> = Some(|d| d.installable.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXECUTION_MODE: ExecutionMode = ExecutionMode::Inherit;
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLE: Group<Self, InstallableArg> = Some(|d| &d.installable);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["run"];
+    const TRAILING_ARGS: Group<Self, Vec<String>> = Some(|d| &d.args_after_double_dash);
 }
 impl JsonCommand for Run {}
 impl TypedCommand for Run {
@@ -214,10 +347,13 @@ pub struct Shell {
 impl NixCliCommand for Shell {
     type Own = ();
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXECUTION_MODE: ExecutionMode = ExecutionMode::Inherit;
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["shell"];
 }
 impl JsonCommand for Shell {}
@@ -238,17 +374,16 @@ pub struct Bundle {
 impl NixCliCommand for Bundle {
     type Own = BundleArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLE: Group<Self, InstallableArg> = Some(|d| d.installable.clone());
-    const OWN_ARGS: Group<Self, BundleArgs> = Some(|d| d.bundle_args.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLE: Group<Self, InstallableArg> = Some(|d| &d.installable);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, BundleArgs> = Some(|d| &d.bundle_args);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["bundle"];
 }
 impl JsonCommand for Bundle {}
-impl TypedCommand for Bundle {
-    type Output = ();
-}
 
 /// `nix store gc` Command
 #[derive(Debug, Default, Clone)]
@@ -260,7 +395,7 @@ pub struct StoreGc {
 impl NixCliCommand for StoreGc {
     type Own = StoreGcArgs;
 
-    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| d.store_gc.clone());
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.store_gc);
     const SUBCOMMAND: &'static [&'static str] = &["store", "gc"];
 }
 
@@ -278,10 +413,12 @@ pub struct NixCopy {
 impl NixCliCommand for NixCopy {
     type Own = CopyArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| d.copy_args.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.copy_args);
     const SUBCOMMAND: &'static [&'static str] = &["copy"];
 }
 
@@ -298,11 +435,12 @@ pub struct PathInfo {
 impl NixCliCommand for PathInfo {
     type Own = PathInfoArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-    const OWN_ARGS: Group<Self, PathInfoArgs> = Some(|d| d.path_info.clone());
-    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const OWN_ARGS: Group<Self, PathInfoArgs> = Some(|d| &d.path_info);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
     const SUBCOMMAND: &'static [&'static str] = &["path-info"];
 }
 impl JsonCommand for PathInfo {}
@@ -325,9 +463,488 @@ pub struct StoreSign {
 impl NixCliCommand for StoreSign {
     type Own = StoreSignArgs;
 
-    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| d.store_sign.clone());
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.store_sign);
     const SUBCOMMAND: &'static [&'static str] = &["store", "sign"];
 }
+
+/// `nix store add-path` Command
+///
+/// Runs to the [StorePath] of the added file or directory.
+#[derive(Debug, Default, Clone)]
+pub struct StoreAddPath {
+    pub add_path: StoreAddPathArgs,
+}
+
+impl NixCliCommand for StoreAddPath {
+    type Own = StoreAddPathArgs;
+
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.add_path);
+    const SUBCOMMAND: &'static [&'static str] = &["store", "add-path"];
+}
+
+/// `nix store make-content-addressed` Command
+///
+/// `--from` and `--to` name the stores the paths are read from and written to.
+#[derive(Debug, Default, Clone)]
+pub struct StoreMakeContentAddressed {
+    pub copy_args: CopyArgs,
+    pub eval: EvaluationArgs,
+    pub flake: FlakeArgs,
+    pub installables: InstallablesArgs,
+}
+
+impl NixCliCommand for StoreMakeContentAddressed {
+    type Own = CopyArgs;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.copy_args);
+    const SUBCOMMAND: &'static [&'static str] = &["store", "make-content-addressed"];
+}
+impl JsonCommand for StoreMakeContentAddressed {}
+impl TypedCommand for StoreMakeContentAddressed {
+    type Output = MakeContentAddressedOut;
+}
+
+/// The output of `nix store make-content-addressed --json`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct MakeContentAddressedOut {
+    /// The content addressed path each of the paths and their dependencies was copied to
+    pub rewrites: HashMap<StorePath, StorePath>,
+}
+
+/// `nix why-depends` Command
+#[derive(Debug, Default, Clone)]
+pub struct WhyDepends {
+    pub eval: EvaluationArgs,
+    pub flake: FlakeArgs,
+    pub source: SourceArgs,
+    pub why_depends: WhyDependsArgs,
+}
+
+impl NixCliCommand for WhyDepends {
+    type Own = WhyDependsArgs;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.why_depends);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
+    const SUBCOMMAND: &'static [&'static str] = &["why-depends"];
+}
+
+/// `nix profile install` Command
+#[derive(Debug, Default, Clone)]
+pub struct ProfileInstall {
+    pub flake: FlakeArgs,
+    pub eval: EvaluationArgs,
+    pub source: SourceArgs,
+    pub profile_install: ProfileInstallArgs,
+}
+
+impl NixCliCommand for ProfileInstall {
+    type Own = ProfileInstallArgs;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.profile_install);
+    const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
+    const SUBCOMMAND: &'static [&'static str] = &["profile", "install"];
+}
+
+/// `nix profile upgrade` Command
+#[derive(Debug, Default, Clone)]
+pub struct ProfileUpgrade {
+    pub flake: FlakeArgs,
+    pub eval: EvaluationArgs,
+    pub profile_upgrade: ProfileUpgradeArgs,
+}
+
+impl NixCliCommand for ProfileUpgrade {
+    type Own = ProfileUpgradeArgs;
+
+    const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = FLAKE_FEATURES;
+    const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+    const INVOCATION_COST: InvocationCost = InvocationCost::Expensive;
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.profile_upgrade);
+    const SUBCOMMAND: &'static [&'static str] = &["profile", "upgrade"];
+}
+
+/// `nix profile remove` Command
+#[derive(Debug, Default, Clone)]
+pub struct ProfileRemove {
+    pub profile_remove: ProfileRemoveArgs,
+}
+
+impl NixCliCommand for ProfileRemove {
+    type Own = ProfileRemoveArgs;
+
+    const OWN_ARGS: Group<Self, Self::Own> = Some(|d| &d.profile_remove);
+    const SUBCOMMAND: &'static [&'static str] = &["profile", "remove"];
+}
+
+/// A chain of store paths, each referencing the next one
+pub type DependencyChain = Vec<StorePath>;
+
+/// The output of `nix why-depends`
+///
+/// Nix prints the dependency graph as a tree:
+///
+/// ```text
+/// /nix/store/...-python3-3.11.6
+/// ├───/nix/store/...-openssl-3.0.12
+/// │   └───/nix/store/...-glibc-2.38-27
+/// └───/nix/store/...-glibc-2.38-27
+/// ```
+///
+/// which is parsed into one [DependencyChain] per branch of the tree.
+/// Without `--all`, nix only prints a single branch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhyDependsOut {
+    pub chains: Vec<DependencyChain>,
+}
+
+impl FromStr for WhyDependsOut {
+    type Err = StorePathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chains = Vec::new();
+        // (column, path) of the current branch
+        let mut branch: Vec<(usize, StorePath)> = Vec::new();
+        // whether the last path of `branch` has no children (yet)
+        let mut is_leaf = false;
+
+        for line in s.lines() {
+            let Some(start) = line.find('/') else {
+                continue;
+            };
+
+            // skip lines that are not part of the tree, e.g. `--precise` annotations
+            let indent = &line[..start];
+            if !indent
+                .chars()
+                .all(|c| matches!(c, ' ' | '│' | '├' | '└' | '─' | '→'))
+            {
+                continue;
+            }
+
+            let column = indent.chars().count();
+            let path = line[start..]
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .parse::<StorePath>()?;
+
+            while matches!(branch.last(), Some((parent, _)) if *parent >= column) {
+                if is_leaf {
+                    chains.push(branch.iter().map(|(_, path)| path.clone()).collect());
+                    is_leaf = false;
+                }
+                branch.pop();
+            }
+
+            branch.push((column, path));
+            is_leaf = true;
+        }
+
+        if is_leaf {
+            chains.push(branch.into_iter().map(|(_, path)| path).collect());
+        }
+
+        Ok(WhyDependsOut { chains })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arguments::ProfileElement;
+    use crate::flake_ref::indirect::IndirectRef;
+    use crate::installable::FlakeAttribute;
+    use crate::store_uri::StoreUri;
+
+    const HELLO: &str = "/nix/store/8vq9f8bq9ayn3ih4hz3ihc2jjq3d5mgl-hello-2.12.1";
+    const GLIBC: &str = "/nix/store/1zy01hjzwvvia6h9dq5xar88v77fgh9x-glibc-2.38-27";
+    const PYTHON: &str = "/nix/store/qp5zys77biz7imbk6yy85q5pdv7qk84j-python3-3.11.6";
+    const OPENSSL: &str = "/nix/store/3mz6x7sb2y1q2k4pn4n0ssv5pmm9s13z-openssl-3.0.12";
+
+    #[test]
+    fn build_rejects_out_link_with_no_link() {
+        let mut build = Build {
+            build: BuildArgs {
+                out_link: Some("result-dev".into()),
+                no_link: Some(true.into()),
+            },
+            ..Default::default()
+        };
+        let conflict = build.validate().unwrap_err();
+        assert_eq!(
+            (conflict.first, conflict.second),
+            ("--out-link", "--no-link")
+        );
+
+        build.build.no_link = Some(false.into());
+        build.validate().unwrap();
+    }
+
+    fn rust_template() -> TemplateFlag {
+        FlakeAttribute {
+            flakeref: FlakeRef::Indirect(IndirectRef::new("templates".into(), Default::default())),
+            attr_path: ["rust"].try_into().unwrap(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn flake_init_args() {
+        // `nix flake init` creates the flake in its working directory,
+        // see [NixArgs::cwd](crate::arguments::NixArgs::cwd)
+        let command = FlakeInit {
+            template: Some(rust_template()),
+            ..Default::default()
+        };
+
+        assert_eq!(command.args(), vec!["--template", "flake:templates#rust"]);
+    }
+
+    #[test]
+    fn flake_new_args() {
+        let command = FlakeNew {
+            flake_new: FlakeNewArgs {
+                template: Some(rust_template()),
+                dest_dir: Some("/tmp/my-project".into()),
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(command.args(), vec![
+            "--template",
+            "flake:templates#rust",
+            "/tmp/my-project"
+        ]);
+    }
+
+    #[test]
+    fn commits_lock_file_with_summary() {
+        let flake = FlakeArgs {
+            commit_lock_file: true.into(),
+            commit_lockfile_summary: Some("flake.lock: Update nixpkgs to 23.11".into()),
+            ..Default::default()
+        };
+        let expected = vec![
+            "--commit-lock-file",
+            "--commit-lockfile-summary",
+            "flake.lock: Update nixpkgs to 23.11",
+        ];
+
+        let update = FlakeUpdate {
+            flake: flake.clone(),
+            ..Default::default()
+        };
+        assert_eq!(update.args(), expected);
+        let lock = FlakeLock {
+            flake,
+            ..Default::default()
+        };
+        assert_eq!(lock.args(), expected);
+    }
+
+    #[test]
+    fn why_depends_args() {
+        let command = WhyDepends {
+            why_depends: WhyDependsArgs {
+                all: Some(true.into()),
+                package: StorePath::from_path(HELLO).unwrap().into(),
+                dependency: StorePath::from_path(GLIBC).unwrap().into(),
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(command.args(), vec!["--all", HELLO, GLIBC]);
+    }
+
+    #[test]
+    fn profile_install_negative_priority() {
+        let command = ProfileInstall {
+            profile_install: ProfileInstallArgs {
+                profile: Some("/tmp/profile".into()),
+                priority: Some((-5).into()),
+                installables: vec![Installable::from(StorePath::from_path(HELLO).unwrap())].into(),
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(command.args(), vec![
+            "--profile",
+            "/tmp/profile",
+            "--priority=-5",
+            HELLO
+        ]);
+    }
+
+    #[test]
+    fn profile_elements_args() {
+        let command = ProfileUpgrade {
+            profile_upgrade: ProfileUpgradeArgs {
+                elements: vec![ProfileElement::All].into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(command.args(), vec!["--all"]);
+
+        let command = ProfileRemove {
+            profile_remove: ProfileRemoveArgs {
+                elements: vec![
+                    ProfileElement::Index(0),
+                    ProfileElement::Regex(".*hello.*".to_string()),
+                    ProfileElement::StorePath(StorePath::from_path(HELLO).unwrap()),
+                ]
+                .into(),
+                ..Default::default()
+            },
+        };
+        assert_eq!(command.args(), vec!["0", ".*hello.*", HELLO]);
+    }
+
+    #[test]
+    fn bundle_args() {
+        let bundler = FlakeAttribute {
+            flakeref: FlakeRef::Indirect(IndirectRef::new("bundlers".into(), Default::default())),
+            attr_path: ["toArx"].try_into().unwrap(),
+        };
+        let command = Bundle {
+            installable: StorePath::from_path(HELLO).unwrap().into(),
+            bundle_args: BundleArgs {
+                bundler: Some(Installable::from(bundler).into()),
+                out_link: Some("hello-bundle".into()),
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(command.args(), vec![
+            HELLO,
+            "--bundler",
+            "flake:bundlers#toArx",
+            "--out-link",
+            "hello-bundle"
+        ]);
+    }
+
+    #[test]
+    fn run_trailing_args() {
+        let command = Run {
+            installable: StorePath::from_path(HELLO).unwrap().into(),
+            args_after_double_dash: vec!["--greeting".to_string(), "hi".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(command.args(), vec![HELLO]);
+        assert_eq!(command.trailing_args(), vec!["--", "--greeting", "hi"]);
+        assert!(Run::default().trailing_args().is_empty());
+    }
+
+    #[test]
+    fn store_add_path_args() {
+        let command = StoreAddPath {
+            add_path: StoreAddPathArgs {
+                name: Some("hello-src".into()),
+                path: "./src".into(),
+                ..Default::default()
+            },
+        };
+        assert_eq!(command.args(), vec!["--name", "hello-src", "./src"]);
+
+        let command = StoreAddPath {
+            add_path: StoreAddPathArgs {
+                dry_run: Some(true.into()),
+                path: "/tmp/hello world".into(),
+                ..Default::default()
+            },
+        };
+        assert_eq!(command.args(), vec!["--dry-run", "/tmp/hello world"]);
+    }
+
+    #[test]
+    fn store_make_content_addressed_args() {
+        let command = StoreMakeContentAddressed {
+            copy_args: CopyArgs {
+                to: Some("file:///tmp/cache".parse::<StoreUri>().unwrap().into()),
+                ..Default::default()
+            },
+            installables: vec![
+                StorePath::from_path(HELLO).unwrap().into(),
+                StorePath::from_path(GLIBC).unwrap().into(),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(command.args(), vec![
+            HELLO,
+            GLIBC,
+            "--to",
+            "file:///tmp/cache"
+        ]);
+    }
+
+    #[test]
+    fn parses_content_addressed_rewrites() {
+        let ca_hello = "/nix/store/gdi2m5jsjprcvbd4rxmiz0xrgv5ja0sj-hello-2.12.1";
+        let ca_glibc = "/nix/store/yddmpw6v1j4qxl35aiw5shl7wj4dlgpw-glibc-2.38-27";
+        let output = format!(r#"{{"rewrites":{{"{GLIBC}":"{ca_glibc}","{HELLO}":"{ca_hello}"}}}}"#);
+
+        let parsed: MakeContentAddressedOut = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed.rewrites.len(), 2);
+        assert_eq!(
+            parsed.rewrites[&StorePath::from_path(HELLO).unwrap()],
+            StorePath::from_path(ca_hello).unwrap()
+        );
+        assert_eq!(
+            parsed.rewrites[&StorePath::from_path(GLIBC).unwrap()],
+            StorePath::from_path(ca_glibc).unwrap()
+        );
+
+        let parsed: MakeContentAddressedOut = serde_json::from_str(r#"{"rewrites":{}}"#).unwrap();
+        assert!(parsed.rewrites.is_empty());
+    }
+
+    #[test]
+    fn parses_single_chain() {
+        let output = format!("{HELLO}\n└───{GLIBC}\n");
+        let parsed: WhyDependsOut = output.parse().unwrap();
+
+        assert_eq!(parsed.chains, vec![vec![
+            StorePath::from_path(HELLO).unwrap(),
+            StorePath::from_path(GLIBC).unwrap(),
+        ]]);
+    }
+
+    #[test]
+    fn parses_all_chains() {
+        let output = format!("{PYTHON}\n├───{OPENSSL}\n│   └───{GLIBC}\n└───{GLIBC}\n",);
+        let parsed: WhyDependsOut = output.parse().unwrap();
+
+        let [python, openssl, glibc] =
+            [PYTHON, OPENSSL, GLIBC].map(|path| StorePath::from_path(path).unwrap());
+        assert_eq!(parsed.chains, vec![
+            vec![python.clone(), openssl, glibc.clone()],
+            vec![python, glibc],
+        ]);
+    }
+
+    #[test]
+    fn parses_empty() {
+        let parsed: WhyDependsOut = "".parse().unwrap();
+        assert!(parsed.chains.is_empty());
+    }
+}
@@ -0,0 +1,513 @@
+//! Direct control over a nix process, see [RunningNix]
+
+use std::process::{ExitStatus, Output, Stdio};
+
+use tokio::io::AsyncRead;
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+
+use super::input::InputSource;
+use super::instrument::{self, Invocation, Stream};
+use super::limit::Permit;
+use super::{NixCommandLine, NixCommandLineError};
+
+/// How a stdio stream of nix is connected, see [SpawnOptions]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StdioMode {
+    /// Shared with the calling process, e.g. the terminal
+    #[default]
+    Inherit,
+    /// Available from the [RunningNix], e.g. [RunningNix::take_stdout]
+    Piped,
+    /// Connected to `/dev/null`
+    Null,
+}
+
+impl From<StdioMode> for Stdio {
+    fn from(mode: StdioMode) -> Self {
+        match mode {
+            StdioMode::Inherit => Stdio::inherit(),
+            StdioMode::Piped => Stdio::piped(),
+            StdioMode::Null => Stdio::null(),
+        }
+    }
+}
+
+/// How nix shares the stdio of the calling process when a command is [run](crate::Run)
+///
+/// Set for an invocation with [NixArgs::execution_mode](crate::arguments::NixArgs::execution_mode),
+/// otherwise the [NixCliCommand::EXECUTION_MODE](super::NixCliCommand::EXECUTION_MODE)
+/// of the command applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// Collect the output of nix and print it once nix exited,
+    /// failures are reported with the error nix printed
+    ///
+    /// Stderr is printed as nix prints it, e.g. build logs and warnings.
+    Captured,
+    /// Let nix own the terminal, e.g. for the shell started by `nix develop`
+    ///
+    /// Only the exit status of nix is reported.
+    /// Ctrl-C reaches nix rather than ending the calling process while nix runs,
+    /// nix dying of it or failing after it is reported as
+    /// [NixCommandLineRunError::Interrupted](super::NixCommandLineRunError::Interrupted).
+    /// How the calling process handles `SIGINT` is restored once nix exited.
+    Inherit,
+    /// Like [ExecutionMode::Captured], but connect stdin to `/dev/null`
+    /// and print nothing, so nix is kept off the terminal entirely
+    Piped,
+}
+
+/// What happens to nix if its [RunningNix] is dropped before nix exited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDrop {
+    /// Send `SIGKILL` to nix, processes nix started keep running
+    #[default]
+    Kill,
+    /// Leave nix running, it is reaped in the background once it exits
+    Detach,
+}
+
+/// How [NixCommandLine::spawn_nix] starts nix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpawnOptions {
+    pub stdin: StdioMode,
+    pub stdout: StdioMode,
+    pub stderr: StdioMode,
+    pub on_drop: OnDrop,
+}
+
+/// A nix process started by [NixCommandLine::spawn_nix]
+///
+/// Every nix invocation of [NixCommandLine] runs as a [RunningNix],
+/// the [Run](crate::Run) implementations wait for it to exit.
+/// Dropping it before nix exited kills or detaches nix, see [OnDrop].
+#[derive(Debug)]
+pub struct RunningNix {
+    child: Child,
+    on_drop: OnDrop,
+    status: Option<ExitStatus>,
+    invocation: Invocation,
+    /// The slot of [NixCommandLine::max_concurrent_invocations] nix runs in
+    permit: Option<Permit>,
+}
+
+impl RunningNix {
+    /// Start `command`, connected to the stdio it has been configured with
+    /// unless `stdin` replaces its stdin
+    pub(super) fn start(
+        command: &mut Command,
+        on_drop: OnDrop,
+        backend: &NixCommandLine,
+        invocation: Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<Self, NixCommandLineError> {
+        let feed = match stdin {
+            Some(source) => source.connect(command)?,
+            None => None,
+        };
+        let child = command.spawn().map_err(|err| backend.start_error(err))?;
+        invocation.spawned(child.id());
+        let mut nix = RunningNix {
+            child,
+            on_drop,
+            status: None,
+            invocation,
+            permit: None,
+        };
+        if let Some(feed) = feed {
+            let stdin = nix.take_stdin().expect("stdin is piped");
+            feed.start(stdin).map_err(NixCommandLineError::Run)?;
+        }
+        Ok(nix)
+    }
+
+    /// Hold `permit` until nix exited or this is dropped
+    pub(super) fn holding(mut self, permit: Option<Permit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    /// The process id of nix, [None] once it has been waited for
+    pub fn pid(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Wait for nix to exit
+    ///
+    /// Stdin is closed before waiting, so that nix does not wait for input.
+    /// Waiting again returns the same status.
+    pub async fn wait(&mut self) -> Result<ExitStatus, NixCommandLineError> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        let status = self.child.wait().await.map_err(NixCommandLineError::Run)?;
+        Ok(self.exited(status))
+    }
+
+    /// The exit status of nix if it exited, without waiting for it
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, NixCommandLineError> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+        let status = self.child.try_wait().map_err(NixCommandLineError::Run)?;
+        Ok(status.map(|status| self.exited(status)))
+    }
+
+    /// Send `SIGKILL` to nix and wait for it to exit
+    ///
+    /// Processes started by nix are not killed.
+    /// Has no effect if nix already exited.
+    pub async fn kill(&mut self) -> Result<(), NixCommandLineError> {
+        if self.try_wait()?.is_none() {
+            self.child.start_kill().map_err(NixCommandLineError::Run)?;
+            self.wait().await?;
+        }
+        Ok(())
+    }
+
+    /// Send `signal` to nix, e.g. to forward a `SIGINT` the caller received
+    ///
+    /// Fails if nix has already been waited for.
+    pub fn signal(&self, signal: libc::c_int) -> Result<(), NixCommandLineError> {
+        let Some(pid) = self.pid() else {
+            return Err(NixCommandLineError::Run(std::io::Error::from_raw_os_error(
+                libc::ESRCH,
+            )));
+        };
+        // SAFETY: `kill` has no memory safety requirements
+        match unsafe { libc::kill(pid as libc::pid_t, signal) } {
+            0 => Ok(()),
+            _ => Err(NixCommandLineError::Run(std::io::Error::last_os_error())),
+        }
+    }
+
+    /// Wait for nix to exit, reading piped stdout and stderr to their end
+    ///
+    /// Streams that are not piped or have been taken are returned empty.
+    pub async fn wait_with_output(mut self) -> Result<Output, NixCommandLineError> {
+        let stdout = self.take_stdout();
+        let stderr = self.take_stderr();
+        let (stdout, stderr) = tokio::try_join!(
+            read_piped(stdout, &self.invocation, Stream::Stdout),
+            read_piped(stderr, &self.invocation, Stream::Stderr),
+        )
+        .map_err(NixCommandLineError::Run)?;
+
+        Ok(Output {
+            status: self.wait().await?,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// The stdin of nix if it is piped, closing it lets nix read to its end
+    ///
+    /// Stdin fed from an [InputSource] is not available.
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    /// The stdout of nix if it is piped
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    /// The stderr of nix if it is piped
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.child.stderr.take()
+    }
+
+    /// Change what happens to nix if this is dropped before nix exited
+    pub fn set_on_drop(&mut self, on_drop: OnDrop) {
+        self.on_drop = on_drop;
+    }
+
+    /// The span of this invocation, for tasks reading the output of nix
+    pub(super) fn invocation(&self) -> &Invocation {
+        &self.invocation
+    }
+
+    fn exited(&mut self, status: ExitStatus) -> ExitStatus {
+        self.invocation.exited(status);
+        self.status = Some(status);
+        self.permit = None;
+        status
+    }
+}
+
+impl Drop for RunningNix {
+    fn drop(&mut self) {
+        if self.on_drop == OnDrop::Kill && self.status.is_none() {
+            // fails if nix already exited, which is fine
+            let _ = self.child.start_kill();
+        }
+    }
+}
+
+/// Read `stream` to its end if it is piped
+async fn read_piped(
+    stream: Option<impl AsyncRead + Unpin>,
+    invocation: &Invocation,
+    which: Stream,
+) -> std::io::Result<Vec<u8>> {
+    match stream {
+        Some(stream) => instrument::read_to_end(stream, invocation, which).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::arguments::NixArgs;
+    use crate::command::Build;
+    use crate::command_line::limit::{InvocationCost, InvocationLimits};
+    use crate::command_line::retry::RetryPolicy;
+    use crate::command_line::tests::script_fixture;
+    use crate::command_line::{Collect, NixCommandLineCollectError};
+
+    fn is_alive(pid: u32) -> bool {
+        Path::new(&format!("/proc/{pid}")).exists()
+    }
+
+    #[tokio::test]
+    async fn kills_nix() {
+        let (_tempdir, backend) = script_fixture("exec sleep 1000");
+        let mut nix = backend
+            .spawn_nix(
+                &Build::default(),
+                &NixArgs::default(),
+                SpawnOptions::default(),
+            )
+            .await
+            .unwrap();
+        let pid = nix.pid().unwrap();
+        assert!(is_alive(pid));
+        assert_eq!(nix.try_wait().unwrap(), None);
+
+        nix.kill().await.unwrap();
+        let status = nix.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(!is_alive(pid));
+    }
+
+    #[tokio::test]
+    async fn forwards_signals() {
+        let (_tempdir, backend) = script_fixture("exec sleep 1000");
+        let mut nix = backend
+            .spawn_nix(
+                &Build::default(),
+                &NixArgs::default(),
+                SpawnOptions::default(),
+            )
+            .await
+            .unwrap();
+        nix.signal(libc::SIGTERM).unwrap();
+        let status = nix.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert!(nix.signal(libc::SIGTERM).is_err());
+    }
+
+    #[tokio::test]
+    async fn pipes_stdio() {
+        let (_tempdir, backend) = script_fixture("cat");
+        let options = SpawnOptions {
+            stdin: StdioMode::Piped,
+            stdout: StdioMode::Piped,
+            ..Default::default()
+        };
+        let mut nix = backend
+            .spawn_nix(&Build::default(), &NixArgs::default(), options)
+            .await
+            .unwrap();
+        let mut stdin = nix.take_stdin().unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stdin, b"hello")
+            .await
+            .unwrap();
+        drop(stdin);
+
+        let mut stdout = String::new();
+        nix.take_stdout()
+            .unwrap()
+            .read_to_string(&mut stdout)
+            .await
+            .unwrap();
+        assert_eq!(stdout, "hello");
+        assert!(nix.take_stderr().is_none());
+        assert!(nix.wait().await.unwrap().success());
+    }
+
+    /// Megabytes of data that are not the same in every pipe buffer
+    fn payload() -> Vec<u8> {
+        (0..8 << 20).map(|i: u32| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn feeds_stdin_while_reading_output() {
+        // cat blocks on writing its output until it is read,
+        // which would never be if all of stdin was written first
+        let (_tempdir, backend) = script_fixture("exec cat");
+        let options = SpawnOptions {
+            stdout: StdioMode::Piped,
+            ..Default::default()
+        };
+        let nix_args = NixArgs::default().with_stdin(InputSource::Bytes(payload()));
+        let mut nix = backend
+            .spawn_nix(&Build::default(), &nix_args, options)
+            .await
+            .unwrap();
+        assert!(nix.take_stdin().is_none());
+
+        let mut stdout = Vec::new();
+        let mut pipe = nix.take_stdout().unwrap();
+        let read = pipe.read_to_end(&mut stdout);
+        tokio::time::timeout(std::time::Duration::from_secs(30), read)
+            .await
+            .expect("nix and the stdin writer deadlocked")
+            .unwrap();
+        assert!(stdout == payload(), "stdout differs from stdin");
+        assert!(nix.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn feeds_stdin_from_reader() {
+        let (_tempdir, backend) = script_fixture("exec cat");
+        let nix_args =
+            NixArgs::default().with_stdin(InputSource::reader(io::Cursor::new(payload())));
+        let output = backend
+            .run_with_output(&Build::default(), &nix_args, |_| {}, |_| {})
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout == payload(), "stdout differs from stdin");
+
+        // the reader is used up
+        let err = backend
+            .run_with_output(&Build::default(), &nix_args, |_| {}, |_| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixCommandLineError::StdinUsedUp));
+    }
+
+    /// A reader can not be fed to a retry, unlike bytes
+    #[tokio::test]
+    async fn retries_replayable_stdin_only() {
+        let (tempdir, mut backend) = script_fixture(
+            r#"cat >> "$(dirname "$0")/stdin"
+echo "error: unable to download 'https://example.com/flake.tar.gz': HTTP error 502" >&2
+exit 1"#,
+        );
+        backend.retry = Some(RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            jitter: 0.0,
+            ..Default::default()
+        });
+        let stdin = tempdir.path().join("stdin");
+        let run = |nix_args: NixArgs| {
+            let backend = &backend;
+            async move {
+                backend
+                    .run_command::<Collect, _, _>(&Build::default(), &nix_args, false)
+                    .await
+                    .unwrap_err()
+            }
+        };
+
+        let err = run(NixArgs::default().with_stdin(InputSource::Bytes(b"x".to_vec()))).await;
+        assert!(matches!(err, NixCommandLineCollectError::Failed { .. }));
+        assert_eq!(std::fs::read_to_string(&stdin).unwrap(), "xxx");
+
+        std::fs::remove_file(&stdin).unwrap();
+        let err = run(NixArgs::default().with_stdin(InputSource::reader(&b"x"[..]))).await;
+        assert!(matches!(err, NixCommandLineCollectError::Failed { .. }));
+        assert_eq!(std::fs::read_to_string(&stdin).unwrap(), "x");
+    }
+
+    #[tokio::test]
+    async fn ignores_unread_stdin() {
+        let (_tempdir, backend) = script_fixture("exit 0");
+        let nix_args = NixArgs::default().with_stdin(InputSource::Bytes(payload()));
+        let output = backend
+            .run_with_output(&Build::default(), &nix_args, |_| {}, |_| {})
+            .await
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    #[tokio::test]
+    async fn drop_kills_or_detaches() {
+        let (_tempdir, backend) = script_fixture("exec sleep 1000");
+        let spawn = |on_drop| {
+            let options = SpawnOptions {
+                on_drop,
+                ..Default::default()
+            };
+            let backend = &backend;
+            async move {
+                backend
+                    .spawn_nix(&Build::default(), &NixArgs::default(), options)
+                    .await
+            }
+        };
+
+        let killed = spawn(OnDrop::Kill).await.unwrap();
+        let killed_pid = killed.pid().unwrap();
+        let detached = spawn(OnDrop::Detach).await.unwrap();
+        let detached_pid = detached.pid().unwrap();
+        drop(killed);
+        drop(detached);
+
+        let mut waited = 0;
+        while is_alive(killed_pid) && waited < 100 {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            waited += 1;
+        }
+        assert!(!is_alive(killed_pid), "nix has not been killed");
+        assert!(is_alive(detached_pid), "detached nix has been killed");
+        // SAFETY: `kill` has no memory safety requirements
+        unsafe { libc::kill(detached_pid as libc::pid_t, libc::SIGKILL) };
+    }
+
+    #[tokio::test]
+    async fn waits_for_invocation_limit() {
+        let (_tempdir, mut backend) = script_fixture("exec sleep 1000");
+        let limits = InvocationLimits::new().with_limit(InvocationCost::Expensive, 1);
+        backend.max_concurrent_invocations = Some(limits.clone());
+        let spawn = |backend: NixCommandLine| async move {
+            backend
+                .spawn_nix(
+                    &Build::default(),
+                    &NixArgs::default(),
+                    SpawnOptions::default(),
+                )
+                .await
+                .unwrap()
+        };
+
+        // waiting for the slot does not block the thread of this single threaded runtime
+        let mut first = spawn(backend.clone()).await;
+        let second = tokio::spawn(spawn(backend.clone()));
+        while limits.queued(InvocationCost::Expensive) == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        assert_eq!(limits.running(InvocationCost::Expensive), 1);
+
+        // the slot is freed once nix exited
+        first.kill().await.unwrap();
+        let mut second = second.await.unwrap();
+        assert_eq!(limits.queued(InvocationCost::Expensive), 0);
+        assert_eq!(limits.running(InvocationCost::Expensive), 1);
+        drop(first);
+        assert_eq!(limits.running(InvocationCost::Expensive), 1);
+
+        second.kill().await.unwrap();
+        assert_eq!(limits.running(InvocationCost::Expensive), 0);
+    }
+}
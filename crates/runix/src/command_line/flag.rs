@@ -3,8 +3,10 @@
 //! This module defines the [Flag] trait adn [FlagType] type.
 //! Thes are used to _generate_ arguments (as oppoed to parsing them).
 
+use std::borrow::Cow;
 use std::ffi::OsStr;
 use std::ops::Deref;
+use std::time::Duration;
 
 use super::ToArgs;
 
@@ -32,6 +34,12 @@ pub enum FlagType<T> {
     ///
     /// Mainly used by nix-config values
     Switch(bool, fn(&T) -> bool),
+    /// An explicitly set nix setting
+    ///
+    /// Always printed as `--option <name> <value>`, where `<name>` is the flag
+    /// without its leading dashes.
+    /// Wrap the flag in an [Option] to leave the setting to nix.
+    Setting(fn(&T) -> String),
     /// A boolean flag
     ///
     /// Flags of this kind just print their name as is regardless of the content
@@ -79,11 +87,15 @@ impl<T: Deref<Target = bool>> FlagType<T> {
     pub const fn switch(default: bool) -> FlagType<T> {
         FlagType::Switch(default, |s| *s.deref())
     }
+
+    pub const fn setting() -> FlagType<T> {
+        FlagType::Setting(|s| s.deref().to_string())
+    }
 }
 
-impl<T: Deref<Target = Vec<String>>> FlagType<T> {
+impl<T: Deref<Target = Vec<impl ToString>>> FlagType<T> {
     pub const fn list() -> FlagType<T> {
-        FlagType::List(|s| s.deref().to_owned())
+        FlagType::List(|s| s.deref().iter().map(ToString::to_string).collect())
     }
 }
 
@@ -119,42 +131,50 @@ impl<T: Deref<Target = u32>> FlagType<T> {
     }
 }
 
+impl<T: Deref<Target = Duration>> FlagType<T> {
+    /// A duration, passed as whole seconds
+    pub const fn seconds_arg() -> FlagType<T> {
+        FlagType::Arg(|s| s.deref().as_secs().to_string())
+    }
+}
+
 impl<T> ToArgs for T
 where
     T: Flag,
 {
-    fn to_args(&self) -> Vec<String> {
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
         match Self::FLAG_TYPE {
             FlagType::Indicator(f) => match f(self) {
-                true => vec![Self::FLAG.to_string()],
+                true => vec![Self::FLAG.into()],
                 false => Default::default(),
             },
             FlagType::Switch(default, f) => match (f(self), default) {
-                (true, false) => vec![Self::FLAG.to_string()],
-                (false, true) => vec![format!("--no-{}", &Self::FLAG[2..])],
+                (true, false) => vec![Self::FLAG.into()],
+                (false, true) => vec![format!("--no-{}", &Self::FLAG[2..]).into()],
                 _ => Default::default(),
             },
+            FlagType::Setting(f) => {
+                vec!["--option".into(), Self::FLAG[2..].into(), f(self).into()]
+            },
             // Todo: should --listarg "" be allowed?
             FlagType::List(f) => {
                 let list = f(self);
                 match list.is_empty() {
                     true => Default::default(),
-                    false => vec![Self::FLAG.to_string(), f(self).join(" ")],
+                    false => vec![Self::FLAG.into(), list.join(" ").into()],
                 }
             },
-            FlagType::Arg(f) => vec![Self::FLAG.to_string(), f(self)],
+            FlagType::Arg(f) => vec![Self::FLAG.into(), f(self).into()],
             FlagType::Args(f) => {
-                let mut list = f(self);
+                let list = f(self);
                 match list.is_empty() {
                     true => Default::default(),
-                    false => {
-                        let mut flags = vec![Self::FLAG.to_string()];
-                        flags.append(&mut list);
-                        flags
-                    },
+                    false => std::iter::once(Self::FLAG.into())
+                        .chain(list.into_iter().map(Cow::Owned))
+                        .collect(),
                 }
             },
-            FlagType::Custom(f) => f(self),
+            FlagType::Custom(f) => f(self).into_iter().map(Cow::Owned).collect(),
         }
     }
 }
@@ -0,0 +1,149 @@
+//! Data fed to the stdin of nix, see [InputSource]
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use log::debug;
+use tokio::process::{ChildStdin, Command};
+
+use super::NixCommandLineError;
+
+/// What nix reads from stdin, set with [NixArgs::stdin](crate::arguments::NixArgs::stdin)
+///
+/// Replaces the stdin an invocation would otherwise get,
+/// e.g. `/dev/null` for [ExecutionMode::Piped](super::child::ExecutionMode::Piped).
+/// Data is written to nix from a thread of its own while its output is read,
+/// so nix may print any amount of output before it read all of its input.
+/// Nix exiting before it read all of its input is not an error,
+/// the rest of the input is dropped.
+///
+/// The data is never logged, [Debug] shows its size only.
+pub enum InputSource {
+    /// The stdin of this process, e.g. the terminal
+    Inherit,
+    /// Bytes written to nix, then stdin is closed
+    ///
+    /// A command that is retried (see [RetryPolicy](super::retry::RetryPolicy))
+    /// reads the bytes again on every attempt.
+    Bytes(Vec<u8>),
+    /// A reader that is copied to nix until it ends, then stdin is closed,
+    /// see [InputSource::reader]
+    ///
+    /// The reader is used up by the first invocation, so a command reading it is not retried.
+    /// Any later invocation with the same [NixArgs](crate::arguments::NixArgs)
+    /// fails with [NixCommandLineError::StdinUsedUp].
+    Reader(Mutex<Option<Box<dyn Read + Send>>>),
+}
+
+impl InputSource {
+    /// Feed the output of `reader` to nix
+    pub fn reader(reader: impl Read + Send + 'static) -> Self {
+        InputSource::Reader(Mutex::new(Some(Box::new(reader))))
+    }
+
+    /// A stand-in for the data in previews, see
+    /// [CommandPreview::stdin](super::preview::CommandPreview::stdin)
+    ///
+    /// Text is shown as it is, other data by its size.
+    pub(super) fn describe(&self) -> Option<String> {
+        match self {
+            InputSource::Inherit => None,
+            InputSource::Bytes(bytes) => Some(match std::str::from_utf8(bytes) {
+                Ok(text) => text.to_string(),
+                Err(_) => format!("<{} bytes>", bytes.len()),
+            }),
+            InputSource::Reader(_) => Some("<reader>".to_string()),
+        }
+    }
+
+    /// Whether every invocation reads the same input,
+    /// which is not the case for a [InputSource::Reader]
+    pub(super) fn is_replayable(&self) -> bool {
+        !matches!(self, InputSource::Reader(_))
+    }
+
+    /// Connect the stdin of `command` to this source,
+    /// returning what needs to be written once nix started
+    pub(super) fn connect(
+        &self,
+        command: &mut Command,
+    ) -> Result<Option<Feed>, NixCommandLineError> {
+        let feed = match self {
+            InputSource::Inherit => {
+                command.stdin(Stdio::inherit());
+                return Ok(None);
+            },
+            InputSource::Bytes(bytes) => Feed::Bytes(bytes.clone()),
+            InputSource::Reader(reader) => Feed::Reader(
+                reader
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .take()
+                    .ok_or(NixCommandLineError::StdinUsedUp)?,
+            ),
+        };
+        command.stdin(Stdio::piped());
+        Ok(Some(feed))
+    }
+}
+
+impl fmt::Debug for InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputSource::Inherit => f.write_str("Inherit"),
+            InputSource::Bytes(bytes) => write!(f, "Bytes(<{} bytes>)", bytes.len()),
+            InputSource::Reader(_) => f.write_str("Reader(..)"),
+        }
+    }
+}
+
+/// The input of a started invocation, see [InputSource::connect]
+pub(super) enum Feed {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Feed {
+    /// Write the input to `stdin` from a new thread, closing it once all is written
+    pub(super) fn start(self, stdin: ChildStdin) -> io::Result<()> {
+        // the thread writes to a blocking duplicate of the pipe tokio made non-blocking
+        let pipe = stdin.as_fd().try_clone_to_owned()?;
+        drop(stdin);
+        set_blocking(&pipe)?;
+        let mut pipe = File::from(pipe);
+
+        std::thread::Builder::new()
+            .name("nix-stdin".to_string())
+            .spawn(move || {
+                let written = match self {
+                    Feed::Bytes(bytes) => pipe.write_all(&bytes),
+                    Feed::Reader(mut reader) => io::copy(&mut reader, &mut pipe).map(drop),
+                };
+                match written {
+                    Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                        debug!("nix exited before reading all of stdin")
+                    },
+                    Err(err) => debug!("could not write to stdin of nix: {err}"),
+                    Ok(()) => {},
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// Clear `O_NONBLOCK` of the open file `fd` refers to
+fn set_blocking(fd: &OwnedFd) -> io::Result<()> {
+    let fd = fd.as_raw_fd();
+    // SAFETY: `fcntl` with `F_GETFL` and `F_SETFL` only reads and sets flags of a valid fd
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
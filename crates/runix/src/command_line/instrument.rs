@@ -0,0 +1,375 @@
+//! Tracing spans and metrics around nix invocations, see [Invocation]
+//!
+//! With the `tracing` feature, every invocation of nix by [NixCommandLine](super::NixCommandLine)
+//! runs in a span named `nix`, a child of the caller's current span, with the fields
+//!
+//! - `command`: the subcommand, e.g. `flake update`
+//! - `argv`: the program and its arguments, with access tokens redacted
+//!   (see [CommandPreview](super::preview::CommandPreview))
+//! - `exit_status`: how nix exited
+//! - `duration_ms`: the time from starting nix until it exited
+//! - `stdout_bytes` and `stderr_bytes`: how much nix printed, where the output is read
+//!
+//! Debug events mark when nix was spawned, printed its first output (where it is read
+//! while nix runs) and exited.
+//! Tasks and threads reading the output of nix run in the span, too.
+//!
+//! Without the feature the span is compiled out.
+//! The same numbers are reported to the [MetricsSink] of the backend, if it has one.
+
+use std::io::Read;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[cfg(not(feature = "tracing"))]
+use disabled::Trace;
+#[cfg(feature = "tracing")]
+use enabled::Trace;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+
+use super::metrics::{InvocationMetrics, MetricsSink};
+
+/// Which stream of nix output was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Read `stream` to its end, counting the output for `invocation` as it arrives
+pub(crate) async fn read_to_end(
+    mut stream: impl AsyncRead + Unpin,
+    invocation: &Invocation,
+    which: Stream,
+) -> std::io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    loop {
+        match stream.read_buf(&mut output).await? {
+            0 => return Ok(output),
+            n => invocation.read(which, n),
+        }
+    }
+}
+
+/// A [Read] counting the output of nix for an [Invocation]
+pub(crate) struct Counted<R> {
+    pub(crate) inner: R,
+    pub(crate) invocation: Invocation,
+    pub(crate) stream: Stream,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.invocation.read(self.stream, n);
+        Ok(n)
+    }
+}
+
+/// The span of a nix invocation and the output counted for it,
+/// reported to a [MetricsSink] once nix exited
+#[derive(Debug, Clone)]
+pub(crate) struct Invocation {
+    subcommand: &'static [&'static str],
+    trace: Trace,
+    counts: Arc<Counts>,
+    sink: Option<Arc<dyn MetricsSink>>,
+    retries: u32,
+}
+
+#[derive(Debug)]
+struct Counts {
+    started: Instant,
+    stdout: AtomicUsize,
+    stderr: AtomicUsize,
+}
+
+impl Invocation {
+    /// Open the span for running `command`, an invocation of the nix `subcommand`
+    pub(crate) fn start(subcommand: &'static [&'static str], command: &Command) -> Self {
+        Invocation {
+            subcommand,
+            trace: Trace::start(subcommand, command),
+            counts: Arc::new(Counts {
+                started: Instant::now(),
+                stdout: AtomicUsize::new(0),
+                stderr: AtomicUsize::new(0),
+            }),
+            sink: None,
+            retries: 0,
+        }
+    }
+
+    /// Report the invocation to `sink` once nix exited,
+    /// as the retry of `retries` earlier attempts
+    pub(crate) fn with_metrics(mut self, sink: Option<Arc<dyn MetricsSink>>, retries: u32) -> Self {
+        self.sink = sink;
+        self.retries = retries;
+        self
+    }
+
+    pub(crate) fn spawned(&self, pid: Option<u32>) {
+        self.trace.spawned(pid);
+    }
+
+    /// Count `bytes` read from `stream` while nix runs
+    pub(crate) fn read(&self, stream: Stream, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let counter = match stream {
+            Stream::Stdout => &self.counts.stdout,
+            Stream::Stderr => &self.counts.stderr,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+        self.trace.read(stream);
+    }
+
+    /// Record how nix exited and how much it printed
+    pub(crate) fn exited(&self, status: ExitStatus) {
+        let metrics = InvocationMetrics {
+            command: self.subcommand,
+            duration: self.counts.started.elapsed(),
+            exit_status: status,
+            stdout_bytes: self.counts.stdout.load(Ordering::Relaxed),
+            stderr_bytes: self.counts.stderr.load(Ordering::Relaxed),
+            retries: self.retries,
+        };
+        self.trace.exited(&metrics);
+        if let Some(sink) = &self.sink {
+            sink.on_invocation(&metrics);
+        }
+    }
+
+    /// Run `future` in the span, e.g. a task reading the output of nix
+    pub(crate) fn instrument<F>(&self, future: F) -> Instrumented<F> {
+        self.trace.instrument(future)
+    }
+
+    /// Run `f` in the span on another thread,
+    /// with the subscriber of the thread that started nix
+    pub(crate) fn in_thread<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.trace.in_thread(f)
+    }
+}
+
+/// A future run in the span of an [Invocation]
+#[cfg(feature = "tracing")]
+pub(crate) type Instrumented<F> = tracing::instrument::Instrumented<F>;
+/// A future run in the span of an [Invocation]
+#[cfg(not(feature = "tracing"))]
+pub(crate) type Instrumented<F> = F;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use tokio::process::Command;
+    use tracing::field::Empty;
+    use tracing::instrument::Instrumented;
+    use tracing::{Dispatch, Instrument, Span};
+
+    use super::Stream;
+    use crate::command_line::metrics::InvocationMetrics;
+    use crate::command_line::preview::CommandPreview;
+
+    /// The span of a nix invocation
+    #[derive(Debug, Clone)]
+    pub(super) struct Trace {
+        span: Span,
+        dispatch: Dispatch,
+        printed: Arc<AtomicBool>,
+    }
+
+    impl Trace {
+        pub(super) fn start(subcommand: &[&str], command: &Command) -> Self {
+            let command = command.as_std();
+            let argv = CommandPreview::new(
+                command.get_program().to_string_lossy(),
+                command
+                    .get_args()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+            );
+            let argv = std::iter::once(&argv.program)
+                .chain(&argv.args)
+                .collect::<Vec<_>>();
+            let span = tracing::info_span!(
+                "nix",
+                command = subcommand.join(" "),
+                argv = ?argv,
+                exit_status = Empty,
+                duration_ms = Empty,
+                stdout_bytes = Empty,
+                stderr_bytes = Empty,
+            );
+            Trace {
+                span,
+                dispatch: tracing::dispatcher::get_default(Dispatch::clone),
+                printed: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        pub(super) fn spawned(&self, pid: Option<u32>) {
+            tracing::debug!(parent: &self.span, pid, "spawned nix");
+        }
+
+        pub(super) fn read(&self, stream: Stream) {
+            if !self.printed.swap(true, Ordering::Relaxed) {
+                tracing::debug!(parent: &self.span, ?stream, "first output of nix");
+            }
+        }
+
+        pub(super) fn exited(&self, metrics: &InvocationMetrics) {
+            let status = metrics.exit_status;
+            self.span
+                .record("exit_status", tracing::field::display(status))
+                .record("duration_ms", metrics.duration.as_millis() as u64)
+                .record("stdout_bytes", metrics.stdout_bytes as u64)
+                .record("stderr_bytes", metrics.stderr_bytes as u64);
+            tracing::debug!(
+                parent: &self.span,
+                %status,
+                duration = ?metrics.duration,
+                "nix exited"
+            );
+        }
+
+        pub(super) fn instrument<F>(&self, future: F) -> Instrumented<F> {
+            future.instrument(self.span.clone())
+        }
+
+        pub(super) fn in_thread<T>(&self, f: impl FnOnce() -> T) -> T {
+            tracing::dispatcher::with_default(&self.dispatch, || self.span.in_scope(f))
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use tokio::process::Command;
+
+    use super::Stream;
+    use crate::command_line::metrics::InvocationMetrics;
+
+    /// Does nothing without the `tracing` feature
+    #[derive(Debug, Clone)]
+    pub(super) struct Trace;
+
+    impl Trace {
+        pub(super) fn start(_subcommand: &[&str], _command: &Command) -> Self {
+            Trace
+        }
+
+        pub(super) fn spawned(&self, _pid: Option<u32>) {}
+
+        pub(super) fn read(&self, _stream: Stream) {}
+
+        pub(super) fn exited(&self, _metrics: &InvocationMetrics) {}
+
+        pub(super) fn instrument<F>(&self, future: F) -> F {
+            future
+        }
+
+        pub(super) fn in_thread<T>(&self, f: impl FnOnce() -> T) -> T {
+            f()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    use crate::arguments::config::NixConfigArgs;
+    use crate::arguments::NixArgs;
+    use crate::command::Build;
+    use crate::command_line::tests::echo_fixture;
+    use crate::command_line::Collect;
+
+    /// The fields of `nix` spans and the messages of events inside them
+    #[derive(Default, Clone)]
+    struct Collected {
+        fields: Arc<Mutex<HashMap<String, String>>>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Fields<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Collected {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            if attrs.metadata().name() == "nix" {
+                attrs.record(&mut Fields(&mut self.fields.lock().unwrap()));
+            }
+        }
+
+        fn on_record(&self, _span: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut Fields(&mut self.fields.lock().unwrap()));
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            if ctx
+                .event_span(event)
+                .is_some_and(|span| span.name() == "nix")
+            {
+                let mut fields = HashMap::new();
+                event.record(&mut Fields(&mut fields));
+                self.events.lock().unwrap().extend(fields.remove("message"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn records_invocation_span() {
+        let (_tempdir, mut backend) = echo_fixture();
+        backend.defaults.config_args = NixConfigArgs {
+            extra_access_tokens: vec![("github.com".to_string(), "ghp_secret".to_string())].into(),
+            ..Default::default()
+        };
+        let collected = Collected::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(collected.clone()),
+        );
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap();
+
+        let fields = collected.fields.lock().unwrap();
+        assert_eq!(fields["command"], "build");
+        assert!(fields["argv"].contains("github.com=<redacted>"));
+        assert!(!fields["argv"].contains("ghp_secret"));
+        assert_eq!(fields["exit_status"], "exit status: 0");
+        assert!(fields.contains_key("duration_ms"));
+        assert_eq!(fields["stdout_bytes"], output.stdout.len().to_string());
+        assert_eq!(fields["stderr_bytes"], "0");
+        assert_eq!(
+            *collected.events.lock().unwrap(),
+            ["spawned nix", "first output of nix", "nix exited"]
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Keeping `SIGINT` from ending this process while nix owns the terminal,
+//! see [InterruptGuard]
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// How many times this process received `SIGINT` while an [InterruptGuard] was held
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many [InterruptGuard]s are held, and the action for `SIGINT` they replaced
+static GUARDS: Mutex<(usize, Option<libc::sigaction>)> = Mutex::new((0, None));
+
+extern "C" fn count_interrupt(_signal: libc::c_int) {
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts `SIGINT` instead of letting it end this process, as long as it is held
+///
+/// The action for `SIGINT` that was set before the first guard is restored
+/// once the last guard is dropped, e.g. the default of ending the process
+/// or the handler of [tokio::signal::ctrl_c].
+/// A caught signal is reset to its default in programs started by this process,
+/// so nix is interrupted by Ctrl-C as usual.
+pub(super) struct InterruptGuard {
+    interrupts: usize,
+}
+
+impl InterruptGuard {
+    pub(super) fn new() -> io::Result<Self> {
+        let mut guards = guards();
+        if guards.0 == 0 {
+            // SAFETY: the action is fully initialized and its handler is async-signal-safe
+            let previous = unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = count_interrupt as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(libc::SIGINT, &action, &mut previous) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                previous
+            };
+            guards.1 = Some(previous);
+        }
+        guards.0 += 1;
+        Ok(InterruptGuard {
+            interrupts: INTERRUPTS.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Whether this process received `SIGINT` since the guard was created
+    pub(super) fn interrupted(&self) -> bool {
+        INTERRUPTS.load(Ordering::Relaxed) != self.interrupts
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        let mut guards = guards();
+        guards.0 -= 1;
+        if guards.0 == 0 {
+            if let Some(previous) = guards.1.take() {
+                // SAFETY: `previous` has been returned by `sigaction`
+                unsafe { libc::sigaction(libc::SIGINT, &previous, std::ptr::null_mut()) };
+            }
+        }
+    }
+}
+
+fn guards() -> MutexGuard<'static, (usize, Option<libc::sigaction>)> {
+    // the count and action are consistent after every update, a panic can not corrupt them
+    GUARDS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
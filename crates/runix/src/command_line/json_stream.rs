@@ -0,0 +1,356 @@
+//! JSON values read from the output of nix while it runs, see [JsonStream]
+
+use std::io::{BufReader, Read, Write};
+use std::process::{ExitStatus, Output};
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::io::SyncIoBridge;
+
+use super::child::RunningNix;
+use super::instrument::{self, Counted, Stream};
+use super::{failure, route_stderr, NixCommandLineCollectError, NixCommandLineError};
+
+/// How many parsed values are buffered until they are taken from the stream
+const BUFFERED_VALUES: usize = 64;
+
+/// How many bytes before and after a parse error are kept for [JsonStreamError::Parse]
+const LINE_LIMIT: usize = 4096;
+
+/// Values parsed from the stdout of a command started by
+/// [NixCommandLine::run_json_stream](super::NixCommandLine::run_json_stream)
+///
+/// Stdout is parsed on a blocking thread while nix is running,
+/// so that only the values not yet taken with [JsonStream::next] are held in memory.
+/// The stream ends after the last value or the first error.
+/// Stderr is collected for [JsonStream::finish].
+#[derive(Debug)]
+pub struct JsonStream<T> {
+    nix: RunningNix,
+    values: mpsc::Receiver<Result<T, JsonStreamError>>,
+    parser: JoinHandle<()>,
+    stderr: JoinHandle<std::io::Result<Vec<u8>>>,
+    captured_output_limit: usize,
+}
+
+/// Errors reading values from a [JsonStream]
+#[derive(Error, Debug)]
+pub enum JsonStreamError {
+    /// Nix printed something that is not JSON or not a `T`
+    ///
+    /// `line` is the line of stdout the error is in,
+    /// long lines are cut to the bytes around the error.
+    #[error("Invalid JSON in the line at byte {offset} of the output of nix: {source}\n{line}")]
+    Parse {
+        /// The byte offset of `line` in stdout
+        offset: usize,
+        line: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Could not read the output of nix")]
+    Read(#[source] std::io::Error),
+}
+
+impl<T: DeserializeOwned + Send + 'static> JsonStream<T> {
+    /// Take over a spawned nix process with piped stdout and stderr
+    pub(super) fn new(mut nix: RunningNix, captured_output_limit: usize) -> Self {
+        let invocation = nix.invocation().clone();
+        let stdout = Counted {
+            inner: SyncIoBridge::new(nix.take_stdout().expect("stdout is piped")),
+            invocation: invocation.clone(),
+            stream: Stream::Stdout,
+        };
+        let (sender, values) = mpsc::channel(BUFFERED_VALUES);
+        let parser_invocation = invocation.clone();
+        let parser = tokio::task::spawn_blocking(move || {
+            parser_invocation.in_thread(|| parse(stdout, sender))
+        });
+
+        let stderr_pipe = nix.take_stderr().expect("stderr is piped");
+        let stderr_invocation = invocation.clone();
+        let stderr = tokio::spawn(invocation.instrument(async move {
+            instrument::read_to_end(stderr_pipe, &stderr_invocation, Stream::Stderr).await
+        }));
+
+        JsonStream {
+            nix,
+            values,
+            parser,
+            stderr,
+            captured_output_limit,
+        }
+    }
+}
+
+impl<T> JsonStream<T> {
+    /// The next value nix printed, `None` once stdout is closed or after an error
+    pub async fn next(&mut self) -> Option<Result<T, JsonStreamError>> {
+        self.values.recv().await
+    }
+
+    /// Wait for nix to exit, discarding values that were not taken
+    ///
+    /// Stderr is handled like in [Collect](super::Collect),
+    /// a failure of nix is reported the same way.
+    /// As stdout is not kept, it is missing from [CapturedOutput](super::CapturedOutput).
+    pub async fn finish(self) -> Result<ExitStatus, NixCommandLineCollectError> {
+        let JsonStream {
+            mut nix,
+            values,
+            parser,
+            stderr,
+            captured_output_limit,
+        } = self;
+        // the parser skips the rest of stdout once nobody takes values
+        drop(values);
+        parser
+            .await
+            .map_err(|err| NixCommandLineError::Run(std::io::Error::other(err)))?;
+
+        let status = nix.wait().await?;
+        let stderr = stderr
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|stderr| stderr)
+            .map_err(NixCommandLineError::Run)?;
+
+        let output = Output {
+            status,
+            stdout: Vec::new(),
+            stderr: route_stderr(&stderr),
+        };
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .map_err(NixCommandLineError::Run)?;
+
+        if !status.success() {
+            return Err(failure(&output, captured_output_limit));
+        }
+        Ok(status)
+    }
+}
+
+/// Send the values in `stdout` until it ends, an error occurs or `values` is closed,
+/// then read the rest of `stdout` so that nix is not blocked writing to it
+fn parse<T: DeserializeOwned>(stdout: impl Read, values: mpsc::Sender<Result<T, JsonStreamError>>) {
+    let mut reader = LineRecorder::new(BufReader::new(stdout));
+    let mut stream = serde_json::Deserializer::from_reader(&mut reader).into_iter::<T>();
+
+    let error = loop {
+        match stream.next() {
+            Some(Ok(value)) => {
+                if values.blocking_send(Ok(value)).is_err() {
+                    break None;
+                }
+            },
+            Some(Err(err)) => break Some(err),
+            None => break None,
+        }
+    };
+
+    if let Some(err) = error {
+        let err = match err.is_io() {
+            true => JsonStreamError::Read(err.into()),
+            false => {
+                let (offset, line) = reader.line();
+                JsonStreamError::Parse {
+                    offset,
+                    line,
+                    source: err,
+                }
+            },
+        };
+        // the stream may have been dropped already
+        let _ = values.blocking_send(Err(err));
+    }
+
+    let _ = std::io::copy(&mut reader.inner, &mut std::io::sink());
+}
+
+/// Remembers the line that is being read, and the one before,
+/// as the parser may have read past the end of the line it fails on
+struct LineRecorder<R> {
+    inner: R,
+    /// The number of bytes read
+    read: usize,
+    current: RecordedLine,
+    previous: Option<RecordedLine>,
+}
+
+/// The end of a line, at most `2 * LINE_LIMIT` bytes
+#[derive(Default)]
+struct RecordedLine {
+    offset: usize,
+    dropped: usize,
+    bytes: Vec<u8>,
+}
+
+impl RecordedLine {
+    /// The line as text, noting how much of its start was dropped
+    fn render(self) -> (usize, String) {
+        let line = String::from_utf8_lossy(&self.bytes);
+        let line = match self.dropped {
+            0 => line.into_owned(),
+            dropped => format!("[{dropped} bytes truncated]{line}"),
+        };
+        (self.offset, line)
+    }
+}
+
+impl<R: Read> LineRecorder<R> {
+    fn new(inner: R) -> Self {
+        LineRecorder {
+            inner,
+            read: 0,
+            current: RecordedLine::default(),
+            previous: None,
+        }
+    }
+
+    /// The offset and content of the line the parser stopped in,
+    /// reading the rest of it
+    fn line(&mut self) -> (usize, String) {
+        if self.current.bytes.is_empty() {
+            if let Some(previous) = self.previous.take() {
+                return previous.render();
+            }
+        }
+
+        let mut rest = Vec::new();
+        let _ = (&mut self.inner)
+            .take(LINE_LIMIT as u64)
+            .read_to_end(&mut rest);
+        let rest = rest.split(|b| *b == b'\n').next().unwrap_or_default();
+
+        let mut line = std::mem::take(&mut self.current);
+        line.bytes.extend_from_slice(rest);
+        line.render()
+    }
+}
+
+impl<R: Read> Read for LineRecorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for (i, byte) in buf[..n].iter().enumerate() {
+            match byte {
+                b'\n' => {
+                    let next = RecordedLine {
+                        offset: self.read + i + 1,
+                        ..Default::default()
+                    };
+                    self.previous = Some(std::mem::replace(&mut self.current, next));
+                },
+                byte => {
+                    let line = &mut self.current;
+                    if line.bytes.len() == 2 * LINE_LIMIT {
+                        line.bytes.drain(..LINE_LIMIT);
+                        line.dropped += LINE_LIMIT;
+                    }
+                    line.bytes.push(*byte);
+                },
+            }
+        }
+        self.read += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::arguments::NixArgs;
+    use crate::command::Eval;
+    use crate::command_line::tests::script_fixture;
+
+    #[derive(Debug, PartialEq, Eq, Deserialize)]
+    struct Item {
+        n: u32,
+    }
+
+    #[tokio::test]
+    async fn streams_ndjson() {
+        // the last value only arrives after the first two were taken
+        let (tempdir, backend) = script_fixture(
+            "printf '{\"n\": 1}\\n{\"n\": 2}\\n'\nwhile [ ! -e \"$0.taken\" ]; do sleep 0.01; done\nprintf '{\"n\": 3}\\n'",
+        );
+
+        let mut stream = backend
+            .run_json_stream::<Item, _>(&Eval::default(), &NixArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), Item { n: 1 });
+        assert_eq!(stream.next().await.unwrap().unwrap(), Item { n: 2 });
+        std::fs::write(tempdir.path().join("nix.taken"), "").unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), Item { n: 3 });
+        assert!(stream.next().await.is_none());
+
+        assert!(stream.finish().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn reports_corrupted_line() {
+        let (_tempdir, backend) = script_fixture(
+            "printf '{\"n\": 1}\\n{\"n\": 2, oops}\\n{\"n\": 3}\\n'\necho 'warning: corrupted' >&2",
+        );
+
+        let mut stream = backend
+            .run_json_stream::<Item, _>(&Eval::default(), &NixArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), Item { n: 1 });
+        let err = stream.next().await.unwrap().unwrap_err();
+        let JsonStreamError::Parse { offset, line, .. } = &err else {
+            panic!("expected a parse error, got {err:?}");
+        };
+        assert_eq!(*offset, 9);
+        assert_eq!(line, "{\"n\": 2, oops}");
+        assert!(err.to_string().contains("at byte 9"), "{err}");
+        assert!(stream.next().await.is_none());
+
+        assert!(stream.finish().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn reports_failure_after_values() {
+        let (_tempdir, backend) =
+            script_fixture("echo '{\"n\": 1}'\necho 'error: it broke' >&2\nexit 1");
+
+        let mut stream = backend
+            .run_json_stream::<Item, _>(&Eval::default(), &NixArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), Item { n: 1 });
+        assert!(stream.next().await.is_none());
+
+        let err = stream.finish().await.unwrap_err();
+        assert_eq!(err.output().unwrap().stderr, b"error: it broke\n");
+    }
+
+    #[test]
+    fn records_long_lines() {
+        let mut text = "x".repeat(3 * LINE_LIMIT);
+        text.push_str("\nnext");
+        let mut reader = LineRecorder::new(text.as_bytes());
+        let mut buf = vec![0; 2 * LINE_LIMIT + 10];
+        reader.read_exact(&mut buf).unwrap();
+
+        let (offset, line) = reader.line();
+        assert_eq!(offset, 0);
+        assert_eq!(
+            line,
+            format!(
+                "[{LINE_LIMIT} bytes truncated]{}",
+                "x".repeat(2 * LINE_LIMIT)
+            )
+        );
+
+        let mut reader = LineRecorder::new("first\nsecond\n".as_bytes());
+        reader.read_exact(&mut [0; 13]).unwrap();
+        assert_eq!(reader.line(), (6, "second".to_string()));
+    }
+}
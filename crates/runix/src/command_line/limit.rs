@@ -0,0 +1,240 @@
+//! Limits on how many nix processes run at once, see [InvocationLimits]
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+/// How demanding running a command is, deciding which of the [InvocationLimits] applies,
+/// see [NixCliCommand::INVOCATION_COST](super::NixCliCommand::INVOCATION_COST)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvocationCost {
+    /// Commands that evaluate or read metadata, e.g. `nix eval` or `nix path-info`
+    Cheap,
+    /// Commands that build or copy store paths, e.g. `nix build` or `nix copy`
+    Expensive,
+}
+
+/// How many nix processes of each [InvocationCost] a backend runs at once,
+/// see [NixCommandLine::max_concurrent_invocations](super::NixCommandLine::max_concurrent_invocations)
+///
+/// Invocations beyond a limit wait until another one of the same cost finished,
+/// in no particular order.
+/// Clones share their limits, so do clones of a backend,
+/// i.e. all invocations of backends cloned from one count against the same limits.
+///
+/// ```
+/// # use runix::command_line::limit::{InvocationCost, InvocationLimits};
+/// let limits = InvocationLimits::new()
+///     .with_limit(InvocationCost::Cheap, 8)
+///     .with_limit(InvocationCost::Expensive, 2);
+/// assert_eq!(limits.limit(InvocationCost::Expensive), Some(2));
+/// assert_eq!(limits.queued(InvocationCost::Expensive), 0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InvocationLimits {
+    cheap: Option<Arc<Limit>>,
+    expensive: Option<Arc<Limit>>,
+}
+
+impl InvocationLimits {
+    /// No limits, until added with [InvocationLimits::with_limit]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run at most `max` commands of `cost` at once
+    ///
+    /// A `max` of 0 is treated as 1, as commands would wait forever otherwise.
+    /// Replaces the limit for `cost`, clones made before keep the previous one.
+    pub fn with_limit(mut self, cost: InvocationCost, max: usize) -> Self {
+        *self.slot_mut(cost) = Some(Arc::new(Limit::new(max.max(1))));
+        self
+    }
+
+    /// The limit for commands of `cost`, [None] if they are not limited
+    pub fn limit(&self, cost: InvocationCost) -> Option<usize> {
+        self.slot(cost).map(|limit| limit.max)
+    }
+
+    /// How many invocations of `cost` are waiting for others to finish
+    pub fn queued(&self, cost: InvocationCost) -> usize {
+        self.slot(cost).map_or(0, |limit| limit.state().queued)
+    }
+
+    /// How many invocations of `cost` are running,
+    /// only counted while they are limited
+    pub fn running(&self, cost: InvocationCost) -> usize {
+        self.slot(cost).map_or(0, |limit| limit.state().running)
+    }
+
+    /// Wait until a command of `cost` may run
+    ///
+    /// The command may run as long as the returned permit is held.
+    /// Returns [None] right away if commands of `cost` are not limited.
+    pub(super) async fn acquire(&self, cost: InvocationCost) -> Option<Permit> {
+        Some(self.slot(cost)?.acquire().await)
+    }
+
+    fn slot(&self, cost: InvocationCost) -> Option<&Arc<Limit>> {
+        match cost {
+            InvocationCost::Cheap => self.cheap.as_ref(),
+            InvocationCost::Expensive => self.expensive.as_ref(),
+        }
+    }
+
+    fn slot_mut(&mut self, cost: InvocationCost) -> &mut Option<Arc<Limit>> {
+        match cost {
+            InvocationCost::Cheap => &mut self.cheap,
+            InvocationCost::Expensive => &mut self.expensive,
+        }
+    }
+}
+
+/// A limit shared by async tasks, woken through [Limit::notify]
+#[derive(Debug)]
+struct Limit {
+    max: usize,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    queued: usize,
+}
+
+impl Limit {
+    fn new(max: usize) -> Self {
+        Limit {
+            max,
+            state: Default::default(),
+            notify: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // the state is consistent after every update, a panic can not corrupt it
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    async fn acquire(self: &Arc<Self>) -> Permit {
+        let mut queued = None;
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            // register before checking, to not miss a release in between
+            notified.as_mut().enable();
+            {
+                let mut state = self.state();
+                if state.running < self.max {
+                    state.running += 1;
+                    break;
+                }
+                if queued.is_none() {
+                    state.queued += 1;
+                    queued = Some(Queued(self));
+                }
+            }
+            notified.await;
+        }
+        drop(queued);
+        Permit(self.clone())
+    }
+
+    /// Wake a task waiting for a free slot, if any
+    fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Counts an async task waiting for a [Limit],
+/// until it got a slot or stopped waiting
+struct Queued<'a>(&'a Limit);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        state.queued -= 1;
+        let free = state.running < self.0.max;
+        drop(state);
+        // a task that stopped waiting may have been woken for a slot it did not take
+        if free {
+            self.0.wake();
+        }
+    }
+}
+
+/// A slot of a [Limit], freed when dropped
+#[derive(Debug)]
+pub(super) struct Permit(Arc<Limit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.state().running -= 1;
+        self.0.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queues_beyond_limit() {
+        let limits = InvocationLimits::new().with_limit(InvocationCost::Expensive, 2);
+        assert!(limits.acquire(InvocationCost::Cheap).await.is_none());
+
+        let first = limits.acquire(InvocationCost::Expensive).await.unwrap();
+        let second = limits.acquire(InvocationCost::Expensive).await.unwrap();
+        assert_eq!(limits.running(InvocationCost::Expensive), 2);
+
+        let mut waiting = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire(InvocationCost::Expensive).await }
+        });
+        let mut also_waiting = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire(InvocationCost::Expensive).await }
+        });
+        while limits.queued(InvocationCost::Expensive) < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        drop(first);
+        let (third, last) = tokio::select! {
+            third = &mut waiting => (third, also_waiting),
+            third = &mut also_waiting => (third, waiting),
+        };
+        let third = third.unwrap();
+        assert!(third.is_some());
+        assert_eq!(limits.running(InvocationCost::Expensive), 2);
+        assert_eq!(limits.queued(InvocationCost::Expensive), 1);
+
+        drop(second);
+        assert!(last.await.unwrap().is_some());
+        assert_eq!(limits.queued(InvocationCost::Expensive), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_leave_the_queue() {
+        let limits = InvocationLimits::new().with_limit(InvocationCost::Cheap, 1);
+        let permit = limits.acquire(InvocationCost::Cheap).await;
+
+        let timed_out = tokio::time::timeout(
+            Duration::from_millis(10),
+            limits.acquire(InvocationCost::Cheap),
+        )
+        .await;
+        assert!(timed_out.is_err());
+        assert_eq!(limits.queued(InvocationCost::Cheap), 0);
+
+        drop(permit);
+        assert_eq!(limits.running(InvocationCost::Cheap), 0);
+        assert!(limits.acquire(InvocationCost::Cheap).await.is_some());
+    }
+}
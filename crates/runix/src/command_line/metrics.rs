@@ -0,0 +1,311 @@
+//! Counting nix invocations and the time they take, see [MetricsSink]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_with::{serde_as, DurationMilliSeconds};
+
+/// A nix invocation that finished, passed to [MetricsSink::on_invocation]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvocationMetrics {
+    /// The subcommand, see [NixCliCommand::SUBCOMMAND](super::NixCliCommand::SUBCOMMAND)
+    pub command: &'static [&'static str],
+    /// The time from starting nix until it exited
+    pub duration: Duration,
+    pub exit_status: ExitStatus,
+    /// How much nix printed to stdout, where the output is read rather than passed through
+    pub stdout_bytes: usize,
+    /// How much nix printed to stderr, where the output is read rather than passed through
+    pub stderr_bytes: usize,
+    /// How often the command has been run before, i.e. how many attempts this retries,
+    /// see [RetryPolicy](super::retry::RetryPolicy)
+    pub retries: u32,
+}
+
+/// Receives the [InvocationMetrics] of every nix invocation of a backend,
+/// see [NixCommandLine::metrics](super::NixCommandLine::metrics)
+///
+/// Invocations are reported once nix exited and has been waited for.
+/// Nix failing to start, or being dropped before it has been waited for, is not reported.
+/// Sinks are called by the task or thread waiting for nix and should return quickly.
+pub trait MetricsSink: fmt::Debug + Send + Sync {
+    fn on_invocation(&self, metrics: &InvocationMetrics);
+}
+
+/// The upper bounds of the buckets of [CommandSummary::durations],
+/// followed by a bucket for longer invocations
+pub const DURATION_BUCKETS: [Duration; 4] = [
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// A [MetricsSink] summing up invocations by command, see [AggregatingSink::summary]
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use runix::command_line::metrics::AggregatingSink;
+/// # use runix::command_line::NixCommandLine;
+/// let sink = Arc::new(AggregatingSink::new());
+/// let backend = NixCommandLine {
+///     metrics: Some(sink.clone()),
+///     ..Default::default()
+/// };
+/// // run commands with `backend`, then
+/// let summary = sink.summary();
+/// println!("{summary}");
+/// let json = serde_json::to_string(&summary).unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct AggregatingSink {
+    commands: Mutex<BTreeMap<String, CommandSummary>>,
+}
+
+impl AggregatingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The invocations so far, by command
+    pub fn summary(&self) -> MetricsSummary {
+        MetricsSummary {
+            commands: self.commands.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl MetricsSink for AggregatingSink {
+    fn on_invocation(&self, metrics: &InvocationMetrics) {
+        self.commands
+            .lock()
+            .unwrap()
+            .entry(metrics.command.join(" "))
+            .or_default()
+            .add(metrics);
+    }
+}
+
+/// The invocations of a command, summed up by an [AggregatingSink]
+///
+/// Durations serialize as milliseconds.
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CommandSummary {
+    pub invocations: u64,
+    /// Invocations after which nix exited unsuccessfully
+    pub failures: u64,
+    /// Invocations that retried a failed one
+    pub retries: u64,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "total_ms")]
+    pub total_duration: Duration,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "min_ms")]
+    pub min_duration: Duration,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "max_ms")]
+    pub max_duration: Duration,
+    /// How many invocations took at most the bounds of [DURATION_BUCKETS] or longer,
+    /// counting each in its first bucket
+    pub durations: [u64; DURATION_BUCKETS.len() + 1],
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+}
+
+impl CommandSummary {
+    /// The average time an invocation took
+    pub fn mean_duration(&self) -> Duration {
+        match self.invocations {
+            0 => Duration::ZERO,
+            n => Duration::from_nanos((self.total_duration.as_nanos() / u128::from(n)) as u64),
+        }
+    }
+
+    fn add(&mut self, metrics: &InvocationMetrics) {
+        if self.invocations == 0 || metrics.duration < self.min_duration {
+            self.min_duration = metrics.duration;
+        }
+        self.max_duration = self.max_duration.max(metrics.duration);
+        self.invocations += 1;
+        self.failures += u64::from(!metrics.exit_status.success());
+        self.retries += u64::from(metrics.retries > 0);
+        self.total_duration += metrics.duration;
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| metrics.duration <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.durations[bucket] += 1;
+        self.stdout_bytes += metrics.stdout_bytes as u64;
+        self.stderr_bytes += metrics.stderr_bytes as u64;
+    }
+}
+
+/// The invocations summed up by an [AggregatingSink], keyed by command, e.g. `flake update`
+///
+/// Serializes to an object keyed by command,
+/// displays as a table with a row per command.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct MetricsSummary {
+    pub commands: BTreeMap<String, CommandSummary>,
+}
+
+impl MetricsSummary {
+    /// How many times nix has been invoked for any command
+    pub fn invocations(&self) -> u64 {
+        self.commands
+            .values()
+            .map(|summary| summary.invocations)
+            .sum()
+    }
+
+    /// How long nix ran for all commands together
+    pub fn total_duration(&self) -> Duration {
+        self.commands
+            .values()
+            .map(|summary| summary.total_duration)
+            .sum()
+    }
+}
+
+impl fmt::Display for MetricsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .commands
+            .keys()
+            .map(String::len)
+            .chain([7])
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            f,
+            "{:width$} {:>6} {:>6} {:>7} {:>9} {:>9} {:>9} {:>12} {:>12}",
+            "command", "runs", "failed", "retried", "total", "mean", "max", "stdout", "stderr"
+        )?;
+        for (command, summary) in &self.commands {
+            writeln!(
+                f,
+                "{command:width$} {:>6} {:>6} {:>7} {:>8.2}s {:>8.2}s {:>8.2}s {:>12} {:>12}",
+                summary.invocations,
+                summary.failures,
+                summary.retries,
+                summary.total_duration.as_secs_f64(),
+                summary.mean_duration().as_secs_f64(),
+                summary.max_duration.as_secs_f64(),
+                summary.stdout_bytes,
+                summary.stderr_bytes,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::arguments::NixArgs;
+    use crate::command::{Build, Eval};
+    use crate::command_line::mock::{MockBackend, MockResponse};
+    use crate::command_line::retry::RetryPolicy;
+    use crate::command_line::NixCommandLine;
+    use crate::{Run, RunJson};
+
+    #[tokio::test]
+    async fn aggregates_mock_invocations() {
+        let sink = Arc::new(AggregatingSink::new());
+        let mut backend = MockBackend::default();
+        backend.cli = NixCommandLine {
+            metrics: Some(sink.clone()),
+            retry: Some(RetryPolicy {
+                initial_delay: Duration::from_millis(1),
+                jitter: 0.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let unreachable = "error: unable to download 'https://github.com/flox/runix/archive/HEAD.tar.gz': HTTP error 502\n";
+        backend.expect::<Eval>(MockResponse::exit(1).with_stderr(unreachable));
+        backend.expect::<Eval>(
+            MockResponse::json(&Value::from(2)).with_delay(Duration::from_millis(20)),
+        );
+        backend.expect::<Build>(MockResponse::success().with_stdout("[]"));
+        backend.expect::<Build>(MockResponse::exit(1).with_stderr("error: oops\n"));
+
+        Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+        Build::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+        Build::default()
+            .run(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+
+        let summary = sink.summary();
+        assert_eq!(summary.invocations(), 4);
+        assert_eq!(
+            summary.commands.keys().collect::<Vec<_>>(),
+            ["build", "eval"]
+        );
+
+        let eval = &summary.commands["eval"];
+        assert_eq!((eval.invocations, eval.failures, eval.retries), (2, 1, 1));
+        assert_eq!(eval.stdout_bytes, 1);
+        assert_eq!(eval.stderr_bytes, unreachable.len() as u64);
+        assert!(eval.max_duration >= Duration::from_millis(20));
+        assert!(eval.min_duration <= eval.mean_duration());
+        assert_eq!(eval.durations.iter().sum::<u64>(), 2);
+
+        let build = &summary.commands["build"];
+        assert_eq!(
+            (build.invocations, build.failures, build.retries),
+            (2, 1, 0)
+        );
+        assert_eq!((build.stdout_bytes, build.stderr_bytes), (2, 12));
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["eval"]["retries"], json!(1));
+        assert!(json["eval"]["total_ms"].as_u64().unwrap() >= 20);
+
+        let table = summary.to_string();
+        let rows = table.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].starts_with("command"));
+        assert!(rows[1].starts_with("build "));
+        assert!(rows[2].starts_with("eval "));
+    }
+
+    #[test]
+    fn buckets_durations() {
+        let sink = AggregatingSink::new();
+        for millis in [5, 100, 101, 2_000, 90_000] {
+            sink.on_invocation(&InvocationMetrics {
+                command: &["flake", "update"],
+                duration: Duration::from_millis(millis),
+                exit_status: ExitStatus::from_raw(0),
+                stdout_bytes: 0,
+                stderr_bytes: 0,
+                retries: 0,
+            });
+        }
+
+        let summary = &sink.summary().commands["flake update"];
+        assert_eq!(summary.durations, [2, 1, 1, 0, 1]);
+        assert_eq!(summary.min_duration, Duration::from_millis(5));
+        assert_eq!(summary.max_duration, Duration::from_secs(90));
+        assert_eq!(summary.mean_duration(), Duration::from_micros(18_441_200));
+    }
+}
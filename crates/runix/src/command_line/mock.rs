@@ -0,0 +1,785 @@
+//! A backend for testing code that uses runix without nix, see [MockBackend]
+//!
+//! Available with the `test-util` feature.
+
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::child::ExecutionMode;
+use super::metrics::InvocationMetrics;
+use super::retry::retrying;
+use super::{
+    execution_mode,
+    failure,
+    JsonCommand,
+    NixCliCommand,
+    NixCommandLine,
+    NixCommandLineCollectError,
+    NixCommandLineRunError,
+    NixCommandLineRunJsonError,
+    TypedCommand,
+};
+use crate::arguments::NixArgs;
+use crate::{NixBackend, Run, RunJson, RunTyped};
+
+/// A [NixBackend] that records the invocations of commands and answers them
+/// with scripted [MockResponse]s instead of running nix
+///
+/// Invocations are rendered like by [NixCommandLine::to_command_preview],
+/// applying the defaults of [MockBackend::cli] and validating the arguments.
+/// Each invocation consumes the first response whose matcher it fits,
+/// in the order they were added.
+/// Invocations without a matching response panic, naming the arguments of the invocation.
+///
+/// Commands run with [Run] succeed unless the response has a failing exit status,
+/// they then fail like with [NixCommandLine] in their [ExecutionMode].
+/// Commands run with [RunJson] or [RunTyped] fail like with [NixCommandLine],
+/// and otherwise parse the stdout of the response.
+/// Like with [NixCommandLine], failures with collected output are retried
+/// according to [NixCommandLine::retry], including those of [Run]
+/// unless it runs in [ExecutionMode::Inherit].
+/// Invocations wait for the
+/// [max_concurrent_invocations](NixCommandLine#structfield.max_concurrent_invocations)
+/// of [MockBackend::cli] and take the [MockResponse::delay] of their response,
+/// [MockBackend::max_concurrency] tells how many were answered at once.
+/// They are reported to the [metrics](NixCommandLine#structfield.metrics) of [MockBackend::cli]
+/// as if nix printed the response.
+///
+#[cfg_attr(feature = "test-util", doc = "```")]
+#[cfg_attr(not(feature = "test-util"), doc = "```ignore")]
+/// # use runix::arguments::NixArgs;
+/// # use runix::command::Build;
+/// # use runix::command_line::mock::{MockBackend, MockResponse};
+/// # use runix::RunJson;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let backend = MockBackend::default();
+/// backend.expect::<Build>(MockResponse::success().with_stdout("[]"));
+///
+/// let built = Build::default()
+///     .run_json(&backend, &NixArgs::default())
+///     .await
+///     .unwrap();
+/// assert_eq!(built, serde_json::json!([]));
+/// assert_eq!(backend.invocations()[0].args.last().unwrap(), "--json");
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    /// The defaults the invocations are rendered with
+    pub cli: NixCommandLine,
+    responses: Mutex<Vec<(Matcher, MockResponse)>>,
+    invocations: Mutex<Vec<Invocation>>,
+    concurrency: Mutex<Concurrency>,
+}
+
+/// How many invocations a [MockBackend] is answering
+#[derive(Debug, Default)]
+struct Concurrency {
+    current: usize,
+    max: usize,
+}
+
+/// A recorded invocation of a [MockBackend]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The type name of the command, e.g. `runix::command::Build`
+    pub command: &'static str,
+    /// The program nix would be run through and its arguments, see [NixCommandLine::wrapper]
+    pub wrapper: Vec<String>,
+    /// The arguments in the order they would be passed to nix
+    pub args: Vec<String>,
+    /// Environment variables set for nix, sorted by name,
+    /// with the values of [NixArgs::secret_env] redacted
+    pub env: Vec<(String, String)>,
+    /// Environment variables nix would not inherit
+    pub env_remove: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// How nix would share the terminal,
+    /// always [ExecutionMode::Captured] for commands run with [RunJson] or [RunTyped]
+    pub mode: ExecutionMode,
+}
+
+/// The output of nix scripted for a [MockBackend]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockResponse {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: ExitStatus,
+    /// How long the invocation takes before it is answered
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    /// Exit successfully without output
+    pub fn success() -> Self {
+        MockResponse::exit(0)
+    }
+
+    /// Exit with `code` without output
+    pub fn exit(code: i32) -> Self {
+        MockResponse {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            status: ExitStatus::from_raw(code << 8),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Exit successfully printing `json`
+    pub fn json(json: &Value) -> Self {
+        MockResponse::success().with_stdout(json.to_string())
+    }
+
+    pub fn with_stdout(mut self, stdout: impl Into<Vec<u8>>) -> Self {
+        self.stdout = stdout.into();
+        self
+    }
+
+    pub fn with_stderr(mut self, stderr: impl Into<Vec<u8>>) -> Self {
+        self.stderr = stderr.into();
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A predicate on the arguments of an invocation
+type ArgsPredicate = Box<dyn Fn(&[String]) -> bool + Send + Sync>;
+
+/// Decides which invocations a [MockResponse] is for
+enum Matcher {
+    Command(&'static str),
+    Args(ArgsPredicate),
+}
+
+impl std::fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Matcher::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            Matcher::Args(_) => f.debug_tuple("Args").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl Matcher {
+    fn matches(&self, invocation: &Invocation) -> bool {
+        match self {
+            Matcher::Command(command) => *command == invocation.command,
+            Matcher::Args(predicate) => predicate(&invocation.args),
+        }
+    }
+}
+
+impl NixBackend for MockBackend {}
+
+impl MockBackend {
+    /// Answer the next invocation of the command `C` with `response`
+    pub fn expect<C: NixCliCommand>(&self, response: MockResponse) {
+        self.push(Matcher::Command(std::any::type_name::<C>()), response);
+    }
+
+    /// Answer the next invocation whose arguments fulfill `predicate` with `response`
+    pub fn expect_args(
+        &self,
+        predicate: impl Fn(&[String]) -> bool + Send + Sync + 'static,
+        response: MockResponse,
+    ) {
+        self.push(Matcher::Args(Box::new(predicate)), response);
+    }
+
+    /// The invocations so far, in order
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    /// Whether all scripted responses were used
+    pub fn is_exhausted(&self) -> bool {
+        self.responses.lock().unwrap().is_empty()
+    }
+
+    /// The most invocations that were answered at the same time so far
+    ///
+    /// Invocations only overlap while waiting for the [MockResponse::delay] of their response.
+    pub fn max_concurrency(&self) -> usize {
+        self.concurrency.lock().unwrap().max
+    }
+
+    fn push(&self, matcher: Matcher, response: MockResponse) {
+        self.responses.lock().unwrap().push((matcher, response));
+    }
+
+    /// Record the invocation of `command` and answer it with the response for it,
+    /// reporting it as the retry of `retries` earlier attempts
+    async fn invoke<C: NixCliCommand>(
+        &self,
+        command: &C,
+        nix_args: &NixArgs,
+        json: bool,
+        mode: ExecutionMode,
+        retries: u32,
+    ) -> Result<Output, super::NixCommandLineError> {
+        let preview = self.cli.command_preview(command, nix_args, json)?;
+        let invocation = Invocation {
+            command: std::any::type_name::<C>(),
+            wrapper: preview.wrapper,
+            args: preview.args,
+            env: preview.env,
+            env_remove: preview.env_remove,
+            cwd: preview.cwd,
+            mode,
+        };
+
+        let response = {
+            let mut responses = self.responses.lock().unwrap();
+            responses
+                .iter()
+                .position(|(matcher, _)| matcher.matches(&invocation))
+                .map(|n| responses.remove(n).1)
+        };
+        let Some(response) = response else {
+            panic!(
+                "no response for the invocation of {}: nix {}",
+                invocation.command,
+                invocation.args.join(" ")
+            );
+        };
+        self.invocations.lock().unwrap().push(invocation);
+
+        let _permit = self.cli.invocation_permit::<C>().await;
+        {
+            let mut concurrency = self.concurrency.lock().unwrap();
+            concurrency.current += 1;
+            concurrency.max = concurrency.max.max(concurrency.current);
+        }
+        let started = Instant::now();
+        tokio::time::sleep(response.delay).await;
+        self.concurrency.lock().unwrap().current -= 1;
+
+        if let Some(sink) = &self.cli.metrics {
+            sink.on_invocation(&InvocationMetrics {
+                command: C::SUBCOMMAND,
+                duration: started.elapsed(),
+                exit_status: response.status,
+                stdout_bytes: response.stdout.len(),
+                stderr_bytes: response.stderr.len(),
+                retries,
+            });
+        }
+
+        Ok(Output {
+            status: response.status,
+            stdout: response.stdout,
+            stderr: response.stderr,
+        })
+    }
+
+    /// Invoke `command` with its output collected in `mode`,
+    /// retrying failures like [NixCommandLine::run_command] does
+    async fn invoke_collected<C: NixCliCommand>(
+        &self,
+        command: &C,
+        nix_args: &NixArgs,
+        json: bool,
+        mode: ExecutionMode,
+    ) -> Result<Output, NixCommandLineCollectError> {
+        let mut attempts = 0;
+        retrying(
+            self.cli.retry_policy(nix_args),
+            NixCommandLineCollectError::nix_error,
+            move || {
+                let retries = attempts;
+                attempts += 1;
+                async move {
+                    let output = self.invoke(command, nix_args, json, mode, retries).await?;
+                    if !output.status.success() {
+                        return Err(failure(&output, self.cli.captured_output_limit()));
+                    }
+                    Ok(output)
+                }
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<C> Run<MockBackend> for C
+where
+    C: NixCliCommand + Send + Sync,
+{
+    type Error = NixCommandLineRunError;
+
+    async fn run(&self, backend: &MockBackend, nix_args: &NixArgs) -> Result<(), Self::Error> {
+        let mode = execution_mode::<C>(nix_args);
+        if mode != ExecutionMode::Inherit {
+            backend
+                .invoke_collected(self, nix_args, false, mode)
+                .await?;
+            return Ok(());
+        }
+        let output = backend.invoke(self, nix_args, false, mode, 0).await?;
+        match run_error(mode, &output, backend.cli.captured_output_limit()) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The error [Run] with [NixCommandLine] reports for nix printing `output` in `mode`, if any,
+/// keeping `limit` bytes of the output
+pub(super) fn run_error(
+    mode: ExecutionMode,
+    output: &Output,
+    limit: usize,
+) -> Option<NixCommandLineRunError> {
+    match mode {
+        ExecutionMode::Inherit if output.status.signal() == Some(libc::SIGINT) => {
+            Some(NixCommandLineRunError::Interrupted(output.status))
+        },
+        ExecutionMode::Inherit if !output.status.success() => {
+            Some(NixCommandLineRunError::Exit(output.status))
+        },
+        ExecutionMode::Captured | ExecutionMode::Piped if !output.status.success() => {
+            Some(NixCommandLineRunError::Failed(failure(output, limit)))
+        },
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl<C> RunJson<MockBackend> for C
+where
+    C: NixCliCommand + JsonCommand + Send + Sync,
+{
+    type JsonError = NixCommandLineRunJsonError;
+
+    async fn run_json(
+        &self,
+        backend: &MockBackend,
+        nix_args: &NixArgs,
+    ) -> Result<Value, Self::JsonError> {
+        let output = backend
+            .invoke_collected(self, nix_args, true, ExecutionMode::Captured)
+            .await?;
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+}
+
+#[async_trait]
+impl<C> RunTyped<MockBackend> for C
+where
+    C: RunJson<MockBackend, JsonError = NixCommandLineRunJsonError> + TypedCommand + Send + Sync,
+    <C as TypedCommand>::Output: for<'de> Deserialize<'de>,
+{
+    type Output = C::Output;
+    type TypedError = NixCommandLineRunJsonError;
+
+    async fn run_typed(
+        &self,
+        backend: &MockBackend,
+        nix_args: &NixArgs,
+    ) -> Result<Self::Output, Self::TypedError> {
+        let json = self.run_json(backend, nix_args).await?;
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::arguments::eval::EvaluationArgs;
+    use crate::arguments::source::SourceArgs;
+    use crate::command::{Build, Develop, Eval, FlakeUpdate, PathInfo, Shell};
+    use crate::command_line::limit::{InvocationCost, InvocationLimits};
+    use crate::command_line::retry::RetryPolicy;
+    use crate::nix_error::NixError;
+    use crate::store_path::StorePath;
+
+    const HELLO: &str = "/nix/store/8vq9f8bq9ayn3ih4hz3ihc2jjq3d5mgl-hello-2.12.1";
+    const HELLO_DRV: &str = "/nix/store/qs8ywbkrf2p2ilbj3p3x0qc2n4jnrdmx-hello-2.12.1.drv";
+
+    #[tokio::test]
+    async fn records_invocations() {
+        let backend = MockBackend::default();
+        backend.expect::<Build>(MockResponse::success());
+        backend.expect::<Eval>(MockResponse::json(&Value::from(2)));
+
+        let project = tempfile::tempdir().unwrap();
+        let nix_args = NixArgs::default()
+            .with_cwd(project.path())
+            .with_secret_env("GITHUB_TOKEN", "ghp_secret");
+        let build = Build {
+            eval: EvaluationArgs {
+                impure: true.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        build.run(&backend, &nix_args).await.unwrap();
+        let eval = Eval {
+            source: SourceArgs {
+                expr: Some("1 + 1".into()),
+            },
+            ..Default::default()
+        };
+        let sum = eval.run_json(&backend, &NixArgs::default()).await.unwrap();
+        assert_eq!(sum, 2);
+
+        let invocations = backend.invocations();
+        assert_eq!(invocations[0], Invocation {
+            command: "runix::command::Build",
+            wrapper: Vec::new(),
+            args: vec![
+                "--extra-experimental-features".to_string(),
+                "nix-command flakes".to_string(),
+                "build".to_string(),
+                "--impure".to_string(),
+            ],
+            env: vec![("GITHUB_TOKEN".to_string(), "<redacted>".to_string())],
+            env_remove: Vec::new(),
+            cwd: Some(project.path().to_path_buf()),
+            mode: ExecutionMode::Captured,
+        });
+        assert_eq!(invocations[1].command, "runix::command::Eval");
+        assert_eq!(&invocations[1].args[2..], [
+            "eval", "--json", "--expr", "1 + 1"
+        ]);
+        assert!(backend.is_exhausted());
+    }
+
+    #[tokio::test]
+    async fn resolves_execution_modes() {
+        let backend = MockBackend::default();
+        for _ in 0..7 {
+            backend.expect_args(|_| true, MockResponse::success());
+        }
+        let nix_args = NixArgs::default();
+        Build::default().run(&backend, &nix_args).await.unwrap();
+        Eval::default().run(&backend, &nix_args).await.unwrap();
+        FlakeUpdate::default()
+            .run(&backend, &nix_args)
+            .await
+            .unwrap();
+        Develop::default().run(&backend, &nix_args).await.unwrap();
+        crate::command::Run::default()
+            .run(&backend, &nix_args)
+            .await
+            .unwrap();
+        Shell::default().run(&backend, &nix_args).await.unwrap();
+        Develop::default()
+            .run(
+                &backend,
+                &NixArgs::default().with_execution_mode(ExecutionMode::Piped),
+            )
+            .await
+            .unwrap();
+
+        let modes: Vec<_> = backend.invocations().iter().map(|i| i.mode).collect();
+        assert_eq!(modes, [
+            ExecutionMode::Captured,
+            ExecutionMode::Captured,
+            ExecutionMode::Captured,
+            ExecutionMode::Inherit,
+            ExecutionMode::Inherit,
+            ExecutionMode::Inherit,
+            ExecutionMode::Piped,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn reports_interrupts() {
+        let backend = MockBackend::default();
+        let interrupted = MockResponse {
+            status: ExitStatus::from_raw(libc::SIGINT),
+            ..MockResponse::success()
+        };
+        backend.expect::<Develop>(interrupted.clone());
+        backend.expect::<Develop>(MockResponse::exit(1));
+        backend.expect::<Build>(interrupted);
+
+        let err = Develop::default()
+            .run(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixCommandLineRunError::Interrupted(status)
+            if status.signal() == Some(libc::SIGINT)));
+        let err = Develop::default()
+            .run(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixCommandLineRunError::Exit(status) if status.code() == Some(1)));
+        // only nix sharing the terminal is interrupted by Ctrl-C
+        let err = Build::default()
+            .run(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixCommandLineRunError::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn records_wrapper() {
+        let backend = MockBackend {
+            cli: NixCommandLine {
+                wrapper: Some(vec!["sudo".into(), "-u".into(), "builder".into()]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        backend.expect::<Build>(MockResponse::success());
+
+        Build::default()
+            .run(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+        let invocation = &backend.invocations()[0];
+        assert_eq!(invocation.wrapper, ["sudo", "-u", "builder"]);
+        assert_eq!(invocation.args.last().unwrap(), "build");
+    }
+
+    #[tokio::test]
+    async fn runs_typed_commands() {
+        let backend = MockBackend::default();
+        backend.expect::<Build>(MockResponse::json(&serde_json::json!([{
+            "drvPath": HELLO_DRV,
+            "outputs": { "out": HELLO },
+        }])));
+        backend.expect::<PathInfo>(MockResponse::json(&serde_json::json!([{
+            "path": HELLO,
+            "valid": false,
+        }])));
+
+        let built = Build::default()
+            .run_typed(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            built[0].outputs["out"],
+            StorePath::from_path(HELLO).unwrap()
+        );
+
+        let path_info = PathInfo::default()
+            .run_typed(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(path_info[0].path, StorePath::from_path(HELLO).unwrap());
+        assert!(!path_info[0].valid);
+    }
+
+    #[tokio::test]
+    async fn matches_args_in_order() {
+        let backend = MockBackend::default();
+        let is_impure = |args: &[String]| args.iter().any(|arg| arg == "--impure");
+        backend.expect_args(is_impure, MockResponse::exit(1));
+        backend.expect_args(is_impure, MockResponse::success());
+        backend.expect::<Build>(
+            MockResponse::exit(1).with_stderr("error: attribute 'hello' missing\n"),
+        );
+
+        let impure = Build {
+            eval: EvaluationArgs {
+                impure: true.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = impure.run(&backend, &NixArgs::default()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NixCommandLineRunError::Failed(NixCommandLineCollectError::Failed { status, .. })
+                if status.code() == Some(1)
+        ));
+        impure.run(&backend, &NixArgs::default()).await.unwrap();
+
+        let err = Build::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        let NixCommandLineRunJsonError::Run(NixCommandLineCollectError::Failed { error, .. }) = err
+        else {
+            panic!("expected a classified failure, got {err:?}");
+        };
+        assert!(
+            matches!(error, NixError::AttributeMissing { attr_path, .. } if attr_path == "hello")
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "no response for the invocation of runix::command::Build: nix")]
+    async fn panics_without_response() {
+        let backend = MockBackend::default();
+        backend.expect::<Eval>(MockResponse::success());
+
+        let _ = Build::default().run(&backend, &NixArgs::default()).await;
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let retries = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let recorded = retries.clone();
+        let backend = MockBackend {
+            cli: NixCommandLine {
+                retry: Some(
+                    RetryPolicy {
+                        initial_delay: std::time::Duration::from_millis(10),
+                        jitter: 0.0,
+                        ..Default::default()
+                    }
+                    .on_retry(move |retry| {
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push((retry.to_string(), retry.delay))
+                    }),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let unreachable = "error: unable to download 'https://api.github.com/repos/flox/runix/commits/HEAD': Could not resolve hostname (6)\n";
+        backend.expect::<Eval>(MockResponse::exit(1).with_stderr(unreachable));
+        backend.expect::<Eval>(
+            MockResponse::exit(1)
+                .with_stderr("error: unable to download 'https://github.com/flox/runix/archive/HEAD.tar.gz': HTTP error 502\n"),
+        );
+        backend.expect::<Eval>(MockResponse::json(&Value::from(2)));
+
+        let started = std::time::Instant::now();
+        let sum = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(sum, 2);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(30));
+        assert_eq!(backend.invocations().len(), 3);
+        assert_eq!(*retries.lock().unwrap(), [
+            (
+                "retrying (2/3)".to_string(),
+                std::time::Duration::from_millis(10)
+            ),
+            (
+                "retrying (3/3)".to_string(),
+                std::time::Duration::from_millis(20)
+            ),
+        ]);
+
+        // attempts are limited, evaluation errors are not retried
+        backend.expect::<Eval>(MockResponse::exit(1).with_stderr(unreachable));
+        backend.expect::<Eval>(MockResponse::exit(1).with_stderr(unreachable));
+        backend.expect::<Eval>(MockResponse::exit(1).with_stderr(unreachable));
+        let err = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, NixCommandLineRunJsonError::Run(err) if err.nix_error().is_some_and(NixError::is_transient)),
+            "{err:?}"
+        );
+
+        backend.expect::<Eval>(
+            MockResponse::exit(1).with_stderr("error: attribute 'hello' missing\n"),
+        );
+        backend.expect::<Eval>(MockResponse::success());
+        Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert_eq!(backend.invocations().len(), 7);
+        assert!(!backend.is_exhausted());
+    }
+
+    /// [Run] is retried unless nix inherits the terminal
+    #[tokio::test]
+    async fn retries_run_with_collected_output() {
+        let backend = MockBackend {
+            cli: NixCommandLine {
+                retry: Some(RetryPolicy {
+                    initial_delay: Duration::from_millis(1),
+                    jitter: 0.0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let unreachable = "error: unable to download 'https://github.com/flox/runix/archive/HEAD.tar.gz': HTTP error 502\n";
+
+        for mode in [ExecutionMode::Captured, ExecutionMode::Piped] {
+            backend.expect::<Build>(MockResponse::exit(1).with_stderr(unreachable));
+            backend.expect::<Build>(MockResponse::success());
+            Build::default()
+                .run(&backend, &NixArgs::default().with_execution_mode(mode))
+                .await
+                .unwrap();
+            assert!(backend.is_exhausted());
+        }
+        assert_eq!(backend.invocations().len(), 4);
+
+        backend.expect::<Build>(MockResponse::exit(1).with_stderr(unreachable));
+        backend.expect::<Build>(MockResponse::success());
+        let nix_args = NixArgs::default().with_execution_mode(ExecutionMode::Inherit);
+        let err = Build::default().run(&backend, &nix_args).await.unwrap_err();
+        assert!(matches!(err, NixCommandLineRunError::Exit(_)), "{err:?}");
+        assert_eq!(backend.invocations().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_invocations() {
+        let limits = InvocationLimits::new()
+            .with_limit(InvocationCost::Cheap, 2)
+            .with_limit(InvocationCost::Expensive, 1);
+        let backend = Arc::new(MockBackend {
+            cli: NixCommandLine {
+                max_concurrent_invocations: Some(limits.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let delay = Duration::from_millis(100);
+        let mut tasks = Vec::new();
+        for _ in 0..6 {
+            backend.expect::<Eval>(MockResponse::json(&Value::Null).with_delay(delay));
+            let backend = backend.clone();
+            tasks.push(tokio::spawn(async move {
+                Eval::default()
+                    .run_json(&*backend, &NixArgs::default())
+                    .await
+            }));
+        }
+        for _ in 0..3 {
+            backend.expect::<Build>(MockResponse::json(&Value::Null).with_delay(delay));
+            let backend = backend.clone();
+            tasks.push(tokio::spawn(async move {
+                Build::default()
+                    .run_json(&*backend, &NixArgs::default())
+                    .await
+            }));
+        }
+
+        tokio::time::sleep(delay / 2).await;
+        assert_eq!(limits.running(InvocationCost::Cheap), 2);
+        assert_eq!(limits.queued(InvocationCost::Cheap), 4);
+        assert_eq!(limits.running(InvocationCost::Expensive), 1);
+        assert_eq!(limits.queued(InvocationCost::Expensive), 2);
+
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        // both kinds run alongside each other
+        assert_eq!(backend.max_concurrency(), 3);
+        assert_eq!(limits.running(InvocationCost::Cheap), 0);
+        assert_eq!(limits.queued(InvocationCost::Cheap), 0);
+    }
+}
@@ -4,26 +4,77 @@
 //! Note also the blanket implementation of the [Run] traits below.
 
 use core::fmt;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 use std::process::{ExitStatus, Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use log::{debug, log};
+use derive_more::Display;
+use log::{debug, log, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::Value;
 use thiserror::Error;
+use tokio::io::AsyncReadExt;
 use tokio::process::Command;
 
-use crate::arguments::common::NixCommonArgs;
-use crate::arguments::config::NixConfigArgs;
-use crate::arguments::eval::EvaluationArgs;
-use crate::arguments::flake::FlakeArgs;
+use crate::arguments::common::{LogFormat, NixCommonArgs, Offline};
+use crate::arguments::config::{
+    AcceptFlakeConfig,
+    ExperimentalFeatures,
+    NixConfigArgs,
+    NixConfigError,
+};
+use crate::arguments::eval::{EvaluationArgs, Refresh};
+use crate::arguments::flake::{CommitLockFile, FlakeArgs, NoWriteLockFile};
 use crate::arguments::source::SourceArgs;
 use crate::arguments::{InstallableArg, InstallablesArgs, NixArgs};
+use crate::command::{Bundle, StoreAddPath, WhyDepends, WhyDependsOut};
+use crate::command_line::child::{ExecutionMode, OnDrop, RunningNix, SpawnOptions};
+use crate::command_line::flag::Flag;
+use crate::command_line::input::InputSource;
+use crate::command_line::instrument::{Invocation, Stream};
+use crate::command_line::interrupt::InterruptGuard;
+use crate::command_line::json_stream::JsonStream;
+use crate::command_line::limit::{InvocationCost, InvocationLimits, Permit};
+use crate::command_line::metrics::MetricsSink;
+use crate::command_line::preview::{CommandPreview, REDACTED};
+use crate::command_line::retry::{retrying, RetryPolicy};
+use crate::command_line::running::RunningCommand;
+use crate::command_line::trust::FlakeConfigTrust;
+use crate::eval_trace::{error_message, EvalTrace};
+use crate::flake_ref::FlakeRef;
+use crate::installable::Installable;
+use crate::internal_log::{self, InternalLog};
+use crate::nix_error::{self, NixError};
+use crate::store_path::{StorePath, StorePathError};
 use crate::{NixBackend, Run, RunJson, RunTyped};
 
+pub mod child;
 pub mod flag;
+pub mod input;
+mod instrument;
+mod interrupt;
+pub mod json_stream;
+pub mod limit;
+pub mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod preview;
+#[cfg(any(test, feature = "test-util"))]
+pub mod recording;
+pub mod retry;
+pub mod running;
+pub mod ssh;
+pub mod trust;
 
 /// Defaults for all option groups
 ///
@@ -44,6 +95,27 @@ pub struct DefaultArgs {
 pub enum NixCommandLineError {
     #[error("Error running Nix: {0}")]
     Run(std::io::Error),
+    #[error("Invalid nix config: {0}")]
+    Config(#[from] NixConfigError),
+    #[error("Conflicting arguments: {0}")]
+    ArgConflict(#[from] ArgConflict),
+    #[error("The working directory '{}' does not exist", .0.display())]
+    MissingCwd(PathBuf),
+    #[error("Nix did not finish within {}s", .after.as_secs_f32())]
+    TimedOut { after: Duration },
+    #[error("Nix was aborted")]
+    Aborted,
+    /// An argument, environment variable or path that has to be passed on as text,
+    /// e.g. to nix on another host through ssh
+    #[error("'{}' is not valid UTF-8", .0.to_string_lossy())]
+    NotUnicode(OsString),
+    /// A host that ssh would read as an option,
+    /// see [NixSshCommandLine::host](ssh::NixSshCommandLine::host)
+    #[error("'{0}' is not a valid ssh host, it starts with '-'")]
+    InvalidSshHost(String),
+    /// The [InputSource::Reader] for stdin has been read by an earlier invocation
+    #[error("The reader for stdin has been used up by an earlier invocation")]
+    StdinUsedUp,
     /// unsused
     #[deprecated]
     #[error("Nix printed {0} bytes to stderr")]
@@ -54,26 +126,205 @@ pub enum NixCommandLineError {
     Exit(std::process::Output),
 }
 
+/// Two options that nix rejects (or silently ignores one of) when used together
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("'{first}' can not be used with '{second}': {reason}")]
+pub struct ArgConflict {
+    pub first: &'static str,
+    pub second: &'static str,
+    pub reason: String,
+}
+
 /// Nix Implementation based on the Nix Command Line
 #[derive(Clone, Debug, Default)]
 pub struct NixCommandLine {
     pub nix_bin: Option<String>,
     pub defaults: DefaultArgs,
+    /// Do not enable the [NixCliCommand::EXPERIMENTAL_FEATURES] of commands,
+    /// leaving it to the nix configuration (or the caller) instead
+    pub disable_feature_injection: bool,
+    /// How many bytes of each output stream are kept in errors, see [CapturedOutput],
+    /// [DEFAULT_CAPTURED_OUTPUT_LIMIT] unless set
+    pub captured_output_limit: Option<usize>,
+    /// Run commands again after transient failures, see [RetryPolicy]
+    pub retry: Option<RetryPolicy>,
+    /// A program nix is run through and its arguments, e.g. `["nice", "-n19"]`
+    ///
+    /// The wrapper is started with its arguments followed by the nix binary and its arguments.
+    /// It inherits the environment and working directory meant for nix and has to pass
+    /// them on (`sudo` resets the environment unless told otherwise).
+    /// [NixCommandLine::spawn] starts the wrapper in a new process group,
+    /// so aborting the command stops nix as well, unless the wrapper moves it to another one.
+    pub wrapper: Option<Vec<OsString>>,
+    /// Defaults for single commands keyed by their [NixCliCommand::SUBCOMMAND],
+    /// applied in addition to [NixCommandLine::defaults],
+    /// see [NixCommandLine::with_command_defaults]
+    pub command_defaults: HashMap<&'static [&'static str], DefaultArgs>,
+    /// Accept the `nixConfig` of some flakes but not others, see [FlakeConfigTrust]
+    pub flake_config_trust: FlakeConfigTrust,
+    /// How many commands of each [InvocationCost] run at once, unlimited if unset
+    ///
+    /// Excess invocations wait for a running one to finish,
+    /// [InvocationLimits::queued] tells how many are waiting.
+    /// All invocations wait asynchronously, never blocking the calling thread.
+    /// Those of [NixCommandLine::spawn], [NixCommandLine::spawn_nix]
+    /// and [NixCommandLine::run_json_stream] hold a slot until nix exited
+    /// and has been waited for or the handle has been dropped.
+    ///
+    /// Runix never starts nix while holding a slot itself.
+    /// Callers starting a command while they hold the handle of another one
+    /// deadlock once the limit is reached, unless they release the handle first
+    /// or the commands differ in their [NixCliCommand::INVOCATION_COST].
+    pub max_concurrent_invocations: Option<InvocationLimits>,
+    /// Receives the [InvocationMetrics] of every invocation of nix, e.g. an [AggregatingSink]
+    ///
+    /// Each attempt of a retried command is reported on its own,
+    /// see [InvocationMetrics::retries].
+    ///
+    /// [InvocationMetrics]: metrics::InvocationMetrics
+    /// [InvocationMetrics::retries]: metrics::InvocationMetrics::retries
+    /// [AggregatingSink]: metrics::AggregatingSink
+    pub metrics: Option<Arc<dyn MetricsSink>>,
+}
+
+/// How many bytes of stdout and stderr of a failed command are kept by default
+pub const DEFAULT_CAPTURED_OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// The output of a failed command, kept in [NixCommandLineCollectError]s
+///
+/// Both streams are limited to [NixCommandLine::captured_output_limit] bytes,
+/// keeping their end, where nix reports errors.
+/// Truncated streams start with a line noting how many bytes were left out.
+/// The output is kept as printed by nix, see [OutputExt] to read it as text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CapturedOutput {
+    fn new(output: &Output, limit: usize) -> Self {
+        CapturedOutput {
+            stdout: truncate_start(&output.stdout, limit),
+            stderr: truncate_start(&output.stderr, limit),
+        }
+    }
+}
+
+/// Stderr first, followed by the end of stdout
+impl fmt::Display for CapturedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.stderr.is_empty() {
+            write!(f, "stderr:\n{}", self.stderr_lossy().trim_end())?;
+        }
+        if !self.stdout.is_empty() {
+            if !self.stderr.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "stdout:\n{}", self.output_lossy().trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Keep the last `limit` bytes of `bytes`, or a few more to not split a character
+fn truncate_start(bytes: &[u8], limit: usize) -> Vec<u8> {
+    if bytes.len() <= limit {
+        return bytes.to_vec();
+    }
+    let mut start = bytes.len() - limit;
+    // UTF-8 characters have at most three continuation bytes
+    for _ in 0..3 {
+        if start == 0 || bytes[start] & 0b1100_0000 != 0b1000_0000 {
+            break;
+        }
+        start -= 1;
+    }
+    let mut truncated = format!("[{start} bytes truncated]\n").into_bytes();
+    truncated.extend_from_slice(&bytes[start..]);
+    truncated
+}
+
+/// An extension trait for the output of nix
+///
+/// Nix passes on whatever builders print, which need not be valid UTF-8,
+/// so output is kept as bytes and only decoded when asked for.
+pub trait OutputExt {
+    /// Stdout as printed by nix
+    fn output_bytes(&self) -> &[u8];
+
+    /// Stderr as printed by nix
+    fn stderr_bytes(&self) -> &[u8];
+
+    /// Stdout as text, invalid UTF-8 is replaced with `U+FFFD`
+    fn output_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.output_bytes())
+    }
+
+    /// Stderr as text, invalid UTF-8 is replaced with `U+FFFD`
+    fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.stderr_bytes())
+    }
+}
+
+impl OutputExt for Output {
+    fn output_bytes(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    fn stderr_bytes(&self) -> &[u8] {
+        &self.stderr
+    }
+}
+
+impl OutputExt for CapturedOutput {
+    fn output_bytes(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    fn stderr_bytes(&self) -> &[u8] {
+        &self.stderr
+    }
+}
+
+/// Pass lines to `on_line` as text, for the callbacks of [NixCommandLine::run_with_output]
+///
+/// Lines that are valid UTF-8 are passed on as they are,
+/// invalid sequences in other lines are replaced with `U+FFFD`.
+pub fn utf8_lines(mut on_line: impl FnMut(&str)) -> impl FnMut(&[u8]) {
+    move |line| on_line(&String::from_utf8_lossy(line))
 }
 
 /// An extensioon trait for [std::process::Command]
 ///
 /// Adds a `POSIX` style logging function.
 pub trait CommandExt {
-    fn log(&self, _level: log::Level) {}
+    fn log(&self, level: log::Level) {
+        self.log_redacted(level, &[])
+    }
+
+    /// Like [CommandExt::log], but replacing the values of the environment variables
+    /// named in `secret` with `<redacted>`
+    fn log_redacted(&self, _level: log::Level, _secret: &[OsString]) {}
 }
 
 impl CommandExt for std::process::Command {
-    fn log(&self, level: log::Level) {
+    fn log_redacted(&self, level: log::Level, secret: &[OsString]) {
+        let envs = self
+            .get_envs()
+            .map(|(k, v)| {
+                let v = match v {
+                    Some(_) if secret.iter().any(|name| name == k) => Some(OsStr::new(REDACTED)),
+                    v => v,
+                };
+                (k, v)
+            })
+            .collect::<Vec<_>>();
+
         debug!(
             "Invoking {executable}:\nenv = {env:?}\nargs = {args:#?}",
             executable = shell_escape::escape(self.get_program().to_string_lossy()),
-            env = self.get_envs().collect::<HashMap<_, _>>(),
+            env = envs.iter().copied().collect::<HashMap<_, _>>(),
             args = self
                 .get_args()
                 .map(|arg| arg.to_string_lossy().to_string())
@@ -88,8 +339,8 @@ impl CommandExt for std::process::Command {
 
                 "{env_string} {executable} {command_string}",
 
-                env_string = self
-                    .get_envs()
+                env_string = envs
+                    .iter()
                     .map(|(k, v)| {
                         format!(
                             "{}={:?}",
@@ -116,10 +367,22 @@ impl CommandExt for std::process::Command {
 /// Mainly used for influencing the destination of `Stdio`
 /// See [Collect] and [Passthru] as examples.
 #[async_trait]
-trait CommandMode {
+pub(crate) trait CommandMode {
     type Output;
-    type Error;
-    async fn run(command: &mut Command) -> Result<Self::Output, Self::Error>;
+    type Error: From<NixCommandLineError>;
+    /// The level the invocation is logged at
+    const LOG_LEVEL: log::Level;
+    /// The classified failure of nix in an error, which may be retried
+    fn nix_error(_error: &Self::Error) -> Option<&NixError> {
+        None
+    }
+    /// Run `command`, feeding it `stdin` if set, see [NixArgs::stdin]
+    async fn run(
+        command: &mut Command,
+        backend: &NixCommandLine,
+        invocation: &Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<Self::Output, Self::Error>;
 }
 
 /// Errors occuring during command exection bin [Collect] Mode
@@ -127,181 +390,1300 @@ trait CommandMode {
 pub enum NixCommandLineCollectError {
     #[error(transparent)]
     CommandLine(#[from] NixCommandLineError),
+    /// unused, see [NixCommandLineCollectError::Failed]
+    #[deprecated]
     #[error("Nix failed with: [{0}]")]
     NixError(ExitStatus),
+    #[error("Nix failed with [{status}]: {error}\n{output}")]
+    Failed {
+        status: ExitStatus,
+        error: NixError,
+        output: CapturedOutput,
+    },
+    #[error("The post-build-hook '{hook}' failed after a successful build: {reason}\n{output}")]
+    PostBuildHook {
+        status: ExitStatus,
+        hook: PathBuf,
+        reason: String,
+        output: CapturedOutput,
+    },
+    #[error("Evaluation failed: {message}")]
+    Evaluation {
+        status: ExitStatus,
+        message: String,
+        trace: EvalTrace,
+        output: CapturedOutput,
+    },
+    #[error("Access to '{}' is forbidden in pure evaluation mode\n{output}", .path.display())]
+    ForbiddenInPureEval {
+        status: ExitStatus,
+        path: PathBuf,
+        output: CapturedOutput,
+    },
+    #[error(
+        "Evaluation needs to build '{drv_path}', but allow-import-from-derivation is disabled\n{output}"
+    )]
+    ImportFromDerivationBlocked {
+        status: ExitStatus,
+        drv_path: StorePath,
+        output: CapturedOutput,
+    },
+    #[error("Building '{drv}' exceeded the {limit} of {}s\n{output}", .after.as_secs())]
+    BuildLimit {
+        status: ExitStatus,
+        limit: BuildLimit,
+        drv: StorePath,
+        after: Duration,
+        output: CapturedOutput,
+    },
+}
+
+impl NixCommandLineCollectError {
+    /// The classified failure of nix, see [NixError::classify]
+    pub fn nix_error(&self) -> Option<&NixError> {
+        match self {
+            NixCommandLineCollectError::Failed { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// The exit status of nix, for failures of nix
+    pub fn status(&self) -> Option<ExitStatus> {
+        match self {
+            NixCommandLineCollectError::Failed { status, .. }
+            | NixCommandLineCollectError::PostBuildHook { status, .. }
+            | NixCommandLineCollectError::Evaluation { status, .. }
+            | NixCommandLineCollectError::ForbiddenInPureEval { status, .. }
+            | NixCommandLineCollectError::ImportFromDerivationBlocked { status, .. }
+            | NixCommandLineCollectError::BuildLimit { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The output of nix, for failures of nix
+    pub fn output(&self) -> Option<&CapturedOutput> {
+        match self {
+            NixCommandLineCollectError::Failed { output, .. }
+            | NixCommandLineCollectError::PostBuildHook { output, .. }
+            | NixCommandLineCollectError::Evaluation { output, .. }
+            | NixCommandLineCollectError::ForbiddenInPureEval { output, .. }
+            | NixCommandLineCollectError::ImportFromDerivationBlocked { output, .. }
+            | NixCommandLineCollectError::BuildLimit { output, .. } => Some(output),
+            _ => None,
+        }
+    }
+}
+
+/// The build time limit that caused nix to kill a build,
+/// see [MaxSilentTime](crate::arguments::config::MaxSilentTime)
+/// and [Timeout](crate::arguments::config::Timeout)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum BuildLimit {
+    #[display(fmt = "max-silent-time")]
+    MaxSilentTime,
+    #[display(fmt = "timeout")]
+    Timeout,
+}
+
+/// Matches nix' message for builds killed by a [BuildLimit]
+static BUILD_LIMIT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"building of '(?P<drv>[^']+)' timed out after (?P<secs>\d+) seconds(?P<silence> of silence)?",
+    )
+    .unwrap()
+});
+
+/// Matches the message nix prints before running a post-build-hook
+static POST_BUILD_HOOK_RUNNING: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^running post-build-hook '(?P<hook>[^']+)'").unwrap());
+
+/// Matches nix' message for a failing program, e.g. a post-build-hook
+///
+/// Nix reports the hook failing as a failure of the build itself,
+/// other programs like git fail with the same message.
+static PROGRAM_FAILED: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)^error: program '(?P<program>[^']+)' (?P<reason>failed with exit code \d+|killed by signal \d+)")
+        .unwrap()
+});
+
+/// Matches nix' message for evaluations that would need to build a derivation,
+/// see [AllowImportFromDerivation](crate::arguments::config::AllowImportFromDerivation)
+///
+/// Newer versions of nix append the output to the derivation (`.drv^out`)
+static IMPORT_FROM_DERIVATION_BLOCKED: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"cannot build '(?P<drv>[^'^]+)(\^[^']*)?' during evaluation because the option 'allow-import-from-derivation' is disabled")
+        .unwrap()
+});
+
+/// Matches nix' message for paths read during pure evaluation,
+/// see [PureEval](crate::arguments::config::PureEval)
+///
+/// Environment variables are not matched,
+/// `builtins.getEnv` returns an empty string in pure evaluation mode instead of failing
+static FORBIDDEN_IN_PURE_EVAL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"access to (absolute |canonical )?path '(?P<path>[^']+)' is forbidden in pure eval(uation)? mode")
+        .unwrap()
+});
+
+/// Find an evaluation error and its trace in the stderr of nix
+///
+/// Only errors with a trace are considered evaluation errors,
+/// use [ShowTrace](crate::arguments::config::ShowTrace) to get the full trace.
+/// The trace is parsed whether or not it is set, as nix prints a few frames regardless
+/// and `show-trace` may as well be enabled in the nix configuration.
+fn evaluation_error(
+    status: ExitStatus,
+    stderr: &[u8],
+    output: &CapturedOutput,
+) -> Option<NixCommandLineCollectError> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let trace = EvalTrace::parse(&stderr);
+    if trace.frames.is_empty() {
+        return None;
+    }
+    Some(NixCommandLineCollectError::Evaluation {
+        status,
+        message: error_message(&stderr).unwrap_or_default(),
+        trace,
+        output: output.clone(),
+    })
+}
+
+/// Classify the failure of nix, see [NixError::classify]
+///
+/// Unclassified failures are left to [evaluation_error] and the generic fallback.
+fn classified_error(
+    status: ExitStatus,
+    stderr: &[u8],
+    output: &CapturedOutput,
+) -> Option<NixCommandLineCollectError> {
+    match NixError::classify(status, &String::from_utf8_lossy(stderr)) {
+        NixError::Other { .. } => None,
+        error => Some(NixCommandLineCollectError::Failed {
+            status,
+            error,
+            output: output.clone(),
+        }),
+    }
+}
+
+/// Find a path access rejected in pure evaluation mode in the stderr of nix
+fn pure_eval_error(
+    status: ExitStatus,
+    stderr: &[u8],
+    output: &CapturedOutput,
+) -> Option<NixCommandLineCollectError> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let captures = FORBIDDEN_IN_PURE_EVAL.captures(&stderr)?;
+    Some(NixCommandLineCollectError::ForbiddenInPureEval {
+        status,
+        path: PathBuf::from(&captures["path"]),
+        output: output.clone(),
+    })
+}
+
+/// Find an evaluation blocked by a disabled allow-import-from-derivation in the stderr of nix
+fn import_from_derivation_error(
+    status: ExitStatus,
+    stderr: &[u8],
+    output: &CapturedOutput,
+) -> Option<NixCommandLineCollectError> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let captures = IMPORT_FROM_DERIVATION_BLOCKED.captures(&stderr)?;
+    let drv_path = captures["drv"].parse().ok()?;
+    Some(NixCommandLineCollectError::ImportFromDerivationBlocked {
+        status,
+        drv_path,
+        output: output.clone(),
+    })
+}
+
+/// Find a failed post-build-hook in the stderr of nix
+///
+/// Only the program nix announced running as the last post-build-hook counts,
+/// if it failed after that.
+fn post_build_hook_error(
+    status: ExitStatus,
+    stderr: &[u8],
+    output: &CapturedOutput,
+) -> Option<NixCommandLineCollectError> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let running = POST_BUILD_HOOK_RUNNING.captures_iter(&stderr).last()?;
+    let hook = &running["hook"];
+    let after = running.get(0)?.end();
+    let failed = PROGRAM_FAILED
+        .captures_iter(&stderr[after..])
+        .find(|captures| &captures["program"] == hook)?;
+    Some(NixCommandLineCollectError::PostBuildHook {
+        status,
+        hook: PathBuf::from(hook),
+        reason: failed["reason"].to_string(),
+        output: output.clone(),
+    })
+}
+
+/// Find a build killed by a [BuildLimit] in the stderr of nix
+fn build_limit_error(
+    status: ExitStatus,
+    stderr: &[u8],
+    output: &CapturedOutput,
+) -> Option<NixCommandLineCollectError> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let captures = BUILD_LIMIT.captures(&stderr)?;
+    let limit = match captures.name("silence") {
+        Some(_) => BuildLimit::MaxSilentTime,
+        None => BuildLimit::Timeout,
+    };
+    let drv = captures["drv"].parse().ok()?;
+    let after = Duration::from_secs(captures["secs"].parse().ok()?);
+    Some(NixCommandLineCollectError::BuildLimit {
+        status,
+        limit,
+        drv,
+        after,
+        output: output.clone(),
+    })
+}
+
+/// Matches the warning nix prints when evaluating an uncommitted git tree,
+/// see [WarnDirty](crate::arguments::config::WarnDirty)
+static DIRTY_WARNING: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^warning: Git tree '.*' is dirty$").unwrap());
+
+/// Matches the warning the nix daemon prints for restricted settings
+/// (e.g. [PostBuildHook](crate::arguments::config::PostBuildHook))
+/// passed by users that are not trusted
+static IGNORED_RESTRICTED_SETTING: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^warning: ignoring the client-specified setting '(?P<setting>[^']+)', because it is a restricted setting and you are not a trusted user$")
+        .unwrap()
+});
+
+/// Route the stderr of a collected command
+///
+/// Dirty tree warnings and ignored restricted settings are sent to the logging framework,
+/// everything else is returned to be forwarded as is.
+///
+/// Other lines are returned as printed, even if they are not valid UTF-8.
+fn route_stderr(stderr: &[u8]) -> Vec<u8> {
+    let mut rest = Vec::new();
+    if stderr.is_empty() {
+        return rest;
+    }
+    let lines = stderr.strip_suffix(b"\n").unwrap_or(stderr);
+    for line in lines.split(|byte| *byte == b'\n') {
+        if route_stderr_line(line) {
+            rest.extend_from_slice(line);
+            rest.push(b'\n');
+        }
+    }
+    rest
+}
+
+/// Route a line of stderr without its newline, see [route_stderr]
+///
+/// Returns whether the line is to be forwarded.
+fn route_stderr_line(line: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(line);
+    let text = text.strip_suffix('\r').unwrap_or(&text);
+    if DIRTY_WARNING.is_match(text) {
+        warn!(target: "nix", "{}", &text["warning: ".len()..]);
+        false
+    } else if let Some(captures) = IGNORED_RESTRICTED_SETTING.captures(text) {
+        warn!(
+            target: "nix",
+            "the nix daemon ignored the restricted setting '{}', add the user to `trusted-users` to apply it",
+            &captures["setting"]
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Accumulates the output of a stream,
+/// passing each complete line on as soon as it was read,
+/// see [NixCommandLine::run_with_output]
+struct LineSplitter<F> {
+    output: Vec<u8>,
+    /// Offset of the first byte not yet passed on
+    delivered: usize,
+    on_line: F,
+}
+
+impl<F: FnMut(&[u8])> LineSplitter<F> {
+    fn new(on_line: F) -> Self {
+        LineSplitter {
+            output: Vec::new(),
+            delivered: 0,
+            on_line,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.output.extend_from_slice(chunk);
+        while let Some(end) = self.output[self.delivered..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+        {
+            let line = &self.output[self.delivered..self.delivered + end];
+            (self.on_line)(line);
+            self.delivered += end + 1;
+        }
+    }
+
+    /// Pass on the last line if it is not terminated by a newline
+    /// and return the accumulated output
+    fn finish(mut self) -> Vec<u8> {
+        if self.delivered < self.output.len() {
+            (self.on_line)(&self.output[self.delivered..]);
+        }
+        self.output
+    }
 }
 
 /// Implementation of a command execution that collects stdout of a process
 /// and logs the stderr of the executed subprocess to the logging framework
 /// of the host process.
 ///
+/// Dirty tree warnings are always logged and never forwarded,
+/// the remaining stderr is kept in [Output::stderr] and forwarded
+/// to the stderr of the host process as nix prints it.
+///
 /// Silent, non user facing operation
-struct Collect;
+pub(crate) struct Collect;
 #[async_trait]
 impl CommandMode for Collect {
     type Error = NixCommandLineCollectError;
     type Output = Output;
 
-    async fn run(command: &mut Command) -> Result<Self::Output, NixCommandLineCollectError> {
-        command.as_std().log(log::Level::Debug);
+    const LOG_LEVEL: log::Level = log::Level::Debug;
 
-        let command = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit());
+    fn nix_error(error: &NixCommandLineCollectError) -> Option<&NixError> {
+        error.nix_error()
+    }
 
-        let child = command.spawn().map_err(NixCommandLineError::Run)?;
+    async fn run(
+        command: &mut Command,
+        backend: &NixCommandLine,
+        invocation: &Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<Self::Output, NixCommandLineCollectError> {
+        command.stdin(Stdio::inherit());
+        collect(command, backend, invocation, stdin, true).await
+    }
+}
 
-        let output = child
-            .wait_with_output()
-            .await
-            .map_err(NixCommandLineError::Run)?;
+/// Like [Collect], but without access to the terminal:
+/// stdin is `/dev/null` unless set by [NixArgs::stdin]
+/// and stderr is not forwarded, see [ExecutionMode::Piped]
+struct Piped;
+#[async_trait]
+impl CommandMode for Piped {
+    type Error = NixCommandLineCollectError;
+    type Output = Output;
 
-        if !output.status.success() {
-            return Err(NixCommandLineCollectError::NixError(output.status));
+    const LOG_LEVEL: log::Level = log::Level::Debug;
+
+    fn nix_error(error: &NixCommandLineCollectError) -> Option<&NixError> {
+        error.nix_error()
+    }
+
+    async fn run(
+        command: &mut Command,
+        backend: &NixCommandLine,
+        invocation: &Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<Self::Output, NixCommandLineCollectError> {
+        command.stdin(Stdio::null());
+        collect(command, backend, invocation, stdin, false).await
+    }
+}
+
+/// Run nix collecting stdout and stderr,
+/// forwarding stderr to the stderr of this process if `forward_stderr` is set
+///
+/// Stderr is routed and forwarded line by line as nix prints it,
+/// so warnings and build logs show while nix runs.
+async fn collect(
+    command: &mut Command,
+    backend: &NixCommandLine,
+    invocation: &Invocation,
+    stdin: Option<&InputSource>,
+    forward_stderr: bool,
+) -> Result<Output, NixCommandLineCollectError> {
+    let command = command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let nix = RunningNix::start(command, OnDrop::Detach, backend, invocation.clone(), stdin)?;
+    let mut routed = Vec::new();
+    let route = |line: &[u8]| {
+        if !route_stderr_line(line) {
+            return;
         }
+        routed.extend_from_slice(line);
+        routed.push(b'\n');
+        if forward_stderr {
+            // nix is not stopped for a closed stderr of this process, so neither is reading it
+            let _ = std::io::stderr().write_all(&[line, b"\n"].concat());
+        }
+    };
+    let mut output = read_lines(nix, |_| {}, route).await?;
+    output.stderr = routed;
 
-        Ok(output)
+    if !output.status.success() {
+        return Err(failure(&output, backend.captured_output_limit()));
     }
+
+    Ok(output)
+}
+
+/// The most specific error for the output of a failed command,
+/// keeping `limit` bytes of each stream
+///
+/// Errors nix printed as JSON objects are read like errors printed as text,
+/// see [nix_error::reported_error].
+/// The error is read from the last `limit` bytes of stderr like they are kept,
+/// so that its [NixError::stderr] is bounded as well.
+fn failure(output: &Output, limit: usize) -> NixCommandLineCollectError {
+    let captured = CapturedOutput::new(output, limit);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = truncate_start(nix_error::reported_as_text(&stderr).as_bytes(), limit);
+    let stderr = stderr.as_slice();
+    build_limit_error(output.status, stderr, &captured)
+        .or_else(|| post_build_hook_error(output.status, stderr, &captured))
+        .or_else(|| import_from_derivation_error(output.status, stderr, &captured))
+        .or_else(|| pure_eval_error(output.status, stderr, &captured))
+        .or_else(|| classified_error(output.status, stderr, &captured))
+        .or_else(|| evaluation_error(output.status, stderr, &captured))
+        .unwrap_or_else(|| NixCommandLineCollectError::Failed {
+            status: output.status,
+            error: NixError::Other {
+                stderr: String::from_utf8_lossy(stderr).into_owned(),
+            },
+            output: captured,
+        })
 }
 
 /// Implementation of a command execution that connects the subprocess' stdio
 /// to the parent process stdio.
 ///
+/// While nix runs, `SIGINT` does not end this process, see [InterruptGuard].
+/// Ctrl-C in the terminal reaches nix as well, as nix stays in the process group
+/// of this process.
+///
 /// User facing operation
 struct Passthru;
 #[async_trait]
 impl CommandMode for Passthru {
     type Error = NixCommandLineError;
-    type Output = ExitStatus;
+    type Output = Interactive;
 
-    async fn run(command: &mut Command) -> Result<ExitStatus, Self::Error> {
-        command.as_std().log(log::Level::Info);
+    const LOG_LEVEL: log::Level = log::Level::Info;
 
+    async fn run(
+        command: &mut Command,
+        backend: &NixCommandLine,
+        invocation: &Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<Interactive, Self::Error> {
         let command = command
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .stdin(Stdio::inherit());
 
-        let status = command.status().await.map_err(NixCommandLineError::Run)?;
+        let guard = InterruptGuard::new().map_err(NixCommandLineError::Run)?;
+        let mut nix =
+            RunningNix::start(command, OnDrop::Detach, backend, invocation.clone(), stdin)?;
+        let status = nix.wait().await?;
+
+        Ok(Interactive::new(status, &guard))
+    }
+}
+
+/// How nix sharing the terminal exited, see [Passthru]
+struct Interactive {
+    status: ExitStatus,
+    /// Whether nix died of an interrupt or failed after this process received one,
+    /// e.g. a shell exiting with the status of a command the user stopped with Ctrl-C
+    ///
+    /// A shell that ignored Ctrl-C and exited successfully later is not interrupted.
+    interrupted: bool,
+}
+
+impl Interactive {
+    /// How nix exited while `guard` was held
+    fn new(status: ExitStatus, guard: &InterruptGuard) -> Self {
+        Interactive {
+            interrupted: status.signal() == Some(libc::SIGINT)
+                || (guard.interrupted() && !status.success()),
+            status,
+        }
+    }
+}
+
+/// Read piped stdout and stderr of `nix` concurrently until nix exited,
+/// passing each line on as it is read, see [NixCommandLine::run_with_output]
+async fn read_lines(
+    mut nix: RunningNix,
+    on_stdout: impl FnMut(&[u8]),
+    on_stderr: impl FnMut(&[u8]),
+) -> Result<Output, NixCommandLineError> {
+    let invocation = nix.invocation().clone();
+    let mut stdout_pipe = nix.take_stdout().expect("stdout is piped");
+    let mut stderr_pipe = nix.take_stderr().expect("stderr is piped");
+    let mut stdout = LineSplitter::new(on_stdout);
+    let mut stderr = LineSplitter::new(on_stderr);
+    let mut stdout_buf = [0; 8192];
+    let mut stderr_buf = [0; 8192];
+    let (mut stdout_open, mut stderr_open) = (true, true);
+
+    // `read` is cancel safe, no data is lost if the other stream is read first
+    while stdout_open || stderr_open {
+        tokio::select! {
+            read = stdout_pipe.read(&mut stdout_buf), if stdout_open => {
+                match read.map_err(NixCommandLineError::Run)? {
+                    0 => stdout_open = false,
+                    n => {
+                        invocation.read(Stream::Stdout, n);
+                        stdout.push(&stdout_buf[..n]);
+                    },
+                }
+            },
+            read = stderr_pipe.read(&mut stderr_buf), if stderr_open => {
+                match read.map_err(NixCommandLineError::Run)? {
+                    0 => stderr_open = false,
+                    n => {
+                        invocation.read(Stream::Stderr, n);
+                        stderr.push(&stderr_buf[..n]);
+                    },
+                }
+            },
+        }
+    }
+
+    Ok(Output {
+        status: nix.wait().await?,
+        stdout: stdout.finish(),
+        stderr: stderr.finish(),
+    })
+}
 
-        Ok(status)
+/// Fail if the [NixArgs::cwd] nix is run in does not exist on this host
+fn check_cwd(nix_args: &NixArgs) -> Result<(), NixCommandLineError> {
+    match nix_args.cwd.as_ref().filter(|cwd| !cwd.is_dir()) {
+        Some(cwd) => Err(NixCommandLineError::MissingCwd(cwd.clone())),
+        None => Ok(()),
     }
 }
 
 impl NixCommandLine {
+    /// Apply `defaults` to the command `B` only, replacing those set for it before
+    ///
+    /// E.g. `--no-write-lock-file` for commands that only read a flake,
+    /// or `-L` in the [DefaultArgs::extra_args] of [Build](crate::command::Build).
+    ///
+    /// Arguments are passed to nix in this order, later ones take precedence:
+    ///
+    /// 1. [NixCommandLine::defaults]
+    /// 2. the defaults of `B`
+    /// 3. the [NixArgs] of the call and the arguments of the command
+    ///
+    /// Environment variables are merged in the same order.
+    /// [DefaultArgs::extra_args] are the exception,
+    /// they follow the arguments of the command in the order of their defaults.
+    pub fn with_command_defaults<B: NixCliCommand>(mut self, defaults: DefaultArgs) -> Self {
+        self.command_defaults.insert(B::SUBCOMMAND, defaults);
+        self
+    }
+
+    /// [NixCommandLine::retry], unless the stdin of `nix_args` can not be read again
+    fn retry_policy(&self, nix_args: &NixArgs) -> Option<&RetryPolicy> {
+        let replayable = nix_args
+            .stdin
+            .as_ref()
+            .is_none_or(InputSource::is_replayable);
+        self.retry.as_ref().filter(|_| replayable)
+    }
+
+    /// [NixCommandLine::defaults] followed by the [NixCommandLine::command_defaults] of `B`
+    fn defaults_for<B: NixCliCommand>(&self) -> impl Iterator<Item = &DefaultArgs> {
+        std::iter::once(&self.defaults).chain(self.command_defaults.get(B::SUBCOMMAND))
+    }
+
     /// Small wrapping helper function to make Run implementations simpler
-    async fn run_command<M: CommandMode, A, B: NixCliCommand<Own = A>>(
+    ///
+    /// Failures are retried according to [NixCommandLine::retry].
+    pub(crate) async fn run_command<M: CommandMode, A, B: NixCliCommand<Own = A>>(
         &self,
         command: &B,
         nix_args: &NixArgs,
         json: bool,
     ) -> Result<M::Output, M::Error> {
-        let args = vec![
-            // apply default args always applicable
-            self.defaults.config_args.to_args(),
-            self.defaults.common_args.to_args(),
-            nix_args.to_args(),
-            B::SUBCOMMAND.iter().map(ToString::to_string).collect(),
-            // apply command specific defaults if applicable
-            // as defined by the command impl
-            B::EVAL_ARGS
-                .map(|_| self.defaults.eval_args.to_args())
-                .unwrap_or_default(),
-            B::FLAKE_ARGS
-                .map(|_| self.defaults.flake_args.to_args())
-                .unwrap_or_default(),
-            if json {
-                vec!["--json".to_string()]
-            } else {
-                vec![]
-            },
-            command.args(),
-            self.defaults.extra_args.clone(),
-        ];
+        let mut attempts = 0;
+        retrying(self.retry_policy(nix_args), M::nix_error, move || {
+            let retries = attempts;
+            attempts += 1;
+            async move {
+                let mut command = self.nix_command(command, nix_args, json)?;
+                command
+                    .as_std()
+                    .log_redacted(M::LOG_LEVEL, &nix_args.secret_env);
+                let _permit = self.invocation_permit::<B>().await;
+                let invocation = self.invocation::<B>(&command, retries);
+                invocation
+                    .instrument(M::run(
+                        &mut command,
+                        self,
+                        &invocation,
+                        nix_args.stdin.as_ref(),
+                    ))
+                    .await
+            }
+        })
+        .await
+    }
 
-        let mut command = Command::new(self.nix_bin.as_deref().unwrap_or("nix"));
+    /// Run a command, passing its output to callbacks line by line as nix prints it
+    ///
+    /// Unlike the [Run] implementations, which either collect the output until nix exits
+    /// or pass it through to the terminal, this allows to show the progress
+    /// of long running builds.
+    /// Both streams are read concurrently, lines are passed on without the trailing newline,
+    /// including a final line that is not terminated by one.
+    /// Lines of the same stream are passed on in order,
+    /// there is no ordering between lines of stdout and stderr.
+    ///
+    /// Lines are passed on as bytes, as nix need not print valid UTF-8,
+    /// wrap the callbacks in [utf8_lines] to receive text.
+    ///
+    /// The complete output is returned no matter the exit status of nix,
+    /// stderr is returned as printed by nix (see [Collect] for its usual treatment).
+    pub async fn run_with_output<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        on_stdout: impl FnMut(&[u8]),
+        on_stderr: impl FnMut(&[u8]),
+    ) -> Result<Output, NixCommandLineError> {
+        let mut command = self.nix_command(command, nix_args, false)?;
         command
-            .envs(&self.defaults.environment)
-            .args(args.into_iter().flatten());
+            .as_std()
+            .log_redacted(log::Level::Debug, &nix_args.secret_env);
 
-        if let Some(ref cwd) = nix_args.cwd {
-            command.current_dir(cwd);
-        }
+        let _permit = self.invocation_permit::<B>().await;
+        let invocation = self.invocation::<B>(&command, 0);
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .stdin(Stdio::inherit());
+        let nix = RunningNix::start(
+            &mut command,
+            OnDrop::Detach,
+            self,
+            invocation,
+            nix_args.stdin.as_ref(),
+        )?;
 
-        M::run(&mut command).await
+        read_lines(nix, on_stdout, on_stderr).await
     }
 
-    // Set the global Nix config via the environment variables in flox.default_args so that
-    // subprocesses called by `flox` (e.g. `parser-util`) can inherit them.
-    pub fn export_env_vars(&self) {
-        for (name, value) in self.defaults.environment.iter() {
-            std::env::set_var(name, value);
+    /// Start a command without waiting for it to finish,
+    /// to be able to time it out or abort it, see [RunningCommand]
+    ///
+    /// Nix is started in a new process group with stdin closed,
+    /// unless set by [NixArgs::stdin].
+    /// See [NixCommandLine::spawn_nix] for interactive commands.
+    pub async fn spawn<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+    ) -> Result<RunningCommand, NixCommandLineError> {
+        let mut command = self.nix_command(command, nix_args, false)?;
+        command
+            .as_std()
+            .log_redacted(log::Level::Debug, &nix_args.secret_env);
+
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .stdin(Stdio::null());
+        // SAFETY: `setpgid` is async-signal-safe
+        unsafe {
+            command.pre_exec(|| match libc::setpgid(0, 0) {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            });
         }
+        let permit = self.invocation_permit::<B>().await;
+        let invocation = self.invocation::<B>(&command, 0);
+        let nix = RunningNix::start(
+            &mut command,
+            OnDrop::Kill,
+            self,
+            invocation,
+            nix_args.stdin.as_ref(),
+        )?
+        .holding(permit);
+
+        Ok(RunningCommand::new(nix))
     }
-}
 
-/// Create a list of arguments from `&Self`
-///
-/// Groups, i.e. Structs that only contain fields which immplement [ToArgs]
-/// can derive this trait using [runix_derive::ToArgs].
-pub trait ToArgs {
-    fn to_args(&self) -> Vec<String>;
-}
+    /// Start a command and hand over control of the nix process, see [RunningNix]
+    ///
+    /// Unlike with [NixCommandLine::spawn], nix stays in the process group of the caller
+    /// and its output is not collected.
+    /// With inherited stdio, nix is connected to the terminal and receives its signals
+    /// (e.g. `Ctrl-C`), like for an interactive `nix develop` or `nix run`.
+    pub async fn spawn_nix<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        options: SpawnOptions,
+    ) -> Result<RunningNix, NixCommandLineError> {
+        let mut command = self.nix_command(command, nix_args, false)?;
+        command
+            .as_std()
+            .log_redacted(log::Level::Debug, &nix_args.secret_env);
 
-impl<T: ToArgs> ToArgs for Option<T> {
-    fn to_args(&self) -> Vec<String> {
-        self.iter().flat_map(|t| t.to_args()).collect()
+        command
+            .stdin(options.stdin)
+            .stdout(options.stdout)
+            .stderr(options.stderr);
+        let permit = self.invocation_permit::<B>().await;
+        let invocation = self.invocation::<B>(&command, 0);
+        let nix = RunningNix::start(
+            &mut command,
+            options.on_drop,
+            self,
+            invocation,
+            nix_args.stdin.as_ref(),
+        )?;
+        Ok(nix.holding(permit))
     }
-}
 
-impl<T: ToArgs> ToArgs for Vec<T> {
-    fn to_args(&self) -> Vec<String> {
-        self.iter().flat_map(|t| t.to_args()).collect()
-    }
-}
+    /// Run a command with `--json`, parsing the values nix prints to stdout as they arrive,
+    /// see [JsonStream]
+    ///
+    /// Meant for newline delimited or concatenated JSON values
+    /// and for documents too large to be buffered.
+    pub async fn run_json_stream<T, B>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+    ) -> Result<JsonStream<T>, NixCommandLineError>
+    where
+        T: DeserializeOwned + Send + 'static,
+        B: NixCliCommand,
+    {
+        let mut command = self.nix_command(command, nix_args, true)?;
+        command
+            .as_std()
+            .log_redacted(log::Level::Debug, &nix_args.secret_env);
 
-/// A group of options that may or may not applicable for a command
-///
-/// If [Some], provides a function that extracts a group `U`
-/// from a refernece to the command.
-///
-/// If [None], no option of this group is generated on the command line.
-/// Any defaults set on the [NixCommandLine] instance for this group
-///  will likewise be ignored.
-pub type Group<T, U> = Option<fn(&T) -> U>;
+        let permit = self.invocation_permit::<B>().await;
+        let invocation = self.invocation::<B>(&command, 0);
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .stdin(Stdio::inherit());
+        let nix = RunningNix::start(
+            &mut command,
+            OnDrop::Kill,
+            self,
+            invocation,
+            nix_args.stdin.as_ref(),
+        )?
+        .holding(permit);
 
-/// Marker trait for abstract commands run on the nix CLI
-///
-/// A new command can be implemented by implementing this trait,
-/// and supplying the applicable option groups.
-///
-/// Used to automatically implement [Run<NixCommandLine>] for implementers
-///
-/// See the implementations in [super::command] for examples of practical
-/// implementations.
-pub trait NixCliCommand: fmt::Debug + Sized {
-    type Own: ToArgs;
+        Ok(JsonStream::new(nix, self.captured_output_limit()))
+    }
 
-    const SUBCOMMAND: &'static [&'static str];
+    /// Like [NixCommandLine::run_with_output], but passing stderr through
+    /// [internal_log::parse_line] if nix is told to use [LogFormat::InternalJson],
+    /// either by `nix_args` or the defaults
+    ///
+    /// With any other log format all lines are passed on as [InternalLog::Raw].
+    pub async fn run_with_log<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        on_stdout: impl FnMut(&[u8]),
+        mut on_log: impl FnMut(InternalLog),
+    ) -> Result<Output, NixCommandLineError> {
+        // `nix_args` are passed after the defaults and take precedence
+        let log_format = nix_args.common.log_format.or(self
+            .defaults_for::<B>()
+            .filter_map(|defaults| defaults.common_args.log_format)
+            .last());
 
-    const INSTALLABLES: Group<Self, InstallablesArgs> = None;
-    const INSTALLABLE: Group<Self, InstallableArg> = None;
+        if log_format == Some(LogFormat::InternalJson) {
+            self.run_with_output(command, nix_args, on_stdout, |line| {
+                on_log(internal_log::parse_line(line))
+            })
+            .await
+        } else {
+            self.run_with_output(command, nix_args, on_stdout, |line| {
+                on_log(InternalLog::Raw(String::from_utf8_lossy(line).into_owned()))
+            })
+            .await
+        }
+    }
+
+    /// Show the nix invocation for `command` without running it,
+    /// with access tokens redacted, see [CommandPreview]
+    ///
+    /// The arguments are validated like for a run.
+    /// [RunJson] implementations additionally pass `--json`.
+    pub fn to_command_preview<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+    ) -> Result<CommandPreview, NixCommandLineError> {
+        self.command_preview(command, nix_args, false)
+    }
+
+    /// See [NixCommandLine::to_command_preview], `json` adds `--json`
+    fn command_preview<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        json: bool,
+    ) -> Result<CommandPreview, NixCommandLineError> {
+        let args = self.render_args(command, nix_args, json)?;
+        check_cwd(nix_args)?;
+        let args = args.into_iter().map(Cow::into_owned).collect();
+        Ok(CommandPreview::new(self.program(), args)
+            .with_env(
+                self.defaults_for::<B>()
+                    .flat_map(|defaults| &defaults.environment),
+                nix_args,
+            )
+            .with_cwd(nix_args.cwd.as_deref())
+            .with_stdin(nix_args)
+            .with_wrapper(self.wrapper.as_deref().unwrap_or_default()))
+    }
+
+    /// See [NixCommandLine::captured_output_limit]
+    fn captured_output_limit(&self) -> usize {
+        self.captured_output_limit
+            .unwrap_or(DEFAULT_CAPTURED_OUTPUT_LIMIT)
+    }
+
+    /// The nix binary that is run
+    fn program(&self) -> &str {
+        self.nix_bin.as_deref().unwrap_or("nix")
+    }
+
+    /// The [NixCommandLine::wrapper] and its arguments, if set
+    fn wrapper(&self) -> Option<(&OsString, &[OsString])> {
+        self.wrapper.as_deref().and_then(<[_]>::split_first)
+    }
+
+    /// A [NixCommandLineError::Run] for a failure to start nix,
+    /// naming the [NixCommandLine::wrapper] if nix is run through one
+    fn start_error(&self, err: std::io::Error) -> NixCommandLineError {
+        match self.wrapper() {
+            Some((wrapper, _)) => NixCommandLineError::Run(std::io::Error::new(
+                err.kind(),
+                format!("{}: {err}", wrapper.to_string_lossy()),
+            )),
+            None => NixCommandLineError::Run(err),
+        }
+    }
+
+    /// Assemble the nix invocation for `command`
+    ///
+    /// Validates the arguments and logs warnings about their use beforehand.
+    fn nix_command<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        json: bool,
+    ) -> Result<Command, NixCommandLineError> {
+        let args = self.render_args(command, nix_args, json)?;
+        check_cwd(nix_args)?;
+        self.warn_about_config(command, nix_args);
+
+        let mut command = match self.wrapper() {
+            Some((wrapper, wrapper_args)) => {
+                let mut command = Command::new(wrapper);
+                command.args(wrapper_args).arg(self.program());
+                command
+            },
+            None => Command::new(self.program()),
+        };
+        for defaults in self.defaults_for::<B>() {
+            command.envs(&defaults.environment);
+        }
+        command.args(args.iter().map(AsRef::<str>::as_ref));
+        for name in &nix_args.env_remove {
+            command.env_remove(name);
+        }
+        command.envs(nix_args.env.iter().cloned());
+
+        if let Some(ref cwd) = nix_args.cwd {
+            command.current_dir(cwd);
+        }
+
+        Ok(command)
+    }
+
+    /// Log warnings about the use of the nix configuration for `command`
+    fn warn_about_config<B: NixCliCommand>(&self, command: &B, nix_args: &NixArgs) {
+        let installables = command.installables();
+        let installables = installables.iter().collect::<Vec<_>>();
+        for warning in self
+            .defaults_for::<B>()
+            .map(|defaults| &defaults.config_args)
+            .chain([&nix_args.config])
+            .flat_map(|config| config.warnings(&installables))
+        {
+            warn!("{warning}");
+        }
+    }
+
+    /// Start tracing `command`, an invocation of `B` after `retries` failed attempts,
+    /// see [NixCommandLine::metrics]
+    fn invocation<B: NixCliCommand>(&self, command: &Command, retries: u32) -> Invocation {
+        Invocation::start(B::SUBCOMMAND, command).with_metrics(self.metrics.clone(), retries)
+    }
+
+    /// Wait until [NixCommandLine::max_concurrent_invocations] allows to run `B`
+    async fn invocation_permit<B: NixCliCommand>(&self) -> Option<Permit> {
+        let limits = self.max_concurrent_invocations.as_ref()?;
+        limits.acquire(B::INVOCATION_COST).await
+    }
+
+    /// Validate the arguments for `command` and render them in the order they are passed to nix
+    ///
+    /// The working directory is not checked, as nix need not run on this host,
+    /// see [check_cwd].
+    fn render_args<'a, B: NixCliCommand>(
+        &'a self,
+        command: &'a B,
+        nix_args: &'a NixArgs,
+        json: bool,
+    ) -> Result<Vec<Cow<'a, str>>, NixCommandLineError> {
+        for defaults in self.defaults_for::<B>() {
+            defaults.config_args.validate()?;
+        }
+        nix_args.config.validate()?;
+        self.check_conflicts(command, nix_args)
+            .and_then(|_| command.validate())?;
+
+        let mut call_args = nix_args.to_cow_args();
+        self.inject_features::<B>(nix_args, &mut call_args);
+        call_args.extend(self.flake_config_decision(command, nix_args));
+
+        let args: [Vec<Cow<str>>; 10] = [
+            // apply default args always applicable
+            self.defaults_for::<B>()
+                .flat_map(|defaults| defaults.config_args.to_cow_args())
+                .collect(),
+            self.defaults_for::<B>()
+                .flat_map(|defaults| defaults.common_args.to_cow_args())
+                .collect(),
+            call_args,
+            B::SUBCOMMAND.iter().map(|s| Cow::Borrowed(*s)).collect(),
+            // apply command specific defaults if applicable
+            // as defined by the command impl
+            B::EVAL_ARGS
+                .map(|_| {
+                    self.defaults_for::<B>()
+                        .flat_map(|defaults| defaults.eval_args.to_cow_args())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            B::FLAKE_ARGS
+                .map(|_| {
+                    self.defaults_for::<B>()
+                        .flat_map(|defaults| defaults.flake_args.to_cow_args())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            if json {
+                vec![Cow::Borrowed("--json")]
+            } else {
+                vec![]
+            },
+            command.cow_args(),
+            self.defaults_for::<B>()
+                .flat_map(|defaults| &defaults.extra_args)
+                .map(|arg| Cow::Borrowed(arg.as_str()))
+                .collect(),
+            command.trailing_args(),
+        ];
+
+        Ok(args.into_iter().flatten().collect())
+    }
+
+    /// `--accept-flake-config` or `--no-accept-flake-config`
+    /// as decided by [NixCommandLine::flake_config_trust] for the flakes `command` uses,
+    /// unless the options already agree
+    ///
+    /// Rejecting is passed even if the options do not accept,
+    /// overriding `accept-flake-config` in the nix configuration.
+    fn flake_config_decision<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+    ) -> Option<Cow<'static, str>> {
+        let installables = command.installables();
+        let own_flake_refs = command.flake_refs();
+        let flake_refs = installables
+            .iter()
+            .filter_map(|installable| match installable {
+                Installable::FlakeAttribute(attribute) => Some(&attribute.flakeref),
+                Installable::StorePath(_) => None,
+            })
+            .chain(&own_flake_refs);
+        let accepted = self
+            .defaults_for::<B>()
+            .any(|defaults| *defaults.config_args.accept_flake_config)
+            || *nix_args.config.accept_flake_config;
+
+        match self.flake_config_trust.accepts(flake_refs)? {
+            true if !accepted => Some(Cow::Borrowed(AcceptFlakeConfig::FLAG)),
+            true => None,
+            false => Some(Cow::Borrowed("--no-accept-flake-config")),
+        }
+    }
+
+    /// Reject combinations of shared option groups nix would only fail on
+    /// with a confusing message (or not at all)
+    fn check_conflicts<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+    ) -> Result<(), ArgConflict> {
+        let defaults_for = || self.defaults_for::<B>();
+        let offline = defaults_for().any(|defaults| *defaults.common_args.offline)
+            || *nix_args.common.offline;
+        let refresh = B::EVAL_ARGS.is_some_and(|f| {
+            defaults_for().any(|defaults| *defaults.eval_args.refresh) || *f(command).refresh
+        });
+        if offline && refresh {
+            return Err(ArgConflict {
+                first: Offline::FLAG,
+                second: Refresh::FLAG,
+                reason: "offline mode never refetches inputs".to_string(),
+            });
+        }
+
+        if let Some(f) = B::FLAKE_ARGS {
+            let flake_args = f(command);
+            let no_write_lock_file = defaults_for()
+                .any(|defaults| *defaults.flake_args.no_write_lock_file)
+                || *flake_args.no_write_lock_file;
+            let commit_lock_file = defaults_for()
+                .any(|defaults| *defaults.flake_args.commit_lock_file)
+                || *flake_args.commit_lock_file;
+            if no_write_lock_file && commit_lock_file {
+                return Err(ArgConflict {
+                    first: NoWriteLockFile::FLAG,
+                    second: CommitLockFile::FLAG,
+                    reason: "the lock file can not be committed without writing it".to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merge the experimental features required by `B`
+    /// that are not enabled by the default or given config
+    /// into the rendered `nix_args`
+    fn inject_features<B: NixCliCommand>(&self, nix_args: &NixArgs, rendered: &mut Vec<Cow<str>>) {
+        if self.disable_feature_injection {
+            return;
+        }
+
+        let enabled = self
+            .defaults_for::<B>()
+            .map(|defaults| &defaults.config_args)
+            .chain([&nix_args.config])
+            .flat_map(|config| config.extra_experimental_features.iter())
+            .collect::<Vec<_>>();
+        let missing = B::EXPERIMENTAL_FEATURES
+            .iter()
+            .filter(|feature| !enabled.iter().any(|enabled| enabled == *feature))
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            return;
+        }
+
+        match rendered
+            .iter()
+            .position(|arg| arg == ExperimentalFeatures::FLAG)
+        {
+            Some(flag) => {
+                let features = &mut rendered[flag + 1];
+                *features = format!("{features} {}", missing.join(" ")).into();
+            },
+            None => {
+                let flag = ExperimentalFeatures::from(missing).to_args();
+                rendered.splice(0..0, flag.into_iter().map(Cow::Owned));
+            },
+        }
+    }
+
+    // Set the global Nix config via the environment variables in flox.default_args so that
+    // subprocesses called by `flox` (e.g. `parser-util`) can inherit them.
+    pub fn export_env_vars(&self) {
+        for (name, value) in self.defaults.environment.iter() {
+            std::env::set_var(name, value);
+        }
+    }
+}
+
+/// Create a list of arguments from `&Self`
+///
+/// Groups, i.e. Structs that only contain fields which immplement [ToArgs]
+/// can derive this trait using [runix_derive::ToArgs].
+pub trait ToArgs {
+    /// Arguments borrowing from `self` where possible
+    ///
+    /// Static flag names are [Cow::Borrowed], only dynamic values allocate.
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>>;
+
+    /// Owned arguments, see [ToArgs::to_cow_args]
+    fn to_args(&self) -> Vec<String> {
+        self.to_cow_args()
+            .into_iter()
+            .map(Cow::into_owned)
+            .collect()
+    }
+}
+
+impl<T: ToArgs> ToArgs for Option<T> {
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        self.iter().flat_map(|t| t.to_cow_args()).collect()
+    }
+}
+
+impl<T: ToArgs> ToArgs for Vec<T> {
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
+        self.iter().flat_map(|t| t.to_cow_args()).collect()
+    }
+}
+
+/// A group of options that may or may not applicable for a command
+///
+/// If [Some], provides a function that borrows a group `U`
+/// from a refernece to the command.
+///
+/// If [None], no option of this group is generated on the command line.
+/// Any defaults set on the [NixCommandLine] instance for this group
+///  will likewise be ignored.
+pub type Group<T, U> = Option<fn(&T) -> &U>;
+
+/// Marker trait for abstract commands run on the nix CLI
+///
+/// A new command can be implemented by implementing this trait,
+/// and supplying the applicable option groups.
+///
+/// Used to automatically implement [Run<NixCommandLine>] for implementers
+///
+/// See the implementations in [super::command] for examples of practical
+/// implementations.
+pub trait NixCliCommand: fmt::Debug + Sized {
+    type Own: ToArgs;
+
+    const SUBCOMMAND: &'static [&'static str];
+
+    /// Experimental features the command requires
+    ///
+    /// Unless [NixCommandLine::disable_feature_injection] is set,
+    /// those not already enabled through [NixConfigArgs::extra_experimental_features]
+    /// are added to the command line.
+    /// All commands of the "new" nix CLI require `nix-command`,
+    /// commands that can evaluate flakes require `flakes` as well.
+    const EXPERIMENTAL_FEATURES: &'static [&'static str] = &["nix-command"];
+
+    const INSTALLABLES: Group<Self, InstallablesArgs> = None;
+    const INSTALLABLE: Group<Self, InstallableArg> = None;
     const FLAKE_ARGS: Group<Self, FlakeArgs> = None;
     const EVAL_ARGS: Group<Self, EvaluationArgs> = None;
     const SOURCE_ARGS: Group<Self, SourceArgs> = None;
     const OWN_ARGS: Group<Self, Self::Own> = None;
+    /// Arguments following a `--` separator
+    ///
+    /// Unlike all other groups these are placed at the very end of the command line,
+    /// behind any [NixCommandLine] defaults, see [NixCliCommand::trailing_args].
+    const TRAILING_ARGS: Group<Self, Vec<String>> = None;
+
+    /// How the command shares the terminal when it is [run](Run),
+    /// unless [NixArgs::execution_mode] is set
+    ///
+    /// Commands that start interactive programs, like [Develop](crate::command::Develop),
+    /// let nix own the terminal, all others are [ExecutionMode::Captured].
+    const EXECUTION_MODE: ExecutionMode = ExecutionMode::Captured;
 
+    /// Which of the [NixCommandLine::max_concurrent_invocations] the command counts against
+    ///
+    /// Commands that build or copy store paths are [InvocationCost::Expensive],
+    /// as are interactive sessions like [Develop](crate::command::Develop),
+    /// which build their environment and hold their slot until they end.
+    const INVOCATION_COST: InvocationCost = InvocationCost::Cheap;
+
+    /// Owned arguments, see [NixCliCommand::cow_args]
     fn args(&self) -> Vec<String> {
+        self.cow_args().into_iter().map(Cow::into_owned).collect()
+    }
+
+    /// The arguments of the command's own groups, borrowed from the command where possible
+    fn cow_args(&self) -> Vec<Cow<'_, str>> {
         let mut acc = Vec::new();
-        acc.append(&mut Self::FLAKE_ARGS.map_or(Vec::new(), |f| f(self).to_args()));
-        acc.append(&mut Self::EVAL_ARGS.map_or(Vec::new(), |f| f(self).to_args()));
-        acc.append(&mut Self::SOURCE_ARGS.map_or(Vec::new(), |f| f(self).to_args()));
-        acc.append(&mut Self::INSTALLABLES.map_or(Vec::new(), |f| f(self).to_args()));
-        acc.append(&mut Self::INSTALLABLE.map_or(Vec::new(), |f| f(self).to_args()));
-        acc.append(&mut Self::OWN_ARGS.map_or(Vec::new(), |f| f(self).to_args()));
+        acc.append(&mut Self::FLAKE_ARGS.map_or(Vec::new(), |f| f(self).to_cow_args()));
+        acc.append(&mut Self::EVAL_ARGS.map_or(Vec::new(), |f| f(self).to_cow_args()));
+        acc.append(&mut Self::SOURCE_ARGS.map_or(Vec::new(), |f| f(self).to_cow_args()));
+        acc.append(&mut Self::INSTALLABLES.map_or(Vec::new(), |f| f(self).to_cow_args()));
+        acc.append(&mut Self::INSTALLABLE.map_or(Vec::new(), |f| f(self).to_cow_args()));
+        acc.append(&mut Self::OWN_ARGS.map_or(Vec::new(), |f| f(self).to_cow_args()));
         acc
     }
+
+    /// The installables the command operates on
+    ///
+    /// Used to check settings against, see [NixConfigArgs::warnings].
+    /// If the command evaluates an `--expr`, installables are attributes of that expression,
+    /// hence none are returned.
+    fn installables(&self) -> Vec<Installable> {
+        if Self::SOURCE_ARGS.is_some_and(|f| f(self).expr.is_some()) {
+            return Vec::new();
+        }
+        let mut acc = Vec::new();
+        acc.extend(Self::INSTALLABLE.and_then(|f| f(self).as_ref().cloned()));
+        acc.extend(Self::INSTALLABLES.map_or(Vec::new(), |f| f(self).to_vec()));
+        acc
+    }
+
+    /// The flakes the command operates on as a whole rather than through installables,
+    /// e.g. the flake `nix flake update` locks
+    ///
+    /// Checked against [NixCommandLine::flake_config_trust] with the installables.
+    fn flake_refs(&self) -> Vec<FlakeRef> {
+        Vec::new()
+    }
+
+    /// The `--` separator and the arguments following it, if any
+    fn trailing_args(&self) -> Vec<Cow<'_, str>> {
+        match Self::TRAILING_ARGS.map(|f| f(self)) {
+            Some(args) if !args.is_empty() => std::iter::once(Cow::Borrowed("--"))
+                .chain(args.iter().map(|arg| Cow::Borrowed(arg.as_str())))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Reject combinations of the command's own arguments
+    ///
+    /// Runs before nix is invoked, in addition to the checks
+    /// of the option groups all commands share.
+    fn validate(&self) -> Result<(), ArgConflict> {
+        Ok(())
+    }
 }
 
 /// Marker Trait for commands that can return JSON
@@ -365,7 +1747,7 @@ pub trait TypedCommand {
 }
 
 impl ToArgs for () {
-    fn to_args(&self) -> Vec<String> {
+    fn to_cow_args(&self) -> Vec<Cow<'_, str>> {
         Default::default()
     }
 }
@@ -378,6 +1760,33 @@ pub enum NixCommandLineRunError {
     Backend(#[from] NixCommandLineError),
     #[error("Nix call unsuccessful: [{0}]")]
     Exit(ExitStatus),
+    /// Nix failed with the error it printed,
+    /// in [ExecutionMode::Captured] and [ExecutionMode::Piped]
+    #[error(transparent)]
+    Failed(NixCommandLineCollectError),
+    /// Nix died of an interrupt, or failed after one, e.g. Ctrl-C, in [ExecutionMode::Inherit]
+    ///
+    /// Reported instead of [NixCommandLineRunError::Exit],
+    /// as the user asked nix to stop.
+    #[error("Nix call interrupted: [{0}]")]
+    Interrupted(ExitStatus),
+}
+
+impl From<NixCommandLineCollectError> for NixCommandLineRunError {
+    /// Nix failing to start is a [NixCommandLineRunError::Backend] error in every mode
+    fn from(error: NixCommandLineCollectError) -> Self {
+        match error {
+            NixCommandLineCollectError::CommandLine(error) => {
+                NixCommandLineRunError::Backend(error)
+            },
+            error => NixCommandLineRunError::Failed(error),
+        }
+    }
+}
+
+/// The [ExecutionMode] of `C` run with `nix_args`
+pub(crate) fn execution_mode<C: NixCliCommand>(nix_args: &NixArgs) -> ExecutionMode {
+    nix_args.execution_mode.unwrap_or(C::EXECUTION_MODE)
 }
 
 #[async_trait]
@@ -392,12 +1801,34 @@ where
         backend: &NixCommandLine,
         nix_args: &NixArgs,
     ) -> Result<(), NixCommandLineRunError> {
-        let exit_status = backend
-            .run_command::<Passthru, _, _>(self, nix_args, false)
-            .await?;
-
-        if !exit_status.success() {
-            Err(NixCommandLineRunError::Exit(exit_status))?
+        match execution_mode::<C>(nix_args) {
+            ExecutionMode::Inherit => {
+                let Interactive {
+                    status,
+                    interrupted,
+                } = backend
+                    .run_command::<Passthru, _, _>(self, nix_args, false)
+                    .await?;
+                if interrupted {
+                    Err(NixCommandLineRunError::Interrupted(status))?
+                }
+                if !status.success() {
+                    Err(NixCommandLineRunError::Exit(status))?
+                }
+            },
+            ExecutionMode::Captured => {
+                let output = backend
+                    .run_command::<Collect, _, _>(self, nix_args, false)
+                    .await?;
+                std::io::stdout()
+                    .write_all(&output.stdout)
+                    .map_err(NixCommandLineError::Run)?;
+            },
+            ExecutionMode::Piped => {
+                backend
+                    .run_command::<Piped, _, _>(self, nix_args, false)
+                    .await?;
+            },
         }
 
         Ok(())
@@ -410,6 +1841,32 @@ pub enum NixCommandLineRunJsonError {
     Json(#[from] serde_json::Error),
     #[error(transparent)]
     Run(NixCommandLineCollectError),
+    /// Nix failed, but printed a JSON object describing the failure,
+    /// to stdout or as its error on stderr (see [nix_error::reported_error])
+    #[error("{source}")]
+    Reported {
+        json: Value,
+        source: NixCommandLineCollectError,
+    },
+}
+
+impl From<NixCommandLineCollectError> for NixCommandLineRunJsonError {
+    /// Prefer a JSON object in the captured stdout over the plain failure,
+    /// followed by an error object nix printed to stderr
+    ///
+    /// Truncated output does not parse and is left to [NixCommandLineRunJsonError::Run].
+    fn from(source: NixCommandLineCollectError) -> Self {
+        let json = source.output().and_then(|output| {
+            serde_json::from_slice::<Value>(&output.stdout)
+                .ok()
+                .filter(Value::is_object)
+                .or_else(|| nix_error::reported_error(&String::from_utf8_lossy(&output.stderr)))
+        });
+        match json {
+            Some(json) => NixCommandLineRunJsonError::Reported { json, source },
+            None => NixCommandLineRunJsonError::Run(source),
+        }
+    }
 }
 
 #[async_trait]
@@ -426,13 +1883,11 @@ where
     ) -> Result<Value, Self::JsonError> {
         let output = backend
             .run_command::<Collect, _, _>(self, nix_args, true)
-            .await
-            .map_err(NixCommandLineRunJsonError::Run)?;
+            .await?;
 
-        let out_str = String::from_utf8_lossy(&output.stdout);
-        debug!("JSON command output: {:?}", out_str);
+        debug!("JSON command output: {:?}", output.output_lossy());
 
-        Ok(serde_json::from_str(&out_str)?)
+        Ok(serde_json::from_slice(&output.stdout)?)
     }
 }
 
@@ -456,3 +1911,1331 @@ where
         }
     }
 }
+
+#[derive(Error, Debug)]
+pub enum NixCommandLineRunWhyDependsError {
+    #[error(transparent)]
+    Run(#[from] NixCommandLineCollectError),
+    #[error("Could not parse `nix why-depends` output: {0}")]
+    Parse(#[from] StorePathError),
+}
+
+/// `nix why-depends` does not support `--json`,
+/// instead the printed tree is parsed into a [WhyDependsOut]
+#[async_trait]
+impl RunTyped<NixCommandLine> for WhyDepends {
+    type Output = WhyDependsOut;
+    type TypedError = NixCommandLineRunWhyDependsError;
+
+    async fn run_typed(
+        &self,
+        backend: &NixCommandLine,
+        nix_args: &NixArgs,
+    ) -> Result<Self::Output, Self::TypedError> {
+        let output = backend
+            .run_command::<Collect, _, _>(self, nix_args, false)
+            .await?;
+
+        let out_str = String::from_utf8_lossy(&output.stdout);
+        debug!("why-depends output: {:?}", out_str);
+
+        Ok(out_str.parse()?)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NixCommandLineRunAddPathError {
+    #[error(transparent)]
+    Run(#[from] NixCommandLineCollectError),
+    #[error("Could not parse `nix store add-path` output: {0}")]
+    Parse(#[from] StorePathError),
+}
+
+/// `nix store add-path` does not support `--json`,
+/// instead the printed path is parsed into a [StorePath]
+#[async_trait]
+impl RunTyped<NixCommandLine> for StoreAddPath {
+    type Output = StorePath;
+    type TypedError = NixCommandLineRunAddPathError;
+
+    async fn run_typed(
+        &self,
+        backend: &NixCommandLine,
+        nix_args: &NixArgs,
+    ) -> Result<Self::Output, Self::TypedError> {
+        let output = backend
+            .run_command::<Collect, _, _>(self, nix_args, false)
+            .await?;
+
+        let out_str = String::from_utf8_lossy(&output.stdout);
+        debug!("store add-path output: {:?}", out_str);
+
+        Ok(out_str.trim().parse()?)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NixCommandLineRunBundleError {
+    #[error(transparent)]
+    Run(#[from] NixCommandLineCollectError),
+    #[error("An out link is required to locate the bundle")]
+    NoOutLink,
+    #[error("Could not resolve out link '{0}': {1}")]
+    ResolveOutLink(PathBuf, std::io::Error),
+    #[error("Bundle is not a store path: {0}")]
+    StorePath(#[from] StorePathError),
+}
+
+/// `nix bundle` only creates an out link to the bundle,
+/// which is resolved to the [StorePath] of the bundle
+#[async_trait]
+impl RunTyped<NixCommandLine> for Bundle {
+    type Output = StorePath;
+    type TypedError = NixCommandLineRunBundleError;
+
+    async fn run_typed(
+        &self,
+        backend: &NixCommandLine,
+        nix_args: &NixArgs,
+    ) -> Result<Self::Output, Self::TypedError> {
+        let out_link = self
+            .bundle_args
+            .out_link
+            .as_deref()
+            .ok_or(NixCommandLineRunBundleError::NoOutLink)?;
+        let out_link = match nix_args.cwd {
+            Some(ref cwd) => cwd.join(out_link),
+            None => out_link.to_path_buf(),
+        };
+
+        backend
+            .run_command::<Collect, _, _>(self, nix_args, false)
+            .await?;
+
+        let bundle = std::fs::canonicalize(&out_link)
+            .map_err(|e| NixCommandLineRunBundleError::ResolveOutLink(out_link, e))?;
+
+        Ok(StorePath::from_path(bundle)?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::*;
+    use crate::arguments::config::{AllowDirty, WarnDirty};
+    use crate::arguments::source::SourceArgs;
+    use crate::arguments::{EvalArgs, PathInfoArgs, ProfileInstallArgs};
+    use crate::command::{
+        Build,
+        Eval,
+        FlakeCheck,
+        FlakeInit,
+        FlakeMetadata,
+        PathInfo,
+        ProfileInstall,
+        Run as RunCommand,
+    };
+    use crate::command_line::recording::RecordingBackend;
+    use crate::flake_ref::path::PathRef;
+    use crate::flake_ref::FlakeRef;
+    use crate::installable::FlakeAttribute;
+    use crate::store_uri::StoreUri;
+
+    /// Static flags are rendered as borrowed strings all the way to the command line
+    #[test]
+    fn static_flags_borrow() {
+        let backend = NixCommandLine {
+            disable_feature_injection: true,
+            ..Default::default()
+        };
+        let command = PathInfo {
+            path_info: PathInfoArgs {
+                closure_size: Some(true.into()),
+                human_readable: Some(true.into()),
+                sigs: Some(true.into()),
+                size: Some(true.into()),
+            },
+            ..Default::default()
+        };
+
+        let nix_args = NixArgs::default();
+
+        let rendered = backend.render_args(&command, &nix_args, true).unwrap();
+        assert!(rendered.iter().all(|arg| matches!(arg, Cow::Borrowed(_))));
+
+        assert_eq!(rendered, vec![
+            "path-info",
+            "--json",
+            "--closure-size",
+            "--human-readable",
+            "--sigs",
+            "--size"
+        ]);
+    }
+
+    /// Stands in for nix' handling of dirty git trees
+    const FAKE_NIX: &str = r#"
+if [ -n "$(git status --porcelain)" ]; then
+  case "$*" in
+    *"allow-dirty false"*) echo "error: Git tree '$PWD' is dirty" >&2; exit 1;;
+    *"warn-dirty false"*) ;;
+    *) echo "warning: Git tree '$PWD' is dirty" >&2;;
+  esac
+fi
+echo "evaluating" >&2
+echo '"ok"'
+"#;
+
+    fn git(repo: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=runix", "-c", "user.email=runix@localhost"])
+            .args(args)
+            .current_dir(repo)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    /// A git repo with uncommitted changes and a fake nix binary
+    fn dirty_fixture() -> (tempfile::TempDir, NixCommandLine) {
+        let (tempdir, backend) = script_fixture(FAKE_NIX);
+        let repo = tempdir.path().join("repo");
+        std::fs::create_dir(&repo).unwrap();
+        git(&repo, &["init", "-q"]);
+        std::fs::write(repo.join("flake.nix"), "{}").unwrap();
+        git(&repo, &["add", "flake.nix"]);
+        git(&repo, &["commit", "-q", "-m", "init"]);
+        std::fs::write(repo.join("flake.nix"), "{ outputs = _: {}; }").unwrap();
+        (tempdir, backend)
+    }
+
+    /// Held by tests running commands in [ExecutionMode::Inherit],
+    /// which share the handling of `SIGINT` in this process
+    pub(super) static INTERRUPTS: Lazy<tokio::sync::Mutex<()>> =
+        Lazy::new(|| tokio::sync::Mutex::new(()));
+
+    /// Write an executable file to `path` running the shell `script`
+    pub(crate) fn write_script(path: &Path, script: &str) {
+        std::fs::write(path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// A fake nix binary running the shell `script`
+    ///
+    /// Feature injection is disabled, so `script` only receives the arguments of the command.
+    pub(crate) fn script_fixture(script: &str) -> (tempfile::TempDir, NixCommandLine) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let nix_bin = tempdir.path().join("nix");
+        write_script(&nix_bin, script);
+
+        let backend = NixCommandLine {
+            nix_bin: Some(nix_bin.to_string_lossy().into_owned()),
+            disable_feature_injection: true,
+            ..Default::default()
+        };
+        (tempdir, backend)
+    }
+
+    /// A fake nix binary printing its arguments, one per line
+    pub(super) fn echo_fixture() -> (tempfile::TempDir, NixCommandLine) {
+        let (tempdir, mut backend) = script_fixture("printf '%s\\n' \"$@\"");
+        backend.disable_feature_injection = false;
+        (tempdir, backend)
+    }
+
+    #[test]
+    fn dirty_settings_args() {
+        assert_eq!(WarnDirty::from(false).to_args(), vec![
+            "--option",
+            "warn-dirty",
+            "false"
+        ]);
+        assert_eq!(AllowDirty::from(true).to_args(), vec![
+            "--option",
+            "allow-dirty",
+            "true"
+        ]);
+
+        let config = NixConfigArgs {
+            warn_dirty: Some(false.into()),
+            allow_dirty: Some(true.into()),
+            ..Default::default()
+        };
+        let config_string = config.to_config_string();
+        assert!(config_string.contains("allow-dirty = true\n"));
+        assert!(config_string.ends_with("warn-dirty = false"));
+        assert!(!NixConfigArgs::default()
+            .to_args()
+            .iter()
+            .any(|arg| arg == "--option"));
+    }
+
+    /// Classify a failure of nix printing `stderr` with the classifier `f`
+    fn classify(
+        f: fn(ExitStatus, &[u8], &CapturedOutput) -> Option<NixCommandLineCollectError>,
+        stderr: &[u8],
+    ) -> Option<NixCommandLineCollectError> {
+        let status = ExitStatus::from_raw(1 << 8);
+        f(status, stderr, &CapturedOutput::default())
+    }
+
+    #[test]
+    fn maps_build_limit_errors() {
+        const DRV: &str = "/nix/store/8vq9f8bq9ayn3ih4hz3ihc2jjq3d5mgl-hello-2.12.1.drv";
+
+        let stderr = format!(
+            "building '{DRV}'...\nerror: building of '{DRV}' timed out after 600 seconds of silence\n"
+        );
+        assert!(matches!(
+            classify(build_limit_error, stderr.as_bytes()),
+            Some(NixCommandLineCollectError::BuildLimit {
+                limit: BuildLimit::MaxSilentTime,
+                drv,
+                after,
+                ..
+            }) if drv.to_string() == DRV && after == Duration::from_secs(600)
+        ));
+
+        let stderr = format!("error: building of '{DRV}' timed out after 3600 seconds\n");
+        let output = Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: stderr.clone().into_bytes(),
+        };
+        let err = failure(&output, usize::MAX);
+        assert!(matches!(err, NixCommandLineCollectError::BuildLimit {
+            limit: BuildLimit::Timeout,
+            ..
+        }));
+        assert_eq!(err.status(), Some(output.status));
+        assert_eq!(err.output().unwrap().stderr, output.stderr);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Building '{DRV}' exceeded the timeout of 3600s\nstderr:\n{}",
+                stderr.trim_end()
+            )
+        );
+
+        let builder = b"error: builder for 'x' failed with exit code 1\n";
+        assert!(classify(build_limit_error, builder).is_none());
+    }
+
+    #[test]
+    fn maps_post_build_hook_errors() {
+        let stderr =
+            b"building '/nix/store/8vq9f8bq9ayn3ih4hz3ihc2jjq3d5mgl-hello-2.12.1.drv'...\n\
+            running post-build-hook '/etc/nix/upload.sh'...\n\
+            error: program '/etc/nix/upload.sh' failed with exit code 1\n";
+        assert!(matches!(
+            classify(post_build_hook_error, stderr),
+            Some(NixCommandLineCollectError::PostBuildHook { hook, reason, .. })
+                if hook == Path::new("/etc/nix/upload.sh") && reason == "failed with exit code 1"
+        ));
+
+        let builder = b"error: builder for 'x' failed with exit code 1\n";
+        assert!(classify(post_build_hook_error, builder).is_none());
+        // other programs fail with the same message
+        let fetch = b"error: program 'git' failed with exit code 128\n";
+        assert!(classify(post_build_hook_error, fetch).is_none());
+        let after_hook = b"running post-build-hook '/etc/nix/upload.sh'...\n\
+            error: program 'git' failed with exit code 128\n";
+        assert!(classify(post_build_hook_error, after_hook).is_none());
+    }
+
+    #[test]
+    fn maps_import_from_derivation_errors() {
+        const DRV: &str = "/nix/store/sxq4n7cmg2iskgdrc6ijm0qw6j3yn8pd-ifd.drv";
+        for stderr in [
+            format!("error: cannot build '{DRV}' during evaluation because the option 'allow-import-from-derivation' is disabled\n"),
+            format!("error:\n       … while evaluating the attribute 'packages'\n\n       error: cannot build '{DRV}^out' during evaluation because the option 'allow-import-from-derivation' is disabled\n"),
+        ] {
+            assert!(matches!(
+                classify(import_from_derivation_error, stderr.as_bytes()),
+                Some(NixCommandLineCollectError::ImportFromDerivationBlocked { drv_path, .. })
+                    if drv_path.to_string() == DRV
+            ));
+        }
+
+        let builder = b"error: builder for 'x' failed\n";
+        assert!(classify(import_from_derivation_error, builder).is_none());
+    }
+
+    /// Config settings apply to every command,
+    /// in particular those that may evaluate IFD
+    #[tokio::test]
+    async fn import_from_derivation_setting_reaches_commands() {
+        let (_tempdir, backend) = echo_fixture();
+        let nix_args = NixArgs {
+            config: NixConfigArgs {
+                allow_import_from_derivation: Some(false.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let outputs = [
+            backend
+                .run_command::<Collect, _, _>(&Eval::default(), &nix_args, false)
+                .await,
+            backend
+                .run_command::<Collect, _, _>(&Build::default(), &nix_args, false)
+                .await,
+            backend
+                .run_command::<Collect, _, _>(&FlakeCheck::default(), &nix_args, false)
+                .await,
+        ];
+        for output in outputs {
+            let args = String::from_utf8(output.unwrap().stdout).unwrap();
+            assert!(args.contains("--option\nallow-import-from-derivation\nfalse\n"));
+        }
+    }
+
+    #[test]
+    fn maps_evaluation_errors() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let stderr = include_bytes!("../../test/eval-trace-nix-2.18.txt");
+        let err = evaluation_error(
+            ExitStatus::from_raw(1 << 8),
+            stderr,
+            &CapturedOutput::default(),
+        )
+        .unwrap();
+        let NixCommandLineCollectError::Evaluation { message, trace, .. } = err else {
+            panic!("expected an evaluation error, got {err:?}");
+        };
+        assert_eq!(message, "this package is broken");
+        assert_eq!(trace.frames.len(), 4);
+
+        assert!(evaluation_error(
+            ExitStatus::from_raw(1 << 8),
+            b"error: flake 'flake:x' does not exist\n",
+            &CapturedOutput::default()
+        )
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn classifies_failures() {
+        let (_tempdir, backend) = script_fixture(
+            [
+                "echo \"error: experimental Nix feature 'flakes' is disabled; add '--extra-experimental-features flakes' to enable it\" >&2",
+                "exit 1",
+            ]
+            .join("\n")
+            .as_str(),
+        );
+
+        let err = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, NixCommandLineCollectError::Failed {
+                error: NixError::ExperimentalFeatureRequired { feature, .. },
+                ..
+            } if feature == "flakes"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn maps_pure_eval_errors() {
+        let stderr = b"error:\n       \xe2\x80\xa6 while calling the 'readFile' builtin\n\n       error: access to absolute path '/etc/passwd' is forbidden in pure eval mode (use '--impure' to override)\n";
+        assert!(matches!(
+            classify(pure_eval_error, stderr),
+            Some(NixCommandLineCollectError::ForbiddenInPureEval { path, .. })
+                if path == Path::new("/etc/passwd")
+        ));
+
+        assert!(classify(pure_eval_error, b"error: undefined variable 'x'\n").is_none());
+    }
+
+    /// A flake that imports from a derivation
+    /// fails with [NixCommandLineCollectError::ImportFromDerivationBlocked]
+    /// if IFD is disallowed
+    ///
+    /// Replays the output of nix, see [RecordingBackend].
+    #[tokio::test]
+    async fn blocks_import_from_derivation() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tempdir.path().join("flake.nix"),
+            r#"{
+  outputs = _: {
+    value = import (derivation {
+      name = "ifd";
+      system = builtins.currentSystem;
+      builder = "/bin/sh";
+      args = [ "-c" "echo 42 > $out" ];
+    });
+  };
+}"#,
+        )
+        .unwrap();
+
+        let eval = Eval {
+            eval_args: EvalArgs {
+                installable: Some(
+                    FlakeAttribute {
+                        flakeref: FlakeRef::Path(PathRef::new(
+                            tempdir.path().to_path_buf(),
+                            Default::default(),
+                        )),
+                        attr_path: ["value"].try_into().unwrap(),
+                    }
+                    .into(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let nix_args = NixArgs {
+            config: NixConfigArgs {
+                allow_import_from_derivation: Some(false.into()),
+                extra_experimental_features: vec!["nix-command".into(), "flakes".into()].into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let backend = RecordingBackend::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test/fixtures/import-from-derivation"
+        ))
+        .normalize(tempdir.path().to_string_lossy(), "<flake>");
+        let err = eval.run_json(&backend, &nix_args).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NixCommandLineRunJsonError::Run(
+                NixCommandLineCollectError::ImportFromDerivationBlocked { .. }
+            )
+        ));
+    }
+
+    #[test]
+    fn routes_restricted_settings() {
+        let stderr = b"warning: ignoring the client-specified setting 'post-build-hook', because it is a restricted setting and you are not a trusted user\nevaluating\n";
+        assert_eq!(route_stderr(stderr), b"evaluating\n");
+    }
+
+    /// `nix flake init` writes to its working directory,
+    /// which is set per invocation instead of for this process
+    #[tokio::test]
+    async fn runs_in_cwd() {
+        let (tempdir, backend) = script_fixture("echo '{}' > flake.nix");
+
+        let project = tempdir.path().join("project");
+        std::fs::create_dir(&project).unwrap();
+        assert_ne!(std::env::current_dir().unwrap(), project);
+        FlakeInit::default()
+            .run(&backend, &NixArgs::default().with_cwd(&project))
+            .await
+            .unwrap();
+        assert!(project.join("flake.nix").is_file());
+
+        let missing = tempdir.path().join("missing");
+        let err = FlakeInit::default()
+            .run(&backend, &NixArgs::default().with_cwd(&missing))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, NixCommandLineRunError::Backend(NixCommandLineError::MissingCwd(path)) if path == &missing),
+            "{err:?}"
+        );
+        assert!(!missing.exists());
+    }
+
+    /// Nix in [ExecutionMode::Inherit] is only interrupted if it failed after Ctrl-C,
+    /// e.g. not a shell in which the user stopped a command but that exited successfully
+    #[tokio::test]
+    async fn reports_interrupts_of_failed_sessions() {
+        let _interrupts = INTERRUPTS.lock().await;
+        // stands in for Ctrl-C in the terminal, which reaches this process as well
+        let (_tempdir, backend) = script_fixture("kill -INT $PPID\nexit $STATUS");
+        let run = |status: &str| {
+            let nix_args = NixArgs::default()
+                .with_env("STATUS", status)
+                .with_execution_mode(ExecutionMode::Inherit);
+            let backend = &backend;
+            async move { FlakeCheck::default().run(backend, &nix_args).await }
+        };
+
+        run("0").await.unwrap();
+        let err = run("1").await.unwrap_err();
+        assert!(
+            matches!(err, NixCommandLineRunError::Interrupted(status) if status.code() == Some(1)),
+            "{err:?}"
+        );
+
+        // SAFETY: the action is only read
+        let handler = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        };
+        assert_eq!(
+            handler,
+            libc::SIG_DFL,
+            "the handler for SIGINT was not restored"
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_through_wrapper() {
+        let (tempdir, backend) = script_fixture("echo \"$WRAPPED\"\nprintf '%s\\n' \"$@\"");
+        let backend = NixCommandLine {
+            wrapper: Some(vec!["env".into(), "WRAPPED=yes".into()]),
+            ..backend
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "yes\nbuild\n");
+
+        let backend = NixCommandLine {
+            wrapper: Some(vec![tempdir.path().join("missing").into()]),
+            ..backend
+        };
+        let err = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap_err();
+        let NixCommandLineCollectError::CommandLine(NixCommandLineError::Run(err)) = err else {
+            panic!("expected the wrapper to be missing, got {err:?}");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}: ", tempdir.path().join("missing").display())));
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_not_run() {
+        let (tempdir, backend) = dirty_fixture();
+        let nix_args = NixArgs {
+            config: NixConfigArgs {
+                post_build_hook: Some(tempdir.path().join("missing.sh").into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let err = backend
+            .run_command::<Collect, _, _>(&Eval::default(), &nix_args, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NixCommandLineCollectError::CommandLine(NixCommandLineError::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn dirty_warning_not_forwarded() {
+        let (tempdir, backend) = dirty_fixture();
+        let nix_args = NixArgs {
+            cwd: Some(tempdir.path().join("repo")),
+            ..Default::default()
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Eval::default(), &nix_args, true)
+            .await
+            .unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "evaluating\n");
+        assert_eq!(
+            serde_json::from_slice::<Value>(&output.stdout).unwrap(),
+            Value::from("ok")
+        );
+    }
+
+    #[tokio::test]
+    async fn dirty_warning_suppressed() {
+        let (tempdir, backend) = dirty_fixture();
+        let nix_args = NixArgs {
+            cwd: Some(tempdir.path().join("repo")),
+            config: NixConfigArgs {
+                warn_dirty: Some(false.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Eval::default(), &nix_args, true)
+            .await
+            .unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "evaluating\n");
+    }
+
+    #[tokio::test]
+    async fn dirty_tree_rejected() {
+        let (tempdir, backend) = dirty_fixture();
+        let nix_args = NixArgs {
+            cwd: Some(tempdir.path().join("repo")),
+            config: NixConfigArgs {
+                allow_dirty: Some(false.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let err = Eval::default()
+            .run_json(&backend, &nix_args)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            NixCommandLineRunJsonError::Run(NixCommandLineCollectError::Failed {
+                error: NixError::Other { .. },
+                ..
+            })
+        ));
+    }
+
+    /// Installing two packages that provide the same file into a profile
+    /// conflicts unless one of them is given a different priority
+    #[tokio::test]
+    #[ignore = "requires nix"]
+    async fn profile_priority_resolves_conflict() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let profile = tempdir.path().join("profile");
+        let package = |name: &str| SourceArgs {
+            expr: Some(
+                format!(
+                    r#"derivation {{
+                    name = "{name}";
+                    system = builtins.currentSystem;
+                    builder = "/bin/sh";
+                    args = [ "-c" "mkdir -p $out/bin && echo {name} > $out/bin/conflict" ];
+                }}"#
+                )
+                .into(),
+            ),
+        };
+        let install = |name: &str, priority: Option<i32>| ProfileInstall {
+            source: package(name),
+            profile_install: ProfileInstallArgs {
+                profile: Some(profile.clone().into()),
+                priority: priority.map(Into::into),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let backend = NixCommandLine::default();
+        let nix_args = NixArgs::default();
+
+        install("first", None)
+            .run(&backend, &nix_args)
+            .await
+            .unwrap();
+        install("second", None)
+            .run(&backend, &nix_args)
+            .await
+            .expect_err("should conflict");
+        install("second", Some(-1))
+            .run(&backend, &nix_args)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(profile.join("bin/conflict")).unwrap(),
+            "second\n"
+        );
+    }
+
+    /// Arguments after `--` reach the program as is, behind any default arguments
+    #[tokio::test]
+    async fn trailing_args_are_last() {
+        let (_tempdir, mut backend) = echo_fixture();
+        backend.defaults.extra_args = vec!["--verbose".to_string()];
+        let command = RunCommand {
+            args_after_double_dash: vec!["--flag".to_string(), "value".to_string()],
+            ..Default::default()
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&command, &NixArgs::default(), false)
+            .await
+            .unwrap();
+        let args = String::from_utf8(output.stdout).unwrap();
+        let args = args.lines().collect::<Vec<_>>();
+
+        assert!(args.ends_with(&["--verbose", "--", "--flag", "value"]));
+    }
+
+    /// Global defaults are overridden by those of the command,
+    /// which are overridden by the arguments of the call
+    #[test]
+    fn command_defaults_take_precedence() {
+        let ttl = |secs| Some(Duration::from_secs(secs).try_into().unwrap());
+        let mut backend = NixCommandLine {
+            disable_feature_injection: true,
+            ..Default::default()
+        };
+        backend.defaults.config_args.tarball_ttl = ttl(3600);
+        backend.defaults.extra_args = vec!["--verbose".to_string()];
+        backend.defaults.environment =
+            HashMap::from([("NIX_SSHOPTS".to_string(), "global".to_string())]);
+        let backend = backend.with_command_defaults::<Build>(DefaultArgs {
+            environment: HashMap::from([("NIX_SSHOPTS".to_string(), "build".to_string())]),
+            config_args: NixConfigArgs {
+                tarball_ttl: ttl(60),
+                ..Default::default()
+            },
+            flake_args: FlakeArgs {
+                no_write_lock_file: true.into(),
+                ..Default::default()
+            },
+            extra_args: vec!["-L".to_string()],
+            ..Default::default()
+        });
+        let ttls = |args: &[String]| {
+            args.windows(2)
+                .filter(|w| w[0] == "--tarball-ttl")
+                .map(|w| w[1].clone())
+                .collect::<Vec<_>>()
+        };
+
+        let preview = backend
+            .to_command_preview(&Build::default(), &NixArgs::default())
+            .unwrap();
+        assert_eq!(ttls(&preview.args), ["3600", "60"]);
+        assert!(preview.args.contains(&"--no-write-lock-file".to_string()));
+        assert!(preview
+            .args
+            .ends_with(&["--verbose".to_string(), "-L".to_string()]));
+        assert_eq!(preview.env, [(
+            "NIX_SSHOPTS".to_string(),
+            "build".to_string()
+        )]);
+
+        let nix_args = NixArgs {
+            config: NixConfigArgs {
+                tarball_ttl: ttl(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .with_env("NIX_SSHOPTS", "call");
+        let preview = backend
+            .to_command_preview(&Build::default(), &nix_args)
+            .unwrap();
+        assert_eq!(ttls(&preview.args), ["3600", "60", "0"]);
+        assert_eq!(preview.env, [(
+            "NIX_SSHOPTS".to_string(),
+            "call".to_string()
+        )]);
+
+        // other commands only get the global defaults
+        let preview = backend
+            .to_command_preview(&Eval::default(), &NixArgs::default())
+            .unwrap();
+        assert_eq!(ttls(&preview.args), ["3600"]);
+        assert!(!preview.args.contains(&"--no-write-lock-file".to_string()));
+        assert!(preview.args.ends_with(&["--verbose".to_string()]));
+        assert_eq!(preview.env, [(
+            "NIX_SSHOPTS".to_string(),
+            "global".to_string()
+        )]);
+    }
+
+    /// `--store` (a common arg) and `--eval-store` (an evaluation arg) can be combined
+    #[tokio::test]
+    async fn store_and_eval_store() {
+        let (_tempdir, mut backend) = echo_fixture();
+        backend.disable_feature_injection = true;
+        let nix_args = NixArgs {
+            common: NixCommonArgs {
+                store: Some("ssh-ng://builder".parse::<StoreUri>().unwrap().into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let build = Build {
+            eval: EvaluationArgs {
+                eval_store: Some("auto".parse::<StoreUri>().unwrap().into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&build, &nix_args, false)
+            .await
+            .unwrap();
+        let args = String::from_utf8(output.stdout).unwrap();
+        let args = args.lines().collect::<Vec<_>>();
+
+        assert_eq!(&args[..5], [
+            "--store",
+            "ssh-ng://builder",
+            "build",
+            "--eval-store",
+            "auto"
+        ]);
+    }
+
+    #[test]
+    fn fresh_sets_tarball_ttl() {
+        let args = NixArgs::default().fresh().to_args();
+        assert!(args.windows(2).any(|w| w == ["--tarball-ttl", "0"]));
+    }
+
+    /// With a zero `tarball-ttl` a branch is resolved to its current head
+    #[tokio::test]
+    #[ignore = "requires nix"]
+    async fn fresh_refetches_mutated_branch() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let repo = tempdir.path();
+        git(repo, &["init", "-q", "-b", "main"]);
+        std::fs::write(repo.join("flake.nix"), "{ outputs = _: {}; }").unwrap();
+        git(repo, &["add", "flake.nix"]);
+        git(repo, &["commit", "-q", "-m", "init"]);
+
+        let metadata = FlakeMetadata {
+            flake_ref: Some(
+                format!("git+file://{}?ref=main", repo.display())
+                    .parse::<FlakeRef>()
+                    .unwrap()
+                    .into(),
+            ),
+            ..Default::default()
+        };
+        let backend = NixCommandLine::default();
+
+        let before = metadata
+            .run_typed(&backend, &NixArgs::default())
+            .await
+            .unwrap();
+
+        git(repo, &["commit", "-q", "--allow-empty", "-m", "mutate"]);
+        let head = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(repo)
+            .output()
+            .unwrap()
+            .stdout;
+        let head = String::from_utf8(head).unwrap();
+
+        let after = metadata
+            .run_typed(&backend, &NixArgs::default().fresh())
+            .await
+            .unwrap();
+
+        assert_ne!(before.revision, after.revision);
+        assert_eq!(after.revision.unwrap().to_string(), head.trim());
+    }
+
+    #[tokio::test]
+    async fn injects_missing_experimental_features() {
+        let (_tempdir, backend) = echo_fixture();
+        let nix_args = NixArgs {
+            config: NixConfigArgs {
+                extra_experimental_features: vec!["nix-command".to_string()].into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&FlakeMetadata::default(), &nix_args, false)
+            .await
+            .unwrap();
+        let args = String::from_utf8(output.stdout).unwrap();
+        let args = args.lines().collect::<Vec<_>>();
+
+        let flags = args
+            .iter()
+            .enumerate()
+            .filter(|(_, arg)| **arg == "--extra-experimental-features")
+            .map(|(n, _)| args[n + 1])
+            .collect::<Vec<_>>();
+        assert_eq!(flags, ["nix-command flakes"]);
+    }
+
+    /// Per-invocation variables reach nix on top of the defaults,
+    /// without changing the environment of this process
+    #[tokio::test]
+    async fn sets_env_per_invocation() {
+        let (_tempdir, mut backend) = script_fixture("env");
+        backend.defaults.environment = HashMap::from([
+            ("RUNIX_DEFAULT".to_string(), "default".to_string()),
+            ("RUNIX_REMOVED".to_string(), "removed".to_string()),
+        ]);
+        let nix_args = NixArgs::default()
+            .with_env("NIX_SSHOPTS", "-p 2222")
+            .with_secret_env("RUNIX_TOKEN", "secret")
+            .without_env("RUNIX_REMOVED");
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Build::default(), &nix_args, false)
+            .await
+            .unwrap();
+        let env = String::from_utf8(output.stdout).unwrap();
+        let env = env.lines().collect::<Vec<_>>();
+
+        assert!(env.contains(&"NIX_SSHOPTS=-p 2222"));
+        assert!(env.contains(&"RUNIX_TOKEN=secret"));
+        assert!(env.contains(&"RUNIX_DEFAULT=default"));
+        assert!(!env.iter().any(|var| var.starts_with("RUNIX_REMOVED=")));
+        assert!(std::env::var_os("RUNIX_TOKEN").is_none());
+    }
+
+    /// A fake nix binary running `script` and failing
+    fn failing_fixture(script: &str) -> (tempfile::TempDir, NixCommandLine) {
+        script_fixture(&format!("{script}\nexit 1"))
+    }
+
+    #[tokio::test]
+    async fn keeps_output_of_failures() {
+        let (_tempdir, mut backend) = failing_fixture(
+            "for i in 1 2 3 4 5 6 7 8 9; do echo \"progress $i\"; done\necho 'error: it broke' >&2",
+        );
+        backend.captured_output_limit = Some(22);
+
+        let err = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap_err();
+        let output = err.output().unwrap();
+        assert_eq!(output.stderr, b"error: it broke\n");
+        assert_eq!(
+            output.stdout,
+            b"[77 bytes truncated]\nprogress 8\nprogress 9\n"
+        );
+
+        let message = err.to_string();
+        let stderr = message.find("stderr:\nerror: it broke").unwrap();
+        let stdout = message.find("stdout:\n[77 bytes truncated]").unwrap();
+        assert!(stderr < stdout, "{message}");
+
+        // so do failures of nix that are more specific than a [NixError]
+        let (_tempdir, backend) = failing_fixture(
+            "echo '{\"partial\": true}'\necho \"error: access to absolute path '/etc/passwd' is forbidden in pure eval mode\" >&2",
+        );
+        let err = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, NixCommandLineCollectError::ForbiddenInPureEval { .. }),
+            "{err:?}"
+        );
+        assert_eq!(err.status().and_then(|status| status.code()), Some(1));
+        let output = err.output().unwrap();
+        assert_eq!(output.stdout, b"{\"partial\": true}\n");
+        assert!(output.stderr.ends_with(b"forbidden in pure eval mode\n"));
+        assert!(err.to_string().ends_with("stdout:\n{\"partial\": true}"));
+    }
+
+    #[tokio::test]
+    async fn bounds_stderr_of_errors() {
+        let (_tempdir, mut backend) = failing_fixture(
+            "for i in 1 2 3 4 5 6 7 8 9; do echo \"building $i\" >&2; done\necho 'error: it broke' >&2",
+        );
+        backend.captured_output_limit = Some(27);
+
+        let err = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap_err();
+        let NixCommandLineCollectError::Failed { error, .. } = err else {
+            panic!("expected a failure, got {err:?}");
+        };
+        assert_eq!(
+            error.stderr(),
+            "[88 bytes truncated]\nbuilding 9\nerror: it broke\n"
+        );
+        assert_eq!(error.to_string(), "it broke");
+    }
+
+    #[tokio::test]
+    async fn reports_json_errors() {
+        let (_tempdir, backend) = failing_fixture(
+            "echo '{\"error\": \"no such package\"}'\necho 'error: no such package' >&2",
+        );
+
+        let err = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        let NixCommandLineRunJsonError::Reported { json, source } = err else {
+            panic!("expected a reported error, got {err:?}");
+        };
+        assert_eq!(json, serde_json::json!({"error": "no such package"}));
+        assert!(matches!(source, NixCommandLineCollectError::Failed { .. }));
+
+        let (_tempdir, backend) = failing_fixture(&format!(
+            "cat >&2 <<'EOF'\n{}EOF",
+            include_str!("../../test/nix-error/attribute-missing-json.txt")
+        ));
+        let err = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        let NixCommandLineRunJsonError::Reported { json, source } = err else {
+            panic!("expected a reported error, got {err:?}");
+        };
+        assert_eq!(json["level"], 0);
+        assert!(
+            matches!(source.nix_error(), Some(NixError::AttributeMissing { attr_path, .. })
+                if attr_path == "packages.x86_64-linux.hello"),
+            "{source:?}"
+        );
+
+        let (_tempdir, backend) = failing_fixture("echo '{\"partial\": '");
+        let err = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixCommandLineRunJsonError::Run(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn feature_injection_can_be_disabled() {
+        let (_tempdir, mut backend) = echo_fixture();
+        backend.disable_feature_injection = true;
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap();
+        let args = String::from_utf8(output.stdout).unwrap();
+
+        assert!(!args
+            .lines()
+            .any(|arg| arg == "--extra-experimental-features"));
+    }
+
+    #[test]
+    fn rejects_offline_with_refresh() {
+        let nix_args = NixArgs {
+            common: NixCommonArgs {
+                offline: true.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let build = Build {
+            eval: EvaluationArgs {
+                refresh: true.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let conflict = NixCommandLine::default()
+            .check_conflicts(&build, &nix_args)
+            .unwrap_err();
+        assert_eq!(
+            (conflict.first, conflict.second),
+            ("--offline", "--refresh")
+        );
+
+        NixCommandLine::default()
+            .check_conflicts(&Build::default(), &nix_args)
+            .unwrap();
+    }
+
+    /// With `--expr` nix resolves installables against the expression, so both are passed on
+    #[tokio::test]
+    async fn passes_expr_with_installable() {
+        let (_tempdir, backend) = echo_fixture();
+        let build = Build {
+            source: SourceArgs {
+                expr: Some("{ value = 1; }".into()),
+            },
+            installables: vec![FlakeAttribute {
+                flakeref: FlakeRef::Path(PathRef::new("/tmp".into(), Default::default())),
+                attr_path: ["value"].try_into().unwrap(),
+            }
+            .into()]
+            .into(),
+            ..Default::default()
+        };
+
+        let output = backend
+            .run_command::<Collect, _, _>(&build, &NixArgs::default(), false)
+            .await
+            .unwrap();
+        let args = String::from_utf8(output.stdout).unwrap();
+
+        assert!(args
+            .lines()
+            .collect::<Vec<_>>()
+            .ends_with(&["--expr", "{ value = 1; }", "path:/tmp#value"]));
+    }
+
+    #[test]
+    fn rejects_uncommitted_lock_file() {
+        let mut backend = NixCommandLine::default();
+        backend.defaults.flake_args.no_write_lock_file = true.into();
+        let metadata = FlakeMetadata {
+            flake: FlakeArgs {
+                commit_lock_file: true.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let conflict = backend
+            .check_conflicts(&metadata, &NixArgs::default())
+            .unwrap_err();
+        assert_eq!(
+            (conflict.first, conflict.second),
+            ("--no-write-lock-file", "--commit-lock-file")
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_output_lines() {
+        let (_tempdir, backend) = script_fixture(
+            [
+                "for i in 1 2 3 4 5; do",
+                "    echo \"out $i\"",
+                "    echo \"err $i\" >&2",
+                "done",
+                "# more than fits into a pipe buffer while stdout is silent",
+                "head -c 100000 /dev/zero | tr '\\0' x >&2",
+                "echo >&2",
+                "printf 'partial'",
+            ]
+            .join("\n")
+            .as_str(),
+        );
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let output = tokio::time::timeout(
+            Duration::from_secs(10),
+            backend.run_with_output(
+                &Build::default(),
+                &NixArgs::default(),
+                utf8_lines(|line| stdout.push(line.to_string())),
+                utf8_lines(|line| stderr.push(line.to_string())),
+            ),
+        )
+        .await
+        .expect("reading both streams must not deadlock")
+        .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(stdout, [
+            "out 1", "out 2", "out 3", "out 4", "out 5", "partial"
+        ]);
+        assert_eq!(&stderr[..5], ["err 1", "err 2", "err 3", "err 4", "err 5"]);
+        assert_eq!(stderr[5].len(), 100000);
+        assert_eq!(stderr.len(), 6);
+        assert_eq!(output.stdout, b"out 1\nout 2\nout 3\nout 4\nout 5\npartial");
+        assert_eq!(output.stderr.len(), 5 * "err 1\n".len() + 100001);
+    }
+
+    #[tokio::test]
+    async fn keeps_invalid_utf8() {
+        let (_tempdir, backend) = script_fixture(
+            "printf 'out \\377\\376\\n'\nprintf 'err \\300\\n' >&2\n[ -n \"$RUNIX_FAIL\" ] && exit 1\nexit 0",
+        );
+
+        let output = backend
+            .run_command::<Collect, _, _>(&Build::default(), &NixArgs::default(), false)
+            .await
+            .unwrap();
+        assert_eq!(output.output_bytes(), b"out \xff\xfe\n");
+        assert_eq!(output.stderr_bytes(), b"err \xc0\n");
+        assert_eq!(output.output_lossy(), "out \u{FFFD}\u{FFFD}\n");
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        backend
+            .run_with_output(
+                &Build::default(),
+                &NixArgs::default(),
+                |line| stdout.push(line.to_vec()),
+                utf8_lines(|line| stderr.push(line.to_string())),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stdout, [b"out \xff\xfe"]);
+        assert_eq!(stderr, ["err \u{FFFD}"]);
+
+        let nix_args = NixArgs::default().with_env("RUNIX_FAIL", "1");
+        let err = backend
+            .run_command::<Collect, _, _>(&Build::default(), &nix_args, false)
+            .await
+            .unwrap_err();
+        let output = err.output().unwrap();
+        assert_eq!(output.output_bytes(), b"out \xff\xfe\n");
+        assert_eq!(output.stderr_bytes(), b"err \xc0\n");
+        assert!(err.to_string().contains("err \u{FFFD}"));
+
+        let err = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixCommandLineRunJsonError::Json(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn parses_internal_json_log() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/internal-json-download.txt");
+        let (_tempdir, backend) = script_fixture(
+            [
+                "echo \"$@\"".to_string(),
+                "echo 'not structured' >&2".to_string(),
+                format!("cat '{}' >&2", fixture.display()),
+            ]
+            .join("\n")
+            .as_str(),
+        );
+
+        let run = |log_format| {
+            let backend = backend.clone();
+            async move {
+                let mut stdout = Vec::new();
+                let mut log = Vec::new();
+                backend
+                    .run_with_log(
+                        &Build::default(),
+                        &NixArgs {
+                            common: NixCommonArgs {
+                                log_format,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                        utf8_lines(|line| stdout.push(line.to_string())),
+                        |line| log.push(line),
+                    )
+                    .await
+                    .unwrap();
+                (stdout, log)
+            }
+        };
+
+        let (stdout, log) = run(Some(LogFormat::InternalJson)).await;
+        assert_eq!(stdout, ["--log-format internal-json build"]);
+        assert_eq!(log[0], InternalLog::Raw("not structured".to_string()));
+        assert_eq!(log.len(), 11);
+        assert!(log[1..]
+            .iter()
+            .all(|line| matches!(line, InternalLog::Event(_))));
+
+        let (stdout, log) = run(None).await;
+        assert_eq!(stdout, ["build"]);
+        assert_eq!(log.len(), 11);
+        assert!(log.iter().all(|line| matches!(line, InternalLog::Raw(_))));
+    }
+}
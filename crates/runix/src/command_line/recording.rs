@@ -0,0 +1,556 @@
+//! A backend replaying recorded nix invocations, see [RecordingBackend]
+//!
+//! Available with the `test-util` feature.
+
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output, Stdio};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+
+use super::child::{OnDrop, RunningNix};
+use super::input::InputSource;
+use super::instrument::Invocation;
+use super::mock::run_error;
+use super::preview::{redacted_secrets, REDACTED};
+use super::{
+    execution_mode,
+    failure,
+    CommandMode,
+    JsonCommand,
+    NixCliCommand,
+    NixCommandLine,
+    NixCommandLineError,
+    NixCommandLineRunError,
+    NixCommandLineRunJsonError,
+    TypedCommand,
+};
+use crate::arguments::NixArgs;
+use crate::{NixBackend, Run, RunJson, RunTyped};
+
+/// Set to record fixtures with [RecordingBackend::new] rather than replaying them
+pub const RECORD_FIXTURES_VAR: &str = "RUNIX_RECORD_FIXTURES";
+
+/// Whether a [RecordingBackend] runs nix or replays what it printed before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureMode {
+    /// Run nix and store its output as a fixture, replacing one stored before
+    Record,
+    /// Answer invocations with stored fixtures, panicking if there is none
+    Replay,
+}
+
+impl FixtureMode {
+    /// [FixtureMode::Record] if [RECORD_FIXTURES_VAR] is set, otherwise [FixtureMode::Replay]
+    pub fn from_env() -> Self {
+        match std::env::var_os(RECORD_FIXTURES_VAR) {
+            Some(value) if !value.is_empty() => FixtureMode::Record,
+            _ => FixtureMode::Replay,
+        }
+    }
+}
+
+/// A [NixBackend] that records what nix prints into a fixture directory
+/// and replays it later, so that tests of real nix flows run without nix or network access
+///
+/// Invocations are rendered like by [NixCommandLine::to_command_preview],
+/// applying the defaults of [RecordingBackend::cli] and validating the arguments.
+/// Each fixture is a JSON file named after a hash of the [NixCliCommand::SUBCOMMAND],
+/// the arguments, the environment set for nix and a hash of [NixArgs::stdin].
+/// Values that differ between runs, like temporary directories,
+/// are replaced by placeholders with [RecordingBackend::normalize],
+/// in the key as well as in the stored output.
+///
+/// Secrets never reach the fixtures: access tokens, the values of [NixArgs::secret_env]
+/// and [NixArgs::secret_stdin] are `<redacted>` in the key and in the stored output.
+/// Output is stored as text, bytes that are not valid UTF-8 are replaced.
+///
+/// Recording runs nix with its output collected and stdin connected to `/dev/null`,
+/// including commands that would otherwise share the terminal.
+/// Only [InputSource::Bytes] can be fed to nix instead,
+/// invocations reading from a reader or the terminal can not be replayed and panic.
+/// Commands fail like with [NixCommandLine] in their [ExecutionMode](super::child::ExecutionMode),
+/// see [MockBackend](super::mock::MockBackend).
+/// They are not retried, as a fixture holds a single invocation
+/// and a replayed failure would fail the same way again.
+///
+/// ```no_run
+/// # use runix::arguments::NixArgs;
+/// # use runix::command::Eval;
+/// # use runix::command_line::recording::RecordingBackend;
+/// # use runix::RunJson;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let project = tempfile::tempdir().unwrap();
+/// // record with `RUNIX_RECORD_FIXTURES=1 cargo test`
+/// let backend = RecordingBackend::new(concat!(env!("CARGO_MANIFEST_DIR"), "/test/fixtures/eval"))
+///     .normalize(project.path().to_string_lossy(), "<project>");
+///
+/// let value = Eval::default()
+///     .run_json(&backend, &NixArgs::default().with_cwd(project.path()))
+///     .await;
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RecordingBackend {
+    /// Runs nix while recording, and renders the invocations the fixtures are keyed by
+    pub cli: NixCommandLine,
+    dir: PathBuf,
+    mode: FixtureMode,
+    placeholders: Vec<(String, String)>,
+}
+
+/// The output of an invocation, as stored by a [RecordingBackend]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Fixture {
+    /// The invocation the output is for, to make fixtures reviewable
+    #[serde(flatten)]
+    key: FixtureKey,
+    /// The exit status as returned by `waitpid`
+    status: i32,
+    stdout: String,
+    stderr: String,
+}
+
+/// What identifies an invocation of nix to a [RecordingBackend]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FixtureKey {
+    /// The [NixCliCommand::SUBCOMMAND], which stays the same if the command type is renamed
+    command: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    /// A hash of the data fed to nix, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stdin: Option<String>,
+}
+
+impl FixtureKey {
+    /// The file name of the fixture, a hash of the key
+    fn file_name(&self) -> String {
+        let json = serde_json::to_vec(self).expect("keys serialize");
+        format!("{}.json", hex_digest(&json, 16))
+    }
+}
+
+/// The first `len` bytes of the SHA-256 hash of `data` as hex
+fn hex_digest(data: &[u8], len: usize) -> String {
+    Sha256::digest(data)
+        .iter()
+        .take(len)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl NixBackend for RecordingBackend {}
+
+impl RecordingBackend {
+    /// Record to or replay from `dir`, depending on [FixtureMode::from_env]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        RecordingBackend {
+            cli: NixCommandLine::default(),
+            dir: dir.into(),
+            mode: FixtureMode::from_env(),
+            placeholders: Vec::new(),
+        }
+    }
+
+    /// Record or replay regardless of the environment
+    pub fn with_mode(mut self, mode: FixtureMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Render the invocations and run nix with `cli`
+    pub fn with_cli(mut self, cli: NixCommandLine) -> Self {
+        self.cli = cli;
+        self
+    }
+
+    /// Store `value` as `placeholder`, e.g. a temporary directory as `<project>`
+    ///
+    /// Replayed output has the placeholder replaced by the value given for this run.
+    /// Values are replaced in the order they were added.
+    pub fn normalize(mut self, value: impl Into<String>, placeholder: impl Into<String>) -> Self {
+        self.placeholders.push((value.into(), placeholder.into()));
+        self
+    }
+
+    /// The directory fixtures are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether invocations are recorded or replayed
+    pub fn mode(&self) -> FixtureMode {
+        self.mode
+    }
+
+    /// Replace the normalized values in `text` by their placeholders
+    fn normalized(&self, text: &str) -> String {
+        self.placeholders
+            .iter()
+            .fold(text.to_string(), |text, (value, placeholder)| {
+                text.replace(value, placeholder)
+            })
+    }
+
+    /// Replace the placeholders in `text` by the values given for this run
+    fn denormalized(&self, text: &str) -> String {
+        self.placeholders
+            .iter()
+            .rev()
+            .fold(text.to_string(), |text, (value, placeholder)| {
+                text.replace(placeholder, value)
+            })
+    }
+
+    /// Record or replay the invocation of `command`
+    async fn invoke<C: NixCliCommand>(
+        &self,
+        command: &C,
+        nix_args: &NixArgs,
+        json: bool,
+    ) -> Result<Output, NixCommandLineError> {
+        let preview = self.cli.command_preview(command, nix_args, json)?;
+        let key = FixtureKey {
+            command: C::SUBCOMMAND.join(" "),
+            args: preview
+                .args
+                .iter()
+                .map(|arg| self.normalized(arg))
+                .collect(),
+            env: preview
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), self.normalized(value)))
+                .collect(),
+            stdin: self.stdin_digest(nix_args),
+        };
+        let path = self.dir.join(key.file_name());
+
+        match self.mode {
+            FixtureMode::Record => {
+                let output = self
+                    .cli
+                    .run_command::<Record, _, _>(command, nix_args, json)
+                    .await?;
+
+                let secrets = self.secrets(command, nix_args, json, &preview.args)?;
+                let scrubbed = |bytes: &[u8]| {
+                    let text = secrets.iter().fold(
+                        String::from_utf8_lossy(bytes).into_owned(),
+                        |text, secret| text.replace(secret.as_str(), REDACTED),
+                    );
+                    self.normalized(&text)
+                };
+                let fixture = Fixture {
+                    key,
+                    status: output.status.into_raw(),
+                    stdout: scrubbed(&output.stdout),
+                    stderr: scrubbed(&output.stderr),
+                };
+                std::fs::create_dir_all(&self.dir)
+                    .and_then(|_| {
+                        let json = serde_json::to_string_pretty(&fixture)?;
+                        std::fs::write(&path, json + "\n")
+                    })
+                    .unwrap_or_else(|err| {
+                        panic!("could not write the fixture {}: {err}", path.display())
+                    });
+                Ok(output)
+            },
+            FixtureMode::Replay => {
+                let fixture = std::fs::read(&path).unwrap_or_else(|err| {
+                    panic!(
+                        "no fixture {} for the invocation nix {} ({err}), record it with {RECORD_FIXTURES_VAR}=1",
+                        path.display(),
+                        key.args.join(" ")
+                    )
+                });
+                let fixture: Fixture = serde_json::from_slice(&fixture)
+                    .unwrap_or_else(|err| panic!("invalid fixture {}: {err}", path.display()));
+                Ok(Output {
+                    status: ExitStatus::from_raw(fixture.status),
+                    stdout: self.denormalized(&fixture.stdout).into_bytes(),
+                    stderr: self.denormalized(&fixture.stderr).into_bytes(),
+                })
+            },
+        }
+    }
+
+    /// A hash of the data fed to nix, normalized if it is text,
+    /// `<redacted>` for [NixArgs::secret_stdin]
+    ///
+    /// Panics if nix would read from a reader or the terminal,
+    /// which can not be replayed.
+    fn stdin_digest(&self, nix_args: &NixArgs) -> Option<String> {
+        match nix_args.stdin.as_ref()? {
+            InputSource::Bytes(_) if nix_args.secret_stdin => Some(REDACTED.to_string()),
+            InputSource::Bytes(bytes) => {
+                let digest = match std::str::from_utf8(bytes) {
+                    Ok(text) => hex_digest(self.normalized(text).as_bytes(), 32),
+                    Err(_) => hex_digest(bytes, 32),
+                };
+                Some(digest)
+            },
+            other => panic!("stdin of nix can not be recorded from {other:?}, use bytes instead"),
+        }
+    }
+
+    /// The secrets of an invocation that must not be stored:
+    /// the values of [NixArgs::secret_env], [NixArgs::secret_stdin]
+    /// and the access tokens and url credentials the preview `shown` redacts
+    fn secrets<C: NixCliCommand>(
+        &self,
+        command: &C,
+        nix_args: &NixArgs,
+        json: bool,
+        shown: &[String],
+    ) -> Result<Vec<String>, NixCommandLineError> {
+        let args = self.cli.render_args(command, nix_args, json)?;
+        let tokens = args
+            .iter()
+            .zip(shown)
+            .flat_map(|(arg, shown)| redacted_secrets(arg, shown));
+        let env = nix_args
+            .env
+            .iter()
+            .filter(|(name, _)| nix_args.secret_env.contains(name))
+            .map(|(_, value)| value.to_string_lossy().into_owned());
+        let stdin = match &nix_args.stdin {
+            Some(InputSource::Bytes(bytes)) if nix_args.secret_stdin => {
+                Some(String::from_utf8_lossy(bytes).into_owned())
+            },
+            _ => None,
+        };
+
+        Ok(tokens
+            .chain(env)
+            .chain(stdin)
+            .filter(|secret| !secret.is_empty())
+            .collect())
+    }
+}
+
+/// Run nix collecting its output whatever its exit status, for a [RecordingBackend]
+struct Record;
+#[async_trait]
+impl CommandMode for Record {
+    type Error = NixCommandLineError;
+    type Output = Output;
+
+    const LOG_LEVEL: log::Level = log::Level::Debug;
+
+    async fn run(
+        command: &mut Command,
+        backend: &NixCommandLine,
+        invocation: &Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<Output, NixCommandLineError> {
+        let command = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        RunningNix::start(command, OnDrop::Kill, backend, invocation.clone(), stdin)?
+            .wait_with_output()
+            .await
+    }
+}
+
+#[async_trait]
+impl<C> Run<RecordingBackend> for C
+where
+    C: NixCliCommand + Send + Sync,
+{
+    type Error = NixCommandLineRunError;
+
+    async fn run(&self, backend: &RecordingBackend, nix_args: &NixArgs) -> Result<(), Self::Error> {
+        let output = backend.invoke(self, nix_args, false).await?;
+        let limit = backend.cli.captured_output_limit();
+        match run_error(execution_mode::<C>(nix_args), &output, limit) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<C> RunJson<RecordingBackend> for C
+where
+    C: NixCliCommand + JsonCommand + Send + Sync,
+{
+    type JsonError = NixCommandLineRunJsonError;
+
+    async fn run_json(
+        &self,
+        backend: &RecordingBackend,
+        nix_args: &NixArgs,
+    ) -> Result<Value, Self::JsonError> {
+        let output = backend
+            .invoke(self, nix_args, true)
+            .await
+            .map_err(|err| NixCommandLineRunJsonError::Run(err.into()))?;
+        if !output.status.success() {
+            Err(failure(&output, backend.cli.captured_output_limit()))?
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+}
+
+#[async_trait]
+impl<C> RunTyped<RecordingBackend> for C
+where
+    C: RunJson<RecordingBackend, JsonError = NixCommandLineRunJsonError>
+        + TypedCommand
+        + Send
+        + Sync,
+    <C as TypedCommand>::Output: for<'de> Deserialize<'de>,
+{
+    type Output = C::Output;
+    type TypedError = NixCommandLineRunJsonError;
+
+    async fn run_typed(
+        &self,
+        backend: &RecordingBackend,
+        nix_args: &NixArgs,
+    ) -> Result<Self::Output, Self::TypedError> {
+        let json = self.run_json(backend, nix_args).await?;
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Eval;
+    use crate::command_line::tests::script_fixture;
+
+    /// A fake nix printing its arguments and `RUNIX_TOKEN` as JSON
+    const FAKE_NIX: &str = r#"
+printf '{"args": "%s", "token": "%s"}' "$*" "$RUNIX_TOKEN"
+echo "warning: in $PWD" >&2
+"#;
+
+    #[tokio::test]
+    async fn records_and_replays() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let project = tempfile::tempdir().unwrap();
+        let (nix, cli) = script_fixture(FAKE_NIX);
+        let backend = |mode| {
+            RecordingBackend::new(fixtures.path())
+                .with_mode(mode)
+                .with_cli(cli.clone())
+                .normalize(project.path().to_string_lossy(), "<project>")
+        };
+        let nix_args = NixArgs::default()
+            .with_cwd(project.path())
+            .with_secret_env("RUNIX_TOKEN", "hunter2");
+
+        let recorded = Eval::default()
+            .run_json(&backend(FixtureMode::Record), &nix_args)
+            .await
+            .unwrap();
+        assert_eq!(recorded["token"], "hunter2");
+
+        let fixture = std::fs::read_dir(fixtures.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .unwrap();
+        let fixture: Fixture =
+            serde_json::from_str(&std::fs::read_to_string(fixture).unwrap()).unwrap();
+        assert_eq!(fixture.key.command, "eval");
+        assert_eq!(fixture.key.env, [(
+            "RUNIX_TOKEN".to_string(),
+            "<redacted>".to_string()
+        )]);
+        assert_eq!(
+            fixture.stdout,
+            r#"{"args": "eval --json", "token": "<redacted>"}"#
+        );
+        assert_eq!(fixture.stderr, "warning: in <project>\n");
+
+        // replayed without running nix
+        std::fs::remove_file(nix.path().join("nix")).unwrap();
+        let replayed = Eval::default()
+            .run_json(&backend(FixtureMode::Replay), &nix_args)
+            .await
+            .unwrap();
+        assert_eq!(replayed["token"], "<redacted>");
+        assert_eq!(replayed["args"], recorded["args"]);
+    }
+
+    #[tokio::test]
+    async fn keys_fixtures_by_stdin() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let (_nix, cli) = script_fixture(FAKE_NIX);
+        let backend = RecordingBackend::new(fixtures.path())
+            .with_mode(FixtureMode::Record)
+            .with_cli(cli);
+        let record = |nix_args: NixArgs| {
+            let backend = &backend;
+            async move { Eval::default().run_json(backend, &nix_args).await.unwrap() }
+        };
+
+        record(NixArgs::default()).await;
+        record(NixArgs::default().with_stdin(InputSource::Bytes(b"1 + 1".to_vec()))).await;
+        record(NixArgs::default().with_stdin(InputSource::Bytes(b"2 + 2".to_vec()))).await;
+        record(NixArgs::default().with_secret_stdin(InputSource::Bytes(b"hunter2".to_vec()))).await;
+
+        let stdin = std::fs::read_dir(fixtures.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .map(|path| {
+                let fixture: Fixture =
+                    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+                fixture.key.stdin
+            })
+            .collect::<Vec<_>>();
+        // one fixture per input, the secret is not hashed
+        assert_eq!(stdin.len(), 4);
+        assert!(stdin.contains(&None));
+        assert!(stdin.contains(&Some("<redacted>".to_string())));
+        assert_eq!(
+            stdin
+                .iter()
+                .flatten()
+                .filter(|digest| digest.len() == 64)
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "stdin of nix can not be recorded from Reader(..)")]
+    async fn panics_for_stdin_reader() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let (_nix, cli) = script_fixture(FAKE_NIX);
+        let backend = RecordingBackend::new(fixtures.path())
+            .with_mode(FixtureMode::Record)
+            .with_cli(cli);
+        let nix_args = NixArgs::default().with_stdin(InputSource::reader(&b"1 + 1"[..]));
+
+        let _ = Eval::default().run_json(&backend, &nix_args).await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "for the invocation nix eval --json")]
+    async fn panics_without_fixture() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let backend = RecordingBackend::new(fixtures.path())
+            .with_mode(FixtureMode::Replay)
+            .with_cli(NixCommandLine {
+                disable_feature_injection: true,
+                ..Default::default()
+            });
+
+        let _ = Eval::default()
+            .run_json(&backend, &NixArgs::default())
+            .await;
+    }
+}
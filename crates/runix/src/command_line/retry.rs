@@ -0,0 +1,228 @@
+//! Running commands again after transient failures, see [RetryPolicy]
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::nix_error::NixError;
+
+/// How often a command is run by default, including the first attempt
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// The delay before the first retry by default
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay between two attempts by default
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// When a [NixCommandLine](super::NixCommandLine) runs a failed command again,
+/// set with [NixCommandLine::retry](super::NixCommandLine::retry)
+///
+/// Only commands whose output is collected are retried,
+/// i.e. those run with [RunJson](crate::RunJson) or [RunTyped](crate::RunTyped),
+/// and those run with [Run](crate::Run) in an [ExecutionMode](super::child::ExecutionMode)
+/// other than [Inherit](super::child::ExecutionMode::Inherit).
+/// Inheriting the terminal, nix prints its errors straight to it,
+/// so its failures can not be classified.
+/// The `RecordingBackend` of the `test-util` feature does not retry.
+///
+/// Commands reading stdin from an [InputSource::Reader](super::input::InputSource::Reader)
+/// are not retried, as the reader can not be read again.
+///
+/// A failure is retried if its [NixError] fulfills [RetryPolicy::retry_if],
+/// which defaults to [NixError::is_transient].
+/// Build failures and evaluation errors are never retried.
+///
+/// The delay before each retry doubles, starting at [RetryPolicy::initial_delay]
+/// up to [RetryPolicy::max_delay].
+/// It is shortened by a random fraction of up to [RetryPolicy::jitter],
+/// so that processes failing together do not retry in lockstep.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// How often a command is run at most, including the first attempt
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The fraction of a delay that may be cut off at random, between `0.0` and `1.0`
+    pub jitter: f64,
+    /// Whether a failure is worth another attempt
+    pub retry_if: fn(&NixError) -> bool,
+    /// Called before waiting for the next attempt, e.g. to show progress
+    pub on_retry: Option<OnRetry>,
+}
+
+/// A callback for [RetryPolicy::on_retry]
+pub type OnRetry = Arc<dyn Fn(&Retry) + Send + Sync>;
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: 0.5,
+            retry_if: NixError::is_transient,
+            on_retry: None,
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_delay", &self.initial_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .field("on_retry", &self.on_retry.as_ref().map(|_| ".."))
+            .finish_non_exhaustive()
+    }
+}
+
+/// An upcoming attempt to run a command, passed to [RetryPolicy::on_retry]
+///
+/// Displays as `retrying (2/3)`.
+#[derive(Debug)]
+pub struct Retry<'a> {
+    /// The number of the upcoming attempt, the first retry is attempt `2`
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// How long until the attempt is made
+    pub delay: Duration,
+    /// Why the previous attempt failed
+    pub error: &'a NixError,
+}
+
+impl fmt::Display for Retry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "retrying ({}/{})", self.attempt, self.max_attempts)
+    }
+}
+
+impl RetryPolicy {
+    /// Set [RetryPolicy::on_retry]
+    pub fn on_retry(mut self, on_retry: impl Fn(&Retry) + Send + Sync + 'static) -> Self {
+        self.on_retry = Some(Arc::new(on_retry));
+        self
+    }
+
+    /// The delay before `attempt` without jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(2).min(31);
+        self.initial_delay
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay)
+    }
+
+    /// The delay before `attempt` with a random share of the jitter cut off
+    fn delay(&self, attempt: u32) -> Duration {
+        let cut = self.jitter.clamp(0.0, 1.0) * random_fraction();
+        self.backoff(attempt).mul_f64(1.0 - cut)
+    }
+
+    fn should_retry(&self, error: &NixError) -> bool {
+        // the build itself failed, downloads during the build included
+        let build_failed = matches!(
+            error,
+            NixError::BuildFailed { .. } | NixError::HashMismatch { .. }
+        );
+        !build_failed && (self.retry_if)(error)
+    }
+}
+
+/// A random number in `[0, 1)`
+///
+/// Every [RandomState] is seeded differently, which is random enough to spread retries.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Run `attempt` until it succeeds, fails for a reason not retried by `policy`,
+/// or `policy` runs out of attempts
+///
+/// `nix_error` picks the classified failure of nix from an error, if there is one.
+pub(crate) async fn retrying<T, E, F, Fut>(
+    policy: Option<&RetryPolicy>,
+    nix_error: impl Fn(&E) -> Option<&NixError>,
+    mut attempt: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut number = 1;
+    loop {
+        let result = attempt().await;
+        let Some(policy) = policy else {
+            return result;
+        };
+        let Some(error) = result
+            .as_ref()
+            .err()
+            .and_then(&nix_error)
+            .filter(|error| number < policy.max_attempts && policy.should_retry(error))
+        else {
+            return result;
+        };
+
+        number += 1;
+        let retry = Retry {
+            attempt: number,
+            max_attempts: policy.max_attempts,
+            delay: policy.delay(number),
+            error,
+        };
+        debug!("{retry} in {:?} after: {error}", retry.delay);
+        if let Some(on_retry) = &policy.on_retry {
+            on_retry(&retry);
+        }
+        tokio::time::sleep(retry.delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let delays = (2..=6).map(|n| policy.backoff(n)).collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 400, 500, 500].map(Duration::from_millis));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(500));
+
+        for n in 2..=6 {
+            let delay = policy.delay(n);
+            assert!(delay <= policy.backoff(n) && delay >= policy.backoff(n) / 2);
+        }
+    }
+
+    #[test]
+    fn never_retries_build_failures() {
+        let policy = RetryPolicy {
+            retry_if: |_| true,
+            ..Default::default()
+        };
+        let build_failed = NixError::BuildFailed {
+            drv_path: "/nix/store/g3g6k3mk7h6lfqnf3hp0ycq4hn8k9g2y-broken-1.0.drv"
+                .parse()
+                .unwrap(),
+            stderr: String::new(),
+        };
+        assert!(!policy.should_retry(&build_failed));
+        assert!(policy.should_retry(&NixError::Other {
+            stderr: String::new()
+        }));
+    }
+}
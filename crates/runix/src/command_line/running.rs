@@ -0,0 +1,399 @@
+//! Nix processes that can time out or be aborted, see [RunningCommand]
+
+use std::process::{ExitStatus, Output};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::child::RunningNix;
+use super::instrument::{self, Invocation, Stream};
+use super::NixCommandLineError;
+
+/// How long nix is given to exit after `SIGTERM` before it is killed
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// A nix process started by [NixCommandLine::spawn](super::NixCommandLine::spawn)
+///
+/// Nix runs in its own process group, so that stopping it
+/// (on timeout or [AbortHandle::abort]) also stops the processes it spawned,
+/// such as local builders or `git`.
+/// Builds delegated to the nix daemon are cancelled by the daemon
+/// once nix disconnects.
+///
+/// The output of nix is collected until it exits, like in [Collect](super::Collect).
+#[derive(Debug)]
+pub struct RunningCommand {
+    nix: RunningNix,
+    /// The process group of nix, which outlives reaping nix
+    pgid: Option<u32>,
+    stdout: JoinHandle<std::io::Result<Vec<u8>>>,
+    stderr: JoinHandle<std::io::Result<Vec<u8>>>,
+    timeout: Option<Duration>,
+    grace_period: Duration,
+    abort: AbortHandle,
+    aborted: watch::Receiver<bool>,
+}
+
+/// Aborts a [RunningCommand] from another task
+#[derive(Debug, Clone)]
+pub struct AbortHandle(Arc<watch::Sender<bool>>);
+
+impl AbortHandle {
+    /// Stop the command, [RunningCommand::wait] then fails with [NixCommandLineError::Aborted]
+    ///
+    /// Has no effect if the command already exited.
+    pub fn abort(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// Read a stream to its end in the background
+fn collect(
+    stream: Option<impl AsyncRead + Unpin + Send + 'static>,
+    invocation: &Invocation,
+    which: Stream,
+) -> JoinHandle<std::io::Result<Vec<u8>>> {
+    let task_invocation = invocation.clone();
+    tokio::spawn(invocation.instrument(async move {
+        match stream {
+            Some(stream) => instrument::read_to_end(stream, &task_invocation, which).await,
+            None => Ok(Vec::new()),
+        }
+    }))
+}
+
+impl RunningCommand {
+    /// Take over a spawned nix process, which has to lead its own process group
+    pub(super) fn new(mut nix: RunningNix) -> Self {
+        let stdout = collect(nix.take_stdout(), nix.invocation(), Stream::Stdout);
+        let stderr = collect(nix.take_stderr(), nix.invocation(), Stream::Stderr);
+        let (sender, aborted) = watch::channel(false);
+        RunningCommand {
+            pgid: nix.pid(),
+            nix,
+            stdout,
+            stderr,
+            timeout: None,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            abort: AbortHandle(Arc::new(sender)),
+            aborted,
+        }
+    }
+
+    /// Stop nix if it did not exit after `timeout`,
+    /// [RunningCommand::wait] then fails with [NixCommandLineError::TimedOut]
+    ///
+    /// The timeout counts from the call to [RunningCommand::wait].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How long nix is given to exit after `SIGTERM` before it is killed,
+    /// [DEFAULT_GRACE_PERIOD] unless set
+    ///
+    /// This is also how long processes nix left behind may keep its output open
+    /// after it exited.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// A handle to abort the command while waiting for it
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// The process id of nix, which is also the id of its process group
+    pub fn id(&self) -> Option<u32> {
+        self.nix.pid()
+    }
+
+    /// Wait for nix to exit and return its output, regardless of its exit status
+    ///
+    /// If the command times out or is aborted, nix and the processes it spawned
+    /// are stopped before this returns.
+    pub async fn wait(mut self) -> Result<Output, NixCommandLineError> {
+        let limit = self.timeout;
+        let timeout = async {
+            match limit {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let mut aborted = self.aborted.clone();
+
+        let stopped = tokio::select! {
+            status = self.nix.wait() => {
+                return self.output(status?).await;
+            },
+            _ = timeout => NixCommandLineError::TimedOut {
+                after: limit.unwrap_or_default(),
+            },
+            _ = aborted.wait_for(|aborted| *aborted) => NixCommandLineError::Aborted,
+        };
+
+        self.stop().await?;
+        Err(stopped)
+    }
+
+    /// Send `SIGTERM` to the process group, `SIGKILL` after the grace period
+    /// and reap nix
+    ///
+    /// The group is killed even if nix exited within the grace period,
+    /// processes it spawned may ignore `SIGTERM`.
+    async fn stop(&mut self) -> Result<(), NixCommandLineError> {
+        let Some(pgid) = self.nix.pid() else {
+            // already reaped
+            return Ok(());
+        };
+        signal_group(pgid, libc::SIGTERM);
+        let _ = tokio::time::timeout(self.grace_period, self.nix.wait()).await;
+        signal_group(pgid, libc::SIGKILL);
+        self.nix.wait().await?;
+        // processes that left the group may still hold the pipes open
+        self.stdout.abort();
+        self.stderr.abort();
+        Ok(())
+    }
+
+    /// Collect the output of nix after it exited
+    ///
+    /// Processes nix left behind may hold the pipes open,
+    /// if they do so longer than the grace period the process group is killed.
+    /// If the pipes are still not closed after another grace period,
+    /// e.g. because the processes left the group, reading fails.
+    async fn output(self, status: ExitStatus) -> Result<Output, NixCommandLineError> {
+        let aborts = [self.stdout.abort_handle(), self.stderr.abort_handle()];
+        let mut readers = std::pin::pin!(async {
+            let stdout = self.stdout.await;
+            let stderr = self.stderr.await;
+            (stdout, stderr)
+        });
+        let (stdout, stderr) = match tokio::time::timeout(self.grace_period, &mut readers).await {
+            Ok(output) => output,
+            Err(_) => {
+                if let Some(pgid) = self.pgid {
+                    signal_group(pgid, libc::SIGKILL);
+                }
+                match tokio::time::timeout(self.grace_period, readers).await {
+                    Ok(output) => output,
+                    Err(_) => {
+                        aborts.iter().for_each(|abort| abort.abort());
+                        return Err(NixCommandLineError::Run(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "processes started by nix kept its output open",
+                        )));
+                    },
+                }
+            },
+        };
+        let read = |output: Result<std::io::Result<Vec<u8>>, tokio::task::JoinError>| {
+            output
+                .map_err(std::io::Error::other)
+                .and_then(|output| output)
+                .map_err(NixCommandLineError::Run)
+        };
+        Ok(Output {
+            status,
+            stdout: read(stdout)?,
+            stderr: read(stderr)?,
+        })
+    }
+}
+
+/// Send `signal` to all processes in the group `pgid`
+fn signal_group(pgid: u32, signal: libc::c_int) {
+    // SAFETY: `kill` has no memory safety requirements,
+    // failures (e.g. the group is gone already) are of no concern here
+    unsafe {
+        libc::kill(-(pgid as libc::pid_t), signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::arguments::NixArgs;
+    use crate::command::Build;
+    use crate::command_line::tests::{echo_fixture, script_fixture};
+    use crate::command_line::NixCommandLine;
+
+    /// A fake nix binary starting a process that sleeps forever and waiting for it,
+    /// the pid of the sleeping process is written to `nix.child`
+    fn sleep_forever(ignore_term: bool) -> (tempfile::TempDir, NixCommandLine) {
+        script_fixture(
+            &[
+                if ignore_term { "trap '' TERM" } else { "" },
+                "sleep 1000 &",
+                "echo $! > \"$0.child\"",
+                "echo started",
+                "wait",
+            ]
+            .join("\n"),
+        )
+    }
+
+    /// Whether `pid` exists and is not a zombie
+    fn is_running(pid: u32) -> bool {
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else {
+            return false;
+        };
+        // the state follows the parenthesized command name
+        let state = stat.rsplit_once(')').map(|(_, rest)| rest.trim_start());
+        !matches!(state, Some(state) if state.starts_with('Z'))
+    }
+
+    /// Both nix and the process it started are gone, nix has been reaped
+    fn assert_stopped(tempdir: &Path, pid: u32) {
+        let child = std::fs::read_to_string(tempdir.join("nix.child")).unwrap();
+        let child: u32 = child.trim().parse().unwrap();
+        // the process is not a child of this one, it may take a moment to die
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while is_running(child) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!is_running(child), "the sleeping process is still running");
+        assert!(
+            !Path::new(&format!("/proc/{pid}")).exists(),
+            "nix has not been reaped"
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let (tempdir, backend) = sleep_forever(false);
+        let running = backend
+            .spawn(&Build::default(), &NixArgs::default())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        let pid = running.id().unwrap();
+
+        let err = running.wait().await.unwrap_err();
+        assert!(
+            matches!(err, NixCommandLineError::TimedOut { after } if after == Duration::from_millis(200)),
+            "{err:?}"
+        );
+        assert_stopped(tempdir.path(), pid);
+    }
+
+    #[tokio::test]
+    async fn aborts_and_kills_after_grace_period() {
+        let (tempdir, backend) = sleep_forever(true);
+        let running = backend
+            .spawn(&Build::default(), &NixArgs::default())
+            .await
+            .unwrap()
+            .with_grace_period(Duration::from_millis(300));
+        let pid = running.id().unwrap();
+
+        let abort = running.abort_handle();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            abort.abort();
+        });
+
+        let start = Instant::now();
+        let err = running.wait().await.unwrap_err();
+        assert!(matches!(err, NixCommandLineError::Aborted), "{err:?}");
+        // `SIGTERM` is ignored, so nix is only killed after the grace period
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_stopped(tempdir.path(), pid);
+    }
+
+    /// Nix exits on `SIGTERM` right away, the process it started does not
+    #[tokio::test]
+    async fn kills_group_after_nix_exited() {
+        let (tempdir, backend) = script_fixture(
+            &[
+                "(trap '' TERM; exec sleep 1000) &",
+                "echo $! > \"$0.child\"",
+                "wait",
+            ]
+            .join("\n"),
+        );
+        let running = backend
+            .spawn(&Build::default(), &NixArgs::default())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(200))
+            .with_grace_period(Duration::from_millis(200));
+        let pid = running.id().unwrap();
+
+        let err = running.wait().await.unwrap_err();
+        assert!(
+            matches!(err, NixCommandLineError::TimedOut { .. }),
+            "{err:?}"
+        );
+        assert_stopped(tempdir.path(), pid);
+    }
+
+    /// Nix exits, but leaves behind a process holding its output open
+    #[tokio::test]
+    async fn kills_group_holding_output_open() {
+        let (tempdir, backend) =
+            script_fixture(&["sleep 1000 &", "echo $! > \"$0.child\"", "echo done"].join("\n"));
+        let running = backend
+            .spawn(&Build::default(), &NixArgs::default())
+            .await
+            .unwrap()
+            .with_grace_period(Duration::from_millis(200));
+        let pid = running.id().unwrap();
+
+        let output = tokio::time::timeout(Duration::from_secs(10), running.wait())
+            .await
+            .expect("reading the output of nix did not time out")
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
+        assert_stopped(tempdir.path(), pid);
+    }
+
+    /// The wrapper and nix share a process group, stopping one stops both
+    #[tokio::test]
+    async fn times_out_through_wrapper() {
+        let (tempdir, mut backend) = sleep_forever(false);
+        // a shell that waits for nix instead of replacing itself with it
+        backend.wrapper = Some(
+            ["sh", "-c", "\"$@\"; exit $?", "wrapper"]
+                .map(Into::into)
+                .to_vec(),
+        );
+        let running = backend
+            .spawn(&Build::default(), &NixArgs::default())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        let pid = running.id().unwrap();
+
+        let err = running.wait().await.unwrap_err();
+        assert!(
+            matches!(err, NixCommandLineError::TimedOut { .. }),
+            "{err:?}"
+        );
+        assert_stopped(tempdir.path(), pid);
+    }
+
+    #[tokio::test]
+    async fn finishes_within_timeout() {
+        let (_tempdir, backend) = echo_fixture();
+        let output = backend
+            .spawn(&Build::default(), &NixArgs::default())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_secs(10))
+            .wait()
+            .await
+            .unwrap();
+
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("build\n"));
+    }
+}
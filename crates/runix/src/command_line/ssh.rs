@@ -0,0 +1,788 @@
+//! The [NixSshCommandLine] backend, running nix on another host
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::debug;
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+use super::child::{OnDrop, RunningNix};
+use super::input::InputSource;
+use super::instrument::{Invocation, Stream};
+use super::interrupt::InterruptGuard;
+use super::preview::REDACTED;
+use super::retry::retrying;
+use super::{
+    execution_mode,
+    Collect,
+    CommandExt,
+    CommandMode,
+    Interactive,
+    JsonCommand,
+    NixCliCommand,
+    NixCommandLine,
+    NixCommandLineCollectError,
+    NixCommandLineError,
+    NixCommandLineRunError,
+    NixCommandLineRunJsonError,
+    OutputExt,
+    Piped,
+    TypedCommand,
+};
+use crate::arguments::NixArgs;
+use crate::command_line::child::ExecutionMode;
+use crate::{NixBackend, Run, RunJson, RunTyped};
+
+/// The exit code of ssh if it failed itself, rather than the remote command
+const SSH_FAILED: i32 = 255;
+
+/// Nix run on another host through `ssh <options> <host> -- <command>`
+///
+/// The nix invocation is rendered by [NixSshCommandLine::cli]
+/// like for running nix locally, including its defaults and [NixArgs],
+/// and run by a shell on the remote host with every word quoted.
+/// Everything the invocation names refers to the remote host:
+/// paths in arguments (e.g. `./flake#app` or `--store /mnt`), [NixArgs::cwd],
+/// [NixCommandLine::nix_bin] and the [wrapper](NixCommandLine#structfield.wrapper).
+/// None of them are checked on this host.
+///
+/// Environment variables are set on the remote command line through `env`,
+/// as ssh only passes on those the server accepts.
+/// The values of [NixArgs::secret_env] are redacted in logs,
+/// but they are part of the arguments of ssh and of the remote command:
+/// other users of this host can read them while ssh runs,
+/// and other users of the remote host while nix runs.
+///
+/// In [ExecutionMode::Inherit] ssh allocates a terminal on the remote host (`-t`),
+/// so that e.g. the shell of `nix develop` is interactive.
+///
+/// Failures of ssh itself are reported as [NixSshError]s, distinct from failures of nix.
+/// They are recognized by ssh exiting with status 255 after printing an error,
+/// a remote command exiting with 255 on its own is a failure of nix.
+/// In [ExecutionMode::Inherit] the remote command prints to the terminal allocated by ssh,
+/// so the stderr of ssh is its own, it is passed on while it is read.
+/// Only its errors are printed there (`-o LogLevel=ERROR`).
+/// If this process has no terminal, ssh can not allocate one on the remote host
+/// and the remote command prints to the stderr of ssh as well,
+/// then a remote command printing an error and exiting with 255 is taken for ssh failing.
+///
+/// The [retry](NixCommandLine#structfield.retry) policy and
+/// [max_concurrent_invocations](NixCommandLine#structfield.max_concurrent_invocations)
+/// of [NixSshCommandLine::cli] apply to the ssh invocations.
+#[derive(Clone, Debug)]
+pub struct NixSshCommandLine {
+    /// The host to run nix on, passed to ssh as is, e.g. `builder@build01`
+    ///
+    /// Hosts starting with `-` are rejected, as ssh would read them as an option.
+    pub host: String,
+    /// The ssh binary, `ssh` unless set
+    pub ssh_bin: Option<String>,
+    /// Share one connection between invocations, see [ControlMaster]
+    pub control_master: Option<ControlMaster>,
+    /// Further arguments to ssh, passed before the host, e.g. `["-p", "2222"]`
+    pub ssh_args: Vec<OsString>,
+    /// Renders the nix invocations run on the remote host
+    pub cli: NixCommandLine,
+}
+
+/// Options letting ssh connections to the same host share one master connection
+///
+/// Rendered as `-o ControlMaster=auto -o ControlPath=<path> [-o ControlPersist=<secs>]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlMaster {
+    /// The socket of the master connection, e.g. `~/.ssh/runix-%C`
+    pub path: PathBuf,
+    /// How long the master connection stays open after the last invocation,
+    /// it closes along with the first invocation unless set
+    pub persist: Option<Duration>,
+}
+
+impl NixSshCommandLine {
+    /// Run nix on `host` with the default [NixCommandLine] settings
+    pub fn new(host: impl Into<String>) -> Self {
+        NixSshCommandLine {
+            host: host.into(),
+            ssh_bin: None,
+            control_master: None,
+            ssh_args: Vec::new(),
+            cli: NixCommandLine::default(),
+        }
+    }
+
+    /// The ssh binary that is run
+    fn program(&self) -> &str {
+        self.ssh_bin.as_deref().unwrap_or("ssh")
+    }
+
+    /// The arguments to ssh before the remote command, up to the `--` following the host
+    fn ssh_args(&self, tty: bool) -> Result<Vec<OsString>, NixCommandLineError> {
+        if self.host.starts_with('-') {
+            return Err(NixCommandLineError::InvalidSshHost(self.host.clone()));
+        }
+        let mut args = Vec::new();
+        if let Some(ref control_master) = self.control_master {
+            args.extend(["-o".into(), "ControlMaster=auto".into(), "-o".into()]);
+            let mut path = OsString::from("ControlPath=");
+            path.push(&control_master.path);
+            args.push(path);
+            if let Some(persist) = control_master.persist {
+                args.extend([
+                    "-o".into(),
+                    format!("ControlPersist={}", persist.as_secs()).into(),
+                ]);
+            }
+        }
+        if tty {
+            args.push("-t".into());
+        }
+        args.extend(self.ssh_args.iter().cloned());
+        if tty {
+            // after the arguments of the caller, the first value of an option is used
+            args.extend(["-o".into(), "LogLevel=ERROR".into()]);
+        }
+        args.extend([self.host.as_str().into(), "--".into()]);
+        Ok(args)
+    }
+
+    /// The shell command running nix for `command` on the remote host,
+    /// followed by the same command with the values of [NixArgs::secret_env] redacted
+    ///
+    /// The command is text, arguments, environment variables and the working directory
+    /// that are not valid UTF-8 fail with [NixCommandLineError::NotUnicode].
+    fn remote_command<B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        json: bool,
+    ) -> Result<(String, String), NixCommandLineError> {
+        let args = self.cli.render_args(command, nix_args, json)?;
+        self.cli.warn_about_config(command, nix_args);
+
+        let mut env = BTreeMap::new();
+        for defaults in self.cli.defaults_for::<B>() {
+            env.extend(
+                defaults
+                    .environment
+                    .iter()
+                    .map(|(name, value)| (OsString::from(name), OsString::from(value))),
+            );
+        }
+        env.extend(nix_args.env.iter().cloned());
+
+        let mut words = Vec::new();
+        let mut redacted = Vec::new();
+        if !env.is_empty() || !nix_args.env_remove.is_empty() {
+            words.push("env".to_string());
+            for name in &nix_args.env_remove {
+                words.extend(["-u".to_string(), utf8(name)?.to_string()]);
+            }
+            redacted.clone_from(&words);
+            for (name, value) in &env {
+                let (name, value) = (utf8(name)?, utf8(value)?);
+                words.push(format!("{name}={value}"));
+                redacted.push(
+                    if nix_args.secret_env.iter().any(|secret| *secret == *name) {
+                        format!("{name}={REDACTED}")
+                    } else {
+                        format!("{name}={value}")
+                    },
+                );
+            }
+        }
+        let program = self
+            .cli
+            .wrapper
+            .iter()
+            .flatten()
+            .map(|arg| utf8(arg).map(ToString::to_string))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .chain([self.cli.program().to_string()])
+            .chain(args.into_iter().map(Cow::into_owned))
+            .collect::<Vec<_>>();
+        words.extend(program.iter().cloned());
+        redacted.extend(program);
+
+        let cd = match nix_args.cwd {
+            Some(ref cwd) => format!("cd {} && ", quote(utf8(cwd.as_os_str())?.into())),
+            None => String::new(),
+        };
+        let join = |words: Vec<String>| {
+            words
+                .into_iter()
+                .map(|word| quote(Cow::Owned(word)).into_owned())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        Ok((
+            format!("{cd}{}", join(words)),
+            format!("{cd}{}", join(redacted)),
+        ))
+    }
+
+    /// The ssh invocation running `remote` on the host, allocating a terminal if `tty`
+    fn ssh_command(&self, remote: String, tty: bool) -> Result<Command, NixCommandLineError> {
+        let mut command = Command::new(self.program());
+        command.args(self.ssh_args(tty)?).arg(remote);
+        Ok(command)
+    }
+
+    /// Like [NixCommandLine::run_command], but running nix through ssh,
+    /// on a terminal allocated by ssh if `tty` is set
+    async fn run_command<M: CommandMode, B: NixCliCommand>(
+        &self,
+        command: &B,
+        nix_args: &NixArgs,
+        json: bool,
+        tty: bool,
+    ) -> Result<M::Output, M::Error> {
+        // Stands in for the backend while ssh runs,
+        // naming ssh if it can not be started
+        let launcher = NixCommandLine {
+            wrapper: Some(vec![self.program().into()]),
+            captured_output_limit: self.cli.captured_output_limit,
+            ..Default::default()
+        };
+
+        let mut attempts = 0;
+        retrying(self.cli.retry_policy(nix_args), M::nix_error, || {
+            let retries = attempts;
+            attempts += 1;
+            let launcher = &launcher;
+            async move {
+                let (remote, redacted) = self.remote_command(command, nix_args, json)?;
+                let mut ssh = self.ssh_command(remote, tty)?;
+                let logged = self.ssh_command(redacted, tty)?;
+                logged.as_std().log(M::LOG_LEVEL);
+                let _permit = self.cli.invocation_permit::<B>().await;
+                let invocation = self.cli.invocation::<B>(&logged, retries);
+                invocation
+                    .instrument(M::run(
+                        &mut ssh,
+                        launcher,
+                        &invocation,
+                        nix_args.stdin.as_ref(),
+                    ))
+                    .await
+            }
+        })
+        .await
+    }
+
+    /// The failure of ssh in a failed collected invocation, if ssh failed
+    fn collect_error(&self, error: &NixCommandLineCollectError) -> Option<NixSshError> {
+        match error {
+            NixCommandLineCollectError::Failed { status, output, .. }
+            | NixCommandLineCollectError::Evaluation { status, output, .. } => {
+                ssh_error(&self.host, *status, &output.stderr_lossy())
+            },
+            _ => None,
+        }
+    }
+}
+
+impl NixBackend for NixSshCommandLine {}
+
+/// `value` as text for the remote command line
+fn utf8(value: &OsStr) -> Result<&str, NixCommandLineError> {
+    value
+        .to_str()
+        .ok_or_else(|| NixCommandLineError::NotUnicode(value.to_owned()))
+}
+
+/// Quote `word` for the remote shell
+fn quote(word: Cow<str>) -> Cow<str> {
+    shell_escape::unix::escape(word)
+}
+
+/// Failures of ssh to run nix on the remote host
+#[derive(Error, Debug)]
+pub enum NixSshError {
+    #[error("Connection to '{host}' refused")]
+    ConnectionRefused { host: String },
+    /// The key of the host is unknown or does not match the known one
+    #[error("Host key verification of '{host}' failed")]
+    HostKeyVerification { host: String },
+    #[error("Could not resolve the hostname of '{host}'")]
+    UnresolvedHost { host: String },
+    #[error("Authentication at '{host}' failed")]
+    Authentication { host: String },
+    /// Any other failure of ssh, with the last line of its error
+    #[error("ssh to '{host}' failed: {message}")]
+    Connection { host: String, message: String },
+}
+
+/// The failure of ssh if it exited with `status`, classified by the `stderr` it printed
+///
+/// Ssh explains its failures, exiting with 255 without a word
+/// is up to the remote command.
+fn ssh_error(host: &str, status: ExitStatus, stderr: &str) -> Option<NixSshError> {
+    if status.code() != Some(SSH_FAILED) || stderr.trim().is_empty() {
+        return None;
+    }
+    let host = host.to_string();
+    let error = if stderr.contains("Connection refused") {
+        NixSshError::ConnectionRefused { host }
+    } else if stderr.contains("Host key verification failed")
+        || stderr.contains("REMOTE HOST IDENTIFICATION HAS CHANGED")
+    {
+        NixSshError::HostKeyVerification { host }
+    } else if stderr.contains("Could not resolve hostname") {
+        NixSshError::UnresolvedHost { host }
+    } else if stderr.contains("Permission denied (") {
+        NixSshError::Authentication { host }
+    } else {
+        let message = stderr.trim().lines().last().unwrap_or_default();
+        NixSshError::Connection {
+            host,
+            message: message.to_string(),
+        }
+    };
+    Some(error)
+}
+
+/// Like [Passthru](super::Passthru), but collecting the stderr of ssh while passing it on,
+/// see [NixSshCommandLine]
+struct SshPassthru;
+#[async_trait]
+impl CommandMode for SshPassthru {
+    type Error = NixCommandLineError;
+    type Output = SshInteractive;
+
+    const LOG_LEVEL: log::Level = log::Level::Info;
+
+    async fn run(
+        command: &mut Command,
+        backend: &NixCommandLine,
+        invocation: &Invocation,
+        stdin: Option<&InputSource>,
+    ) -> Result<SshInteractive, Self::Error> {
+        let command = command
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::piped())
+            .stdin(Stdio::inherit());
+
+        let guard = InterruptGuard::new().map_err(NixCommandLineError::Run)?;
+        let mut ssh =
+            RunningNix::start(command, OnDrop::Detach, backend, invocation.clone(), stdin)?;
+        let mut pipe = ssh.take_stderr().expect("stderr is piped");
+        let mut stderr = Vec::new();
+        let mut buf = [0; 8192];
+        loop {
+            let n = pipe
+                .read(&mut buf)
+                .await
+                .map_err(NixCommandLineError::Run)?;
+            if n == 0 {
+                break;
+            }
+            invocation.read(Stream::Stderr, n);
+            let _ = std::io::stderr().write_all(&buf[..n]);
+            stderr.extend_from_slice(&buf[..n]);
+        }
+        let status = ssh.wait().await?;
+
+        Ok(SshInteractive {
+            nix: Interactive::new(status, &guard),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+}
+
+/// How ssh sharing the terminal exited and what it printed to stderr, see [SshPassthru]
+struct SshInteractive {
+    nix: Interactive,
+    stderr: String,
+}
+
+#[derive(Error, Debug)]
+pub enum NixSshRunError {
+    #[error(transparent)]
+    Ssh(#[from] NixSshError),
+    #[error(transparent)]
+    Nix(#[from] NixCommandLineRunError),
+}
+
+#[async_trait]
+impl<C> Run<NixSshCommandLine> for C
+where
+    C: NixCliCommand + Send + Sync,
+{
+    type Error = NixSshRunError;
+
+    async fn run(
+        &self,
+        backend: &NixSshCommandLine,
+        nix_args: &NixArgs,
+    ) -> Result<(), NixSshRunError> {
+        let collected = |error: NixCommandLineCollectError| match backend.collect_error(&error) {
+            Some(ssh) => NixSshRunError::Ssh(ssh),
+            None => NixCommandLineRunError::from(error).into(),
+        };
+
+        match execution_mode::<C>(nix_args) {
+            ExecutionMode::Inherit => {
+                let SshInteractive { nix, stderr } = backend
+                    .run_command::<SshPassthru, _>(self, nix_args, false, true)
+                    .await
+                    .map_err(NixCommandLineRunError::from)?;
+                let Interactive {
+                    status,
+                    interrupted,
+                } = nix;
+                if let Some(error) = ssh_error(&backend.host, status, &stderr) {
+                    Err(error)?
+                }
+                if interrupted {
+                    Err(NixCommandLineRunError::Interrupted(status))?
+                }
+                if !status.success() {
+                    Err(NixCommandLineRunError::Exit(status))?
+                }
+            },
+            ExecutionMode::Captured => {
+                let output = backend
+                    .run_command::<Collect, _>(self, nix_args, false, false)
+                    .await
+                    .map_err(collected)?;
+                std::io::stdout()
+                    .write_all(&output.stdout)
+                    .map_err(|err| NixCommandLineRunError::from(NixCommandLineError::Run(err)))?;
+            },
+            ExecutionMode::Piped => {
+                backend
+                    .run_command::<Piped, _>(self, nix_args, false, false)
+                    .await
+                    .map_err(collected)?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NixSshRunJsonError {
+    #[error(transparent)]
+    Ssh(#[from] NixSshError),
+    #[error(transparent)]
+    Nix(#[from] NixCommandLineRunJsonError),
+}
+
+#[async_trait]
+impl<C> RunJson<NixSshCommandLine> for C
+where
+    C: NixCliCommand + JsonCommand + Send + Sync,
+{
+    type JsonError = NixSshRunJsonError;
+
+    async fn run_json(
+        &self,
+        backend: &NixSshCommandLine,
+        nix_args: &NixArgs,
+    ) -> Result<Value, Self::JsonError> {
+        let output = backend
+            .run_command::<Collect, _>(self, nix_args, true, false)
+            .await
+            .map_err(|error| match backend.collect_error(&error) {
+                Some(ssh) => NixSshRunJsonError::Ssh(ssh),
+                None => NixCommandLineRunJsonError::from(error).into(),
+            })?;
+
+        debug!("JSON command output: {:?}", output.output_lossy());
+
+        Ok(serde_json::from_slice(&output.stdout).map_err(NixCommandLineRunJsonError::from)?)
+    }
+}
+
+#[async_trait]
+impl<C> RunTyped<NixSshCommandLine> for C
+where
+    C: RunJson<NixSshCommandLine, JsonError = NixSshRunJsonError> + TypedCommand + Send + Sync,
+    <C as TypedCommand>::Output: for<'de> Deserialize<'de>,
+{
+    type Output = C::Output;
+    type TypedError = NixSshRunJsonError;
+
+    async fn run_typed(
+        &self,
+        backend: &NixSshCommandLine,
+        nix_args: &NixArgs,
+    ) -> Result<Self::Output, Self::TypedError> {
+        let json = self.run_json(backend, nix_args).await?;
+        Ok(serde_json::from_value(json).map_err(NixCommandLineRunJsonError::from)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Build, Run as RunCommand};
+    use crate::command_line::tests::{script_fixture, write_script};
+
+    /// Records the arguments of ssh to `ssh-args` and runs the remote command with `sh`
+    const FAKE_SSH: &str = r#"
+printf '%s\n' "$@" > "$(dirname "$0")/ssh-args"
+while [ "$1" != "--" ]; do shift; done
+exec sh -c "$2"
+"#;
+
+    /// A fake ssh running the remote command locally
+    /// and a fake nix printing its arguments, working directory and `RUNIX_VAR`
+    fn ssh_fixture(ssh: &str) -> (tempfile::TempDir, NixSshCommandLine) {
+        let (tempdir, cli) =
+            script_fixture("printf '%s\\n' \"$@\" \"cwd=$PWD\" \"RUNIX_VAR=$RUNIX_VAR\"");
+        let ssh_bin = tempdir.path().join("ssh");
+        write_script(&ssh_bin, ssh);
+
+        let mut backend = NixSshCommandLine::new("builder@remote");
+        backend.ssh_bin = Some(ssh_bin.to_string_lossy().into_owned());
+        backend.cli = cli;
+        (tempdir, backend)
+    }
+
+    /// Arguments reach the remote nix as they are, however the shell would read them
+    #[tokio::test]
+    async fn quotes_remote_arguments() {
+        let (tempdir, backend) = ssh_fixture(FAKE_SSH);
+        let tricky = [
+            "with space",
+            "it's",
+            "$HOME",
+            "`id`",
+            "a;b && c",
+            "\"quoted\"",
+            "",
+        ];
+        let command = RunCommand {
+            args_after_double_dash: tricky.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        };
+        let cwd = tempdir.path().join("a dir");
+        std::fs::create_dir(&cwd).unwrap();
+        let nix_args = NixArgs {
+            cwd: Some(cwd.clone()),
+            ..Default::default()
+        }
+        .with_env("RUNIX_VAR", "$value; exit 1");
+
+        let output = backend
+            .run_command::<Collect, _>(&command, &nix_args, false, false)
+            .await
+            .unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        let (args, rest) = lines.split_at(lines.len() - 2);
+        assert!(args.ends_with(&tricky), "{args:?}");
+        assert_eq!(rest, [
+            format!("cwd={}", cwd.display()),
+            "RUNIX_VAR=$value; exit 1".to_string()
+        ]);
+    }
+
+    #[tokio::test]
+    async fn renders_ssh_options() {
+        let (tempdir, mut backend) = ssh_fixture(FAKE_SSH);
+        backend.control_master = Some(ControlMaster {
+            path: PathBuf::from("/tmp/runix-%C"),
+            persist: Some(Duration::from_secs(60)),
+        });
+        backend.ssh_args = vec!["-p".into(), "2222".into()];
+        let nix_args = NixArgs::default().with_secret_env("RUNIX_VAR", "secret");
+
+        backend
+            .run_command::<Collect, _>(&RunCommand::default(), &nix_args, false, false)
+            .await
+            .unwrap();
+        let ssh_args = std::fs::read_to_string(tempdir.path().join("ssh-args")).unwrap();
+        let ssh_args = ssh_args.lines().collect::<Vec<_>>();
+
+        assert_eq!(ssh_args[..9], [
+            "-o",
+            "ControlMaster=auto",
+            "-o",
+            "ControlPath=/tmp/runix-%C",
+            "-o",
+            "ControlPersist=60",
+            "-p",
+            "2222",
+            "builder@remote",
+        ]);
+        assert_eq!(ssh_args[9], "--");
+        assert!(ssh_args[10].starts_with("env RUNIX_VAR=secret "));
+
+        let (_, redacted) = backend
+            .remote_command(&RunCommand::default(), &nix_args, false)
+            .unwrap();
+        assert!(redacted.starts_with("env 'RUNIX_VAR=<redacted>' "));
+    }
+
+    /// The working directory is not checked on this host
+    #[tokio::test]
+    async fn cwd_is_remote() {
+        let (_tempdir, backend) = ssh_fixture(FAKE_SSH);
+        let nix_args = NixArgs {
+            cwd: Some(PathBuf::from("/does/not/exist")),
+            ..Default::default()
+        };
+        let (remote, _) = backend
+            .remote_command(&RunCommand::default(), &nix_args, false)
+            .unwrap();
+        assert!(remote.starts_with("cd /does/not/exist && "));
+    }
+
+    /// The remote command line is text, other bytes are not replaced
+    #[test]
+    fn rejects_non_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (_tempdir, backend) = ssh_fixture(FAKE_SSH);
+        let invalid = OsStr::from_bytes(b"caf\xe9");
+        let rejected = |nix_args: NixArgs| {
+            let err = backend
+                .remote_command(&RunCommand::default(), &nix_args, false)
+                .unwrap_err();
+            assert!(
+                matches!(&err, NixCommandLineError::NotUnicode(value) if value == invalid),
+                "{err:?}"
+            );
+        };
+
+        rejected(NixArgs::default().with_env("RUNIX_VAR", invalid));
+        rejected(NixArgs {
+            cwd: Some(PathBuf::from(invalid)),
+            ..Default::default()
+        });
+    }
+
+    /// A host starting with `-` would be an option to ssh, it is not passed on
+    #[tokio::test]
+    async fn rejects_option_like_host() {
+        let (tempdir, mut backend) = ssh_fixture(FAKE_SSH);
+        backend.host = "-oProxyCommand=touch pwned".to_string();
+        let nix_args = NixArgs::default().with_execution_mode(ExecutionMode::Piped);
+
+        let err = RunCommand::default()
+            .run(&backend, &nix_args)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                NixSshRunError::Nix(NixCommandLineRunError::Backend(
+                    NixCommandLineError::InvalidSshHost(host)
+                )) if *host == backend.host
+            ),
+            "{err:?}"
+        );
+        assert!(!tempdir.path().join("ssh-args").exists());
+    }
+
+    /// Output of an unexpected shape is an error, rather than a panic
+    #[tokio::test]
+    async fn rejects_unexpected_typed_output() {
+        let (tempdir, backend) = ssh_fixture(FAKE_SSH);
+        write_script(&tempdir.path().join("nix"), "echo '{\"not\": \"a list\"}'");
+
+        let err = Build::default()
+            .run_typed(&backend, &NixArgs::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, NixSshRunJsonError::Nix(NixCommandLineRunJsonError::Json(_))),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_ssh_failures() {
+        let failing =
+            |stderr: &str, code: i32| ssh_fixture(&format!("echo '{stderr}' >&2\nexit {code}"));
+        let run = |backend: NixSshCommandLine| async move {
+            let nix_args = NixArgs::default().with_execution_mode(ExecutionMode::Piped);
+            RunCommand::default().run(&backend, &nix_args).await
+        };
+
+        let (_tempdir, backend) = failing(
+            "ssh: connect to host remote port 22: Connection refused",
+            255,
+        );
+        assert!(matches!(
+            run(backend).await,
+            Err(NixSshRunError::Ssh(NixSshError::ConnectionRefused { host })) if host == "builder@remote"
+        ));
+
+        let (_tempdir, backend) = failing("Host key verification failed.", 255);
+        assert!(matches!(
+            run(backend).await,
+            Err(NixSshRunError::Ssh(NixSshError::HostKeyVerification { .. }))
+        ));
+
+        let (_tempdir, backend) = failing("builder@remote: Permission denied (publickey).", 255);
+        assert!(matches!(
+            run(backend).await,
+            Err(NixSshRunError::Ssh(NixSshError::Authentication { .. }))
+        ));
+
+        let (_tempdir, backend) = failing("error: flake 'remote' does not provide attribute", 1);
+        assert!(matches!(
+            run(backend).await,
+            Err(NixSshRunError::Nix(NixCommandLineRunError::Failed(_)))
+        ));
+
+        // without an error of ssh, 255 is the status of the remote command
+        let (_tempdir, backend) = failing("", 255);
+        assert!(matches!(
+            run(backend).await,
+            Err(NixSshRunError::Nix(NixCommandLineRunError::Failed(_)))
+        ));
+    }
+
+    /// With a terminal, ssh prints only its own errors to stderr,
+    /// anything the remote command prints goes to the terminal
+    #[tokio::test]
+    async fn maps_ssh_failures_on_terminal() {
+        let _interrupts = crate::command_line::tests::INTERRUPTS.lock().await;
+        let ssh = |stderr: &str| {
+            ssh_fixture(&format!(
+                "printf '%s\\n' \"$@\" > \"$(dirname \"$0\")/ssh-args\"\nprintf '{stderr}' >&2\nexit 255"
+            ))
+        };
+        let run = |backend: &NixSshCommandLine| {
+            let nix_args = NixArgs::default().with_execution_mode(ExecutionMode::Inherit);
+            let backend = backend.clone();
+            async move { RunCommand::default().run(&backend, &nix_args).await }
+        };
+
+        let (tempdir, backend) = ssh("ssh: connect to host remote port 22: Connection refused\\n");
+        assert!(matches!(
+            run(&backend).await,
+            Err(NixSshRunError::Ssh(NixSshError::ConnectionRefused { .. }))
+        ));
+        let ssh_args = std::fs::read_to_string(tempdir.path().join("ssh-args")).unwrap();
+        assert_eq!(ssh_args.lines().take(4).collect::<Vec<_>>(), [
+            "-t",
+            "-o",
+            "LogLevel=ERROR",
+            "builder@remote"
+        ]);
+
+        let (_tempdir, backend) = ssh("");
+        let err = run(&backend).await.unwrap_err();
+        assert!(
+            matches!(err, NixSshRunError::Nix(NixCommandLineRunError::Exit(status)) if status.code() == Some(255)),
+            "{err:?}"
+        );
+    }
+}
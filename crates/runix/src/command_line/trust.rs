@@ -0,0 +1,164 @@
+//! Accepting the `nixConfig` of some flakes but not others, see [FlakeConfigTrust]
+
+use crate::flake_ref::FlakeRef;
+
+/// Decisions whether to accept the `nixConfig` of flakes,
+/// set as [NixCommandLine::flake_config_trust](super::NixCommandLine::flake_config_trust)
+///
+/// A flake's `nixConfig` may set substituters or run hooks,
+/// so accepting it for all flakes (see [AcceptFlakeConfig](crate::arguments::config::AcceptFlakeConfig))
+/// is too coarse for tools building flakes they do not control.
+/// The backend decides per command, considering the flakes of its installables:
+///
+/// - if any of them is distrusted, `--no-accept-flake-config` is passed,
+///   overriding the options of the command and the nix configuration
+/// - if all of them are trusted, `--accept-flake-config` is passed
+/// - otherwise the options of the command and the nix configuration decide
+///
+/// Refs are compared without their pins (see [FlakeRef::unpinned]),
+/// so trusting `github:NixOS/nixpkgs` trusts any commit of its default branch,
+/// but not other branches.
+#[derive(Clone, Debug, Default)]
+pub struct FlakeConfigTrust {
+    decisions: Vec<(FlakeRef, bool)>,
+}
+
+impl FlakeConfigTrust {
+    /// Accept the `nixConfig` of `flake_ref`, replacing an earlier decision
+    pub fn trust(&mut self, flake_ref: FlakeRef) {
+        self.decide(flake_ref, true);
+    }
+
+    /// Never accept the `nixConfig` of `flake_ref`, replacing an earlier decision
+    pub fn distrust(&mut self, flake_ref: FlakeRef) {
+        self.decide(flake_ref, false);
+    }
+
+    /// Whether the `nixConfig` of `flake_ref` is accepted, if that was decided
+    pub fn is_trusted(&self, flake_ref: &FlakeRef) -> Option<bool> {
+        let flake_ref = flake_ref.clone().unpinned();
+        self.decisions
+            .iter()
+            .find(|(decided, _)| *decided == flake_ref)
+            .map(|(_, trusted)| *trusted)
+    }
+
+    /// Whether the `nixConfig` of the flakes a command uses is accepted,
+    /// `None` if that is left to the options of the command
+    pub fn accepts<'a>(&self, flake_refs: impl IntoIterator<Item = &'a FlakeRef>) -> Option<bool> {
+        let mut all_trusted = None;
+        for flake_ref in flake_refs {
+            match self.is_trusted(flake_ref) {
+                Some(false) => return Some(false),
+                Some(true) => all_trusted = all_trusted.or(Some(true)),
+                None => all_trusted = Some(false),
+            }
+        }
+        all_trusted.filter(|trusted| *trusted)
+    }
+
+    fn decide(&mut self, flake_ref: FlakeRef, trusted: bool) {
+        let flake_ref = flake_ref.unpinned();
+        self.decisions.retain(|(decided, _)| *decided != flake_ref);
+        self.decisions.push((flake_ref, trusted));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::arguments::config::NixConfigArgs;
+    use crate::arguments::NixArgs;
+    use crate::command::{Build, FlakeUpdate};
+    use crate::command_line::NixCommandLine;
+    use crate::flake_ref::git_service::GitServiceRef;
+    use crate::installable::FlakeAttribute;
+
+    fn github(s: &str) -> FlakeRef {
+        FlakeRef::Github(GitServiceRef::from_str(s).unwrap())
+    }
+
+    fn build(flake_refs: &[&FlakeRef]) -> Build {
+        Build {
+            installables: flake_refs
+                .iter()
+                .map(|flake_ref| {
+                    FlakeAttribute {
+                        flakeref: (*flake_ref).clone(),
+                        attr_path: ["hello"].try_into().unwrap(),
+                    }
+                    .into()
+                })
+                .collect::<Vec<_>>()
+                .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decides_per_flake() {
+        let trusted = github("github:flox/runix");
+        let untrusted = github("github:someone/untrusted");
+        let unknown = github("github:someone/unknown");
+        let mut backend = NixCommandLine {
+            disable_feature_injection: true,
+            ..Default::default()
+        };
+        backend.flake_config_trust.trust(trusted.clone());
+        backend.flake_config_trust.distrust(untrusted.clone());
+
+        let args = |command: &Build, nix_args: &NixArgs| {
+            backend.to_command_preview(command, nix_args).unwrap().args
+        };
+        let accept = "--accept-flake-config".to_string();
+        let reject = "--no-accept-flake-config".to_string();
+
+        // pins are ignored
+        let pinned = github("github:flox/runix/50500a744e3c2af9d89123ae17b71406b428c3ab");
+        assert!(args(&build(&[&pinned]), &NixArgs::default()).contains(&accept));
+        let untrusted_args = args(&build(&[&untrusted]), &NixArgs::default());
+        assert!(!untrusted_args.contains(&accept));
+        assert!(untrusted_args.contains(&reject));
+
+        // a single distrusted flake is enough to not accept any config
+        let accepting = NixArgs {
+            config: NixConfigArgs {
+                accept_flake_config: true.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mixed = args(&build(&[&trusted, &untrusted]), &accepting);
+        let position = |flag: &String| mixed.iter().position(|arg| arg == flag).unwrap();
+        assert!(position(&reject) > position(&accept));
+
+        // undecided flakes are left to the options
+        assert!(args(&build(&[&trusted, &unknown]), &NixArgs::default())
+            .iter()
+            .all(|arg| *arg != accept && *arg != reject));
+        assert!(args(&build(&[]), &NixArgs::default())
+            .iter()
+            .all(|arg| *arg != accept && *arg != reject));
+
+        // as are the flakes of `nix flake` commands
+        let update = FlakeUpdate {
+            flake_ref: Some(untrusted.clone().into()),
+            ..Default::default()
+        };
+        let update_args = backend
+            .to_command_preview(&update, &accepting)
+            .unwrap()
+            .args;
+        let position = |flag: &String| update_args.iter().position(|arg| arg == flag).unwrap();
+        assert!(position(&reject) > position(&accept));
+
+        // later decisions replace earlier ones
+        backend.flake_config_trust.trust(untrusted.clone());
+        assert_eq!(
+            backend.flake_config_trust.is_trusted(&untrusted),
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Evaluation traces as printed by nix on evaluation errors, see [EvalTrace]
+//!
+//! Nix prints a few frames for every evaluation error,
+//! the full trace only with [ShowTrace](crate::arguments::config::ShowTrace) enabled.
+//! The option is part of the nix configuration rather than the evaluation arguments,
+//! so traces are parsed whether it is passed on the command line or not.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Matches the position of a frame, e.g. `at /tmp/flake/flake.nix:4:5:`
+static POSITION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^at (?P<file>.+):(?P<line>\d+):(?P<column>\d+):$").unwrap());
+
+/// Matches the source excerpt following a position, e.g. `4|     packages = {`
+static EXCERPT: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\d*\|").unwrap());
+
+/// A single frame of an [EvalTrace]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// What nix was doing, e.g. `while evaluating the attribute 'packages'`
+    pub description: String,
+    /// The file the frame is located in,
+    /// this may be a pseudo file such as `«string»`
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl TraceFrame {
+    fn new(description: &str) -> Self {
+        TraceFrame {
+            description: description.to_string(),
+            file: None,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+/// The frames (`… while evaluating ...`) nix prints for an evaluation error,
+/// in the order they were printed
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EvalTrace {
+    pub frames: Vec<TraceFrame>,
+}
+
+impl EvalTrace {
+    /// Collect the frames from the stderr of nix
+    ///
+    /// Frames start with an ellipsis (`…` or `...`),
+    /// descriptions wrapped over multiple lines are joined.
+    /// Anything that is not part of a frame, such as source excerpts
+    /// or the position of the error itself, is skipped.
+    pub fn parse(stderr: &str) -> Self {
+        let mut frames = Vec::new();
+        let mut current: Option<TraceFrame> = None;
+        // whether the next line may continue the description of the current frame
+        let mut wrapped = false;
+
+        for line in stderr.lines().map(str::trim) {
+            let frame_start = line
+                .strip_prefix('…')
+                .or_else(|| line.strip_prefix("..."))
+                .map(str::trim_start);
+
+            if let Some(description) = frame_start {
+                frames.extend(current.replace(TraceFrame::new(description)));
+                wrapped = true;
+            } else if let Some(captures) = POSITION.captures(line) {
+                if let Some(frame) = current.as_mut().filter(|frame| frame.file.is_none()) {
+                    frame.file = Some(captures["file"].to_string());
+                    frame.line = captures["line"].parse().ok();
+                    frame.column = captures["column"].parse().ok();
+                }
+                wrapped = false;
+            } else if line.starts_with("error:") {
+                frames.extend(current.take());
+                wrapped = false;
+            } else if line.is_empty() || EXCERPT.is_match(line) {
+                wrapped = false;
+            } else if let Some(frame) = current.as_mut().filter(|_| wrapped) {
+                frame.description.push(' ');
+                frame.description.push_str(line);
+            }
+        }
+        frames.extend(current);
+
+        for frame in frames.iter_mut() {
+            let trimmed = frame.description.trim_end_matches(':').len();
+            frame.description.truncate(trimmed);
+        }
+
+        EvalTrace { frames }
+    }
+}
+
+/// The message of the (innermost) evaluation error in the stderr of nix
+///
+/// Depending on the version of nix, the message is printed before or after the trace
+pub(crate) fn error_message(stderr: &str) -> Option<String> {
+    stderr
+        .lines()
+        .rev()
+        .filter_map(|line| line.trim().strip_prefix("error:"))
+        .map(str::trim)
+        .find(|message| !message.is_empty())
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(description: &str, position: Option<(&str, u32, u32)>) -> TraceFrame {
+        TraceFrame {
+            description: description.to_string(),
+            file: position.map(|(file, _, _)| file.to_string()),
+            line: position.map(|(_, line, _)| line),
+            column: position.map(|(_, _, column)| column),
+        }
+    }
+
+    // the fixtures are written after nix' sources, see `test/README.md`
+    #[test]
+    fn parses_nix_2_11_trace() {
+        let stderr = include_str!("../test/eval-trace-nix-2.11.txt");
+
+        assert_eq!(EvalTrace::parse(stderr).frames, vec![
+            frame("while evaluating the file '/tmp/flake/default.nix'", None),
+            frame(
+                "while evaluating the attribute 'packages.x86_64-linux.default'",
+                Some(("/tmp/flake/flake.nix", 4, 5))
+            ),
+        ]);
+        assert_eq!(
+            error_message(stderr).as_deref(),
+            Some("this package is broken")
+        );
+    }
+
+    #[test]
+    fn parses_nix_2_18_trace() {
+        let stderr = include_str!("../test/eval-trace-nix-2.18.txt");
+
+        assert_eq!(EvalTrace::parse(stderr).frames, vec![
+            frame(
+                "while evaluating the attribute 'packages.x86_64-linux.default'",
+                Some(("/tmp/flake/flake.nix", 4, 5))
+            ),
+            frame(
+                "while evaluating derivation 'broken-1.0' whose name attribute is located at /nix/store/vlq2hhb7q0l6a4bb3axhij5gs1hdrdzj-source/pkgs/stdenv/generic/make-derivation.nix:353:7",
+                None
+            ),
+            frame(
+                "while evaluating attribute 'buildInputs' of derivation 'broken-1.0'",
+                Some(("/tmp/flake/default.nix", 3, 3))
+            ),
+            frame(
+                "while calling the 'throw' builtin",
+                Some(("/tmp/flake/default.nix", 3, 20))
+            ),
+        ]);
+        assert_eq!(
+            error_message(stderr).as_deref(),
+            Some("this package is broken")
+        );
+    }
+
+    #[test]
+    fn parses_ascii_ellipsis() {
+        let stderr = "error:\n       ... while evaluating 'x'\n\n       at «string»:1:1:\n\n       error: boom\n";
+        assert_eq!(EvalTrace::parse(stderr).frames, vec![frame(
+            "while evaluating 'x'",
+            Some(("«string»", 1, 1))
+        )]);
+    }
+
+    #[test]
+    fn no_trace() {
+        assert!(EvalTrace::parse("error: flake 'flake:x' does not exist\n")
+            .frames
+            .is_empty());
+    }
+}
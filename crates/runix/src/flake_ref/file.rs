@@ -1,6 +1,8 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::path::Path;
+use std::process::ExitStatus;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
@@ -230,24 +232,25 @@ impl<Protocol: FileProtocol, App: ApplicationProtocol> FlakeRefSource
         // - `proto` the transport protocol from the url scheme
         // - `url` the url with application prepended
         // - `wrapped` the parsed url with guaranteed scheme
-        let (app, proto, url, wrapped) = if let Some((app, proto)) = &url.scheme().split_once('+') {
-            let wrapped = url
-                .to_string()
-                .trim_start_matches(&format!("{app}+"))
-                .parse()?;
-
-            (app.to_string(), proto.to_string(), url, wrapped)
-        } else if !App::required(&url) {
-            let app = App::protocol();
-            let proto = url.scheme();
-            let fixed = Url::parse(&format!("{app}+{url}"))?;
-            (app.to_string(), proto.to_string(), fixed, url.try_into()?)
-        } else {
-            return Err(ParseFileError::InvalidScheme(
-                Self::scheme().to_string(),
-                url.scheme().to_string(),
-            ));
-        };
+        let (app, proto, url, mut wrapped): (_, _, _, FileUrl<Protocol>) =
+            if let Some((app, proto)) = &url.scheme().split_once('+') {
+                let wrapped = url
+                    .to_string()
+                    .trim_start_matches(&format!("{app}+"))
+                    .parse()?;
+
+                (app.to_string(), proto.to_string(), url, wrapped)
+            } else if !App::required(&url) {
+                let app = App::protocol();
+                let proto = url.scheme();
+                let fixed = Url::parse(&format!("{app}+{url}"))?;
+                (app.to_string(), proto.to_string(), fixed, url.try_into()?)
+            } else {
+                return Err(ParseFileError::InvalidScheme(
+                    Self::scheme().to_string(),
+                    url.scheme().to_string(),
+                ));
+            };
 
         if app != App::protocol() || proto != Protocol::scheme() {
             return Err(ParseFileError::InvalidScheme(
@@ -256,8 +259,11 @@ impl<Protocol: FileProtocol, App: ApplicationProtocol> FlakeRefSource
             ));
         };
 
-        let mut pairs = url
+        let (attribute_pairs, url_pairs): (Vec<_>, Vec<_>) = url
             .query_pairs()
+            .partition(|(k, _)| ["narHash", "unpack", "name"].contains(&k.as_ref()));
+        let mut pairs = attribute_pairs
+            .into_iter()
             .map(|(k, v)| (k, v.to_string()))
             .collect::<HashMap<_, _>>();
 
@@ -267,6 +273,15 @@ impl<Protocol: FileProtocol, App: ApplicationProtocol> FlakeRefSource
             name: pairs.remove("name"),
         };
 
+        // any other parameters are part of the url to fetch, e.g. an access token,
+        // and are kept in front of the attributes, see [Display]
+        if !url_pairs.is_empty() {
+            let query = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(url_pairs)
+                .finish();
+            wrapped.set_query(Some(&query));
+        }
+
         Ok(FileBasedRef {
             url: wrapped,
             attributes,
@@ -283,8 +298,47 @@ impl<Protocol: FileProtocol, App: ApplicationProtocol> FileBasedRef<Protocol, Ap
             _type: Default::default(),
         }
     }
+
+    /// Check that the locally available copy of the file matches `narHash`
+    ///
+    /// `local` is the fetched file, or for tarballs the unpacked tree,
+    /// e.g. the store path nix fetched it to.
+    /// Runs `nix hash path --type sha256 --sri <local>` using `nix_bin`
+    /// and compares the result to `narHash`, which is expected in SRI form
+    /// as written by nix into lock files.
+    pub fn verify_nar_hash(&self, local: &Path, nix_bin: &Path) -> Result<(), VerifyNarHashError> {
+        let expected = self
+            .attributes
+            .nar_hash
+            .as_ref()
+            .ok_or(VerifyNarHashError::Missing)?;
+
+        let output = std::process::Command::new(nix_bin)
+            .args(["--extra-experimental-features", "nix-command"])
+            .args(["hash", "path", "--type", "sha256", "--sri"])
+            .arg(local)
+            .output()
+            .map_err(VerifyNarHashError::NixCall)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            return Err(VerifyNarHashError::NixHash(output.status, stderr));
+        }
+
+        let actual = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if &actual != expected {
+            return Err(VerifyNarHashError::Mismatch {
+                expected: expected.clone(),
+                actual,
+            });
+        }
+        Ok(())
+    }
 }
 
+/// The query of the url to fetch comes first, followed by `narHash`, `unpack` and `name`,
+/// i.e. the flake attributes keep their order,
+/// no matter the order they were parsed in.
 impl<Protocol: FileProtocol, App: ApplicationProtocol> Display for FileBasedRef<Protocol, App> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut url = if App::required(&self.url) {
@@ -327,6 +381,18 @@ impl<Protocol: FileProtocol, App: ApplicationProtocol> FromStr for FileBasedRef<
     }
 }
 
+#[derive(Debug, Error)]
+pub enum VerifyNarHashError {
+    #[error("flake ref has no narHash to verify")]
+    Missing,
+    #[error("could not call nix: {0}")]
+    NixCall(std::io::Error),
+    #[error("nix hash failed with {0}: {1}")]
+    NixHash(ExitStatus, String),
+    #[error("hash mismatch: expected '{expected}', got '{actual}'")]
+    Mismatch { expected: NarHash, actual: String },
+}
+
 #[derive(Debug, Error)]
 pub enum ParseFileError {
     #[error(transparent)]
@@ -435,4 +501,54 @@ mod tests {
     fn test_parse_nar_hash() {
         roundtrip::<FileFileRef>("file:///somewhere/there?narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D")
     }
+
+    #[test]
+    fn tarball_nar_hash_unpack_roundtrips() {
+        roundtrip::<HttpsTarballRef>(
+            "https://somewhere/there.tar.gz?narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D&unpack=1",
+        );
+        roundtrip_to::<HttpsTarballRef>(
+            "https://somewhere/there.tar.gz?unpack=1&narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D",
+            "https://somewhere/there.tar.gz?narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D&unpack=1",
+        );
+
+        // parameters of the url itself stay with the url
+        let tarball = HttpsTarballRef::from_str(
+            "https://somewhere/there.tar.gz?narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D&token=abc&unpack=1",
+        )
+        .unwrap();
+        assert_eq!(tarball.url.query(), Some("token=abc"));
+        assert_eq!(
+            tarball.to_string(),
+            "https://somewhere/there.tar.gz?token=abc&narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D&unpack=1"
+        );
+        roundtrip::<HttpsTarballRef>(&tarball.to_string());
+    }
+
+    #[test]
+    fn verifies_nar_hash() {
+        use crate::command_line::tests::write_script;
+
+        const NAR_HASH: &str = "sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp/bRB3C2M=";
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let nix_bin = tempdir.path().join("nix");
+        write_script(&nix_bin, &format!("echo {NAR_HASH}"));
+
+        let mut tarball = HttpsTarballRef::from_str("https://somewhere/there.tar.gz").unwrap();
+        assert!(matches!(
+            tarball.verify_nar_hash(tempdir.path(), &nix_bin),
+            Err(VerifyNarHashError::Missing)
+        ));
+
+        tarball.attributes.nar_hash = Some(NAR_HASH.to_string());
+        tarball.verify_nar_hash(tempdir.path(), &nix_bin).unwrap();
+
+        tarball.attributes.nar_hash =
+            Some("sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string());
+        assert!(matches!(
+            tarball.verify_nar_hash(tempdir.path(), &nix_bin),
+            Err(VerifyNarHashError::Mismatch { actual, .. }) if actual == NAR_HASH
+        ));
+    }
 }
@@ -9,12 +9,13 @@ use serde_with::skip_serializing_none;
 use thiserror::Error;
 use url::Url;
 
-use super::lock::{LastModified, NarHash, Rev, RevCount};
+use super::lock::{DirtyRev, LastModified, NarHash, Rev, RevCount};
 use super::protocol::{self, Protocol, WrappedUrl, WrappedUrlParseError};
 use super::{Attrs, FlakeRefSource, Timestamp, TimestampDeserialize};
 use crate::url_parser::{
     extract_all_refs_attr,
     extract_dir_attr,
+    extract_dirty_rev_attr,
     extract_last_modified_attr,
     extract_nar_hash_attr,
     extract_ref_attr,
@@ -48,9 +49,15 @@ pub struct GitAttributes {
     #[serde(rename = "revCount")]
     pub rev_count: Option<RevCount>,
 
-    #[serde(flatten)]
     pub rev: Option<Rev>,
 
+    /// Set by nix instead of `rev` if the working tree has uncommitted changes
+    #[serde(rename = "dirtyRev")]
+    pub dirty_rev: Option<DirtyRev>,
+
+    #[serde(rename = "dirtyShortRev")]
+    pub dirty_short_rev: Option<DirtyRev>,
+
     #[serde(rename = "ref")]
     pub reference: Option<String>,
 
@@ -72,6 +79,8 @@ impl TryFrom<Attrs> for GitAttributes {
         let all_refs = extract_all_refs_attr(&attrs)?;
         let rev_count = extract_rev_count_attr(&attrs)?;
         let rev = extract_rev_attr(&attrs)?;
+        let dirty_rev = extract_dirty_rev_attr(&attrs, "dirtyRev")?;
+        let dirty_short_rev = extract_dirty_rev_attr(&attrs, "dirtyShortRev")?;
         let reference = extract_ref_attr(&attrs)?;
         let dir = extract_dir_attr(&attrs)?;
         let last_modified = extract_last_modified_attr(&attrs)?;
@@ -82,6 +91,8 @@ impl TryFrom<Attrs> for GitAttributes {
             all_refs,
             rev_count,
             rev,
+            dirty_rev,
+            dirty_short_rev,
             reference,
             dir,
             last_modified,
@@ -100,6 +111,35 @@ impl<Protocol: GitProtocol> GitRef<Protocol> {
     pub fn new(url: GitUrl<Protocol>, attributes: GitAttributes) -> Self {
         Self { url, attributes }
     }
+
+    /// Point the ref at the branch or tag `reference`
+    ///
+    /// Unlike git service refs, git refs may set both `ref` and `rev`,
+    /// a `rev` that is already set is kept.
+    pub fn with_ref(mut self, reference: impl Into<String>) -> Self {
+        self.attributes.reference = Some(reference.into());
+        self
+    }
+
+    /// Pin the ref to the commit `rev`
+    pub fn with_rev(mut self, rev: Rev) -> Self {
+        self.attributes.rev = Some(rev);
+        self
+    }
+
+    /// Remove `rev`, `narHash` and `lastModified`, the reverse of pinning the ref
+    pub fn unpinned(mut self) -> Self {
+        self.attributes.rev = None;
+        self.attributes.nar_hash = None;
+        self.attributes.last_modified = None;
+        self
+    }
+
+    /// Select the flake in the subdirectory `dir` of the repository
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.attributes.dir = Some(dir.into());
+        self
+    }
 }
 
 impl<Protocol: GitProtocol> FlakeRefSource for GitRef<Protocol> {
@@ -137,6 +177,16 @@ impl<Protocol: GitProtocol> FlakeRefSource for GitRef<Protocol> {
                 .map(|v| Rev::from_str(&v))
                 .map_or(Ok(None), |v| v.map(Some))
                 .map_err(|e| ParseGitError::Query(e.to_string()))?,
+            dirty_rev: pairs
+                .remove("dirtyRev")
+                .map(|v| DirtyRev::from_str(&v))
+                .map_or(Ok(None), |v| v.map(Some))
+                .map_err(|e| ParseGitError::Query(e.to_string()))?,
+            dirty_short_rev: pairs
+                .remove("dirtyShortRev")
+                .map(|v| DirtyRev::from_str(&v))
+                .map_or(Ok(None), |v| v.map(Some))
+                .map_err(|e| ParseGitError::Query(e.to_string()))?,
             reference: pairs.remove("ref"),
             dir: pairs.remove("dir").map(PathBuf::from),
             last_modified: pairs
@@ -181,6 +231,12 @@ impl<Protocol: GitProtocol> Display for GitRef<Protocol> {
         if let Some(v) = self.attributes.all_refs {
             pairs.append_pair("allRefs", &(v as u8).to_string());
         }
+        if let Some(ref v) = self.attributes.dirty_rev {
+            pairs.append_pair("dirtyRev", &v.to_string());
+        }
+        if let Some(ref v) = self.attributes.dirty_short_rev {
+            pairs.append_pair("dirtyShortRev", &v.to_string());
+        }
         if let Some(ref v) = self.attributes.dir {
             pairs.append_pair("dir", &v.to_string_lossy());
         }
@@ -249,6 +305,7 @@ mod tests {
 
     use super::*;
     use crate::flake_ref::FlakeRef;
+    use crate::url_parser::{DeserializedFlakeRef, ParsedFlakeReference};
 
     static FLAKE_REF: &'_ str = "git+file:///somewhere/on/the/drive?dir=abc&lastModified=1666570118&ref=feature%2Fxyz&shallow=0&submodules=0";
 
@@ -263,6 +320,8 @@ mod tests {
                 rev_count: None,
                 dir: Some("abc".into()),
                 rev: None,
+                dirty_rev: None,
+                dirty_short_rev: None,
                 last_modified: Some(Utc.timestamp_opt(1666570118, 0).unwrap().into()),
                 reference: Some("feature/xyz".to_string()),
                 nar_hash: None,
@@ -355,6 +414,42 @@ mod tests {
         serde_json::from_value::<FlakeRef>(expected).expect("should parse");
     }
 
+    #[test]
+    fn parses_dirty_rev() {
+        let parsed: DeserializedFlakeRef = serde_json::from_value(json!({
+            "attrs": {
+                "type": "git",
+                "url": "file:///somewhere/on/the/drive",
+                "dirtyRev": "0630fc9307852b30ea4c5915b6b74fa9db51d641-dirty",
+                "dirtyShortRev": "0630fc9-dirty",
+                "lastModified": 1688730350,
+            },
+            "string": "git+file:///somewhere/on/the/drive",
+        }))
+        .unwrap();
+        let parsed = ParsedFlakeReference::try_from(parsed).unwrap();
+
+        let FlakeRef::GitPath(git_ref) = FlakeRef::from_parsed(&parsed).unwrap() else {
+            panic!("expected a git+file flake ref")
+        };
+        let dirty_rev = git_ref.attributes.dirty_rev.as_ref().unwrap();
+        assert_eq!(dirty_rev.head(), "0630fc9307852b30ea4c5915b6b74fa9db51d641");
+        assert_eq!(
+            git_ref.attributes.dirty_short_rev.as_ref().unwrap().head(),
+            "0630fc9"
+        );
+        assert_eq!(git_ref.attributes.rev, None);
+
+        assert_eq!(
+            serde_json::to_value(&git_ref).unwrap()["dirtyRev"],
+            json!("0630fc9307852b30ea4c5915b6b74fa9db51d641-dirty")
+        );
+        assert_eq!(
+            GitRef::<protocol::File>::from_str(&git_ref.to_string()).unwrap(),
+            git_ref
+        );
+    }
+
     /// assert that relative file urls are resolved to git urls correctly
     #[test]
     fn relative_git_urls() {
@@ -9,7 +9,7 @@ use thiserror::Error;
 use url::Url;
 
 use self::service::GitService;
-use super::lock::{LastModified, NarHash, Rev, RevOrRef};
+use super::lock::{InvalidRev, LastModified, NarHash, Rev, RevOrRef};
 use super::{Attrs, FlakeRefSource};
 use crate::url_parser::{
     extract_dir_attr,
@@ -142,6 +142,204 @@ impl<Service: Default> GitServiceRef<Service> {
     }
 }
 
+impl<Service> GitServiceRef<Service> {
+    /// Point the ref at the branch or tag `reference`
+    ///
+    /// Unsets `rev`, the services accept either of the two but not both.
+    pub fn with_ref(mut self, reference: impl Into<String>) -> Self {
+        self.attributes.reference = Some(reference.into());
+        self.attributes.rev = None;
+        self
+    }
+
+    /// Pin the ref to the commit `rev`
+    ///
+    /// Unsets `ref`, the services accept either of the two but not both.
+    pub fn with_rev(mut self, rev: Rev) -> Self {
+        self.attributes.rev = Some(rev);
+        self.attributes.reference = None;
+        self
+    }
+
+    /// Remove `rev`, `narHash` and `lastModified`, the reverse of pinning the ref
+    pub fn unpinned(mut self) -> Self {
+        self.attributes.rev = None;
+        self.attributes.nar_hash = None;
+        self.attributes.last_modified = None;
+        self
+    }
+
+    /// Select the flake in the subdirectory `dir` of the repository
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.attributes.dir = Some(dir.into());
+        self
+    }
+
+    /// Fetch from a self-hosted instance of the service,
+    /// e.g. GitHub Enterprise or a GitLab instance, instead of the public one
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.attributes.host = Some(host.into());
+        self
+    }
+}
+
+impl GitServiceRef<service::Github> {
+    /// Parse the url of a repository's main page, e.g. `https://github.com/flox/runix`
+    ///
+    /// A trailing `.git` (as in clone urls) or `/` is stripped,
+    /// the query and fragment are ignored.
+    /// Urls pointing into the repository (`/tree/...`, `/commit/...`) are rejected.
+    /// Hosts other than `github.com` are kept as the `host` attribute,
+    /// e.g. for GitHub Enterprise, see also [Self::to_clone_url].
+    pub fn from_repo_url(url: &Url) -> Result<Self, ParseGitServiceError> {
+        if !["https", "http"].contains(&url.scheme()) {
+            return Err(ParseGitServiceError::InvalidScheme(
+                "https".to_string(),
+                url.scheme().to_string(),
+            ));
+        }
+
+        let path = url.path().trim_matches('/');
+        let (owner, repo) = path.split_once('/').ok_or(ParseGitServiceError::NoRepo)?;
+        let repo = repo.strip_suffix(".git").unwrap_or(repo);
+        if owner.is_empty() || repo.is_empty() {
+            return Err(ParseGitServiceError::NoRepo);
+        }
+        if repo.contains('/') {
+            return Err(ParseGitServiceError::NotRepoUrl(url.to_string()));
+        }
+
+        let host = url.host_str().filter(|host| *host != "github.com");
+        Ok(Self::new(
+            owner.to_string(),
+            repo.to_string(),
+            GitServiceAttributes {
+                host: host.map(ToString::to_string),
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// The commit a GitHub Actions workflow runs for,
+    /// [None] if this process does not run in GitHub Actions
+    ///
+    /// The repository is read from `GITHUB_REPOSITORY` (e.g. `flox/runix`),
+    /// hosts other than `github.com` in `GITHUB_SERVER_URL` are kept as the `host` attribute.
+    /// The ref is pinned to the commit in `GITHUB_SHA`.
+    /// As a ref can not name a branch and a commit at once,
+    /// the branch or tag in `GITHUB_REF` (e.g. `refs/heads/main`) is only used
+    /// if `GITHUB_SHA` is not set.
+    /// Other refs, such as `refs/pull/1/merge` for pull requests, are ignored.
+    pub fn from_github_actions_env() -> Result<Option<Self>, ParseGitServiceError> {
+        Self::from_github_actions_vars(|name| std::env::var(name).ok())
+    }
+
+    /// See [Self::from_github_actions_env], reading the variables `var` looks up
+    fn from_github_actions_vars(
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, ParseGitServiceError> {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        if var("GITHUB_ACTIONS").as_deref() != Some("true") {
+            return Ok(None);
+        }
+
+        let invalid =
+            |name: &'static str, reason: String| ParseGitServiceError::ActionsEnv { name, reason };
+        let repository = var("GITHUB_REPOSITORY")
+            .ok_or_else(|| invalid("GITHUB_REPOSITORY", "not set".to_string()))?;
+        let server = var("GITHUB_SERVER_URL").unwrap_or_else(|| "https://github.com".to_string());
+        let url = Url::parse(&format!("{}/", server.trim_end_matches('/')))
+            .and_then(|server| server.join(&repository))
+            .map_err(|err| invalid("GITHUB_SERVER_URL", err.to_string()))?;
+        let github_ref = Self::from_repo_url(&url)
+            .map_err(|err| invalid("GITHUB_REPOSITORY", err.to_string()))?;
+
+        if let Some(sha) = var("GITHUB_SHA") {
+            let rev = sha
+                .parse()
+                .map_err(|err: InvalidRev| invalid("GITHUB_SHA", err.to_string()))?;
+            return Ok(Some(github_ref.with_rev(rev)));
+        }
+        let branch = var("GITHUB_REF").and_then(|reference| {
+            ["refs/heads/", "refs/tags/"]
+                .iter()
+                .find_map(|prefix| reference.strip_prefix(prefix).map(ToString::to_string))
+        });
+        Ok(Some(match branch {
+            Some(branch) => github_ref.with_ref(branch),
+            None => github_ref,
+        }))
+    }
+
+    /// The HTTPS url to clone the repository with `git`
+    ///
+    /// `https://github.com/{owner}/{repo}.git`,
+    /// or the custom `host` if set (e.g. for GitHub Enterprise).
+    /// The `rev` or `ref` are not part of the url, pass them to `git` separately,
+    /// e.g. `git clone --depth 1 -b {ref} {url}`.
+    ///
+    /// Fails if the custom `host` is not a valid host.
+    pub fn to_clone_url(&self) -> Result<Url, url::ParseError> {
+        let host = self.attributes.host.as_deref().unwrap_or("github.com");
+        let mut url = Url::parse(&format!("https://{host}"))?;
+        url.path_segments_mut()
+            .expect("https urls can be a base")
+            .clear()
+            .push(&self.owner)
+            .push(&format!("{}.git", self.repo));
+        Ok(url)
+    }
+
+    /// The same repository on the GitLab instance at `host`, e.g. after migrating a mirror
+    ///
+    /// Owner, repo and all attributes are kept,
+    /// as a mirror has the same commits (and contents) as the original.
+    pub fn into_gitlab_ref(self, host: impl Into<String>) -> GitServiceRef<service::Gitlab> {
+        GitServiceRef::new(self.owner, self.repo, self.attributes).with_host(host)
+    }
+}
+
+impl GitServiceRef<service::Gitlab> {
+    /// The HTTPS url to clone the repository with `git`
+    ///
+    /// `https://gitlab.com/{owner}/{repo}.git`, or the custom `host` if set.
+    /// Subgroups, which flake refs escape as `%2F` in the owner, become path segments,
+    /// e.g. `gitlab:group%2Fsub/repo` is cloned from `https://gitlab.com/group/sub/repo.git`.
+    ///
+    /// Fails if the custom `host` is not a valid host.
+    pub fn to_clone_url(&self) -> Result<Url, url::ParseError> {
+        let host = self.attributes.host.as_deref().unwrap_or("gitlab.com");
+        let mut url = Url::parse(&format!("https://{host}"))?;
+        url.path_segments_mut()
+            .expect("https urls can be a base")
+            .clear()
+            .extend(self.owner.split("%2F"))
+            .push(&format!("{}.git", self.repo));
+        Ok(url)
+    }
+}
+
+/// Find two places a github or gitlab url names a ref or rev in, if it does so more than once
+///
+/// Nix accepts only one of a ref or rev as the path segment after the repo,
+/// a `ref` or a `rev` in the query, and rejects the url otherwise,
+/// even if they agree.
+/// The places are described as `path '<segment>'`, `ref=<ref>` and `rev=<rev>`.
+pub(crate) fn conflicting_ref_rev(url: &Url) -> Option<(String, String)> {
+    let path = url
+        .path()
+        .splitn(3, '/')
+        .nth(2)
+        .map(|segment| format!("path '{segment}'"));
+    let query = url
+        .query_pairs()
+        .filter(|(name, _)| name == "ref" || name == "rev")
+        .map(|(name, value)| format!("{name}={value}"));
+
+    let mut places = path.into_iter().chain(query);
+    Some((places.next()?, places.next()?))
+}
+
 impl<Service: service::GitServiceHost> FlakeRefSource for GitServiceRef<Service> {
     type ParseErr = ParseGitServiceError;
 
@@ -164,6 +362,8 @@ impl<Service: service::GitServiceHost> FlakeRefSource for GitServiceRef<Service>
             Some((repo, rev_or_ref)) => (repo, Some(rev_or_ref.to_owned().into())),
             None => (rest, None),
         };
+        // clone urls name the repository with a `.git` suffix, the flake ref does not
+        let repo = repo.strip_suffix(".git").unwrap_or(repo);
 
         let mut attributes: GitServiceAttributes =
             serde_urlencoded::from_str(url.query().unwrap_or_default())?;
@@ -241,8 +441,12 @@ pub enum ParseGitServiceError {
     InvalidScheme(String, String),
     #[error("No repo specified")]
     NoRepo,
+    #[error("Not the url of a repository: {0}")]
+    NotRepoUrl(String),
     #[error("Unkown Attribute: {0}")]
     UnkownAttribute(String),
+    #[error("Invalid {name} in GitHub Actions: {reason}")]
+    ActionsEnv { name: &'static str, reason: String },
 }
 
 #[cfg(test)]
@@ -252,6 +456,8 @@ mod tests {
     use chrono::{TimeZone, Utc};
 
     use super::*;
+    use crate::flake_ref::git::GitRef;
+    use crate::flake_ref::protocol;
     use crate::flake_ref::tests::{roundtrip, roundtrip_to};
 
     #[test]
@@ -268,6 +474,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn github_into_gitlab() {
+        let github = GitServiceRef::<service::Github>::from_str(
+            "github:flox/runix/50500a744e3c2af9d89123ae17b71406b428c3ab?dir=sub",
+        )
+        .unwrap();
+        let gitlab = github.into_gitlab_ref("gitlab.example.com");
+        assert_eq!(
+            gitlab.to_string(),
+            "gitlab:flox/runix/50500a744e3c2af9d89123ae17b71406b428c3ab?host=gitlab.example.com&dir=sub"
+        );
+
+        let github = GitServiceRef::<service::Github>::from_str("github:flox/runix/main").unwrap();
+        let gitlab = github.into_gitlab_ref("gitlab.example.com");
+        assert_eq!(gitlab.attributes.reference.as_deref(), Some("main"));
+        assert_eq!(
+            gitlab,
+            GitServiceRef::from_str("gitlab:flox/runix/main?host=gitlab.example.com").unwrap()
+        );
+    }
+
+    #[test]
+    fn strips_git_suffix() {
+        roundtrip_to::<GitServiceRef<service::Github>>("github:o/r.git", "github:o/r");
+        roundtrip_to::<GitServiceRef<service::Github>>("github:o/r.git/main", "github:o/r/main");
+        roundtrip_to::<GitServiceRef<service::Gitlab>>(
+            "gitlab:o/r.git?dir=sub",
+            "gitlab:o/r?dir=sub",
+        );
+        assert_eq!(
+            GitServiceRef::<service::Github>::from_str("github:o/r.git").unwrap(),
+            GitServiceRef::<service::Github>::from_str("github:o/r").unwrap()
+        );
+
+        // only the suffix is stripped
+        roundtrip::<GitServiceRef<service::Github>>("github:o/r.github.io");
+        // raw git urls keep it, the server may depend on it
+        roundtrip::<GitRef<protocol::HTTPS>>("git+https://example.com/o/r.git");
+    }
+
+    #[test]
+    fn with_ref_rev_dir() {
+        let flake_ref = GitServiceRef::<service::Github>::from_str("github:o/r").unwrap();
+
+        let with_ref = flake_ref.clone().with_ref("main");
+        assert_eq!(with_ref.to_string(), "github:o/r/main");
+        assert_eq!(
+            with_ref,
+            GitServiceRef::from_str("github:o/r?ref=main").unwrap()
+        );
+
+        let rev: Rev = "50500a744e3c2af9d89123ae17b71406b428c3ab".parse().unwrap();
+        let pinned = with_ref.with_rev(rev).with_dir("sub");
+        assert_eq!(
+            pinned.to_string(),
+            "github:o/r/50500a744e3c2af9d89123ae17b71406b428c3ab?dir=sub"
+        );
+        assert_eq!(pinned.attributes.reference, None);
+        assert_eq!(flake_ref.to_string(), "github:o/r");
+    }
+
     #[test]
     fn parse_invalid_simple() {
         GitServiceRef::<service::Github>::from_str("github:owner/repo/feature?ref=another-feature")
@@ -311,6 +578,128 @@ mod tests {
         );
     }
 
+    #[test]
+    fn github_clone_url() {
+        let flakeref =
+            GitServiceRef::<service::Github>::from_str("github:flox/runix/main").unwrap();
+        assert_eq!(
+            flakeref.to_clone_url().unwrap().as_str(),
+            "https://github.com/flox/runix.git"
+        );
+
+        let flakeref =
+            GitServiceRef::<service::Github>::from_str("github:flox/runix?host=github.example.com")
+                .unwrap();
+        assert_eq!(
+            flakeref.to_clone_url().unwrap().as_str(),
+            "https://github.example.com/flox/runix.git"
+        );
+    }
+
+    #[test]
+    fn gitlab_clone_url() {
+        let flakeref = GitServiceRef::<service::Gitlab>::from_str(
+            "gitlab:group%2Fsub/runix?host=gitlab.example.com",
+        )
+        .unwrap();
+        assert_eq!(
+            flakeref.to_clone_url().unwrap().as_str(),
+            "https://gitlab.example.com/group/sub/runix.git"
+        );
+    }
+
+    #[test]
+    fn github_from_repo_url() {
+        let from_repo_url = |url: &str| {
+            GitServiceRef::<service::Github>::from_repo_url(&Url::parse(url).unwrap())
+                .map(|flakeref| flakeref.to_string())
+        };
+
+        for url in [
+            "https://github.com/flox/runix",
+            "https://github.com/flox/runix/",
+            "https://github.com/flox/runix.git",
+            "https://github.com/flox/runix?tab=readme-ov-file#runix",
+        ] {
+            assert_eq!(from_repo_url(url).unwrap(), "github:flox/runix", "{url}");
+        }
+        assert_eq!(
+            from_repo_url("https://github.example.com/flox/runix").unwrap(),
+            "github:flox/runix?host=github.example.com"
+        );
+
+        assert!(matches!(
+            from_repo_url("https://github.com/flox/runix/tree/main"),
+            Err(ParseGitServiceError::NotRepoUrl(_))
+        ));
+        assert!(matches!(
+            from_repo_url("https://github.com/flox"),
+            Err(ParseGitServiceError::NoRepo)
+        ));
+        assert!(matches!(
+            from_repo_url("ssh://git@github.com/flox/runix.git"),
+            Err(ParseGitServiceError::InvalidScheme(..))
+        ));
+    }
+
+    #[test]
+    fn github_from_actions_env() {
+        let from_vars = |vars: &[(&str, &str)]| {
+            GitServiceRef::<service::Github>::from_github_actions_vars(|name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            })
+        };
+        let in_actions = |vars: &[(&str, &str)]| {
+            let vars = [
+                &[
+                    ("GITHUB_ACTIONS", "true"),
+                    ("GITHUB_REPOSITORY", "flox/runix"),
+                ],
+                vars,
+            ]
+            .concat();
+            from_vars(&vars).map(|flakeref| flakeref.unwrap().to_string())
+        };
+        let sha = "50500a744e3c2af9d89123ae17b71406b428c3ab";
+
+        assert!(from_vars(&[("GITHUB_REPOSITORY", "flox/runix")])
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            in_actions(&[("GITHUB_REF", "refs/heads/main"), ("GITHUB_SHA", sha)]).unwrap(),
+            format!("github:flox/runix/{sha}")
+        );
+        assert_eq!(
+            in_actions(&[("GITHUB_REF", "refs/tags/v1.0")]).unwrap(),
+            "github:flox/runix/v1.0"
+        );
+        assert_eq!(
+            in_actions(&[("GITHUB_REF", "refs/pull/1/merge")]).unwrap(),
+            "github:flox/runix"
+        );
+        assert_eq!(
+            in_actions(&[("GITHUB_SERVER_URL", "https://github.example.com")]).unwrap(),
+            "github:flox/runix?host=github.example.com"
+        );
+
+        assert!(matches!(
+            from_vars(&[("GITHUB_ACTIONS", "true")]),
+            Err(ParseGitServiceError::ActionsEnv {
+                name: "GITHUB_REPOSITORY",
+                ..
+            })
+        ));
+        assert!(matches!(
+            in_actions(&[("GITHUB_SHA", "main")]),
+            Err(ParseGitServiceError::ActionsEnv {
+                name: "GITHUB_SHA",
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn parses_github_flakeref() {
         let expected = GitServiceRef::<service::Github> {
@@ -3,30 +3,114 @@ use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use serde::de::{Error as _, Unexpected};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use thiserror::Error;
 use url::Url;
 
+use super::lock::{InvalidRev, Rev};
 use super::{Attrs, FlakeRef, FlakeRefSource};
-use crate::url_parser::{resolve_flake_ref, UrlParseError, PARSER_UTIL_BIN_PATH};
+use crate::url_parser::{
+    extract_ref_attr,
+    extract_rev_attr,
+    FlakeRefParser,
+    FlakeRefResolver,
+    UrlParseError,
+};
+
+/// An indirect flake reference without the `flake:` scheme,
+/// i.e. `<id>[/<ref-or-rev>][/<rev>][?<query>]`
+///
+/// See `flakeRegex` in <https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/flake/flakeref.cc>
+static BARE_INDIRECT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?P<id>[a-zA-Z][a-zA-Z0-9_-]*)(?:/(?P<ref>[^/?#]+))?(?:/(?P<rev>[0-9a-f]{40}))?(?:\?(?P<query>[^#]*))?$",
+    )
+    .unwrap()
+});
 
 /// <https://cs.github.com/NixOS/nix/blob/f225f4307662fe9a57543d0c86c28aa9fddaf0d2/src/libfetchers/path.cc#L46>
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, PartialOrd, Ord)]
+///
+/// Serializes as a flat map of strings, like nix' attribute set of the ref
+/// (`{"type": "indirect", "id": "nixpkgs", "dir": "lib"}`).
+/// Unlike a struct with flattened [IndirectRef::attributes],
+/// the map has a known length and is deserialized as a map,
+/// which formats that are not self-describing (e.g. `bincode`) support.
+#[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord)]
 pub struct IndirectRef {
     /// The name of the flake registry entry i.e. the part
     /// immediately after `flake:`
     pub id: String,
 
     /// This will always be "indirect"
-    #[serde(rename = "type")]
     pub(crate) _type: Tag,
 
-    /// Contains the revision, git ref, etc specified as part of the flake reference
-    #[serde(flatten)]
+    /// The branch or tag, e.g. `nixos-23.11` in `flake:nixpkgs/nixos-23.11`
+    pub reference: Option<String>,
+
+    /// The commit, e.g. the last segment of `flake:nixpkgs/nixos-23.11/<rev>`
+    pub rev: Option<Rev>,
+
+    /// Contains any other attributes specified as part of the flake reference,
+    /// e.g. `dir`
     pub attributes: BTreeMap<String, String>,
 }
 
+impl Serialize for IndirectRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let len =
+            2 + self.reference.iter().count() + self.rev.iter().count() + self.attributes.len();
+        let mut map = serializer.serialize_map(Some(len))?;
+        map.serialize_entry("id", &self.id)?;
+        map.serialize_entry("type", "indirect")?;
+        if let Some(reference) = &self.reference {
+            map.serialize_entry("ref", reference)?;
+        }
+        if let Some(rev) = &self.rev {
+            map.serialize_entry("rev", rev)?;
+        }
+        for (name, value) in &self.attributes {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for IndirectRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut attributes = BTreeMap::<String, String>::deserialize(deserializer)?;
+        match attributes.remove("type").as_deref() {
+            Some("indirect") => {},
+            Some(other) => Err(D::Error::invalid_value(
+                Unexpected::Str(other),
+                &"\"indirect\"",
+            ))?,
+            None => Err(D::Error::missing_field("type"))?,
+        }
+        let id = attributes
+            .remove("id")
+            .ok_or_else(|| D::Error::missing_field("id"))?;
+        let rev = attributes
+            .remove("rev")
+            .map(|rev| Rev::from_str(&rev))
+            .transpose()
+            .map_err(D::Error::custom)?;
+
+        Ok(IndirectRef {
+            id,
+            _type: Tag::Indirect,
+            reference: attributes.remove("ref"),
+            rev,
+            attributes,
+        })
+    }
+}
+
 impl TryFrom<Attrs> for IndirectRef {
     type Error = UrlParseError;
 
@@ -36,6 +120,11 @@ impl TryFrom<Attrs> for IndirectRef {
             return Err(UrlParseError::MissingAttribute("id"));
         };
         let id = id.clone();
+        let reference = extract_ref_attr(&attrs)?;
+        let rev = extract_rev_attr(&attrs)?;
+        for name in ["id", "type", "ref", "rev"] {
+            attrs.remove(name);
+        }
         let mut attributes = BTreeMap::new();
 
         for (k, v) in attrs.drain() {
@@ -51,6 +140,8 @@ impl TryFrom<Attrs> for IndirectRef {
         Ok(IndirectRef {
             id,
             _type: tag,
+            reference,
+            rev,
             attributes,
         })
     }
@@ -64,23 +155,124 @@ pub enum Tag {
 }
 
 impl IndirectRef {
-    pub fn new(id: String, attributes: BTreeMap<String, String>) -> Self {
+    /// Create an indirect flake reference
+    ///
+    /// `ref` and `rev` attributes are moved to [IndirectRef::reference]
+    /// and [IndirectRef::rev].
+    /// A `rev` that is not a full commit hash is kept as an attribute.
+    pub fn new(id: String, mut attributes: BTreeMap<String, String>) -> Self {
+        let reference = attributes.remove("ref");
+        let rev = match attributes.get("rev").map(|rev| Rev::from_str(rev)) {
+            Some(Ok(rev)) => {
+                attributes.remove("rev");
+                Some(rev)
+            },
+            _ => None,
+        };
         Self {
             id,
             _type: Tag::Indirect,
+            reference,
+            rev,
             attributes,
         }
     }
 
+    /// Create an indirect flake reference from key-value pairs of attributes
+    ///
+    /// ```
+    /// # use runix::flake_ref::indirect::IndirectRef;
+    /// let flake_ref = IndirectRef::from_pairs("nixpkgs", [
+    ///     ("ref".to_string(), "unstable".to_string()),
+    ///     ("dir".to_string(), "lib".to_string()),
+    /// ]);
+    /// assert_eq!(flake_ref.reference.as_deref(), Some("unstable"));
+    /// assert_eq!(flake_ref.to_string(), "flake:nixpkgs/unstable?dir=lib");
+    /// ```
+    pub fn from_pairs(
+        id: impl Into<String>,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        Self::new(id.into(), pairs.into_iter().collect())
+    }
+
+    /// Parse an indirect flake reference written without the `flake:` scheme,
+    /// e.g. `nixpkgs`, `nixpkgs/nixos-23.05` or `nixpkgs/<rev>`
+    ///
+    /// Nix takes any scheme-less reference starting with a flake id
+    /// and containing at most two more path segments for an indirect one,
+    /// even if a directory of that name exists.
+    /// Local paths have to start with `.` or `/` to be told apart.
+    /// A single segment following the id is a `rev` if it is a full commit hash,
+    /// otherwise a `ref`.
+    pub fn from_bare(s: &str) -> Option<Self> {
+        if !BARE_INDIRECT_REGEX.is_match(s) {
+            return None;
+        }
+        Self::from_url(Url::parse(&format!("{}:{s}", Self::scheme())).ok()?).ok()
+    }
+
+    /// This reference as a `flake:` [Url], which is also its [Display] form
+    ///
+    /// The url is built from the parts of the reference
+    /// rather than by formatting and parsing it again.
+    /// A `ref` that would not survive as a path segment is moved to the query.
+    pub fn as_url(&self) -> Url {
+        let mut url = Url::parse("flake:").expect("'flake:' is a valid url");
+
+        let mut path = self.id.clone();
+        let mut query = self
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<BTreeMap<_, _>>();
+        match self.reference {
+            Some(ref reference) if reference.contains(['/', '?', '#', '%']) => {
+                query.insert("ref", reference);
+            },
+            Some(ref reference) => {
+                path.push('/');
+                path.push_str(reference);
+            },
+            None => {},
+        }
+        if let Some(ref rev) = self.rev {
+            path.push('/');
+            path.push_str(rev.as_str());
+        }
+        url.set_path(&path);
+
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        url
+    }
+
     /// Resolves an indirect flake reference to a concrete reference
     ///
     /// Note that this method calls `parser-util`, which relies on the `NIX_USER_CONF_FILES`
     /// environment variable to be set and contain conf files that point to custom registries
     /// that you want to use for resolution, otherwise only the user's local registry is used.
+    ///
+    /// Fails with [UrlParseError::UnresolvedIndirect] if the result is still indirect,
+    /// e.g. because a registry entry points to another indirect reference that could not be resolved.
     pub fn resolve(&self) -> Result<FlakeRef, UrlParseError> {
+        self.resolve_with(&FlakeRefParser::default())
+    }
+
+    /// Like [IndirectRef::resolve], but resolving the reference with `resolver`
+    pub fn resolve_with(&self, resolver: &dyn FlakeRefResolver) -> Result<FlakeRef, UrlParseError> {
         let json = serde_json::to_string(&self)?;
-        let resolved = resolve_flake_ref(json, PARSER_UTIL_BIN_PATH)?;
-        FlakeRef::from_parsed(&resolved.resolved_ref)
+        let resolved = resolver.resolve(&json)?;
+        self.ensure_resolved(FlakeRef::from_parsed(&resolved)?)
+    }
+
+    /// Reject resolution results that are not concrete
+    fn ensure_resolved(&self, resolved: FlakeRef) -> Result<FlakeRef, UrlParseError> {
+        match resolved {
+            FlakeRef::Indirect(_) => Err(UrlParseError::UnresolvedIndirect(self.id.clone())),
+            resolved => Ok(resolved),
+        }
     }
 }
 
@@ -91,38 +283,61 @@ impl FlakeRefSource for IndirectRef {
         "flake".into()
     }
 
+    /// Parses `flake:<id>[/<ref-or-rev>][/<rev>][?<attributes>]`
+    ///
+    /// A single segment following the id is a `rev` if it is a full commit hash,
+    /// otherwise a `ref`.
+    /// The `ref` is percent-decoded like the id, e.g. `my%20branch` is `my branch`.
     fn from_url(url: Url) -> Result<Self, Self::ParseErr> {
-        let id = url.path().to_string();
         let attributes = serde_urlencoded::from_str(url.query().unwrap_or_default())?;
-        let _type = Tag::Indirect;
-        Ok(IndirectRef {
-            id,
-            attributes,
-            _type,
-        })
+        let segments = url.path().split('/').collect::<Vec<_>>();
+        let (id, reference, rev) = match segments[..] {
+            [id] => (id, None, None),
+            [id, rev] if Rev::from_str(rev).is_ok() => (id, None, Some(rev)),
+            [id, reference] => (id, Some(reference), None),
+            [id, reference, rev] => (id, Some(reference), Some(rev)),
+            _ => return Err(ParseIndirectError::Path(url.path().to_string())),
+        };
+        if [Some(id), reference, rev].contains(&Some("")) {
+            return Err(ParseIndirectError::Path(url.path().to_string()));
+        }
+        let id = percent_decode_str(id).decode_utf8_lossy();
+        check_id(&id)?;
+
+        let mut indirect = IndirectRef::new(id.into_owned(), attributes);
+        if let Some(reference) = reference {
+            indirect.reference = Some(
+                percent_decode_str(reference)
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            );
+        }
+        if let Some(rev) = rev {
+            indirect.rev = Some(Rev::from_str(rev)?);
+        }
+        Ok(indirect)
     }
 }
 
+/// Renders `ref` and `rev` as path segments, e.g. `flake:nixpkgs/nixos-23.11/<rev>`,
+/// unless the `ref` contains characters that would not survive as a path segment,
+/// see [IndirectRef::as_url]
 impl Display for IndirectRef {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{prefix}:{id}", prefix = Self::scheme(), id = self.id)?;
-        if !self.attributes.is_empty() {
-            write!(
-                f,
-                "?{attributes}",
-                attributes = serde_urlencoded::to_string(&self.attributes).unwrap_or_default()
-            )?
-        }
-
-        Ok(())
+        write!(f, "{}", self.as_url())
     }
 }
 
 impl FromStr for IndirectRef {
     type Err = ParseIndirectError;
 
+    /// Parses both `flake:<id>` and the scheme-less form, see [IndirectRef::from_bare]
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let url = match Url::parse(s) {
+            Err(e @ url::ParseError::RelativeUrlWithoutBase) => {
+                check_id(s.split(['/', '?', '#']).next().unwrap_or_default())?;
+                return Self::from_bare(s).ok_or(e.into());
+            },
             Ok(url) if url.scheme() == Self::scheme() => url,
             Ok(url_bad_scheme) => Err(ParseIndirectError::InvalidScheme(
                 url_bad_scheme.scheme().to_string(),
@@ -134,6 +349,20 @@ impl FromStr for IndirectRef {
     }
 }
 
+/// Reject ids nix does not accept,
+/// i.e. anything but a letter followed by letters, digits, `-` and `_`
+fn check_id(id: &str) -> Result<(), ParseIndirectError> {
+    let mut chars = id.chars();
+    let invalid = match chars.next() {
+        Some(first) if !first.is_ascii_alphabetic() => Some(first),
+        _ => chars.find(|c| !(c.is_ascii_alphanumeric() || ['-', '_'].contains(c))),
+    };
+    match invalid {
+        Some(invalid) => Err(ParseIndirectError::InvalidCharInId(invalid)),
+        None => Ok(()),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ParseIndirectError {
     #[error(transparent)]
@@ -142,18 +371,31 @@ pub enum ParseIndirectError {
     InvalidScheme(String, String),
     #[error("Couldn't parse query: {0}")]
     Query(#[from] serde_urlencoded::de::Error),
+    #[error("Invalid path '{0}' (expected: '<id>[/<ref>][/<rev>]')")]
+    Path(String),
+    #[error(transparent)]
+    Rev(#[from] InvalidRev),
+    #[error("Invalid character {0:?} in flake id (expected letters, digits, '-' and '_')")]
+    InvalidCharInId(char),
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
 
     use serde_json::json;
     use temp_env::with_var;
 
     use super::*;
+    use crate::command_line::tests::write_script;
     use crate::flake_ref::FlakeRef;
     use crate::registry::Registry;
-    use crate::url_parser::PARSER_UTIL_BIN_PATH;
+    use crate::url_parser::{
+        DeserializedFlakeRef,
+        ParsedFlakeReference,
+        ParserUtil,
+        PARSER_UTIL_BIN_PATH,
+    };
 
     /// Ensure that an indirect flake ref serializes without information loss
     #[test]
@@ -162,6 +404,8 @@ mod tests {
         let expect = IndirectRef {
             _type: Tag::Indirect,
             id: "nixpkgs-flox".into(),
+            reference: None,
+            rev: None,
             attributes: BTreeMap::default(),
         };
 
@@ -169,6 +413,53 @@ mod tests {
         assert_eq!(expect.to_string(), original);
     }
 
+    /// Ensure that attributes which need url encoding survive a roundtrip
+    #[test]
+    fn indirect_to_from_url_special_chars() {
+        let expect = IndirectRef::from_pairs("nixpkgs", [
+            ("ref".to_string(), "feature&fix=1+2".to_string()),
+            ("dir".to_string(), "sub dir/ünïcödé".to_string()),
+        ]);
+
+        let serialized = expect.to_string();
+        assert_eq!(
+            serialized,
+            "flake:nixpkgs/feature&fix=1+2?dir=sub+dir%2F%C3%BCn%C3%AFc%C3%B6d%C3%A9"
+        );
+        assert_eq!(IndirectRef::from_str(&serialized).unwrap(), expect);
+
+        for reference in ["my branch", "ünïcödé", "100%"] {
+            let expect =
+                IndirectRef::from_pairs("nixpkgs", [("ref".to_string(), reference.to_string())]);
+            assert_eq!(IndirectRef::from_str(&expect.to_string()).unwrap(), expect);
+        }
+        assert_eq!(
+            IndirectRef::from_str("flake:nixpkgs/my%20branch")
+                .unwrap()
+                .reference
+                .as_deref(),
+            Some("my branch")
+        );
+    }
+
+    #[test]
+    fn as_url() {
+        for flake_ref in [
+            "flake:nixpkgs",
+            "flake:nixpkgs/nixos-23.11",
+            "flake:nixpkgs/nixos-23.11/0123456789abcdef0123456789abcdef01234567",
+            "flake:nixpkgs/0123456789abcdef0123456789abcdef01234567",
+            "flake:nixpkgs?dir=lib&ref=feature%2Fbranch",
+            "flake:nixpkgs/feature&fix=1+2?dir=sub+dir%2F%C3%BCn%C3%AFc%C3%B6d%C3%A9",
+        ] {
+            let indirect = IndirectRef::from_str(flake_ref).unwrap();
+            let url = indirect.as_url();
+            assert_eq!(url.as_str(), flake_ref);
+            assert_eq!(url.as_str(), indirect.to_string());
+            assert_eq!(IndirectRef::from_url(url).unwrap(), indirect);
+        }
+    }
+
     #[test]
     fn parses_registry_flakeref() {
         let original = "nixpkgs".to_string();
@@ -181,6 +472,8 @@ mod tests {
         let expected = IndirectRef {
             _type: Tag::Indirect,
             id: "nixpkgs".to_string(),
+            reference: None,
+            rev: None,
             attributes: expected_attrs,
         };
         let actual_flakeref = FlakeRef::from_url(original, PARSER_UTIL_BIN_PATH).unwrap();
@@ -193,6 +486,8 @@ mod tests {
         let expected = IndirectRef {
             _type: Tag::Indirect,
             id: "nixpkgs".to_string(),
+            reference: None,
+            rev: None,
             attributes: BTreeMap::default(),
         };
         let actual = IndirectRef::from_str("flake:nixpkgs").unwrap();
@@ -239,9 +534,199 @@ mod tests {
         )
     }
 
+    /// Resolves indirect references from a map of ids to references
+    struct InMemoryResolver(HashMap<String, FlakeRef>);
+
+    impl FlakeRefResolver for InMemoryResolver {
+        fn resolve(&self, flake_ref: &str) -> Result<ParsedFlakeReference, UrlParseError> {
+            let indirect: IndirectRef = serde_json::from_str(flake_ref)?;
+            let resolved = self
+                .0
+                .get(&indirect.id)
+                .cloned()
+                .unwrap_or(FlakeRef::Indirect(indirect));
+            let Value::Object(attrs) = serde_json::to_value(&resolved)? else {
+                unreachable!("flake refs serialize to objects")
+            };
+            ParsedFlakeReference::try_from(DeserializedFlakeRef {
+                attrs: attrs.into_iter().collect(),
+                string: resolved.to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn resolves_with_custom_resolver() {
+        let runix = FlakeRef::Github("github:flox/runix".parse().unwrap());
+        let resolver = InMemoryResolver(HashMap::from([
+            ("runix".to_string(), runix.clone()),
+            (
+                "unresolvable".to_string(),
+                FlakeRef::Indirect(IndirectRef::from_pairs("does-not-exist", [])),
+            ),
+        ]));
+
+        let resolved = IndirectRef::from_str("flake:runix")
+            .unwrap()
+            .resolve_with(&resolver)
+            .unwrap();
+        assert_eq!(resolved, runix);
+
+        let err = IndirectRef::from_str("flake:unresolvable")
+            .unwrap()
+            .resolve_with(&resolver)
+            .unwrap_err();
+        assert!(matches!(err, UrlParseError::UnresolvedIndirect(ref id) if id == "unresolvable"));
+    }
+
+    #[test]
+    fn rejects_indirect_resolution() {
+        let indirect = IndirectRef::from_pairs("testref", []);
+        let err = indirect
+            .ensure_resolved(FlakeRef::Indirect(IndirectRef::from_pairs("missing", [])))
+            .unwrap_err();
+        assert!(matches!(err, UrlParseError::UnresolvedIndirect(ref id) if id == "testref"));
+
+        let concrete = FlakeRef::Github("github:flox/runix".parse().unwrap());
+        assert_eq!(
+            indirect.ensure_resolved(concrete.clone()).unwrap(),
+            concrete
+        );
+    }
+
+    /// A registry entry pointing to another indirect ref that is missing from the registry,
+    /// which `parser-util` returns as it is
+    #[test]
+    fn does_not_resolve_to_unresolvable_indirect() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let bin_path = tempdir.path().join("parser-util");
+        write_script(
+            &bin_path,
+            r#"
+cat <<EOF
+{
+  "input": { "id": "testref", "type": "indirect" },
+  "originalRef": {
+    "attrs": { "id": "testref", "type": "indirect" },
+    "string": "flake:testref"
+  },
+  "resolvedRef": {
+    "attrs": { "id": "does-not-exist", "type": "indirect" },
+    "string": "flake:does-not-exist"
+  }
+}
+EOF
+"#,
+        );
+
+        let err = IndirectRef::from_str("flake:testref")
+            .unwrap()
+            .resolve_with(&ParserUtil { bin_path })
+            .unwrap_err();
+        assert!(
+            matches!(err, UrlParseError::UnresolvedIndirect(ref id) if id == "testref"),
+            "{err:?}"
+        );
+    }
+
     #[test]
     fn does_not_parse_other() {
         IndirectRef::from_str("github:nixpkgs").unwrap_err();
+        IndirectRef::from_str("./nixpkgs").unwrap_err();
+        IndirectRef::from_str("/nixpkgs").unwrap_err();
+        IndirectRef::from_str("nixpkgs/a/b/c").unwrap_err();
+        IndirectRef::from_str("flake:nixpkgs/a/b/c").unwrap_err();
+        IndirectRef::from_str("flake:nixpkgs/main/not-a-rev").unwrap_err();
+    }
+
+    #[test]
+    fn rejects_invalid_chars_in_id() {
+        for (s, invalid) in [
+            ("flake:my repo", ' '),
+            ("my repo", ' '),
+            ("flake:my%20repo", ' '),
+            ("flake:nix.pkgs/unstable", '.'),
+            ("nix+pkgs?dir=lib", '+'),
+            ("flake:9lives", '9'),
+        ] {
+            let err = s.parse::<IndirectRef>().unwrap_err();
+            assert!(
+                matches!(err, ParseIndirectError::InvalidCharInId(c) if c == invalid),
+                "{s}: {err:?}"
+            );
+        }
+
+        let indirect = "flake:nixpkgs-flox_2/main".parse::<IndirectRef>().unwrap();
+        assert_eq!(indirect.id, "nixpkgs-flox_2");
+    }
+
+    #[test]
+    fn parses_ref_and_rev_segments() {
+        let rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641";
+
+        let branch = IndirectRef::from_str("flake:nixpkgs/nixos-23.11").unwrap();
+        assert_eq!(branch.id, "nixpkgs");
+        assert_eq!(branch.reference.as_deref(), Some("nixos-23.11"));
+        assert_eq!(branch.rev, None);
+        assert_eq!(branch.to_string(), "flake:nixpkgs/nixos-23.11");
+
+        let pinned = IndirectRef::from_str(&format!("flake:nixpkgs/{rev}")).unwrap();
+        assert_eq!(pinned.reference, None);
+        assert_eq!(pinned.rev, Some(rev.parse().unwrap()));
+        assert_eq!(pinned.to_string(), format!("flake:nixpkgs/{rev}"));
+
+        let both =
+            IndirectRef::from_str(&format!("flake:nixpkgs/nixos-23.11/{rev}?dir=lib")).unwrap();
+        assert_eq!(both.reference.as_deref(), Some("nixos-23.11"));
+        assert_eq!(both.rev, Some(rev.parse().unwrap()));
+        assert_eq!(both.attributes.get("dir").map(String::as_str), Some("lib"));
+        assert_eq!(
+            both.to_string(),
+            format!("flake:nixpkgs/nixos-23.11/{rev}?dir=lib")
+        );
+
+        // query attributes are equivalent to path segments
+        assert_eq!(
+            IndirectRef::from_str(&format!("flake:nixpkgs?ref=nixos-23.11&rev={rev}")).unwrap(),
+            IndirectRef::from_str(&format!("flake:nixpkgs/nixos-23.11/{rev}")).unwrap()
+        );
+
+        // refs containing a `/` can't be written as a path segment
+        let nested = IndirectRef::from_pairs("nixpkgs", [(
+            "ref".to_string(),
+            "release/23.11".to_string(),
+        )]);
+        assert_eq!(nested.to_string(), "flake:nixpkgs?ref=release%2F23.11");
+        assert_eq!(IndirectRef::from_str(&nested.to_string()).unwrap(), nested);
+    }
+
+    #[test]
+    fn parses_bare_indirect_ref() {
+        assert_eq!(
+            IndirectRef::from_str("nixpkgs").unwrap(),
+            IndirectRef::from_str("flake:nixpkgs").unwrap()
+        );
+
+        let branch = IndirectRef::from_str("nixpkgs/nixos-23.05").unwrap();
+        assert_eq!(
+            branch,
+            IndirectRef::from_pairs("nixpkgs", [("ref".to_string(), "nixos-23.05".to_string())])
+        );
+        assert_eq!(branch.to_string(), "flake:nixpkgs/nixos-23.05");
+
+        let rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641";
+        assert_eq!(
+            IndirectRef::from_str(&format!("nixpkgs/{rev}")).unwrap(),
+            IndirectRef::from_pairs("nixpkgs", [("rev".to_string(), rev.to_string())])
+        );
+        assert_eq!(
+            IndirectRef::from_str(&format!("nixpkgs/main/{rev}?dir=lib")).unwrap(),
+            IndirectRef::from_pairs("nixpkgs", [
+                ("dir".to_string(), "lib".to_string()),
+                ("ref".to_string(), "main".to_string()),
+                ("rev".to_string(), rev.to_string()),
+            ])
+        );
     }
 
     #[test]
@@ -254,6 +739,8 @@ mod tests {
         let flakeref = IndirectRef {
             _type: Tag::Indirect,
             id: "test".to_string(),
+            reference: None,
+            rev: None,
             attributes: Default::default(),
         };
 
@@ -262,26 +749,82 @@ mod tests {
         assert_eq!(serialized, flakeref);
     }
 
-    /// https://github.com/serde-rs/serde/issues/2423  :(
+    /// Serialization is unchanged from the derived implementation
+    /// that flattened the attributes
     #[test]
-    #[ignore]
-    fn xyz() {
-        #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
-        #[serde(tag = "x", rename = "y")]
-        pub struct MyStruct {
-            #[serde(flatten)]
-            pub attributes: BTreeMap<String, String>,
+    fn json_shape() {
+        let rev = "1e684b371cf05300bc2b432f958f285855bac8fb";
+        let flakeref = IndirectRef::from_str(&format!("nixpkgs/main/{rev}?dir=lib")).unwrap();
+        let expected = json!({
+            "id": "nixpkgs",
+            "type": "indirect",
+            "ref": "main",
+            "rev": rev,
+            "dir": "lib",
+        });
+        assert_eq!(serde_json::to_value(&flakeref).unwrap(), expected);
+        assert_eq!(
+            serde_json::to_string(&flakeref).unwrap(),
+            format!(
+                r#"{{"id":"nixpkgs","type":"indirect","ref":"main","rev":"{rev}","dir":"lib"}}"#
+            )
+        );
+        assert_eq!(
+            serde_json::from_value::<IndirectRef>(expected).unwrap(),
+            flakeref
+        );
+
+        for invalid in [
+            json!({"id": "nixpkgs"}),
+            json!({"type": "indirect"}),
+            json!({"id": "nixpkgs", "type": "path"}),
+            json!({"id": "nixpkgs", "type": "indirect", "rev": "main"}),
+            json!({"id": "nixpkgs", "type": "indirect", "dir": 1}),
+        ] {
+            assert!(
+                serde_json::from_value::<IndirectRef>(invalid.clone()).is_err(),
+                "{invalid}"
+            );
         }
+    }
 
-        let expected = json!({ "x": "y", });
-        let my_struct = MyStruct {
-            attributes: Default::default(),
-        };
+    #[test]
+    fn roundtrips_through_bincode() {
+        for flakeref in [
+            "flake:nixpkgs",
+            "flake:nixpkgs/nixos-23.11?dir=lib",
+            "flake:nixpkgs/main/1e684b371cf05300bc2b432f958f285855bac8fb?dir=lib&foo=bar",
+        ] {
+            let flakeref = IndirectRef::from_str(flakeref).unwrap();
+            let bytes = bincode::serialize(&flakeref).unwrap();
+            assert_eq!(
+                bincode::deserialize::<IndirectRef>(&bytes).unwrap(),
+                flakeref
+            );
+        }
+    }
 
-        assert_eq!(serde_json::to_value(&my_struct).unwrap(), expected);
-        assert_eq!(
-            serde_json::from_value::<MyStruct>(expected).unwrap(),
-            my_struct
+    /// With `#[serde(tag = "type")]` and flattened attributes,
+    /// the derived implementation read the tag back as an attribute,
+    /// see <https://github.com/serde-rs/serde/issues/2423>
+    #[test]
+    fn tag_roundtrips_with_attributes() {
+        let expected = json!({ "id": "test", "type": "indirect", "dir": "lib" });
+        let flakeref = IndirectRef::new(
+            "test".to_string(),
+            [("dir".to_string(), "lib".to_string())].into(),
         );
+
+        assert_eq!(serde_json::to_value(&flakeref).unwrap(), expected);
+        let deserialized = serde_json::from_value::<IndirectRef>(expected).unwrap();
+        assert_eq!(deserialized, flakeref);
+        assert!(!deserialized.attributes.contains_key("type"));
+
+        // the tag is written once, however often the ref is roundtripped
+        let twice =
+            serde_json::from_value::<IndirectRef>(serde_json::to_value(&deserialized).unwrap())
+                .unwrap();
+        assert_eq!(twice, flakeref);
+        assert_eq!(twice.to_string(), "flake:test?dir=lib");
     }
 }
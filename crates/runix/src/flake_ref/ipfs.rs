@@ -0,0 +1,231 @@
+//! Flake refs fetched from IPFS, `ipfs://<cid>` and `ipns://<name>`
+//!
+//! Available with the `ipfs` feature.
+//! Nix does not fetch these refs itself, they are meant for deployments
+//! whose nix has been extended with IPFS fetchers.
+
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use thiserror::Error;
+use url::Url;
+
+use self::namespace::{Namespace, NamespaceTag};
+use super::lock::{LastModified, NarHash};
+use super::FlakeRefSource;
+
+/// A flake ref addressing content on IPFS
+///
+/// `ipfs://<cid>` refs are immutable, the CID addresses the content itself.
+/// `ipns://<name>` refs point to the content a name is published for,
+/// which changes over time unless the ref carries a `narHash`.
+///
+/// Serializes like the other refs, e.g.
+/// `{"type": "ipfs", "cid": "bafy…", "narHash": "sha256-…"}`.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct IpfsRef<N> {
+    /// The CID of the content, or the name published for it with IPNS
+    pub cid: String,
+
+    #[serde(flatten)]
+    pub attributes: IpfsAttributes,
+
+    #[serde(rename = "type")]
+    #[serde(bound(deserialize = "NamespaceTag<N>: Deserialize<'de>"))]
+    #[serde(bound(serialize = "NamespaceTag<N>: Serialize"))]
+    pub(crate) _type: NamespaceTag<N>,
+}
+
+#[skip_serializing_none]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct IpfsAttributes {
+    #[serde(rename = "narHash")]
+    pub nar_hash: Option<NarHash>,
+
+    #[serde(rename = "lastModified")]
+    pub last_modified: Option<LastModified>,
+}
+
+pub mod namespace {
+    use std::borrow::Cow;
+
+    use serde::{Deserialize, Serialize};
+
+    /// The `type` of an [IpfsRef](super::IpfsRef), the name of its [Namespace]
+    #[derive(Default, Debug, PartialEq, Eq, Clone)]
+    pub struct NamespaceTag<N>(pub(crate) N);
+
+    impl<N: Namespace> Serialize for NamespaceTag<N> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            serializer.serialize_str(&N::scheme())
+        }
+    }
+
+    impl<'de, N: Namespace> Deserialize<'de> for NamespaceTag<N> {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            let s = String::deserialize(deserializer)?;
+            if s != N::scheme() {
+                return Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Str(&s),
+                    &&*N::scheme(),
+                ));
+            }
+
+            Ok(Default::default())
+        }
+    }
+
+    pub trait Namespace: Default + Eq {
+        fn scheme() -> Cow<'static, str>;
+    }
+
+    /// Content addressed by its CID
+    #[derive(Default, Debug, PartialEq, Eq, Clone)]
+    pub struct Ipfs;
+    impl Namespace for Ipfs {
+        fn scheme() -> Cow<'static, str> {
+            "ipfs".into()
+        }
+    }
+
+    /// Content a name has been published for
+    #[derive(Default, Debug, PartialEq, Eq, Clone)]
+    pub struct Ipns;
+    impl Namespace for Ipns {
+        fn scheme() -> Cow<'static, str> {
+            "ipns".into()
+        }
+    }
+}
+
+impl<N: Default> IpfsRef<N> {
+    pub fn new(cid: String, attributes: IpfsAttributes) -> Self {
+        Self {
+            cid,
+            attributes,
+            _type: Default::default(),
+        }
+    }
+}
+
+impl<N: Namespace> FlakeRefSource for IpfsRef<N> {
+    type ParseErr = ParseIpfsRefError;
+
+    fn scheme() -> Cow<'static, str> {
+        N::scheme()
+    }
+
+    fn from_url(url: Url) -> Result<Self, Self::ParseErr> {
+        if url.scheme() != Self::scheme() {
+            return Err(ParseIpfsRefError::InvalidScheme(
+                Self::scheme().to_string(),
+                url.scheme().to_string(),
+            ));
+        }
+        let cid = match url.host_str() {
+            Some(cid) if !cid.is_empty() && matches!(url.path(), "" | "/") => cid.to_string(),
+            _ => return Err(ParseIpfsRefError::InvalidCid(url.to_string())),
+        };
+        let attributes = serde_urlencoded::from_str(url.query().unwrap_or_default())?;
+
+        Ok(IpfsRef::new(cid, attributes))
+    }
+}
+
+impl<N: Namespace> FromStr for IpfsRef<N> {
+    type Err = ParseIpfsRefError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s)?;
+        Self::from_url(url)
+    }
+}
+
+impl<N: Namespace> Display for IpfsRef<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", N::scheme(), self.cid)?;
+
+        let query = serde_urlencoded::to_string(&self.attributes).unwrap_or_default();
+        if !query.is_empty() {
+            write!(f, "?{query}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseIpfsRefError {
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error("Invalid scheme (expected: '{0}:', found '{1}:'")]
+    InvalidScheme(String, String),
+    #[error("Expected a single CID or name in '{0}'")]
+    InvalidCid(String),
+    #[error("Couldn't parse query: {0}")]
+    Query(#[from] serde_urlencoded::de::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flake_ref::FlakeRef;
+
+    const CID: &str = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+    const NAR_HASH: &str = "sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4=";
+
+    #[test]
+    fn ipfs_to_from_url() {
+        let query = "narHash=sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4%3D";
+        let url = format!("ipfs://{CID}?{query}");
+        let flake_ref = IpfsRef::<namespace::Ipfs>::from_str(&url).unwrap();
+        assert_eq!(flake_ref, IpfsRef::new(CID.to_string(), IpfsAttributes {
+            nar_hash: Some(NAR_HASH.to_string()),
+            last_modified: None,
+        }));
+        assert_eq!(flake_ref.to_string(), url);
+        // `parser-util` is not involved
+        assert_eq!(url.parse::<FlakeRef>().unwrap(), FlakeRef::Ipfs(flake_ref));
+
+        // CIDv0 is case sensitive
+        let v0 = "ipfs://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+        let v0_ref: IpfsRef<namespace::Ipfs> = v0.parse().unwrap();
+        assert_eq!(v0_ref.to_string(), v0);
+
+        assert!(IpfsRef::<namespace::Ipfs>::from_str("ipns://example.org").is_err());
+        assert!(IpfsRef::<namespace::Ipfs>::from_str(&format!("ipfs://{CID}/sub")).is_err());
+    }
+
+    #[test]
+    fn ipfs_serde_round_trip() {
+        let flake_ref = FlakeRef::Ipfs(IpfsRef::new(CID.to_string(), IpfsAttributes {
+            nar_hash: Some(NAR_HASH.to_string()),
+            last_modified: None,
+        }));
+        let json = serde_json::to_value(&flake_ref).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "type": "ipfs", "cid": CID, "narHash": NAR_HASH })
+        );
+        assert_eq!(serde_json::from_value::<FlakeRef>(json).unwrap(), flake_ref);
+
+        let ipns = FlakeRef::Ipns("ipns://example.org".parse().unwrap());
+        let json = serde_json::to_string(&ipns).unwrap();
+        assert_eq!(serde_json::from_str::<FlakeRef>(&json).unwrap(), ipns);
+
+        assert_eq!(flake_ref.nar_hash(), Some(NAR_HASH));
+        assert_eq!(
+            flake_ref.clone().unpinned().to_string(),
+            format!("ipfs://{CID}")
+        );
+    }
+}
@@ -1,15 +1,17 @@
+use std::fmt::Display;
 use std::str::FromStr;
 
 use derive_more::Deref;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_with::DeserializeFromStr;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 use thiserror::Error;
 
 use super::Timestamp;
 
 static HASH_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("[a-f0-9]{40}").unwrap());
+static DIRTY_REV_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^([a-f0-9]{7,40})-dirty$").unwrap());
 
 /// todo: parse/validate narHash?
 pub type NarHash = String;
@@ -35,7 +37,7 @@ impl From<String> for RevOrRef {
     }
 }
 
-#[derive(DeserializeFromStr, Serialize, Clone, Debug, PartialEq, Eq, Deref)]
+#[derive(DeserializeFromStr, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deref)]
 pub struct Rev(String);
 impl FromStr for Rev {
     type Err = InvalidRev;
@@ -53,6 +55,41 @@ impl FromStr for Rev {
 #[error("Invalid revision hash")]
 pub struct InvalidRev;
 
+/// Revision of a git tree with uncommitted changes
+///
+/// Nix reports these as `dirtyRev`/`dirtyShortRev`,
+/// i.e. the (short) revision of `HEAD` suffixed with `-dirty`
+#[derive(DeserializeFromStr, SerializeDisplay, Clone, Debug, PartialEq, Eq)]
+pub struct DirtyRev(String);
+
+impl DirtyRev {
+    /// The revision of `HEAD` the changes are based on
+    pub fn head(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for DirtyRev {
+    type Err = InvalidDirtyRev;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let captures = DIRTY_REV_REGEX
+            .captures(s)
+            .ok_or_else(|| InvalidDirtyRev(s.to_string()))?;
+        Ok(DirtyRev(captures[1].to_string()))
+    }
+}
+
+impl Display for DirtyRev {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-dirty", self.0)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid dirty revision '{0}'")]
+pub struct InvalidDirtyRev(String);
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 #[serde(try_from = "StringOrInt")]
 pub struct RevCount(pub u64);
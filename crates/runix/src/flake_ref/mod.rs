@@ -1,11 +1,11 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 use std::os::unix::prelude::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use chrono::{NaiveDateTime, TimeZone, Utc};
+use chrono::{NaiveDateTime, SecondsFormat, TimeZone, Utc};
 use derive_more::{Display, From};
 use log::debug;
 use once_cell::sync::Lazy;
@@ -15,33 +15,42 @@ use serde_json::Value;
 use thiserror::Error;
 use url::Url;
 
+use self::file::application::ApplicationProtocol;
 use self::file::{FileAttributes, FileRef, TarballRef};
 use self::git::GitRef;
 use self::git_service::{service, GitServiceRef};
 use self::indirect::IndirectRef;
 use self::path::PathRef;
+use crate::arguments::{BuildArgs, NixArgs};
+use crate::command::{Build, BuildOut};
 use crate::flake_ref::git::GitAttributes;
 use crate::flake_ref::git_service::service::GitService;
 use crate::flake_ref::git_service::GitServiceAttributes;
 use crate::flake_ref::protocol::WrappedUrl;
+use crate::installable::FlakeAttribute;
+use crate::store_path::{StorePath, StorePathError};
 use crate::url_parser::{
     self,
     FileProtocolType,
+    FlakeRefParser,
     FlakeType,
     GitProtocolType,
     ParsedFlakeReference,
     TarballProtocolType,
     UrlParseError,
-    PARSER_UTIL_BIN_PATH,
 };
+use crate::{NixBackend, RunTyped};
 
 pub mod file;
 pub mod git;
 pub mod git_service;
 pub mod indirect;
+#[cfg(feature = "ipfs")]
+pub mod ipfs;
 pub mod lock;
 pub mod path;
 pub mod protocol;
+pub mod tagged;
 
 pub static FLAKE_ID_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new("^[a-zA-Z][a-zA-Z0-9_-]*(/[a-zA-Z][a-zA-Z0-9_-])*\\??").unwrap());
@@ -58,6 +67,13 @@ pub trait FlakeRefSource: FromStr + Display {
     }
 }
 
+/// A flake reference of any kind
+///
+/// Serializes like the attribute sets nix uses for flake refs,
+/// e.g. in lock files and the registry.
+/// Deserializing this representation requires a self-describing format,
+/// it is tested with JSON and MessagePack (`rmp-serde`).
+/// For other formats, such as `bincode`, see [tagged].
 #[derive(Serialize, Deserialize, Display, From, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum FlakeRef {
@@ -74,6 +90,10 @@ pub enum FlakeRef {
     GitSsh(GitRef<protocol::SSH>),
     GitHttps(GitRef<protocol::HTTPS>),
     GitHttp(GitRef<protocol::HTTP>),
+    #[cfg(feature = "ipfs")]
+    Ipfs(ipfs::IpfsRef<ipfs::namespace::Ipfs>),
+    #[cfg(feature = "ipfs")]
+    Ipns(ipfs::IpfsRef<ipfs::namespace::Ipns>),
     Indirect(IndirectRef),
     // /// https://cs.github.com/NixOS/nix/blob/f225f4307662fe9a57543d0c86c28aa9fddaf0d2/src/libfetchers/tarball.cc#L206
     // Tarball(TarballRef),
@@ -81,6 +101,25 @@ pub enum FlakeRef {
 
 type Attrs = HashMap<String, Value>;
 
+/// The kind of a [FlakeRef], disregarding the protocol used to fetch it
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum FlakeRefKind {
+    #[display(fmt = "path")]
+    Path,
+    #[display(fmt = "git")]
+    Git,
+    #[display(fmt = "github")]
+    Github,
+    #[display(fmt = "gitlab")]
+    Gitlab,
+    #[display(fmt = "tarball")]
+    Tarball,
+    #[display(fmt = "file")]
+    File,
+    #[display(fmt = "indirect")]
+    Indirect,
+}
+
 impl FromStr for FlakeRef {
     type Err = UrlParseError;
 
@@ -92,8 +131,12 @@ impl FromStr for FlakeRef {
     /// Note: if not "well-defined" parsing flakerefs is "impure",
     ///       i.e. depends on the state of the local system (files).
     ///       The resulting flakeref however, serializes into well-defined form.
+    ///
+    /// Parses with [FlakeRefParser::default], so `parser-util` is killed
+    /// after `RUNIX_TIMEOUT_SECS`, or if that is unset after
+    /// [DEFAULT_PARSER_TIMEOUT](url_parser::DEFAULT_PARSER_TIMEOUT).
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        FlakeRef::from_url(s, PARSER_UTIL_BIN_PATH)
+        FlakeRefParser::default().parse(s)
     }
 }
 
@@ -214,14 +257,342 @@ impl FlakeRef {
     }
 
     /// Parses a URI into a flake reference given the URI and the path to the `parser-util` binary
+    ///
+    /// `parser-util` may take as long as [FlakeRefParser::default] allows.
+    /// To parse several references, set up a [FlakeRefParser] once and reuse it,
+    /// see [FlakeRefParser::parse] for the references that are accepted.
     pub fn from_url<U, P>(url: U, bin_path: P) -> Result<Self, UrlParseError>
     where
         U: AsRef<str>,
         P: AsRef<Path>,
     {
-        let parsed = url_parser::installable_flake_ref(url, bin_path)?;
-        let parsed_ref = parsed.r#ref;
-        Self::from_parsed(&parsed_ref)
+        FlakeRefParser::with_bin_path(bin_path).parse(url.as_ref())
+    }
+
+    /// Guess the kind of flake ref a string refers to, without parsing it
+    ///
+    /// Meant for dispatching early or pointing users to the right syntax,
+    /// so this follows what the string most likely refers to rather than how nix reads it:
+    /// `https://github.com/{owner}/{repo}` is inferred as [FlakeRefKind::Github],
+    /// although nix would fetch the page as a file.
+    /// A kind inferred from a string does not guarantee that it parses.
+    ///
+    /// Returns [None] for strings that could be several kinds of refs,
+    /// e.g. `https://` urls not naming a tarball or repository,
+    /// and strings that are no flake ref at all.
+    pub fn infer_type_from_url(url: &str) -> Option<FlakeRefKind> {
+        if url.starts_with(['/', '.', '~']) {
+            return Some(FlakeRefKind::Path);
+        }
+        if IndirectRef::from_bare(url).is_some() {
+            return Some(FlakeRefKind::Indirect);
+        }
+        // scp-like ssh urls, e.g. `git@github.com:owner/repo`
+        if !url.contains("://")
+            && url
+                .split_once(':')
+                .is_some_and(|(user_host, _)| user_host.contains('@'))
+        {
+            return Some(FlakeRefKind::Git);
+        }
+
+        let parsed = Url::parse(url).ok()?;
+        let kind = match parsed.scheme().split_once('+') {
+            Some(("git", _)) => FlakeRefKind::Git,
+            Some(("tarball", _)) => FlakeRefKind::Tarball,
+            Some(("file", _)) => FlakeRefKind::File,
+            Some(_) => return None,
+            None => match parsed.scheme() {
+                "github" => FlakeRefKind::Github,
+                "gitlab" => FlakeRefKind::Gitlab,
+                "path" => FlakeRefKind::Path,
+                "flake" => FlakeRefKind::Indirect,
+                "ssh" => FlakeRefKind::Git,
+                "http" | "https" | "file" => {
+                    let segments = parsed
+                        .path_segments()
+                        .map(|segments| segments.filter(|s| !s.is_empty()).count());
+                    match parsed.host_str() {
+                        Some("github.com") if segments == Some(2) => FlakeRefKind::Github,
+                        Some("gitlab.com") if segments == Some(2) => FlakeRefKind::Gitlab,
+                        _ if !file::application::Tarball::required(&parsed) => {
+                            FlakeRefKind::Tarball
+                        },
+                        _ if parsed.path().ends_with(".git") => FlakeRefKind::Git,
+                        _ => return None,
+                    }
+                },
+                _ => return None,
+            },
+        };
+        Some(kind)
+    }
+
+    /// The closest equivalent of a Cargo `git = "<url>", rev = "<rev>"` dependency
+    ///
+    /// - `https://github.com/{owner}/{repo}` becomes `github:{owner}/{repo}/{rev}`,
+    /// - `https://gitlab.com/{owner}/{repo}` becomes `gitlab:{owner}/{repo}/{rev}`,
+    /// - any other `https`, `http`, `ssh` or `file` url becomes a `git+` ref with `?rev={rev}`.
+    ///   SSH urls are never turned into github/gitlab refs
+    ///   as they are typically used for private repositories.
+    ///   scp-like urls (`git@github.com:owner/repo.git`) are accepted as well.
+    ///
+    /// A trailing `.git` and the fragment Cargo lockfiles append to urls are dropped.
+    /// Unlike Cargo, nix requires `rev` to be a full commit hash.
+    pub fn from_cargo_git_dep(url: &str, rev: &str) -> Result<FlakeRef, ParseFlakeRefError> {
+        let rev = lock::Rev::from_str(rev)?;
+
+        // scp-like syntax, e.g. `git@github.com:owner/repo.git`
+        let scp_like = match url.split_once(':') {
+            Some((user_host, path)) if !url.contains("://") && user_host.contains('@') => {
+                Some(format!("ssh://{user_host}/{path}"))
+            },
+            _ => None,
+        };
+        let mut url =
+            Url::parse(scp_like.as_deref().unwrap_or(url)).map_err(git::ParseGitError::from)?;
+        url.set_fragment(None);
+
+        let service_repo = match url
+            .path_segments()
+            .map(|segments| segments.collect::<Vec<_>>())
+        {
+            Some(segments) if url.scheme() == "https" => match segments[..] {
+                [owner, repo] | [owner, repo, ""] if !owner.is_empty() => {
+                    Some((owner.to_string(), repo.trim_end_matches(".git").to_string()))
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let flake_ref = match (url.host_str(), service_repo) {
+            (Some("github.com"), Some((owner, repo))) => {
+                FlakeRef::Github(GitServiceRef::new(owner, repo, Default::default()).with_rev(rev))
+            },
+            (Some("gitlab.com"), Some((owner, repo))) => {
+                FlakeRef::Gitlab(GitServiceRef::new(owner, repo, Default::default()).with_rev(rev))
+            },
+            _ => {
+                if let Some(repo) = url.path().strip_suffix(".git").map(ToString::to_string) {
+                    url.set_path(&repo);
+                }
+                fn git_ref<P: git::GitProtocol>(
+                    url: Url,
+                    rev: lock::Rev,
+                ) -> Result<GitRef<P>, git::ParseGitError> {
+                    Ok(GitRef::new(url.try_into()?, Default::default()).with_rev(rev))
+                }
+                match url.scheme() {
+                    "https" => FlakeRef::GitHttps(git_ref(url, rev)?),
+                    "http" => FlakeRef::GitHttp(git_ref(url, rev)?),
+                    "ssh" => FlakeRef::GitSsh(git_ref(url, rev)?),
+                    "file" => FlakeRef::GitPath(git_ref(url, rev)?),
+                    scheme => Err(ParseFlakeRefError::CargoGitProtocol(scheme.to_string()))?,
+                }
+            },
+        };
+        Ok(flake_ref)
+    }
+
+    /// The flake ref of an input declared in `flake.nix`, given as JSON
+    ///
+    /// Accepts the value of `inputs.<name>` in either form nix supports:
+    ///
+    /// - `{ url = "github:NixOS/nixpkgs"; }`, where further attributes such as `dir`
+    ///   are merged into the ref like nix does,
+    /// - `{ type = "github"; owner = "NixOS"; repo = "nixpkgs"; }`,
+    ///   where `url` is an attribute of the ref like for `type = "git"`.
+    ///
+    /// `flake`, `inputs` and `follows` do not belong to the ref and are ignored.
+    /// Inputs without a ref refer to the registry entry of their name, i.e. `flake:<name>`.
+    ///
+    /// Unlike [FlakeRef::from_url], this does not call `parser-util`,
+    /// the url has to name its kind of ref (e.g. `path:./sub` or `git+https://...`),
+    /// except for paths (`./sub`) and registry entries (`nixpkgs`).
+    pub fn from_flake_nix_input(name: &str, input: &Value) -> Result<FlakeRef, ParseFlakeRefError> {
+        let invalid = |reason: &str| ParseFlakeRefError::Input {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        };
+        let Value::Object(input) = input else {
+            return Err(invalid("expected an attribute set"));
+        };
+        let mut attrs = input
+            .iter()
+            .filter(|(key, _)| !matches!(key.as_str(), "flake" | "inputs" | "follows"))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Attrs>();
+
+        // with a `type`, `url` is an attribute of `git`, `tarball` and `file` refs
+        let url = match attrs.contains_key("type") {
+            true => None,
+            false => attrs.remove("url"),
+        };
+        let attrs = match url {
+            Some(Value::String(url)) if attrs.is_empty() => return Self::from_input_url(&url),
+            Some(Value::String(url)) => {
+                let Value::Object(url_attrs) = serde_json::to_value(Self::from_input_url(&url)?)
+                    .map_err(|_| invalid("the url can not be merged with other attributes"))?
+                else {
+                    unreachable!("flake refs serialize as objects");
+                };
+                url_attrs.into_iter().chain(attrs).collect()
+            },
+            Some(_) => return Err(invalid("'url' is not a string")),
+            None if attrs.is_empty() && input.contains_key("follows") => {
+                return Err(invalid("the input follows another input"))
+            },
+            None if attrs.is_empty() => {
+                return Ok(FlakeRef::Indirect(IndirectRef::new(
+                    name.to_string(),
+                    Default::default(),
+                )))
+            },
+            None => attrs,
+        };
+
+        let deserialized = url_parser::DeserializedFlakeRef {
+            attrs,
+            string: String::new(),
+        };
+        let parsed = ParsedFlakeReference::try_from(deserialized)?;
+        Ok(Self::from_parsed(&parsed)?)
+    }
+
+    /// The declaration of this ref as the input `name` in `flake.nix`,
+    /// the counterpart of [FlakeRef::from_flake_nix_input]
+    ///
+    /// Refs are declared by their url, e.g. `inputs.nixpkgs.url = "github:NixOS/nixpkgs";`,
+    /// unless the url does not parse back into the same ref,
+    /// e.g. for paths containing `#` or `?`.
+    /// Those are declared by their attributes instead,
+    /// e.g. `inputs.src = { type = "path"; path = "/a#b"; };`.
+    pub fn to_flake_nix_input(&self, name: &str) -> String {
+        let name = nix_attr_name(name);
+        let url = self.to_string();
+        if Self::from_input_url(&url).is_ok_and(|parsed| &parsed == self) {
+            return format!("inputs.{name}.url = {};", nix_string(&url));
+        }
+
+        let Ok(Value::Object(mut attrs)) = serde_json::to_value(self) else {
+            unreachable!("flake refs serialize as objects");
+        };
+        let flake_type = attrs.remove("type");
+        let attrs = flake_type
+            .iter()
+            .map(|flake_type| ("type", flake_type))
+            .chain(attrs.iter().map(|(key, value)| (key.as_str(), value)))
+            .map(|(key, value)| format!("{} = {};", nix_attr_name(key), nix_value(value)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("inputs.{name} = {{ {attrs} }};")
+    }
+
+    /// This ref as the entry `name` of `NIX_PATH` (or a `-I` option),
+    /// e.g. `nixpkgs=/nix/store/...-source`
+    ///
+    /// Path refs are given by their path, any attributes are dropped.
+    /// Other refs are given by their url, e.g. `nixpkgs=https://example.com/nixpkgs.tar.gz`.
+    /// Older versions of nix only accept urls of tarballs there,
+    /// not other flake refs such as `flake:nixpkgs`.
+    pub fn to_env_var_form(&self, name: &str) -> String {
+        match self {
+            FlakeRef::Path(path_ref) => format!("{name}={}", path_ref.path.display()),
+            flake_ref => format!("{name}={flake_ref}"),
+        }
+    }
+
+    /// Parse an entry of `NIX_PATH` (or a `-I` option) into its name and ref,
+    /// the counterpart of [FlakeRef::to_env_var_form]
+    ///
+    /// `nixpkgs=/nix/store/...-source` becomes a path ref,
+    /// `nixpkgs=github:NixOS/nixpkgs` a github ref
+    /// and `nixpkgs=flake:nixpkgs` an indirect ref.
+    /// Like [FlakeRef::from_flake_nix_input], this does not call `parser-util`.
+    pub fn from_env_var_form(s: &str) -> Result<(String, FlakeRef), ParseFlakeRefError> {
+        let Some((name, url)) = s.split_once('=').filter(|(name, _)| !name.is_empty()) else {
+            return Err(ParseFlakeRefError::EnvVarForm(s.to_string()));
+        };
+        Ok((name.to_string(), Self::from_input_url(url)?))
+    }
+
+    /// Environment variables describing the ref to later steps of a CI pipeline,
+    /// named `{prefix}_{NAME}`
+    ///
+    /// - `{prefix}_FLAKE_REF`: the ref as a url
+    /// - `{prefix}_OWNER` and `{prefix}_REPO`: the repository of github and gitlab refs
+    /// - `{prefix}_REV`: the commit the ref is locked to, see [FlakeRef::rev]
+    /// - `{prefix}_REF`: the branch or tag of git, github, gitlab and indirect refs
+    ///
+    /// Variables that do not apply to the ref are left out rather than set empty.
+    pub fn to_ci_env_vars(&self, prefix: &str) -> HashMap<String, String> {
+        let (repo, reference) = match self {
+            FlakeRef::Github(r) => (Some((&r.owner, &r.repo)), r.attributes.reference.as_ref()),
+            FlakeRef::Gitlab(r) => (Some((&r.owner, &r.repo)), r.attributes.reference.as_ref()),
+            FlakeRef::GitPath(r) => (None, r.attributes.reference.as_ref()),
+            FlakeRef::GitSsh(r) => (None, r.attributes.reference.as_ref()),
+            FlakeRef::GitHttps(r) => (None, r.attributes.reference.as_ref()),
+            FlakeRef::GitHttp(r) => (None, r.attributes.reference.as_ref()),
+            FlakeRef::Indirect(r) => (None, r.reference.as_ref().or(r.attributes.get("ref"))),
+            _ => (None, None),
+        };
+
+        let mut vars = HashMap::from([(format!("{prefix}_FLAKE_REF"), self.to_string())]);
+        if let Some((owner, repo)) = repo {
+            vars.insert(format!("{prefix}_OWNER"), owner.clone());
+            vars.insert(format!("{prefix}_REPO"), repo.clone());
+        }
+        if let Some(rev) = self.rev() {
+            vars.insert(format!("{prefix}_REV"), rev.to_string());
+        }
+        if let Some(reference) = reference {
+            vars.insert(format!("{prefix}_REF"), reference.clone());
+        }
+        vars
+    }
+
+    /// Parse the `url` of a flake input by its scheme
+    fn from_input_url(url: &str) -> Result<FlakeRef, ParseFlakeRefError> {
+        if url.starts_with(['.', '/']) {
+            return Ok(FlakeRef::Path(format!("path:{url}").parse()?));
+        }
+        let Ok(parsed) = Url::parse(url) else {
+            return Ok(FlakeRef::Indirect(url.parse()?));
+        };
+        let flake_ref = match parsed.scheme() {
+            "github" => FlakeRef::Github(url.parse()?),
+            "gitlab" => FlakeRef::Gitlab(url.parse()?),
+            "path" => FlakeRef::Path(url.parse()?),
+            "flake" => FlakeRef::Indirect(url.parse()?),
+            "git+file" => FlakeRef::GitPath(url.parse()?),
+            "git+ssh" => FlakeRef::GitSsh(url.parse()?),
+            "git+https" => FlakeRef::GitHttps(url.parse()?),
+            "git+http" => FlakeRef::GitHttp(url.parse()?),
+            "tarball+file" => FlakeRef::TarballFile(url.parse()?),
+            "tarball+http" => FlakeRef::TarballHTTP(url.parse()?),
+            "tarball+https" => FlakeRef::TarballHTTPS(url.parse()?),
+            "file+file" => FlakeRef::FileFile(url.parse()?),
+            "file+http" => FlakeRef::FileHTTP(url.parse()?),
+            "file+https" => FlakeRef::FileHTTPS(url.parse()?),
+            #[cfg(feature = "ipfs")]
+            "ipfs" => FlakeRef::Ipfs(url.parse()?),
+            #[cfg(feature = "ipfs")]
+            "ipns" => FlakeRef::Ipns(url.parse()?),
+            // like nix, plain urls are tarballs if they name an archive
+            "file" | "http" | "https" if !file::application::Tarball::required(&parsed) => {
+                match parsed.scheme() {
+                    "file" => FlakeRef::TarballFile(url.parse()?),
+                    "http" => FlakeRef::TarballHTTP(url.parse()?),
+                    _ => FlakeRef::TarballHTTPS(url.parse()?),
+                }
+            },
+            "file" => FlakeRef::FileFile(url.parse()?),
+            "http" => FlakeRef::FileHTTP(url.parse()?),
+            "https" => FlakeRef::FileHTTPS(url.parse()?),
+            _ => Err(ParseFlakeRefError::Invalid)?,
+        };
+        Ok(flake_ref)
     }
 
     /// Converts a parsed flake reference from `parser-util` to a [FlakeRef]
@@ -392,6 +763,551 @@ impl FlakeRef {
             },
         }
     }
+
+    /// The `narHash` of the flake's source tree, if set
+    pub fn nar_hash(&self) -> Option<&str> {
+        match self {
+            FlakeRef::FileFile(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::FileHTTP(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::FileHTTPS(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::TarballFile(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::TarballHTTP(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::TarballHTTPS(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::Github(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::Gitlab(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::Path(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::GitPath(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::GitSsh(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::GitHttps(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::GitHttp(r) => r.attributes.nar_hash.as_deref(),
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipfs(r) => r.attributes.nar_hash.as_deref(),
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipns(r) => r.attributes.nar_hash.as_deref(),
+            FlakeRef::Indirect(r) => r.attributes.get("narHash").map(String::as_str),
+        }
+    }
+
+    /// Check that `hash`, the `narHash` of fetched contents, is the one the ref expects
+    ///
+    /// For now this only compares `hash` to [FlakeRef::nar_hash],
+    /// computing it from the fetched source tree is left to the caller
+    /// (see [file::FileBasedRef::verify_nar_hash] for files and tarballs).
+    pub fn verify_nar_hash(&self, hash: &lock::NarHash) -> Result<(), VerificationError> {
+        let expected = self.nar_hash().ok_or(VerificationError::Missing)?;
+        if expected != hash {
+            return Err(VerificationError::HashMismatch {
+                expected: expected.to_string(),
+                found: hash.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Set the `narHash` of the flake's source tree, locking its contents
+    pub fn with_nar_hash(self, nar_hash: lock::NarHash) -> Self {
+        let nar_hash = Some(nar_hash);
+        match self {
+            FlakeRef::FileFile(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::FileFile(r)
+            },
+            FlakeRef::FileHTTP(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::FileHTTP(r)
+            },
+            FlakeRef::FileHTTPS(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::FileHTTPS(r)
+            },
+            FlakeRef::TarballFile(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::TarballFile(r)
+            },
+            FlakeRef::TarballHTTP(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::TarballHTTP(r)
+            },
+            FlakeRef::TarballHTTPS(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::TarballHTTPS(r)
+            },
+            FlakeRef::Github(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::Github(r)
+            },
+            FlakeRef::Gitlab(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::Gitlab(r)
+            },
+            FlakeRef::Path(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::Path(r)
+            },
+            FlakeRef::GitPath(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::GitPath(r)
+            },
+            FlakeRef::GitSsh(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::GitSsh(r)
+            },
+            FlakeRef::GitHttps(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::GitHttps(r)
+            },
+            FlakeRef::GitHttp(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::GitHttp(r)
+            },
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipfs(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::Ipfs(r)
+            },
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipns(mut r) => {
+                r.attributes.nar_hash = nar_hash;
+                FlakeRef::Ipns(r)
+            },
+            FlakeRef::Indirect(mut r) => {
+                r.attributes
+                    .extend(nar_hash.map(|hash| ("narHash".to_string(), hash)));
+                FlakeRef::Indirect(r)
+            },
+        }
+    }
+
+    /// The git revision the flake ref is pinned to
+    pub fn rev(&self) -> Option<&str> {
+        match self {
+            FlakeRef::FileFile(_)
+            | FlakeRef::FileHTTP(_)
+            | FlakeRef::FileHTTPS(_)
+            | FlakeRef::TarballFile(_)
+            | FlakeRef::TarballHTTP(_)
+            | FlakeRef::TarballHTTPS(_) => None,
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipfs(_) | FlakeRef::Ipns(_) => None,
+            FlakeRef::Github(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::Gitlab(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::Path(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::GitPath(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::GitSsh(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::GitHttps(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::GitHttp(r) => r.attributes.rev.as_deref().map(String::as_str),
+            FlakeRef::Indirect(r) => r
+                .rev
+                .as_deref()
+                .or_else(|| r.attributes.get("rev"))
+                .map(String::as_str),
+        }
+    }
+
+    /// The first 7 characters of [FlakeRef::rev], as commonly shown by git
+    ///
+    /// Shorter revs are returned as they are.
+    pub fn rev_short(&self) -> Option<&str> {
+        self.rev().map(|rev| rev.get(..7).unwrap_or(rev))
+    }
+
+    /// The `lastModified` time of the flake's source tree as an RFC 3339 date in UTC,
+    /// e.g. `2023-07-07T11:45:50Z`, for showing it without depending on a date crate
+    pub fn last_modified_rfc3339(&self) -> Option<String> {
+        let last_modified = match self {
+            FlakeRef::FileFile(_)
+            | FlakeRef::FileHTTP(_)
+            | FlakeRef::FileHTTPS(_)
+            | FlakeRef::TarballFile(_)
+            | FlakeRef::TarballHTTP(_)
+            | FlakeRef::TarballHTTPS(_) => None,
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipfs(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipns(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::Github(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::Gitlab(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::Path(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::GitPath(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::GitSsh(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::GitHttps(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::GitHttp(r) => r.attributes.last_modified.as_ref().map(|ts| ts.0),
+            FlakeRef::Indirect(r) => r
+                .attributes
+                .get("lastModified")
+                .and_then(|seconds| seconds.parse().ok())
+                .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single()),
+        };
+        Some(last_modified?.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    /// Whether the ref points to a fixed commit, see [FlakeRef::rev]
+    ///
+    /// The contents fetched for a locked ref are not verified,
+    /// use [FlakeRef::is_pinned] for that.
+    pub fn is_locked(&self) -> bool {
+        self.rev().is_some()
+    }
+
+    /// Whether the ref has both a `rev` and a `narHash`,
+    /// i.e. nix fetches the same source tree for it every time or fails
+    ///
+    /// Refs without a `rev` attribute (files and tarballs) are never pinned.
+    pub fn is_pinned(&self) -> bool {
+        self.is_locked() && self.nar_hash().is_some()
+    }
+
+    /// Point the ref at the branch or tag `reference`, see e.g. [GitServiceRef::with_ref]
+    ///
+    /// Returns [None] for kinds of refs without a `ref` attribute,
+    /// i.e. anything but git, github, gitlab and indirect refs.
+    pub fn with_ref(self, reference: impl Into<String>) -> Option<Self> {
+        Some(match self {
+            FlakeRef::Github(r) => FlakeRef::Github(r.with_ref(reference)),
+            FlakeRef::Gitlab(r) => FlakeRef::Gitlab(r.with_ref(reference)),
+            FlakeRef::GitPath(r) => FlakeRef::GitPath(r.with_ref(reference)),
+            FlakeRef::GitSsh(r) => FlakeRef::GitSsh(r.with_ref(reference)),
+            FlakeRef::GitHttps(r) => FlakeRef::GitHttps(r.with_ref(reference)),
+            FlakeRef::GitHttp(r) => FlakeRef::GitHttp(r.with_ref(reference)),
+            FlakeRef::Indirect(mut r) => {
+                r.reference = Some(reference.into());
+                FlakeRef::Indirect(r)
+            },
+            _ => return None,
+        })
+    }
+
+    /// Pin the ref to the commit `rev`, see e.g. [GitServiceRef::with_rev]
+    ///
+    /// Returns [None] for kinds of refs without a `rev` attribute,
+    /// i.e. anything but git, github, gitlab and indirect refs.
+    pub fn with_rev(self, rev: lock::Rev) -> Option<Self> {
+        Some(match self {
+            FlakeRef::Github(r) => FlakeRef::Github(r.with_rev(rev)),
+            FlakeRef::Gitlab(r) => FlakeRef::Gitlab(r.with_rev(rev)),
+            FlakeRef::GitPath(r) => FlakeRef::GitPath(r.with_rev(rev)),
+            FlakeRef::GitSsh(r) => FlakeRef::GitSsh(r.with_rev(rev)),
+            FlakeRef::GitHttps(r) => FlakeRef::GitHttps(r.with_rev(rev)),
+            FlakeRef::GitHttp(r) => FlakeRef::GitHttp(r.with_rev(rev)),
+            FlakeRef::Indirect(mut r) => {
+                r.attributes.remove("rev");
+                r.rev = Some(rev);
+                FlakeRef::Indirect(r)
+            },
+            _ => return None,
+        })
+    }
+
+    /// Compare the attributes of two refs, except for those named in `keys`
+    ///
+    /// Attributes are named as in the attribute set nix uses for the ref,
+    /// e.g. `["lastModified", "narHash"]` finds refs to the same source
+    /// whose lock metadata differs.
+    /// Refs of different kinds are never equal, unless `type` is ignored.
+    pub fn eq_ignoring_attributes(&self, keys: &[&str], other: &FlakeRef) -> bool {
+        let attrs = |flake_ref: &FlakeRef| {
+            let Ok(Value::Object(mut attrs)) = serde_json::to_value(flake_ref) else {
+                unreachable!("flake refs serialize as objects");
+            };
+            attrs.retain(|key, _| !keys.contains(&key.as_str()));
+            attrs
+        };
+        attrs(self) == attrs(other)
+    }
+
+    /// Remove `rev`, `narHash` and `lastModified`,
+    /// turning a locked ref back into a floating one that follows its branch or url
+    pub fn unpinned(self) -> Self {
+        match self {
+            FlakeRef::FileFile(mut r) => {
+                r.attributes.nar_hash = None;
+                FlakeRef::FileFile(r)
+            },
+            FlakeRef::FileHTTP(mut r) => {
+                r.attributes.nar_hash = None;
+                FlakeRef::FileHTTP(r)
+            },
+            FlakeRef::FileHTTPS(mut r) => {
+                r.attributes.nar_hash = None;
+                FlakeRef::FileHTTPS(r)
+            },
+            FlakeRef::TarballFile(mut r) => {
+                r.attributes.nar_hash = None;
+                FlakeRef::TarballFile(r)
+            },
+            FlakeRef::TarballHTTP(mut r) => {
+                r.attributes.nar_hash = None;
+                FlakeRef::TarballHTTP(r)
+            },
+            FlakeRef::TarballHTTPS(mut r) => {
+                r.attributes.nar_hash = None;
+                FlakeRef::TarballHTTPS(r)
+            },
+            FlakeRef::Github(r) => FlakeRef::Github(r.unpinned()),
+            FlakeRef::Gitlab(r) => FlakeRef::Gitlab(r.unpinned()),
+            FlakeRef::Path(mut r) => {
+                r.attributes.rev = None;
+                r.attributes.nar_hash = None;
+                r.attributes.last_modified = None;
+                FlakeRef::Path(r)
+            },
+            FlakeRef::GitPath(r) => FlakeRef::GitPath(r.unpinned()),
+            FlakeRef::GitSsh(r) => FlakeRef::GitSsh(r.unpinned()),
+            FlakeRef::GitHttps(r) => FlakeRef::GitHttps(r.unpinned()),
+            FlakeRef::GitHttp(r) => FlakeRef::GitHttp(r.unpinned()),
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipfs(mut r) => {
+                r.attributes.nar_hash = None;
+                r.attributes.last_modified = None;
+                FlakeRef::Ipfs(r)
+            },
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipns(mut r) => {
+                r.attributes.nar_hash = None;
+                r.attributes.last_modified = None;
+                FlakeRef::Ipns(r)
+            },
+            FlakeRef::Indirect(mut r) => {
+                r.rev = None;
+                for attr in ["rev", "narHash", "lastModified"] {
+                    r.attributes.remove(attr);
+                }
+                FlakeRef::Indirect(r)
+            },
+        }
+    }
+
+    /// Select the flake in the subdirectory `dir` of the source tree
+    ///
+    /// Returns [None] for kinds of refs without a `dir` attribute,
+    /// i.e. anything but git, github, gitlab and indirect refs.
+    pub fn with_dir(self, dir: impl Into<PathBuf>) -> Option<Self> {
+        Some(match self {
+            FlakeRef::Github(r) => FlakeRef::Github(r.with_dir(dir)),
+            FlakeRef::Gitlab(r) => FlakeRef::Gitlab(r.with_dir(dir)),
+            FlakeRef::GitPath(r) => FlakeRef::GitPath(r.with_dir(dir)),
+            FlakeRef::GitSsh(r) => FlakeRef::GitSsh(r.with_dir(dir)),
+            FlakeRef::GitHttps(r) => FlakeRef::GitHttps(r.with_dir(dir)),
+            FlakeRef::GitHttp(r) => FlakeRef::GitHttp(r.with_dir(dir)),
+            FlakeRef::Indirect(mut r) => {
+                let dir = dir.into().to_string_lossy().into_owned();
+                r.attributes.insert("dir".to_string(), dir);
+                FlakeRef::Indirect(r)
+            },
+            _ => return None,
+        })
+    }
+
+    /// Fetch from a self-hosted forge, see [GitServiceRef::with_host]
+    ///
+    /// Kinds of refs without a `host` attribute, i.e. anything but github and gitlab refs,
+    /// are returned unchanged.
+    pub fn with_host(self, host: impl Into<String>) -> Self {
+        match self {
+            FlakeRef::Github(r) => FlakeRef::Github(r.with_host(host)),
+            FlakeRef::Gitlab(r) => FlakeRef::Gitlab(r.with_host(host)),
+            other => other,
+        }
+    }
+
+    /// The custom host of a github or gitlab ref, see [FlakeRef::with_host]
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            FlakeRef::Github(r) => r.attributes.host.as_deref(),
+            FlakeRef::Gitlab(r) => r.attributes.host.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// A short form of the flake ref for display, e.g. `github:NixOS/nixpkgs@0630fc9`
+    ///
+    /// Only the scheme, the location (e.g. owner and repo, or the id of an indirect ref) and,
+    /// if the ref is pinned, the [FlakeRef::rev_short] are included.
+    /// All other attributes, including the query of tarball urls, are omitted.
+    /// The result is meant to identify a flake at a glance,
+    /// it can not be parsed back into an equivalent [FlakeRef].
+    pub fn to_compact_string(&self) -> String {
+        let location = match self {
+            FlakeRef::Github(r) => format!("github:{}/{}", r.owner, r.repo),
+            FlakeRef::Gitlab(r) => format!("gitlab:{}/{}", r.owner, r.repo),
+            FlakeRef::Indirect(r) => format!("flake:{}", r.id),
+            other => other.to_string(),
+        };
+
+        let location = location.split(['?', '#']).next().unwrap_or_default();
+        match self.rev_short() {
+            Some(rev) => format!("{location}@{rev}"),
+            None => location.to_string(),
+        }
+    }
+
+    /// Whether the flake's source can be obtained without invoking nix
+    ///
+    /// This is the case for
+    ///
+    /// - `path:` refs, whose files are already local,
+    /// - locked (i.e. with a `narHash`) tarball refs, which are a plain download
+    ///   whose unpacked contents can be verified against the `narHash`,
+    /// - git refs locked to a `rev`, which can be checked out by `git` directly.
+    ///
+    /// Caveats:
+    ///
+    /// - `path:` refs are not verified, even if they carry a `narHash`.
+    /// - Fetching git refs still requires a `git` binary and, for remote repos,
+    ///   network access and credentials.
+    ///   Nix' handling of `submodules`, `shallow` and `allRefs` needs to be reproduced by the caller.
+    /// - Github/Gitlab refs could be fetched as tarballs from the service's API,
+    ///   but are reported as `false` since that depends on nix' access token configuration.
+    /// - Indirect refs need to be resolved against a registry first.
+    /// - IPFS refs require an IPFS fetcher, which nix lacks.
+    pub fn is_fetchable_without_nix(&self) -> bool {
+        match self {
+            FlakeRef::Path(_) => true,
+            FlakeRef::TarballFile(r) => r.attributes.nar_hash.is_some(),
+            FlakeRef::TarballHTTP(r) => r.attributes.nar_hash.is_some(),
+            FlakeRef::TarballHTTPS(r) => r.attributes.nar_hash.is_some(),
+            FlakeRef::GitPath(r) => r.attributes.rev.is_some(),
+            FlakeRef::GitSsh(r) => r.attributes.rev.is_some(),
+            FlakeRef::GitHttps(r) => r.attributes.rev.is_some(),
+            FlakeRef::GitHttp(r) => r.attributes.rev.is_some(),
+            FlakeRef::FileFile(_)
+            | FlakeRef::FileHTTP(_)
+            | FlakeRef::FileHTTPS(_)
+            | FlakeRef::Github(_)
+            | FlakeRef::Gitlab(_)
+            | FlakeRef::Indirect(_) => false,
+            #[cfg(feature = "ipfs")]
+            FlakeRef::Ipfs(_) | FlakeRef::Ipns(_) => false,
+        }
+    }
+
+    /// The url of the `.narinfo` of the flake's source on a binary cache
+    ///
+    /// Binary caches serve `<substituter>/<hash>.narinfo` for every store path they provide,
+    /// where `<hash>` is the hash part of the store path.
+    /// The source's store path is derived from its `narHash`
+    /// (see [StorePath::from_nar_hash]), which needs to be set.
+    pub fn narinfo_url(&self, substituter: &Url) -> Option<Url> {
+        let source = StorePath::from_nar_hash(self.nar_hash()?, "source")?;
+
+        let mut base = substituter.clone();
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        base.join(&format!("{}.narinfo", source.hash())).ok()
+    }
+
+    /// The `nix-store://` url of the flake's source, e.g.
+    /// `nix-store:///nix/store/083m43hjhry94cvfmqdv7kjpvsl3zzvi-source`
+    ///
+    /// Addresses the source tree by its content rather than where it is fetched from,
+    /// so tools can look it up in a store or binary cache before fetching it.
+    /// Like [FlakeRef::narinfo_url], this needs a `sha256` `narHash`,
+    /// the url points to the root of the source tree regardless of `dir`.
+    pub fn to_nix_store_url(&self) -> Option<Url> {
+        let source = StorePath::from_nar_hash(self.nar_hash()?, "source")?;
+        Url::parse(&format!("nix-store://{source}")).ok()
+    }
+
+    /// A link to the changes between `self` and `other` on GitHub
+    ///
+    /// `https://github.com/{owner}/{repo}/compare/{rev}...{other_rev}`,
+    /// using the refs' custom `host` if set.
+    /// Returns [None] unless both refs are `github:` refs locked to a `rev`
+    /// and point to the same repository (compared case insensitively, like GitHub does).
+    pub fn to_github_compare_url(&self, other: &FlakeRef) -> Option<Url> {
+        let (FlakeRef::Github(old), FlakeRef::Github(new)) = (self, other) else {
+            return None;
+        };
+
+        let host = |r: &GitServiceRef<service::Github>| {
+            r.attributes
+                .host
+                .clone()
+                .unwrap_or_else(|| "github.com".to_string())
+        };
+        let same_repo = host(old).eq_ignore_ascii_case(&host(new))
+            && old.owner.eq_ignore_ascii_case(&new.owner)
+            && old.repo.eq_ignore_ascii_case(&new.repo);
+        if !same_repo {
+            return None;
+        }
+
+        let (old_rev, new_rev) = (old.attributes.rev.as_ref()?, new.attributes.rev.as_ref()?);
+        let mut url = Url::parse(&format!("https://{}", host(old))).ok()?;
+        url.path_segments_mut()
+            .ok()?
+            .clear()
+            .push(&old.owner)
+            .push(&old.repo)
+            .push("compare")
+            .push(&format!("{}...{}", old_rev.as_str(), new_rev.as_str()));
+        Some(url)
+    }
+
+    /// The url to `git clone` the flake's repository from
+    ///
+    /// Github and gitlab refs are cloned over HTTPS from their (custom) host,
+    /// see [GitServiceRef::to_clone_url], `git+` refs from their url without the `git+` prefix,
+    /// e.g. `ssh://git@example.com/repo.git` for `git+ssh://git@example.com/repo.git?ref=main`.
+    /// Like for those, the `rev`, `ref` and `dir` are not part of the url.
+    ///
+    /// Returns [None] for refs that are not backed by a git repository,
+    /// i.e. indirect, path, file, tarball and IPFS refs,
+    /// and if the custom host of a github or gitlab ref is not a valid host.
+    pub fn git_url(&self) -> Option<Url> {
+        let url: &Url = match self {
+            FlakeRef::Github(r) => return r.to_clone_url().ok(),
+            FlakeRef::Gitlab(r) => return r.to_clone_url().ok(),
+            FlakeRef::GitPath(r) => &r.url,
+            FlakeRef::GitSsh(r) => &r.url,
+            FlakeRef::GitHttps(r) => &r.url,
+            FlakeRef::GitHttp(r) => &r.url,
+            _ => return None,
+        };
+        let mut url = url.clone();
+        url.set_query(None);
+        Some(url)
+    }
+
+    /// Build the flake's default package and return its store path
+    ///
+    /// Runs [Build] for the flake with `--no-link` through `backend`,
+    /// i.e. `nix build --json <flakeref> --no-link`
+    /// with a [NixCommandLine](crate::command_line::NixCommandLine).
+    /// If the package has multiple outputs, the path of `out` is returned,
+    /// without one the path of the first output by name.
+    pub async fn to_store_path<B>(
+        &self,
+        backend: &B,
+        nix_args: &NixArgs,
+    ) -> Result<StorePath, StorePathError>
+    where
+        B: NixBackend,
+        Build: RunTyped<B, Output = BuildOut>,
+    {
+        let build = Build {
+            installables: vec![FlakeAttribute::from(self.clone()).into()].into(),
+            build: BuildArgs {
+                no_link: Some(true.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let built = build
+            .run_typed(backend, nix_args)
+            .await
+            .map_err(|err| StorePathError::NixBuild(Box::new(err)))?;
+
+        let mut outputs = built
+            .into_iter()
+            .flat_map(|entry| entry.outputs)
+            .collect::<BTreeMap<_, _>>();
+        outputs
+            .remove("out")
+            .or_else(|| outputs.into_values().next())
+            .ok_or(StorePathError::NoOutPath)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -421,6 +1337,50 @@ pub enum ResolveLocalRefError {
     InconsistentDirParam(String, String),
 }
 
+/// Error returned by [FlakeRef::verify_nar_hash]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum VerificationError {
+    #[error("flake ref has no narHash to verify")]
+    Missing,
+    #[error("hash mismatch: expected '{expected}', found '{found}'")]
+    HashMismatch {
+        expected: lock::NarHash,
+        found: lock::NarHash,
+    },
+}
+
+/// `name` as an attribute name in a nix expression, quoted unless it is an identifier
+fn nix_attr_name(name: &str) -> String {
+    static IDENTIFIER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_'-]*$").unwrap());
+    match IDENTIFIER.is_match(name) {
+        true => name.to_string(),
+        false => nix_string(name),
+    }
+}
+
+/// `value` as a nix string literal
+fn nix_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{escaped}\"")
+}
+
+/// An attribute of a flake ref as a nix value
+fn nix_value(value: &Value) -> String {
+    match value {
+        Value::String(string) => nix_string(string),
+        Value::Bool(bool) => bool.to_string(),
+        Value::Number(number) => number.to_string(),
+        other => nix_string(&other.to_string()),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ParseFlakeRefError {
     #[error(transparent)]
@@ -435,6 +1395,19 @@ pub enum ParseFlakeRefError {
     Path(#[from] path::ParsePathRefError),
     #[error(transparent)]
     Local(#[from] ResolveLocalRefError),
+    #[error(transparent)]
+    Rev(#[from] lock::InvalidRev),
+    #[cfg(feature = "ipfs")]
+    #[error(transparent)]
+    Ipfs(#[from] ipfs::ParseIpfsRefError),
+    #[error("Unsupported protocol for a cargo git dependency: '{0}'")]
+    CargoGitProtocol(String),
+    #[error("Invalid flake input '{name}': {reason}")]
+    Input { name: String, reason: String },
+    #[error("Expected a NIX_PATH entry of the form '<name>=<flake ref>', got '{0}'")]
+    EnvVarForm(String),
+    #[error(transparent)]
+    Attributes(#[from] UrlParseError),
     #[error("Invalid flakeref")]
     Invalid,
 }
@@ -508,7 +1481,11 @@ pub(super) mod tests {
     use std::fmt::Debug;
     use std::fs::{self, File};
 
+    use serde_json::json;
+
+    use self::path::PathAttributes;
     use super::*;
+    use crate::command_line::mock::{MockBackend, MockResponse};
 
     #[test]
     fn test_all_parsing() {
@@ -804,4 +1781,768 @@ pub(super) mod tests {
             ResolveLocalRefError::GitRepoBoundary(_)
         ));
     }
+
+    #[test]
+    fn fetchable_without_nix() {
+        let path: FlakeRef = PathRef::from_str("path:/some/where").unwrap().into();
+        assert!(path.is_fetchable_without_nix());
+
+        let tarball: TarballRef<protocol::HTTPS> =
+            TarballRef::from_str("tarball+https://example.com/source.tar.gz").unwrap();
+        assert!(!FlakeRef::from(tarball.clone()).is_fetchable_without_nix());
+        let locked = TarballRef::new(tarball.url, FileAttributes {
+            nar_hash: Some("sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4=".into()),
+            ..Default::default()
+        });
+        assert!(FlakeRef::from(locked).is_fetchable_without_nix());
+
+        let git: GitRef<protocol::HTTPS> =
+            GitRef::from_str("git+https://example.com/repo").unwrap();
+        assert!(!FlakeRef::from(git.clone()).is_fetchable_without_nix());
+        let locked = GitRef::new(git.url, GitAttributes {
+            rev: Some("1e684b371cf05300bc2b432f958f285855bac8fb".parse().unwrap()),
+            ..Default::default()
+        });
+        assert!(FlakeRef::from(locked).is_fetchable_without_nix());
+
+        let indirect = FlakeRef::Indirect(IndirectRef::new("nixpkgs".into(), Default::default()));
+        assert!(!indirect.is_fetchable_without_nix());
+    }
+
+    #[test]
+    fn rev_short() {
+        let pinned = FlakeRef::Github(
+            GitServiceRef::from_str("github:flox/runix/0630fc9307852b30ea4c5915b6b74fa9db51d641")
+                .unwrap(),
+        );
+        assert_eq!(pinned.rev_short(), Some("0630fc9"));
+
+        let unpinned = FlakeRef::Github(GitServiceRef::from_str("github:flox/runix").unwrap());
+        assert_eq!(unpinned.rev_short(), None);
+
+        let short = FlakeRef::Indirect(IndirectRef::from_pairs("nixpkgs", [(
+            "rev".to_string(),
+            "abc".to_string(),
+        )]));
+        assert_eq!(short.rev_short(), Some("abc"));
+    }
+
+    #[test]
+    fn last_modified_rfc3339() {
+        let github = FlakeRef::Github(
+            GitServiceRef::from_str("github:flox/runix?lastModified=1688730350").unwrap(),
+        );
+        assert_eq!(
+            github.last_modified_rfc3339().as_deref(),
+            Some("2023-07-07T11:45:50Z")
+        );
+
+        let indirect = FlakeRef::Indirect(IndirectRef::from_pairs("nixpkgs", [(
+            "lastModified".to_string(),
+            "0".to_string(),
+        )]));
+        assert_eq!(
+            indirect.last_modified_rfc3339().as_deref(),
+            Some("1970-01-01T00:00:00Z")
+        );
+
+        let unlocked = FlakeRef::Github(GitServiceRef::from_str("github:flox/runix").unwrap());
+        assert_eq!(unlocked.last_modified_rfc3339(), None);
+    }
+
+    #[test]
+    fn infers_type_from_url() {
+        let cases = [
+            ("/home/user/flake", Some(FlakeRefKind::Path)),
+            ("./flake", Some(FlakeRefKind::Path)),
+            ("path:/home/user/flake", Some(FlakeRefKind::Path)),
+            ("nixpkgs", Some(FlakeRefKind::Indirect)),
+            ("nixpkgs/nixos-23.05", Some(FlakeRefKind::Indirect)),
+            ("flake:nixpkgs", Some(FlakeRefKind::Indirect)),
+            ("github:flox/runix", Some(FlakeRefKind::Github)),
+            ("https://github.com/flox/runix", Some(FlakeRefKind::Github)),
+            ("https://gitlab.com/flox/runix/", Some(FlakeRefKind::Gitlab)),
+            ("git+https://github.com/flox/runix", Some(FlakeRefKind::Git)),
+            ("ssh://git@example.com/runix.git", Some(FlakeRefKind::Git)),
+            ("git@github.com:flox/runix.git", Some(FlakeRefKind::Git)),
+            ("https://example.com/runix.git", Some(FlakeRefKind::Git)),
+            (
+                "https://example.com/runix.tar.gz",
+                Some(FlakeRefKind::Tarball),
+            ),
+            (
+                "tarball+https://example.com/runix",
+                Some(FlakeRefKind::Tarball),
+            ),
+            (
+                "file+https://example.com/runix.tar.gz",
+                Some(FlakeRefKind::File),
+            ),
+            ("https://example.com/runix", None),
+            ("https://github.com/flox/runix/tree/main", None),
+            ("hg+https://example.com/runix", None),
+            ("ftp://example.com/runix", None),
+            ("not a flake ref", None),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(FlakeRef::infer_type_from_url(url), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn from_cargo_git_dep() {
+        let rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641";
+        let cases = [
+            (
+                "https://github.com/flox/runix",
+                "github:flox/runix/0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+            (
+                "https://github.com/flox/runix.git",
+                "github:flox/runix/0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+            (
+                "https://gitlab.com/flox/runix/",
+                "gitlab:flox/runix/0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+            (
+                "https://gitlab.com/group/subgroup/repo.git",
+                "git+https://gitlab.com/group/subgroup/repo?rev=0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+            (
+                "ssh://git@github.com/flox/runix.git",
+                "git+ssh://git@github.com/flox/runix?rev=0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+            (
+                "git@github.com:flox/runix.git",
+                "git+ssh://git@github.com/flox/runix?rev=0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+            (
+                "https://example.com/runix.git#0630fc9307852b30ea4c5915b6b74fa9db51d641",
+                "git+https://example.com/runix?rev=0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            ),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(
+                FlakeRef::from_cargo_git_dep(url, rev).unwrap().to_string(),
+                expected,
+                "{url}"
+            );
+        }
+
+        assert!(matches!(
+            FlakeRef::from_cargo_git_dep("https://github.com/flox/runix", "0630fc9"),
+            Err(ParseFlakeRefError::Rev(_))
+        ));
+        assert!(matches!(
+            FlakeRef::from_cargo_git_dep("ftp://example.com/runix", rev),
+            Err(ParseFlakeRefError::CargoGitProtocol(_))
+        ));
+    }
+
+    #[test]
+    fn from_flake_nix_input() {
+        let rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641";
+        let cases = [
+            (
+                json!({"url": "github:NixOS/nixpkgs/nixos-23.11"}),
+                "github:NixOS/nixpkgs/nixos-23.11",
+            ),
+            (
+                json!({"url": "github:flox/runix", "flake": false, "inputs": {"nixpkgs": {"follows": "nixpkgs"}}}),
+                "github:flox/runix",
+            ),
+            (
+                json!({"url": "github:NixOS/nixpkgs", "dir": "lib"}),
+                "github:NixOS/nixpkgs?dir=lib",
+            ),
+            (
+                json!({"type": "gitlab", "owner": "flox", "repo": "runix", "rev": rev}),
+                &format!("gitlab:flox/runix/{rev}"),
+            ),
+            (
+                json!({"type": "git", "url": "https://example.com/repo", "ref": "main", "submodules": true}),
+                "git+https://example.com/repo?ref=main&submodules=1",
+            ),
+            (
+                json!({"type": "path", "path": "/some/where"}),
+                "path:/some/where",
+            ),
+            (
+                json!({"type": "indirect", "id": "nixpkgs", "ref": "main"}),
+                "flake:nixpkgs/main",
+            ),
+            (json!({"url": "./sub"}), "path:./sub"),
+            (
+                json!({"url": "nixpkgs/nixos-23.11"}),
+                "flake:nixpkgs/nixos-23.11",
+            ),
+            (
+                json!({"url": "git+ssh://git@github.com/flox/runix"}),
+                "git+ssh://git@github.com/flox/runix",
+            ),
+            (
+                json!({"url": "https://example.com/src.tar.gz"}),
+                "https://example.com/src.tar.gz",
+            ),
+            (
+                json!({"inputs": {"nixpkgs": {"follows": "nixpkgs"}}}),
+                "flake:runix",
+            ),
+            (json!({}), "flake:runix"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                FlakeRef::from_flake_nix_input("runix", &input)
+                    .unwrap_or_else(|e| panic!("{input}: {e}"))
+                    .to_string(),
+                expected,
+                "{input}"
+            );
+        }
+
+        for input in [
+            json!("github:flox/runix"),
+            json!({"url": 1}),
+            json!({"follows": "nixpkgs"}),
+        ] {
+            let err = FlakeRef::from_flake_nix_input("runix", &input).unwrap_err();
+            assert!(
+                matches!(&err, ParseFlakeRefError::Input { name, .. } if name == "runix"),
+                "{input}: {err:?}"
+            );
+        }
+        assert!(matches!(
+            FlakeRef::from_flake_nix_input("runix", &json!({"type": "github", "owner": "flox"})),
+            Err(ParseFlakeRefError::Attributes(
+                UrlParseError::MissingAttribute("repo")
+            ))
+        ));
+        assert!(matches!(
+            FlakeRef::from_flake_nix_input("runix", &json!({"url": "hg+https://example.com/repo"})),
+            Err(ParseFlakeRefError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn to_env_var_form() {
+        let path = FlakeRef::Path(PathRef::new(
+            "/nix/store/5hr3iyanlxmlylqbi1rp2kdgn9ja3lqi-source".into(),
+            PathAttributes {
+                nar_hash: Some("sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp/bRB3C2M=".into()),
+                ..Default::default()
+            },
+        ));
+        assert_eq!(
+            path.to_env_var_form("nixpkgs"),
+            "nixpkgs=/nix/store/5hr3iyanlxmlylqbi1rp2kdgn9ja3lqi-source"
+        );
+
+        let github =
+            FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs/nixos-23.11").unwrap());
+        assert_eq!(
+            github.to_env_var_form("nixpkgs"),
+            "nixpkgs=github:NixOS/nixpkgs/nixos-23.11"
+        );
+
+        let tarball = FlakeRef::TarballHTTPS(
+            TarballRef::from_str("https://example.com/nixpkgs.tar.gz").unwrap(),
+        );
+        assert_eq!(
+            tarball.to_env_var_form("nixpkgs"),
+            "nixpkgs=https://example.com/nixpkgs.tar.gz"
+        );
+    }
+
+    #[test]
+    fn to_ci_env_vars() {
+        let github =
+            FlakeRef::Github(GitServiceRef::from_str("github:flox/runix/main?dir=crates").unwrap());
+        assert_eq!(
+            github.to_ci_env_vars("RUNIX"),
+            HashMap::from(
+                [
+                    ("RUNIX_FLAKE_REF", "github:flox/runix/main?dir=crates"),
+                    ("RUNIX_OWNER", "flox"),
+                    ("RUNIX_REPO", "runix"),
+                    ("RUNIX_REF", "main"),
+                ]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+            )
+        );
+
+        let rev = "7b5e1a0c3e5bbd1ea2c5a24c2cd4e2b4be2e6c2b";
+        let git = FlakeRef::GitHttps(
+            GitRef::from_str(&format!("git+https://example.com/runix.git?rev={rev}")).unwrap(),
+        );
+        let vars = git.to_ci_env_vars("SRC");
+        assert_eq!(vars["SRC_REV"], rev);
+        assert_eq!(vars["SRC_FLAKE_REF"], git.to_string());
+        assert_eq!(vars.len(), 2);
+    }
+
+    #[test]
+    fn from_env_var_form() {
+        let (name, path) = FlakeRef::from_env_var_form(
+            "nixpkgs=/nix/store/5hr3iyanlxmlylqbi1rp2kdgn9ja3lqi-source",
+        )
+        .unwrap();
+        assert_eq!(name, "nixpkgs");
+        assert_eq!(
+            path,
+            FlakeRef::Path(PathRef::new(
+                "/nix/store/5hr3iyanlxmlylqbi1rp2kdgn9ja3lqi-source".into(),
+                Default::default()
+            ))
+        );
+        assert_eq!(
+            path.to_env_var_form(&name),
+            "nixpkgs=/nix/store/5hr3iyanlxmlylqbi1rp2kdgn9ja3lqi-source"
+        );
+
+        let (_, github) = FlakeRef::from_env_var_form("nixpkgs=github:NixOS/nixpkgs").unwrap();
+        assert_eq!(
+            github,
+            FlakeRef::Github(GitServiceRef::new(
+                "NixOS".to_string(),
+                "nixpkgs".to_string(),
+                Default::default()
+            ))
+        );
+
+        let (_, indirect) = FlakeRef::from_env_var_form("nixpkgs=flake:nixpkgs").unwrap();
+        assert!(matches!(indirect, FlakeRef::Indirect(_)));
+        assert_eq!(indirect.to_env_var_form("nixpkgs"), "nixpkgs=flake:nixpkgs");
+
+        assert!(matches!(
+            FlakeRef::from_env_var_form("/nix/store/5hr3iyanlxmlylqbi1rp2kdgn9ja3lqi-source"),
+            Err(ParseFlakeRefError::EnvVarForm(_))
+        ));
+        assert!(matches!(
+            FlakeRef::from_env_var_form("=github:NixOS/nixpkgs"),
+            Err(ParseFlakeRefError::EnvVarForm(_))
+        ));
+    }
+
+    #[test]
+    fn to_flake_nix_input() {
+        let nar_hash = "sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D";
+        let github = FlakeRef::Github(
+            GitServiceRef::from_str(&format!(
+                "github:NixOS/nixpkgs/nixos-23.11?dir=lib&narHash={nar_hash}"
+            ))
+            .unwrap(),
+        );
+        assert_eq!(
+            github.to_flake_nix_input("nixpkgs"),
+            format!(
+                r#"inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-23.11?dir=lib&narHash={nar_hash}";"#
+            )
+        );
+
+        let tarball = FlakeRef::TarballHTTPS(
+            TarballRef::from_str(&format!(
+                "https://example.com/src.tar.gz?narHash={nar_hash}"
+            ))
+            .unwrap(),
+        );
+        assert_eq!(
+            tarball.to_flake_nix_input("my.src"),
+            format!(
+                r#"inputs."my.src".url = "https://example.com/src.tar.gz?narHash={nar_hash}";"#
+            )
+        );
+
+        // a url would end at `#` and `?`
+        let path = FlakeRef::Path(PathRef {
+            path: "/srv/${x}/a#b?c".into(),
+            attributes: PathAttributes {
+                nar_hash: Some("sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp/bRB3C2M=".into()),
+                last_modified: Some(Timestamp(Utc.timestamp_opt(1666570118, 0).unwrap())),
+                ..Default::default()
+            },
+        });
+        assert_eq!(
+            path.to_flake_nix_input("src"),
+            r#"inputs.src = { type = "path"; lastModified = 1666570118; narHash = "sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp/bRB3C2M="; path = "/srv/\${x}/a#b?c"; };"#
+        );
+
+        for flake_ref in [github, tarball] {
+            let url = flake_ref.to_string();
+            assert_eq!(
+                FlakeRef::from_flake_nix_input("src", &json!({ "url": url })).unwrap(),
+                flake_ref
+            );
+        }
+        let attrs = serde_json::to_value(&path).unwrap();
+        assert_eq!(FlakeRef::from_flake_nix_input("src", &attrs).unwrap(), path);
+    }
+
+    #[test]
+    fn with_ref_rev_dir() {
+        let github = FlakeRef::Github(GitServiceRef::from_str("github:o/r").unwrap());
+        assert_eq!(
+            github.clone().with_ref("main").unwrap().to_string(),
+            "github:o/r/main"
+        );
+
+        let rev: lock::Rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641".parse().unwrap();
+        let git = FlakeRef::GitHttps(GitRef::from_str("git+https://example.com/r").unwrap())
+            .with_ref("main")
+            .and_then(|r| r.with_rev(rev.clone()))
+            .and_then(|r| r.with_dir("sub"))
+            .unwrap();
+        assert_eq!(git.rev(), Some(rev.as_str()));
+        assert_eq!(
+            GitRef::<protocol::HTTPS>::from_str(&git.to_string()).unwrap(),
+            GitRef::from_str(
+                "git+https://example.com/r?dir=sub&ref=main&rev=0630fc9307852b30ea4c5915b6b74fa9db51d641"
+            )
+            .unwrap()
+        );
+
+        let indirect = FlakeRef::Indirect(IndirectRef::from_pairs("nixpkgs", []))
+            .with_ref("unstable")
+            .unwrap();
+        assert_eq!(indirect.to_string(), "flake:nixpkgs/unstable");
+
+        let tarball =
+            FlakeRef::TarballHTTPS(TarballRef::from_str("https://example.com/r.tar.gz").unwrap());
+        assert_eq!(tarball.with_rev(rev), None);
+    }
+
+    #[test]
+    fn with_host() {
+        let gitlab = FlakeRef::Gitlab(GitServiceRef::from_str("gitlab:o/r").unwrap());
+        assert_eq!(gitlab.host(), None);
+
+        let gitlab = gitlab.with_host("gitlab.example.com");
+        assert_eq!(gitlab.host(), Some("gitlab.example.com"));
+        assert_eq!(
+            gitlab,
+            FlakeRef::Gitlab(
+                GitServiceRef::from_str("gitlab:o/r?host=gitlab.example.com").unwrap()
+            )
+        );
+
+        let tarball =
+            FlakeRef::TarballHTTPS(TarballRef::from_str("https://example.com/r.tar.gz").unwrap());
+        assert_eq!(tarball.clone().with_host("example.org"), tarball);
+        assert_eq!(tarball.host(), None);
+    }
+
+    #[test]
+    fn locked_and_pinned() {
+        let rev: lock::Rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641".parse().unwrap();
+        let nar_hash = "sha256-Gzcv5BkK4SIQVbxqMLxIBbJJcC0k6nGjgfve0X5lSzw=".to_string();
+
+        let github = FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs").unwrap());
+        assert!(!github.is_locked());
+        assert!(!github.is_pinned());
+
+        let hashed = github.clone().with_nar_hash(nar_hash.clone());
+        assert!(!hashed.is_locked());
+        assert!(!hashed.is_pinned());
+
+        let locked = github.with_rev(rev).unwrap();
+        assert!(locked.is_locked());
+        assert!(!locked.is_pinned());
+
+        let pinned = locked.with_nar_hash(nar_hash.clone());
+        assert!(pinned.is_locked());
+        assert!(pinned.is_pinned());
+
+        let tarball =
+            FlakeRef::TarballHTTPS(TarballRef::from_str("https://example.com/r.tar.gz").unwrap())
+                .with_nar_hash(nar_hash);
+        assert!(!tarball.is_pinned());
+    }
+
+    #[test]
+    fn verifies_nar_hash() {
+        let nar_hash = "sha256-Gzcv5BkK4SIQVbxqMLxIBbJJcC0k6nGjgfve0X5lSzw=".to_string();
+        let other = "sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4=".to_string();
+
+        let github = FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs").unwrap());
+        assert_eq!(
+            github.verify_nar_hash(&nar_hash),
+            Err(VerificationError::Missing)
+        );
+
+        let github = github.with_nar_hash(nar_hash.clone());
+        assert_eq!(github.verify_nar_hash(&nar_hash), Ok(()));
+        assert_eq!(
+            github.verify_nar_hash(&other),
+            Err(VerificationError::HashMismatch {
+                expected: nar_hash,
+                found: other
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_ref_rev() {
+        let rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641";
+
+        let err =
+            FlakeRef::from_str(&format!("github:NixOS/nixpkgs/nixos-23.11?rev={rev}")).unwrap_err();
+        assert!(
+            matches!(&err, UrlParseError::ConflictingRefRev { first, second }
+                if first == "path 'nixos-23.11'" && second == &format!("rev={rev}")),
+            "{err:?}"
+        );
+
+        let err =
+            FlakeRef::from_str(&format!("gitlab:o/r?ref=main&rev={rev}&dir=sub")).unwrap_err();
+        assert!(
+            matches!(&err, UrlParseError::ConflictingRefRev { first, second }
+                if first == "ref=main" && second == &format!("rev={rev}")),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn rejects_blank_urls() {
+        // `parser-util` is never called for these
+        let err = FlakeRef::from_url("", "/does/not/exist").unwrap_err();
+        assert!(matches!(err, UrlParseError::EmptyInput), "{err:?}");
+
+        for url in [" ", "\t\n", "\u{a0}"] {
+            let err = FlakeRef::from_url(url, "/does/not/exist").unwrap_err();
+            assert!(
+                matches!(err, UrlParseError::WhitespaceOnly),
+                "{url:?}: {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_single_ref_or_rev() {
+        let rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641";
+        for url in [
+            "github:NixOS/nixpkgs?dir=lib".to_string(),
+            "github:NixOS/nixpkgs/nixos-23.11?dir=lib".to_string(),
+            format!("github:NixOS/nixpkgs/{rev}"),
+            format!("github:NixOS/nixpkgs?rev={rev}"),
+            "github:NixOS/nixpkgs?ref=nixos-23.11".to_string(),
+        ] {
+            let parsed = Url::parse(&url).unwrap();
+            assert_eq!(git_service::conflicting_ref_rev(&parsed), None, "{url}");
+            GitServiceRef::<service::Github>::from_str(&url).unwrap();
+        }
+    }
+
+    #[test]
+    fn compact_string() {
+        let github = FlakeRef::Github(
+            GitServiceRef::from_str(
+                "github:NixOS/nixpkgs/0630fc9307852b30ea4c5915b6b74fa9db51d641?narHash=sha256-Gzcv5BkK4SIQVbxqMLxIBbJJcC0k6nGjgfve0X5lSzw%3D&lastModified=1688730350",
+            )
+            .unwrap(),
+        );
+        assert_eq!(github.to_compact_string(), "github:NixOS/nixpkgs@0630fc9");
+
+        let unpinned =
+            FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs/unstable").unwrap());
+        assert_eq!(unpinned.to_compact_string(), "github:NixOS/nixpkgs");
+
+        let git = FlakeRef::GitHttps(
+            GitRef::from_str("git+https://example.com/repo?ref=main&rev=1e684b371cf05300bc2b432f958f285855bac8fb&shallow=1").unwrap(),
+        );
+        assert_eq!(
+            git.to_compact_string(),
+            "git+https://example.com/repo@1e684b3"
+        );
+
+        let tarball = FlakeRef::TarballHTTPS(
+            TarballRef::from_str("https://example.com/source.tar.gz?narHash=sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4%3D").unwrap(),
+        );
+        assert_eq!(
+            tarball.to_compact_string(),
+            "https://example.com/source.tar.gz"
+        );
+
+        let indirect = FlakeRef::Indirect(IndirectRef::from_pairs("nixpkgs", []));
+        assert_eq!(indirect.to_compact_string(), "flake:nixpkgs");
+
+        let pinned = FlakeRef::Indirect(
+            IndirectRef::from_str(
+                "flake:nixpkgs/nixos-23.05/0630fc9307852b30ea4c5915b6b74fa9db51d641?dir=lib",
+            )
+            .unwrap(),
+        );
+        assert_eq!(pinned.to_compact_string(), "flake:nixpkgs@0630fc9");
+    }
+
+    #[test]
+    fn github_compare_url() {
+        let github = |s: &str| FlakeRef::Github(GitServiceRef::from_str(s).unwrap());
+        let old = github("github:flox/runix/0630fc9307852b30ea4c5915b6b74fa9db51d641");
+        let new = github("github:flox/runix/50500a744e3c2af9d89123ae17b71406b428c3ab");
+
+        assert_eq!(
+            old.to_github_compare_url(&new).unwrap().as_str(),
+            "https://github.com/flox/runix/compare/0630fc9307852b30ea4c5915b6b74fa9db51d641...50500a744e3c2af9d89123ae17b71406b428c3ab"
+        );
+
+        let other_repo = github("github:flox/nixpkgs/50500a744e3c2af9d89123ae17b71406b428c3ab");
+        assert_eq!(old.to_github_compare_url(&other_repo), None);
+
+        let unlocked = github("github:flox/runix/main");
+        assert_eq!(old.to_github_compare_url(&unlocked), None);
+        assert_eq!(unlocked.to_github_compare_url(&new), None);
+    }
+
+    #[test]
+    fn git_url() {
+        let github = FlakeRef::Github("github:flox/runix/main".parse().unwrap());
+        assert_eq!(
+            github.git_url().unwrap().as_str(),
+            "https://github.com/flox/runix.git"
+        );
+
+        let gitlab = FlakeRef::Gitlab("gitlab:flox/runix?host=gitlab.example.com".parse().unwrap());
+        assert_eq!(
+            gitlab.git_url().unwrap().as_str(),
+            "https://gitlab.example.com/flox/runix.git"
+        );
+
+        let ssh = FlakeRef::GitSsh(
+            "git+ssh://git@example.com/flox/runix.git?ref=main&dir=sub"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            ssh.git_url().unwrap().as_str(),
+            "ssh://git@example.com/flox/runix.git"
+        );
+
+        let indirect = FlakeRef::Indirect("flake:nixpkgs".parse().unwrap());
+        assert_eq!(indirect.git_url(), None);
+    }
+
+    #[test]
+    fn narinfo_url() {
+        let flake_ref = FlakeRef::Path(PathRef {
+            path: "/nix/store/083m43hjhry94cvfmqdv7kjpvsl3zzvi-source".into(),
+            attributes: PathAttributes {
+                nar_hash: Some("sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4=".into()),
+                ..Default::default()
+            },
+        });
+
+        for substituter in ["https://cache.nixos.org", "https://cache.nixos.org/"] {
+            assert_eq!(
+                flake_ref
+                    .narinfo_url(&substituter.parse().unwrap())
+                    .unwrap()
+                    .as_str(),
+                "https://cache.nixos.org/083m43hjhry94cvfmqdv7kjpvsl3zzvi.narinfo"
+            );
+        }
+        assert_eq!(
+            flake_ref
+                .narinfo_url(&"https://example.com/cache".parse().unwrap())
+                .unwrap()
+                .as_str(),
+            "https://example.com/cache/083m43hjhry94cvfmqdv7kjpvsl3zzvi.narinfo"
+        );
+
+        let unlocked = FlakeRef::Indirect(IndirectRef::new("nixpkgs".into(), Default::default()));
+        assert_eq!(
+            unlocked.narinfo_url(&"https://cache.nixos.org".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn nix_store_url() {
+        let flake_ref = FlakeRef::Github(
+            GitServiceRef::from_str(
+                "github:NixOS/nixpkgs/0630fc9307852b30ea4c5915b6b74fa9db51d641?dir=lib&narHash=sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4%3D",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            flake_ref.to_nix_store_url().unwrap().as_str(),
+            "nix-store:///nix/store/083m43hjhry94cvfmqdv7kjpvsl3zzvi-source"
+        );
+
+        let unlocked = FlakeRef::Indirect(IndirectRef::new("nixpkgs".into(), Default::default()));
+        assert_eq!(unlocked.to_nix_store_url(), None);
+    }
+
+    #[test]
+    fn eq_ignoring_attributes() {
+        let github = |query: &str| {
+            FlakeRef::Github(
+                GitServiceRef::from_str(&format!(
+                    "github:NixOS/nixpkgs/0630fc9307852b30ea4c5915b6b74fa9db51d641?{query}"
+                ))
+                .unwrap(),
+            )
+        };
+        let metadata = ["lastModified", "narHash"];
+        let locked = github(
+            "lastModified=1687000000&narHash=sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4%3D",
+        );
+        let relocked = github(
+            "lastModified=1688000000&narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D",
+        );
+        assert_ne!(locked, relocked);
+        assert!(locked.eq_ignoring_attributes(&metadata, &relocked));
+        assert!(locked.eq_ignoring_attributes(&metadata, &github("")));
+        assert!(!locked.eq_ignoring_attributes(&["lastModified"], &relocked));
+        assert!(!locked.eq_ignoring_attributes(&metadata, &github("dir=lib")));
+
+        let gitlab = FlakeRef::Gitlab(
+            GitServiceRef::from_str(
+                "gitlab:NixOS/nixpkgs/0630fc9307852b30ea4c5915b6b74fa9db51d641",
+            )
+            .unwrap(),
+        );
+        assert!(!gitlab.eq_ignoring_attributes(&metadata, &github("")));
+        assert!(gitlab.eq_ignoring_attributes(&["type"], &github("")));
+    }
+
+    #[tokio::test]
+    async fn builds_store_path() {
+        let backend = MockBackend::default();
+        backend.expect::<Build>(MockResponse::json(&json!([{
+            "drvPath": "/nix/store/5zjs0p2yv9ynkfsxv1kkl1bjnclxwgw6-python3-3.10.10.drv",
+            "outputs": {
+                "dev": "/nix/store/2ldr1f3fi00dq0mlwi9n9k3zwq3z8gxm-python3-3.10.10-dev",
+                "out": "/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10",
+            },
+        }])));
+
+        let flake_ref = FlakeRef::Indirect(IndirectRef::new("nixpkgs".into(), Default::default()));
+        assert_eq!(
+            flake_ref
+                .to_store_path(&backend, &NixArgs::default())
+                .await
+                .unwrap(),
+            StorePath::from_path("/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10")
+                .unwrap()
+        );
+        assert_eq!(backend.invocations()[0].args, [
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "build",
+            "--json",
+            "flake:nixpkgs",
+            "--no-link"
+        ]);
+
+        backend.expect::<Build>(
+            MockResponse::exit(1).with_stderr("error: cannot find flake 'flake:other'\n"),
+        );
+        let flake_ref = FlakeRef::Indirect(IndirectRef::new("other".into(), Default::default()));
+        assert!(matches!(
+            flake_ref.to_store_path(&backend, &NixArgs::default()).await,
+            Err(StorePathError::NixBuild(_))
+        ));
+    }
 }
@@ -21,7 +21,7 @@ use crate::url_parser::{
 
 /// <https://cs.github.com/NixOS/nix/blob/f225f4307662fe9a57543d0c86c28aa9fddaf0d2/src/libfetchers/path.cc#L46>
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
-#[serde(tag = "path")]
+#[serde(tag = "type", rename = "path")]
 pub struct PathRef {
     pub path: PathBuf,
     #[serde(flatten)]
@@ -68,6 +68,16 @@ impl<P: Protocol> TryFrom<Url> for WrappedUrl<P> {
     }
 }
 
+impl<P> WrappedUrl<P> {
+    /// Set the query of the inner url
+    ///
+    /// Queries are dropped when wrapping a url,
+    /// as they usually carry flake attributes rather than belong to the url.
+    pub(crate) fn set_query(&mut self, query: Option<&str>) {
+        self.inner.set_query(query)
+    }
+}
+
 impl<P: Protocol> FromStr for WrappedUrl<P> {
     type Err = WrappedUrlParseError;
 
@@ -0,0 +1,242 @@
+//! A representation of [FlakeRef] for formats that are not self-describing
+//!
+//! The derived serde implementation of [FlakeRef] is untagged
+//! and flattens the attributes of each kind of ref into it.
+//! Deserializing it relies on the format describing its own structure,
+//! which holds for JSON and MessagePack (`rmp-serde`), but not e.g. for `bincode`.
+//!
+//! This module serializes a [FlakeRef] as an externally tagged enum
+//! holding the url of the ref instead, which every serde format supports:
+//!
+//! ```
+//! # use runix::flake_ref::FlakeRef;
+//! # use serde::{Deserialize, Serialize};
+//! #[derive(Serialize, Deserialize)]
+//! struct Message {
+//!     #[serde(with = "runix::flake_ref::tagged")]
+//!     flake_ref: FlakeRef,
+//! }
+//! ```
+//!
+//! Urls are parsed by the type of the tagged variant, without calling `parser-util`.
+//! Formats identifying variants by their index (e.g. `bincode`) stay compatible
+//! as long as variants are only ever added to the end of [Tagged].
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::file::{FileRef, TarballRef};
+use super::git::GitRef;
+use super::git_service::GitServiceRef;
+use super::indirect::IndirectRef;
+#[cfg(feature = "ipfs")]
+use super::ipfs::IpfsRef;
+use super::path::PathRef;
+use super::FlakeRef;
+
+/// The kind of a [FlakeRef] and its url
+#[derive(Serialize, Deserialize)]
+enum Tagged {
+    FileFile(String),
+    FileHTTP(String),
+    FileHTTPS(String),
+    TarballFile(String),
+    TarballHTTP(String),
+    TarballHTTPS(String),
+    Github(String),
+    Gitlab(String),
+    Path(String),
+    GitPath(String),
+    GitSsh(String),
+    GitHttps(String),
+    GitHttp(String),
+    Indirect(String),
+    #[cfg(feature = "ipfs")]
+    Ipfs(String),
+    #[cfg(feature = "ipfs")]
+    Ipns(String),
+}
+
+/// Serialize `flake_ref` as its kind and url
+pub fn serialize<S: Serializer>(flake_ref: &FlakeRef, serializer: S) -> Result<S::Ok, S::Error> {
+    let url = flake_ref.to_string();
+    let tagged = match flake_ref {
+        FlakeRef::FileFile(_) => Tagged::FileFile(url),
+        FlakeRef::FileHTTP(_) => Tagged::FileHTTP(url),
+        FlakeRef::FileHTTPS(_) => Tagged::FileHTTPS(url),
+        FlakeRef::TarballFile(_) => Tagged::TarballFile(url),
+        FlakeRef::TarballHTTP(_) => Tagged::TarballHTTP(url),
+        FlakeRef::TarballHTTPS(_) => Tagged::TarballHTTPS(url),
+        FlakeRef::Github(_) => Tagged::Github(url),
+        FlakeRef::Gitlab(_) => Tagged::Gitlab(url),
+        FlakeRef::Path(_) => Tagged::Path(url),
+        FlakeRef::GitPath(_) => Tagged::GitPath(url),
+        FlakeRef::GitSsh(_) => Tagged::GitSsh(url),
+        FlakeRef::GitHttps(_) => Tagged::GitHttps(url),
+        FlakeRef::GitHttp(_) => Tagged::GitHttp(url),
+        FlakeRef::Indirect(_) => Tagged::Indirect(url),
+        #[cfg(feature = "ipfs")]
+        FlakeRef::Ipfs(_) => Tagged::Ipfs(url),
+        #[cfg(feature = "ipfs")]
+        FlakeRef::Ipns(_) => Tagged::Ipns(url),
+    };
+    tagged.serialize(serializer)
+}
+
+/// Deserialize a [FlakeRef] serialized by [serialize]
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FlakeRef, D::Error> {
+    fn parse<T, E>(url: &str) -> Result<T, E>
+    where
+        T: FromStr,
+        T::Err: Display,
+        E: Error,
+    {
+        url.parse()
+            .map_err(|err| E::custom(format!("invalid flake ref '{url}': {err}")))
+    }
+
+    let flake_ref = match Tagged::deserialize(deserializer)? {
+        Tagged::FileFile(url) => FlakeRef::FileFile(parse::<FileRef<_>, _>(&url)?),
+        Tagged::FileHTTP(url) => FlakeRef::FileHTTP(parse::<FileRef<_>, _>(&url)?),
+        Tagged::FileHTTPS(url) => FlakeRef::FileHTTPS(parse::<FileRef<_>, _>(&url)?),
+        Tagged::TarballFile(url) => FlakeRef::TarballFile(parse::<TarballRef<_>, _>(&url)?),
+        Tagged::TarballHTTP(url) => FlakeRef::TarballHTTP(parse::<TarballRef<_>, _>(&url)?),
+        Tagged::TarballHTTPS(url) => FlakeRef::TarballHTTPS(parse::<TarballRef<_>, _>(&url)?),
+        Tagged::Github(url) => FlakeRef::Github(parse::<GitServiceRef<_>, _>(&url)?),
+        Tagged::Gitlab(url) => FlakeRef::Gitlab(parse::<GitServiceRef<_>, _>(&url)?),
+        Tagged::Path(url) => FlakeRef::Path(parse::<PathRef, _>(&url)?),
+        Tagged::GitPath(url) => FlakeRef::GitPath(parse::<GitRef<_>, _>(&url)?),
+        Tagged::GitSsh(url) => FlakeRef::GitSsh(parse::<GitRef<_>, _>(&url)?),
+        Tagged::GitHttps(url) => FlakeRef::GitHttps(parse::<GitRef<_>, _>(&url)?),
+        Tagged::GitHttp(url) => FlakeRef::GitHttp(parse::<GitRef<_>, _>(&url)?),
+        Tagged::Indirect(url) => FlakeRef::Indirect(parse::<IndirectRef, _>(&url)?),
+        #[cfg(feature = "ipfs")]
+        Tagged::Ipfs(url) => FlakeRef::Ipfs(parse::<IpfsRef<_>, _>(&url)?),
+        #[cfg(feature = "ipfs")]
+        Tagged::Ipns(url) => FlakeRef::Ipns(parse::<IpfsRef<_>, _>(&url)?),
+    };
+    Ok(flake_ref)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flake_ref::protocol;
+
+    /// A ref of every kind, with attributes
+    fn all_kinds() -> Vec<FlakeRef> {
+        let rev = "rev=1e684b371cf05300bc2b432f958f285855bac8fb";
+        let nar_hash = "narHash=sha256-MjeRjunqfGTBGU401nxIjs7PC9PZZ1FBCZp%2FbRB3C2M%3D";
+        vec![
+            FlakeRef::FileFile(
+                format!("file:///somewhere/there?{nar_hash}")
+                    .parse()
+                    .unwrap(),
+            ),
+            FlakeRef::FileHTTP("http://somewhere/there?unpack=1".parse().unwrap()),
+            FlakeRef::FileHTTPS("https://somewhere/there".parse().unwrap()),
+            FlakeRef::TarballFile("tarball+file:///somewhere/there".parse().unwrap()),
+            FlakeRef::TarballHTTP("tarball+http://somewhere/there".parse().unwrap()),
+            FlakeRef::TarballHTTPS(
+                format!("https://somewhere/there.tar.gz?{nar_hash}")
+                    .parse()
+                    .unwrap(),
+            ),
+            FlakeRef::Github(
+                "github:NixOS/nixpkgs/nixos-unstable?dir=lib"
+                    .parse()
+                    .unwrap(),
+            ),
+            FlakeRef::Gitlab(
+                format!("gitlab:flox/runix?{rev}&host=gitlab.example.com")
+                    .parse()
+                    .unwrap(),
+            ),
+            FlakeRef::Path("path:/can/be/missing".parse().unwrap()),
+            FlakeRef::GitPath(
+                GitRef::<protocol::File>::from_str(&format!("git+file:///var/www?ref=main&{rev}"))
+                    .unwrap(),
+            ),
+            FlakeRef::GitSsh(
+                "git+ssh://git@github.com/flox/runix?shallow=1"
+                    .parse()
+                    .unwrap(),
+            ),
+            FlakeRef::GitHttps("git+https://example.com/repo?ref=main".parse().unwrap()),
+            FlakeRef::GitHttp("git+http://example.com/repo".parse().unwrap()),
+            FlakeRef::Indirect("flake:nixpkgs/23.05?dir=lib".parse().unwrap()),
+        ]
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        #[serde(with = "crate::flake_ref::tagged")]
+        flake_ref: FlakeRef,
+    }
+
+    /// The derived implementation works with MessagePack,
+    /// whether structs are written as maps or as arrays
+    #[test]
+    fn derived_roundtrips_through_msgpack() {
+        for flake_ref in all_kinds() {
+            for bytes in [
+                rmp_serde::to_vec(&flake_ref).unwrap(),
+                rmp_serde::to_vec_named(&flake_ref).unwrap(),
+            ] {
+                let parsed: FlakeRef = rmp_serde::from_slice(&bytes)
+                    .unwrap_or_else(|e| panic!("'{flake_ref}' should roundtrip: {e}"));
+                assert_eq!(parsed, flake_ref);
+            }
+        }
+    }
+
+    /// `bincode` is not self-describing, and can only handle the tagged representation
+    #[test]
+    fn derived_fails_with_bincode() {
+        for flake_ref in all_kinds() {
+            let roundtrip = bincode::serialize(&flake_ref)
+                .and_then(|bytes| bincode::deserialize::<FlakeRef>(&bytes));
+            assert!(roundtrip.is_err(), "'{flake_ref}' should not roundtrip");
+        }
+    }
+
+    #[test]
+    fn tagged_roundtrips() {
+        for flake_ref in all_kinds() {
+            let message = Message { flake_ref };
+
+            let bytes = bincode::serialize(&message).unwrap();
+            assert_eq!(bincode::deserialize::<Message>(&bytes).unwrap(), message);
+
+            let bytes = rmp_serde::to_vec(&message).unwrap();
+            assert_eq!(rmp_serde::from_slice::<Message>(&bytes).unwrap(), message);
+
+            let json = serde_json::to_string(&message).unwrap();
+            assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn tagged_json() {
+        let message = Message {
+            flake_ref: FlakeRef::Indirect("flake:nixpkgs".parse().unwrap()),
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({"flake_ref": {"Indirect": "flake:nixpkgs"}})
+        );
+
+        let err = serde_json::from_value::<Message>(
+            serde_json::json!({"flake_ref": {"Github": "gitlab:flox/runix"}}),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("invalid flake ref 'gitlab:flox/runix'"),
+            "{err}"
+        );
+    }
+}
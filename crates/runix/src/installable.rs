@@ -26,7 +26,8 @@ static VALID_ATTRIBUTE: Lazy<Regex> =
 
 #[derive(Clone, Debug, Display, Eq, From, PartialEq)]
 pub enum Installable {
-    FlakeAttribute(FlakeAttribute),
+    #[from(ignore)]
+    FlakeAttribute(Box<FlakeAttribute>),
     StorePath(StorePath),
     // TODO Nix file and Nix expression
 }
@@ -37,6 +38,29 @@ pub struct FlakeAttribute {
     pub attr_path: AttrPath,
 }
 
+/// Refer to the default output of a flake, i.e. with an empty attr path
+impl From<FlakeRef> for FlakeAttribute {
+    fn from(flakeref: FlakeRef) -> Self {
+        FlakeAttribute {
+            flakeref,
+            attr_path: AttrPath::default(),
+        }
+    }
+}
+
+impl From<FlakeAttribute> for Installable {
+    fn from(attribute: FlakeAttribute) -> Self {
+        Installable::FlakeAttribute(Box::new(attribute))
+    }
+}
+
+/// Refer to the default output of a flake, i.e. with an empty attr path
+impl From<FlakeRef> for Installable {
+    fn from(flakeref: FlakeRef) -> Self {
+        FlakeAttribute::from(flakeref).into()
+    }
+}
+
 /// The attrpath component of an installable
 ///
 /// This implementation wraps a [Vec<String>] for components.
@@ -274,6 +298,7 @@ pub enum ParseInstallableError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flake_ref::indirect::IndirectRef;
 
     fn assert_parse_as(input: &str, expected: &str, description: &str) {
         let output = input.parse::<AttrPath>().expect(description);
@@ -327,4 +352,16 @@ mod tests {
             .expect_err("should not parse with interpolation in the front");
         AttrPath::try_from(["x.${asdf}", "c"]).expect_err("should not parse with dynamic element");
     }
+
+    #[test]
+    fn installable_from_flakeref() {
+        let flakeref = FlakeRef::Indirect(IndirectRef::new("nixpkgs".into(), Default::default()));
+        let installable = Installable::from(flakeref);
+
+        assert!(matches!(
+            installable,
+            Installable::FlakeAttribute(ref attribute) if attribute.attr_path.is_empty()
+        ));
+        assert_eq!(installable.to_string(), "flake:nixpkgs");
+    }
 }
@@ -0,0 +1,363 @@
+//! Structured logs as printed by nix with `--log-format internal-json`, see [parse_line]
+//!
+//! Nix describes what it is doing as nested activities (downloads, builds, copies),
+//! each started and stopped once and reporting results (progress, build log lines, phases)
+//! in between.
+//! See `JSONLogger` in <https://github.com/NixOS/nix/blob/2.18.1/src/libutil/logging.cc>
+//! and the activity and result types in
+//! <https://github.com/NixOS/nix/blob/2.18.1/src/libutil/logging.hh>.
+
+use serde::Deserialize;
+
+/// The prefix nix puts in front of every structured log line
+const PREFIX: &str = "@nix ";
+
+/// Identifies an activity across its [LogEvent]s, `0` is used for "no parent"
+pub type ActivityId = u64;
+
+/// A line nix printed to stderr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InternalLog {
+    /// A structured event
+    Event(LogEvent),
+    /// Anything else, e.g. output of the builder or lines logged before
+    /// the log format took effect
+    Raw(String),
+}
+
+/// Parse a line of `--log-format internal-json` output
+///
+/// Lines without the `@nix ` prefix, as well as events this module can not make sense of
+/// (e.g. of an action added by a later version of nix), are passed on as [InternalLog::Raw].
+///
+/// Lines are parsed as bytes, as nix does not ensure that builders print valid UTF-8.
+/// Invalid UTF-8 in raw lines is replaced with `U+FFFD`.
+pub fn parse_line(line: impl AsRef<[u8]>) -> InternalLog {
+    let line = line.as_ref();
+    line.strip_prefix(PREFIX.as_bytes())
+        .and_then(|json| serde_json::from_slice(json).ok())
+        .map_or_else(
+            || InternalLog::Raw(String::from_utf8_lossy(line).into_owned()),
+            InternalLog::Event,
+        )
+}
+
+/// A structured log event
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum LogEvent {
+    /// An activity started
+    Start {
+        #[serde(rename = "id")]
+        activity_id: ActivityId,
+        /// The activity this one is part of, `0` for none
+        parent: ActivityId,
+        level: Verbosity,
+        #[serde(rename = "type")]
+        activity_type: ActivityType,
+        /// A human readable description, may be empty
+        text: String,
+        /// Type specific details, e.g. the url of a [ActivityType::FileTransfer]
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+    /// An activity reported a result
+    Result {
+        #[serde(rename = "id")]
+        activity_id: ActivityId,
+        #[serde(rename = "type")]
+        result_type: ResultType,
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+    /// An activity finished
+    Stop {
+        #[serde(rename = "id")]
+        activity_id: ActivityId,
+    },
+    /// A message that is not associated with an activity, e.g. warnings or errors
+    Msg {
+        level: Verbosity,
+        #[serde(rename = "msg")]
+        text: String,
+    },
+}
+
+impl LogEvent {
+    /// The progress reported by a [ResultType::Progress] result
+    pub fn progress(&self) -> Option<Progress> {
+        let LogEvent::Result {
+            result_type: ResultType::Progress,
+            fields,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        match fields[..] {
+            [Field::Int(done), Field::Int(expected), Field::Int(running), Field::Int(failed)] => {
+                Some(Progress {
+                    done,
+                    expected,
+                    running,
+                    failed,
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Progress of an activity
+///
+/// Counted in bytes for transfers, in items (e.g. derivations) otherwise.
+/// `expected` is `0` if unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub done: u64,
+    pub expected: u64,
+    pub running: u64,
+    pub failed: u64,
+}
+
+/// A field of a [LogEvent::Start] or [LogEvent::Result]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Field {
+    Int(u64),
+    String(String),
+}
+
+/// The kind of an activity
+///
+/// Types this module does not know about are kept as [ActivityType::Other].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(from = "u64")]
+pub enum ActivityType {
+    Unknown,
+    CopyPath,
+    FileTransfer,
+    Realise,
+    CopyPaths,
+    Builds,
+    Build,
+    OptimiseStore,
+    VerifyPaths,
+    Substitute,
+    QueryPathInfo,
+    PostBuildHook,
+    BuildWaiting,
+    FetchTree,
+    Other(u64),
+}
+
+impl From<u64> for ActivityType {
+    fn from(value: u64) -> Self {
+        match value {
+            0 => ActivityType::Unknown,
+            100 => ActivityType::CopyPath,
+            101 => ActivityType::FileTransfer,
+            102 => ActivityType::Realise,
+            103 => ActivityType::CopyPaths,
+            104 => ActivityType::Builds,
+            105 => ActivityType::Build,
+            106 => ActivityType::OptimiseStore,
+            107 => ActivityType::VerifyPaths,
+            108 => ActivityType::Substitute,
+            109 => ActivityType::QueryPathInfo,
+            110 => ActivityType::PostBuildHook,
+            111 => ActivityType::BuildWaiting,
+            112 => ActivityType::FetchTree,
+            other => ActivityType::Other(other),
+        }
+    }
+}
+
+/// The kind of a [LogEvent::Result]
+///
+/// Types this module does not know about are kept as [ResultType::Other].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(from = "u64")]
+pub enum ResultType {
+    FileLinked,
+    /// A line printed by the builder, the only field
+    BuildLogLine,
+    UntrustedPath,
+    CorruptedPath,
+    /// The builder entered a phase, e.g. `buildPhase`
+    SetPhase,
+    /// See [Progress]
+    Progress,
+    SetExpected,
+    PostBuildLogLine,
+    FetchStatus,
+    Other(u64),
+}
+
+impl From<u64> for ResultType {
+    fn from(value: u64) -> Self {
+        match value {
+            100 => ResultType::FileLinked,
+            101 => ResultType::BuildLogLine,
+            102 => ResultType::UntrustedPath,
+            103 => ResultType::CorruptedPath,
+            104 => ResultType::SetPhase,
+            105 => ResultType::Progress,
+            106 => ResultType::SetExpected,
+            107 => ResultType::PostBuildLogLine,
+            108 => ResultType::FetchStatus,
+            other => ResultType::Other(other),
+        }
+    }
+}
+
+/// The level of a log event, from most to least important
+///
+/// Levels above [Verbosity::Vomit] are treated as such.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(from = "u64")]
+pub enum Verbosity {
+    Error,
+    Warn,
+    Notice,
+    Info,
+    Talkative,
+    Chatty,
+    Debug,
+    Vomit,
+}
+
+impl From<u64> for Verbosity {
+    fn from(value: u64) -> Self {
+        match value {
+            0 => Verbosity::Error,
+            1 => Verbosity::Warn,
+            2 => Verbosity::Notice,
+            3 => Verbosity::Info,
+            4 => Verbosity::Talkative,
+            5 => Verbosity::Chatty,
+            6 => Verbosity::Debug,
+            _ => Verbosity::Vomit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The events of a log, the fixtures are written after nix' sources, see `test/README.md`
+    fn events(stream: &str) -> Vec<LogEvent> {
+        stream
+            .lines()
+            .map(|line| match parse_line(line) {
+                InternalLog::Event(event) => event,
+                InternalLog::Raw(raw) => panic!("'{raw}' should parse"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_build() {
+        let events = events(include_str!("../test/internal-json-build.txt"));
+        assert_eq!(events.len(), 20);
+
+        let build_id = 53021371269123;
+        let drv = "/nix/store/qs8ywbkrf2p2ilbj3p3x0qc2n4jnrdmx-hello-2.12.1.drv";
+        assert!(events.contains(&LogEvent::Start {
+            activity_id: build_id,
+            parent: 0,
+            level: Verbosity::Info,
+            activity_type: ActivityType::Build,
+            text: format!("building '{drv}'"),
+            fields: vec![
+                Field::String(drv.to_string()),
+                Field::String(String::new()),
+                Field::Int(1),
+                Field::Int(1),
+            ],
+        }));
+
+        let phases = events
+            .iter()
+            .filter_map(|event| match event {
+                LogEvent::Result {
+                    activity_id,
+                    result_type: ResultType::SetPhase,
+                    fields,
+                } if *activity_id == build_id => Some(fields.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(phases, [
+            vec![Field::String("unpackPhase".to_string())],
+            vec![Field::String("buildPhase".to_string())],
+            vec![Field::String("installPhase".to_string())],
+        ]);
+
+        assert!(matches!(&events[2], LogEvent::Msg {
+            level: Verbosity::Warn,
+            text
+        } if text.ends_with("Git tree '/tmp/flake' is dirty")));
+
+        assert_eq!(events.last().unwrap(), &LogEvent::Stop {
+            activity_id: 53021371269121
+        });
+    }
+
+    #[test]
+    fn parses_download_progress() {
+        let events = events(include_str!("../test/internal-json-download.txt"));
+
+        assert!(matches!(events[0], LogEvent::Start {
+            activity_type: ActivityType::FetchTree,
+            ..
+        }));
+
+        let tarball_id = 53021371269122;
+        let progress = events
+            .iter()
+            .filter(|event| matches!(event, LogEvent::Result { activity_id, .. } if *activity_id == tarball_id))
+            .filter_map(LogEvent::progress)
+            .map(|progress| (progress.done, progress.expected))
+            .collect::<Vec<_>>();
+        assert_eq!(progress, [
+            (1048576, 0),
+            (16777216, 0),
+            (36370432, 36370432)
+        ]);
+    }
+
+    #[test]
+    fn keeps_unknown_types() {
+        let InternalLog::Event(start) = parse_line(
+            r#"@nix {"action":"start","id":1,"level":3,"parent":0,"text":"","type":199}"#,
+        ) else {
+            panic!("unknown activity types should parse");
+        };
+        assert!(matches!(start, LogEvent::Start {
+            activity_type: ActivityType::Other(199),
+            fields,
+            ..
+        } if fields.is_empty()));
+
+        let InternalLog::Event(result) =
+            parse_line(r#"@nix {"action":"result","fields":[],"id":1,"type":199}"#)
+        else {
+            panic!("unknown result types should parse");
+        };
+        assert_eq!(result.progress(), None);
+    }
+
+    #[test]
+    fn passes_other_lines_through() {
+        for line in [
+            "warning: unknown setting 'foo'",
+            "@nix not json",
+            r#"@nix {"action":"setPhase"}"#,
+            r#"{"action":"stop","id":1}"#,
+        ] {
+            assert_eq!(parse_line(line), InternalLog::Raw(line.to_string()));
+        }
+    }
+}
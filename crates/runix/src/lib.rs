@@ -8,6 +8,10 @@
 //!
 //! While this is the reference implmentation, other backends such as an
 //! FFI based implementation or Mocking shims for testing are possible.
+//! With the `test-util` feature, `command_line::mock::MockBackend` records
+//! the commands it runs and answers them with scripted output.
+//! With the `tracing` feature, every invocation of nix runs in a `nix` span
+//! of the [tracing](https://docs.rs/tracing) crate.
 //!
 //! > **Warning**
 //! > runix is still in active development!
@@ -137,10 +141,10 @@
 //!     const SUBCOMMAND: &'static [&'static str] = &["shell"];
 //!
 //!     // shell supports three groups of options and multiple installables
-//!     const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| d.eval.clone());
-//!     const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| d.flake.clone());
-//!     const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| d.installables.clone());
-//!     const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| d.source.clone());
+//!     const EVAL_ARGS: Group<Self, EvaluationArgs> = Some(|d| &d.eval);
+//!     const FLAKE_ARGS: Group<Self, FlakeArgs> = Some(|d| &d.flake);
+//!     const INSTALLABLES: Group<Self, InstallablesArgs> = Some(|d| &d.installables);
+//!     const SOURCE_ARGS: Group<Self, SourceArgs> = Some(|d| &d.source);
 //! }
 //! ```
 //! 
@@ -194,7 +198,6 @@
 //! we plan to approach native bindings to Nix commands and concepts.
 
 use std::error::Error;
-use std::path::PathBuf;
 
 /// Rust abstraction over the nix command line
 /// Candidate for a standalone library to build arbitrary Nix commands in a safe manner
@@ -204,17 +207,18 @@ use async_trait::async_trait;
 pub mod arguments;
 pub mod command;
 pub mod command_line;
+pub mod eval_trace;
 pub mod flake_metadata;
 pub mod flake_ref;
 pub mod installable;
+pub mod internal_log;
 pub mod narinfo;
+pub mod nix_error;
 pub mod registry;
 pub mod store_path;
+pub mod store_uri;
 pub mod url_parser;
 
-// TODO drop in favor of store_path::StorePath
-pub type DerivationPath = PathBuf;
-
 pub use command_line as default;
 use serde_json::Value;
 
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::DerivationPath;
+use crate::store_path::StorePath;
 
 fn default_true() -> bool {
     true
@@ -12,7 +12,7 @@ fn default_true() -> bool {
 /// Narinfo stores information output by `nix path-info --json`
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Narinfo {
-    pub path: DerivationPath,
+    pub path: StorePath,
     // TODO remove this
     // https://github.com/NixOS/nix/pull/7924 made it into 2.15.0, but keep this
     // a bit longer to support older Nix versions
@@ -0,0 +1,483 @@
+//! Classification of nix failures by their stderr, see [NixError::classify]
+//!
+//! Nix' messages are not a stable interface and their wording drifts between versions,
+//! each pattern is tested against messages in the wording of nix 2.13 and 2.19.
+//! The fixtures are written after nix' sources rather than captured, see `test/README.md`.
+
+use std::borrow::Cow;
+use std::process::ExitStatus;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::store_path::StorePath;
+
+/// Matches the message for a flake output that does not exist
+/// or an attribute missing from an attribute set
+static ATTRIBUTE_MISSING: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"does not provide attribute '(?P<attr>[^']+)'|attribute '(?P<missing>[^']+)' missing",
+    )
+    .unwrap()
+});
+
+/// Matches the message for an indirect flake ref missing from the registries
+static FLAKE_NOT_FOUND: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"cannot find flake '(?P<ref>[^']+)' in the flake registries").unwrap()
+});
+
+/// Matches the hashes of a fixed-output derivation producing an unexpected output
+static HASH_MISMATCH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"hash mismatch in fixed-output derivation '[^']+':\s+specified:\s+(?P<expected>\S+)\s+got:\s+(?P<got>\S+)")
+        .unwrap()
+});
+
+/// Matches what `fetchurl` (and the fetchers built on it) print before a download
+static TRYING_URL: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)trying (?P<url>\S+)$").unwrap());
+
+/// Matches the message for a failing builder
+///
+/// Older versions of nix print the former, newer ones the latter
+static BUILD_FAILED: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"builder for '(?P<drv>[^']+)' failed|Cannot build '(?P<cannot>[^']+)'").unwrap()
+});
+
+/// Matches curl failing to reach a host, which reports errors with their code, e.g. `(6)`
+///
+/// HTTP errors (`HTTP error 404`) are not considered network errors.
+static NETWORK_ERROR: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^\s*error: unable to download '[^']+': .* \(\d+\)$").unwrap());
+
+/// Matches failures of downloads that may succeed when tried again:
+/// server errors, connections closed early and timeouts
+///
+/// These are not [NixError::NetworkError]s when curl reports them without an error code.
+static TRANSIENT_DOWNLOAD: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)^\s*error: unable to download '[^']+': (HTTP error 5\d\d|.*(unexpected end-of-file|timed out|Timeout was reached))").unwrap()
+});
+
+/// Matches an operation rejected by the file system (or the daemon socket)
+static PERMISSION_DENIED: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^error: .*: Permission denied$").unwrap());
+
+/// Matches the message for a disabled experimental feature
+static EXPERIMENTAL_FEATURE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"experimental Nix feature '(?P<feature>[^']+)' is disabled").unwrap());
+
+/// Matches the escape sequences nix colors its messages with
+static ANSI_ESCAPE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
+
+/// Why nix failed, as far as can be told from its stderr
+///
+/// Every variant retains the stderr it was classified from.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum NixError {
+    #[error("attribute '{attr_path}' does not exist")]
+    AttributeMissing { attr_path: String, stderr: String },
+    #[error("flake '{flake_ref}' could not be found")]
+    FlakeNotFound { flake_ref: String, stderr: String },
+    #[error("hash mismatch{}: expected '{expected}', got '{got}'", .url.as_ref().map(|url| format!(" for '{url}'")).unwrap_or_default())]
+    HashMismatch {
+        expected: String,
+        got: String,
+        /// The url that was downloaded, if nix printed it
+        url: Option<String>,
+        stderr: String,
+    },
+    #[error("building '{drv_path}' failed")]
+    BuildFailed { drv_path: StorePath, stderr: String },
+    #[error("network error")]
+    NetworkError { stderr: String },
+    #[error("permission denied")]
+    PermissionDenied { stderr: String },
+    #[error("experimental feature '{feature}' is required")]
+    ExperimentalFeatureRequired { feature: String, stderr: String },
+    #[error("{}", crate::eval_trace::error_message(stderr).unwrap_or_else(|| "unknown error".to_string()))]
+    Other { stderr: String },
+}
+
+impl NixError {
+    /// Classify a failure of nix by its exit status and stderr
+    ///
+    /// Nix exits with `100` or above if builds failed,
+    /// in that case build failures take precedence over errors
+    /// that may just be part of the build log (e.g. `Permission denied`).
+    ///
+    /// Errors nix printed as JSON objects are classified by their message,
+    /// see [reported_error].
+    pub fn classify(status: ExitStatus, stderr: &str) -> NixError {
+        let stderr = &*reported_as_text(stderr);
+        let classifiers = if status.code().is_some_and(|code| code >= 100) {
+            [BUILD, EVALUATION, ENVIRONMENT]
+        } else {
+            [EVALUATION, BUILD, ENVIRONMENT]
+        };
+
+        classifiers
+            .into_iter()
+            .flatten()
+            .find_map(|classify| classify(stderr))
+            .unwrap_or_else(|| NixError::Other {
+                stderr: stderr.to_string(),
+            })
+    }
+
+    /// The stderr of nix this error was classified from
+    pub fn stderr(&self) -> &str {
+        match self {
+            NixError::AttributeMissing { stderr, .. }
+            | NixError::FlakeNotFound { stderr, .. }
+            | NixError::HashMismatch { stderr, .. }
+            | NixError::BuildFailed { stderr, .. }
+            | NixError::NetworkError { stderr }
+            | NixError::PermissionDenied { stderr }
+            | NixError::ExperimentalFeatureRequired { stderr, .. }
+            | NixError::Other { stderr } => stderr,
+        }
+    }
+
+    /// Whether nix may succeed if run again, because a download failed on the way
+    ///
+    /// Build failures are never transient, even if the build failed to download something.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NixError::NetworkError { .. } => true,
+            NixError::Other { stderr } => TRANSIENT_DOWNLOAD.is_match(stderr),
+            _ => false,
+        }
+    }
+}
+
+/// The last error nix printed to `stderr` as a JSON object rather than as text
+///
+/// Nix prints errors as JSON objects with `--log-format internal-json`,
+/// prefixed with `@nix `, and for some failures of commands run with `--json`.
+/// Lines are taken for errors if they are objects
+/// with a `msg` (logged at level `0`, if a level is given) or `error` string.
+pub fn reported_error(stderr: &str) -> Option<Value> {
+    stderr
+        .lines()
+        .rev()
+        .find_map(reported_message)
+        .map(|(json, _)| json)
+}
+
+/// An error object and its message without colors, if `line` is one,
+/// see [reported_error]
+fn reported_message(line: &str) -> Option<(Value, String)> {
+    let line = line.trim();
+    let line = line.strip_prefix("@nix ").unwrap_or(line);
+    if !line.starts_with('{') {
+        return None;
+    }
+    let json: Value = serde_json::from_str(line).ok()?;
+    let message = match (json.get("msg"), json.get("error")) {
+        (Some(Value::String(msg)), _) => {
+            if json.get("level").is_some_and(|level| level != 0) {
+                return None;
+            }
+            ANSI_ESCAPE.replace_all(msg, "").into_owned()
+        },
+        (_, Some(Value::String(error))) if error.starts_with("error:") => error.clone(),
+        (_, Some(Value::String(error))) => format!("error: {error}"),
+        _ => return None,
+    };
+    Some((json, message))
+}
+
+/// `stderr` with the error objects nix printed replaced by their messages,
+/// so that they are classified like errors printed as text
+pub(crate) fn reported_as_text(stderr: &str) -> Cow<'_, str> {
+    if reported_error(stderr).is_none() {
+        return Cow::Borrowed(stderr);
+    }
+    let mut text = String::with_capacity(stderr.len());
+    for line in stderr.lines() {
+        match reported_message(line) {
+            Some((_, message)) => text.push_str(&message),
+            None => text.push_str(line),
+        }
+        text.push('\n');
+    }
+    Cow::Owned(text)
+}
+
+type Classifier = fn(&str) -> Option<NixError>;
+
+const EVALUATION: &[Classifier] = &[
+    experimental_feature_required,
+    attribute_missing,
+    flake_not_found,
+];
+const BUILD: &[Classifier] = &[hash_mismatch, build_failed];
+const ENVIRONMENT: &[Classifier] = &[permission_denied, network_error];
+
+fn attribute_missing(stderr: &str) -> Option<NixError> {
+    let captures = ATTRIBUTE_MISSING.captures(stderr)?;
+    let attr_path = captures.name("attr").or(captures.name("missing"))?;
+    Some(NixError::AttributeMissing {
+        attr_path: attr_path.as_str().to_string(),
+        stderr: stderr.to_string(),
+    })
+}
+
+fn flake_not_found(stderr: &str) -> Option<NixError> {
+    let captures = FLAKE_NOT_FOUND.captures(stderr)?;
+    Some(NixError::FlakeNotFound {
+        flake_ref: captures["ref"].to_string(),
+        stderr: stderr.to_string(),
+    })
+}
+
+fn hash_mismatch(stderr: &str) -> Option<NixError> {
+    let captures = HASH_MISMATCH.captures(stderr)?;
+    // the last url tried before the mismatch was reported
+    let url = TRYING_URL
+        .captures_iter(&stderr[..captures.get(0)?.start()])
+        .last()
+        .map(|url| url["url"].to_string());
+    Some(NixError::HashMismatch {
+        expected: captures["expected"].to_string(),
+        got: captures["got"].to_string(),
+        url,
+        stderr: stderr.to_string(),
+    })
+}
+
+fn build_failed(stderr: &str) -> Option<NixError> {
+    let captures = BUILD_FAILED.captures(stderr)?;
+    let drv_path = captures.name("drv").or(captures.name("cannot"))?;
+    Some(NixError::BuildFailed {
+        drv_path: drv_path.as_str().parse().ok()?,
+        stderr: stderr.to_string(),
+    })
+}
+
+fn network_error(stderr: &str) -> Option<NixError> {
+    NETWORK_ERROR
+        .is_match(stderr)
+        .then(|| NixError::NetworkError {
+            stderr: stderr.to_string(),
+        })
+}
+
+fn permission_denied(stderr: &str) -> Option<NixError> {
+    PERMISSION_DENIED
+        .is_match(stderr)
+        .then(|| NixError::PermissionDenied {
+            stderr: stderr.to_string(),
+        })
+}
+
+fn experimental_feature_required(stderr: &str) -> Option<NixError> {
+    let captures = EXPERIMENTAL_FEATURE.captures(stderr)?;
+    Some(NixError::ExperimentalFeatureRequired {
+        feature: captures["feature"].to_string(),
+        stderr: stderr.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    const EXIT_FAILURE: i32 = 1;
+    const EXIT_BUILD_FAILURE: i32 = 100;
+    const EXIT_HASH_MISMATCH: i32 = 102;
+
+    /// Classify the fixtures in the wording of nix 2.13 and 2.19
+    fn classify(fixtures: [&str; 2], code: i32) -> [NixError; 2] {
+        fixtures.map(|stderr| NixError::classify(ExitStatus::from_raw(code << 8), stderr))
+    }
+
+    #[test]
+    fn classifies_attribute_missing() {
+        for error in classify(
+            [
+                include_str!("../test/nix-error/attribute-missing-nix-2.13.txt"),
+                include_str!("../test/nix-error/attribute-missing-nix-2.19.txt"),
+            ],
+            EXIT_FAILURE,
+        ) {
+            assert!(
+                matches!(&error, NixError::AttributeMissing { attr_path, .. } if attr_path == "packages.x86_64-linux.hello"),
+                "{error:?}"
+            );
+        }
+
+        let error = NixError::classify(
+            ExitStatus::from_raw(EXIT_FAILURE << 8),
+            "error: attribute 'hello' missing\n",
+        );
+        assert!(
+            matches!(error, NixError::AttributeMissing { attr_path, .. } if attr_path == "hello")
+        );
+    }
+
+    #[test]
+    fn classifies_flake_not_found() {
+        // the wording of nix 2.19 is not covered by a fixture
+        let error = NixError::classify(
+            ExitStatus::from_raw(EXIT_FAILURE << 8),
+            include_str!("../test/nix-error/flake-not-found-nix-2.13.txt"),
+        );
+        assert!(
+            matches!(&error, NixError::FlakeNotFound { flake_ref, .. } if flake_ref == "flake:nixpkgs-unstable"),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn classifies_hash_mismatch() {
+        for error in classify(
+            [
+                include_str!("../test/nix-error/hash-mismatch-nix-2.13.txt"),
+                include_str!("../test/nix-error/hash-mismatch-nix-2.19.txt"),
+            ],
+            EXIT_HASH_MISMATCH,
+        ) {
+            assert_eq!(error, NixError::HashMismatch {
+                expected: "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string(),
+                got: "sha256-k8v3BRKqWHkt2Eo0dZ5LoOJgLVgTTCsqCmOFXKBq5fo=".to_string(),
+                url: Some("https://github.com/flox/runix/archive/0630fc9307852b30ea4c5915b6b74fa9db51d641.tar.gz".to_string()),
+                stderr: error.stderr().to_string(),
+            });
+        }
+    }
+
+    #[test]
+    fn classifies_build_failed() {
+        for error in classify(
+            [
+                include_str!("../test/nix-error/build-failed-nix-2.13.txt"),
+                include_str!("../test/nix-error/build-failed-nix-2.19.txt"),
+            ],
+            EXIT_BUILD_FAILURE,
+        ) {
+            // the build log contains `Permission denied`
+            assert!(
+                matches!(&error, NixError::BuildFailed { drv_path, .. } if drv_path.to_string() == "/nix/store/g3g6k3mk7h6lfqnf3hp0ycq4hn8k9g2y-broken-1.0.drv"),
+                "{error:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_network_error() {
+        for error in classify(
+            [
+                include_str!("../test/nix-error/network-nix-2.13.txt"),
+                include_str!("../test/nix-error/network-nix-2.19.txt"),
+            ],
+            EXIT_FAILURE,
+        ) {
+            assert!(matches!(error, NixError::NetworkError { .. }), "{error:?}");
+        }
+
+        let error = NixError::classify(
+            ExitStatus::from_raw(EXIT_FAILURE << 8),
+            "error: unable to download 'https://example.com/x.tar.gz': HTTP error 404\n",
+        );
+        assert!(matches!(error, NixError::Other { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn detects_transient_errors() {
+        for stderr in [
+            include_str!("../test/nix-error/network-nix-2.19.txt"),
+            "error: unable to download 'https://cache.nixos.org/x.narinfo': HTTP error 503\n",
+            "error:\n       … while fetching the input 'github:flox/runix'\n\n       error: unable to download 'https://github.com/flox/runix/archive/HEAD.tar.gz': unexpected end-of-file\n",
+            "error: unable to download 'https://example.com/x.tar.gz': Operation timed out after 300000 milliseconds\n",
+        ] {
+            let error = NixError::classify(ExitStatus::from_raw(EXIT_FAILURE << 8), stderr);
+            assert!(error.is_transient(), "{error:?}");
+        }
+
+        for (stderr, code) in [
+            (
+                "error: unable to download 'https://example.com/x.tar.gz': HTTP error 404\n",
+                EXIT_FAILURE,
+            ),
+            (
+                include_str!("../test/nix-error/build-failed-nix-2.19.txt"),
+                EXIT_BUILD_FAILURE,
+            ),
+            (
+                include_str!("../test/nix-error/attribute-missing-nix-2.19.txt"),
+                EXIT_FAILURE,
+            ),
+        ] {
+            let error = NixError::classify(ExitStatus::from_raw(code << 8), stderr);
+            assert!(!error.is_transient(), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn classifies_permission_denied() {
+        for error in classify(
+            [
+                include_str!("../test/nix-error/permission-denied-nix-2.13.txt"),
+                include_str!("../test/nix-error/permission-denied-nix-2.19.txt"),
+            ],
+            EXIT_FAILURE,
+        ) {
+            assert!(
+                matches!(error, NixError::PermissionDenied { .. }),
+                "{error:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_experimental_feature_required() {
+        let [old, new] = classify(
+            [
+                include_str!("../test/nix-error/experimental-feature-nix-2.13.txt"),
+                include_str!("../test/nix-error/experimental-feature-nix-2.19.txt"),
+            ],
+            EXIT_FAILURE,
+        );
+        assert!(
+            matches!(&old, NixError::ExperimentalFeatureRequired { feature, .. } if feature == "nix-command"),
+            "{old:?}"
+        );
+        assert!(
+            matches!(&new, NixError::ExperimentalFeatureRequired { feature, .. } if feature == "flakes"),
+            "{new:?}"
+        );
+    }
+
+    #[test]
+    fn classifies_reported_errors() {
+        let stderr = include_str!("../test/nix-error/attribute-missing-json.txt");
+        let error = NixError::classify(ExitStatus::from_raw(EXIT_FAILURE << 8), stderr);
+        assert!(
+            matches!(&error, NixError::AttributeMissing { attr_path, .. }
+                if attr_path == "packages.x86_64-linux.hello"),
+            "{error:?}"
+        );
+        assert!(!error.stderr().contains('\x1b'));
+        assert_eq!(reported_error(stderr).unwrap()["action"], "msg");
+
+        let internal = format!("@nix {stderr}");
+        assert!(reported_error(&internal).is_some());
+        assert!(reported_error(r#"{"error": "flake 'nixpkgs' could not be found"}"#).is_some());
+        // progress messages and text are no errors
+        assert!(reported_error(r#"{"action":"msg","level":3,"msg":"copying"}"#).is_none());
+        assert!(reported_error("error: {oops}").is_none());
+    }
+
+    #[test]
+    fn falls_back_to_other() {
+        let stderr = "error: Git tree '/tmp/flake' is dirty\n";
+        let error = NixError::classify(ExitStatus::from_raw(EXIT_FAILURE << 8), stderr);
+        assert_eq!(error, NixError::Other {
+            stderr: stderr.to_string()
+        });
+        assert_eq!(error.to_string(), "Git tree '/tmp/flake' is dirty");
+    }
+}
@@ -1,16 +1,31 @@
 //! A rust implementaiton of the `registry` file format
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
-use serde_with::skip_serializing_none;
 use thiserror::Error;
 
 use crate::flake_ref::indirect::IndirectRef;
+use crate::flake_ref::lock::{NarHash, Rev};
 use crate::flake_ref::FlakeRef;
+use crate::url_parser::UrlParseError;
 
 #[derive(Error, Debug)]
-pub enum RegistryError {}
+pub enum RegistryError {
+    #[error("Could not read or write registry: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid registry: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Unsupported registry version {0}")]
+    UnsupportedVersion(u64),
+    #[error("Invalid flake reference '{uri}' in version 1 registry: {source}")]
+    V1FlakeRef { uri: String, source: UrlParseError },
+    #[error("No registry entry for '{0}'")]
+    EntryNotFound(String),
+    #[error("Can not pin '{0}' to a revision")]
+    Unpinnable(String),
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
 pub struct Registry {
@@ -29,11 +44,71 @@ impl Registry {
         let entry = RegistryEntry {
             from: IndirectRef::new(name.to_string(), Default::default()),
             to,
-            exact: None,
+            exact: false,
         };
         self.flakes.replace(entry);
     }
 
+    /// Lock the entry for `id` to the commit `rev` with the source tree hash `nar_hash`
+    ///
+    /// The first entry from `flake:<id>` is pinned, regardless of further attributes
+    /// its `from` may have.
+    /// Fails with [RegistryError::Unpinnable] for entries pointing to kinds of refs
+    /// without a `rev` attribute, see [FlakeRef::with_rev].
+    pub fn pin_entry(
+        &mut self,
+        id: &str,
+        rev: Rev,
+        nar_hash: NarHash,
+    ) -> Result<(), RegistryError> {
+        let mut entry = self
+            .flakes
+            .iter()
+            .find(|entry| entry.from.id == id)
+            .cloned()
+            .ok_or_else(|| RegistryError::EntryNotFound(id.to_string()))?;
+
+        entry.to = entry
+            .to
+            .clone()
+            .with_rev(rev)
+            .ok_or_else(|| RegistryError::Unpinnable(entry.to.to_string()))?
+            .with_nar_hash(nar_hash);
+        self.flakes.replace(entry);
+        Ok(())
+    }
+
+    /// Remove `rev`, `narHash` and `lastModified` from the target of the entry for `id`,
+    /// the reverse of [Registry::pin_entry]
+    ///
+    /// The entry then follows its branch (or url) again.
+    pub fn unpin_entry(&mut self, id: &str) -> Result<(), RegistryError> {
+        let mut entry = self
+            .flakes
+            .iter()
+            .find(|entry| entry.from.id == id)
+            .cloned()
+            .ok_or_else(|| RegistryError::EntryNotFound(id.to_string()))?;
+
+        entry.to = entry.to.clone().unpinned();
+        self.flakes.replace(entry);
+        Ok(())
+    }
+
+    /// Replace the target of every entry with `f` applied to it,
+    /// e.g. to point all github refs at a mirror
+    ///
+    /// The `from` and `exact` of the entries are kept.
+    pub fn map_refs(&mut self, mut f: impl FnMut(FlakeRef) -> FlakeRef) {
+        self.flakes = std::mem::take(&mut self.flakes)
+            .into_iter()
+            .map(|mut entry| {
+                entry.to = f(entry.to);
+                entry
+            })
+            .collect();
+    }
+
     #[allow(unused)]
     /// Todo: more functions such as remove, get, etc
     pub fn remove(&mut self, _name: impl ToString) {}
@@ -42,6 +117,37 @@ impl Registry {
     pub fn entries(&self) -> impl Iterator<Item = &RegistryEntry> {
         self.flakes.iter()
     }
+
+    /// Read a registry file
+    ///
+    /// Registries of an older version are upgraded to the current version (2),
+    /// so writing them back with [Registry::write_to_path] stores them as such.
+    ///
+    /// Version 1 registries map flake ids to flake reference urls
+    /// (`{"flakes": {"nixpkgs": {"uri": "github:NixOS/nixpkgs"}}, "version": 1}`),
+    /// each of them becomes an entry from `flake:<id>` to the parsed url.
+    /// Parsing these urls requires `parser-util`, see [FlakeRef::from_str].
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, RegistryError> {
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        match json.get("version").and_then(serde_json::Value::as_u64) {
+            Some(1) => serde_json::from_value::<RegistryV1>(json)?.upgrade(),
+            Some(2) => Ok(serde_json::from_value(json)?),
+            Some(version) => Err(RegistryError::UnsupportedVersion(version)),
+            None => Err(RegistryError::Json(serde::de::Error::missing_field(
+                "version",
+            ))),
+        }
+    }
+
+    /// Write the registry to a file, in the current version
+    pub fn write_to_path(&self, path: impl AsRef<Path>) -> Result<(), RegistryError> {
+        let registry = Registry {
+            version: Version::default(),
+            flakes: self.flakes.clone(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&registry)?)?;
+        Ok(())
+    }
 }
 
 impl FromIterator<RegistryEntry> for Registry {
@@ -53,6 +159,25 @@ impl FromIterator<RegistryEntry> for Registry {
     }
 }
 
+/// Entries from `flake:<name>`, later entries for the same name replace earlier ones
+/// like with [Registry::set]
+impl FromIterator<(String, FlakeRef)> for Registry {
+    fn from_iter<T: IntoIterator<Item = (String, FlakeRef)>>(iter: T) -> Self {
+        let mut registry = Registry::default();
+        registry.extend(iter);
+        registry
+    }
+}
+
+/// Adds entries like [Registry::set], replacing existing entries for the same name
+impl Extend<(String, FlakeRef)> for Registry {
+    fn extend<T: IntoIterator<Item = (String, FlakeRef)>>(&mut self, iter: T) {
+        for (name, to) in iter {
+            self.set(name, to);
+        }
+    }
+}
+
 /// TODO: use https://github.com/dtolnay/serde-repr?
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 struct Version(u8);
@@ -62,12 +187,43 @@ impl Default for Version {
     }
 }
 
-#[skip_serializing_none]
+/// The registry format before version 2, see [Registry::from_path]
+#[derive(Debug, Deserialize)]
+struct RegistryV1 {
+    flakes: BTreeMap<String, RegistryEntryV1>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryEntryV1 {
+    uri: String,
+}
+
+impl RegistryV1 {
+    fn upgrade(self) -> Result<Registry, RegistryError> {
+        let mut registry = Registry::default();
+        for (id, RegistryEntryV1 { uri }) in self.flakes {
+            let to = uri
+                .parse()
+                .map_err(|source| RegistryError::V1FlakeRef { uri, source })?;
+            registry.set(id, to);
+        }
+        Ok(registry)
+    }
+}
+
+/// An entry of a [Registry], resolving `from` to `to`
+///
+/// Serializes like nix writes registry entries,
+/// e.g. `{"from": {"id": "nixpkgs", "type": "indirect"}, "to": {...}, "exact": true}`.
+/// Like nix, `exact` is only written if it is set and considered unset if it is missing.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RegistryEntry {
     pub from: IndirectRef, // TODO merge into single flakeRef type @notgne2?
     pub to: FlakeRef,
-    pub exact: Option<bool>,
+    /// Only resolve `from` itself, not refs to a branch or revision of it
+    /// (e.g. `nixpkgs/nixos-23.11` for an entry from `nixpkgs`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exact: bool,
 }
 
 impl Ord for RegistryEntry {
@@ -82,13 +238,6 @@ impl PartialOrd for RegistryEntry {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
-#[serde(tag = "type")]
-#[serde(rename_all = "lowercase")]
-enum FromFlakeRef {
-    Indirect(IndirectRef),
-}
-
 #[cfg(test)]
 mod tests {
     use std::fs::File;
@@ -99,4 +248,235 @@ mod tests {
         serde_json::from_reader::<_, Registry>(File::open("./test/registry.test.json").unwrap())
             .expect("should parse");
     }
+
+    #[test]
+    fn upgrades_v1_registry() {
+        let registry = Registry::from_path("./test/registry-v1.test.json").unwrap();
+        assert_eq!(registry.version, Version::default());
+        assert_eq!(registry.entries().count(), 2);
+
+        let nixpkgs = registry
+            .entries()
+            .find(|entry| entry.from.id == "nixpkgs")
+            .unwrap();
+        assert_eq!(nixpkgs.to, "github:flox/nixpkgs/stable".parse().unwrap());
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("registry.json");
+        registry.write_to_path(&path).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["version"], 2);
+        assert_eq!(Registry::from_path(&path).unwrap(), registry);
+    }
+
+    #[test]
+    fn collects_entries() {
+        use std::str::FromStr;
+
+        use crate::flake_ref::git_service::GitServiceRef;
+
+        let nixpkgs =
+            FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs/nixos-23.11").unwrap());
+        let unstable = FlakeRef::Github(
+            GitServiceRef::from_str("github:NixOS/nixpkgs/nixos-unstable").unwrap(),
+        );
+        let flox = FlakeRef::Github(GitServiceRef::from_str("github:flox/flox").unwrap());
+
+        let mut registry = vec![
+            ("nixpkgs".to_string(), nixpkgs.clone()),
+            ("flox".to_string(), flox.clone()),
+        ]
+        .into_iter()
+        .collect::<Registry>();
+        assert_eq!(
+            registry
+                .entries()
+                .map(|entry| (entry.from.id.as_str(), &entry.to))
+                .collect::<Vec<_>>(),
+            [("flox", &flox), ("nixpkgs", &nixpkgs)]
+        );
+
+        registry.extend([("nixpkgs".to_string(), unstable.clone())]);
+        assert_eq!(registry.entries().count(), 2);
+        assert_eq!(
+            registry
+                .entries()
+                .find(|entry| entry.from.id == "nixpkgs")
+                .map(|entry| &entry.to),
+            Some(&unstable)
+        );
+    }
+
+    #[test]
+    fn pins_entry() {
+        use std::str::FromStr;
+
+        use crate::flake_ref::file::TarballRef;
+        use crate::flake_ref::git_service::GitServiceRef;
+
+        let rev: Rev = "0630fc9307852b30ea4c5915b6b74fa9db51d641".parse().unwrap();
+        let nar_hash = "sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4=".to_string();
+
+        let mut registry = Registry::default();
+        registry.set(
+            "nixpkgs",
+            FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs/nixos-23.11").unwrap()),
+        );
+        registry.set(
+            "tarball",
+            FlakeRef::TarballHTTPS(TarballRef::from_str("https://example.com/r.tar.gz").unwrap()),
+        );
+
+        registry
+            .pin_entry("nixpkgs", rev.clone(), nar_hash.clone())
+            .unwrap();
+        let pinned = &registry.entries().next().unwrap().to;
+        assert_eq!(pinned.rev(), Some(rev.as_str()));
+        assert_eq!(pinned.nar_hash(), Some(nar_hash.as_str()));
+        assert_eq!(
+            pinned,
+            &FlakeRef::Github(
+                GitServiceRef::from_str(&format!(
+                    "github:NixOS/nixpkgs/{}?narHash={}",
+                    rev.as_str(),
+                    nar_hash.replace('=', "%3D")
+                ))
+                .unwrap()
+            )
+        );
+
+        assert!(matches!(
+            registry.pin_entry("missing", rev.clone(), nar_hash.clone()),
+            Err(RegistryError::EntryNotFound(id)) if id == "missing"
+        ));
+        assert!(matches!(
+            registry.pin_entry("tarball", rev, nar_hash),
+            Err(RegistryError::Unpinnable(_))
+        ));
+    }
+
+    #[test]
+    fn unpins_entry() {
+        use std::str::FromStr;
+
+        use crate::flake_ref::git_service::GitServiceRef;
+
+        let mut registry = Registry::default();
+        registry.set(
+            "nixpkgs",
+            FlakeRef::Github(
+                GitServiceRef::from_str(
+                    "github:NixOS/nixpkgs/0630fc9307852b30ea4c5915b6b74fa9db51d641?dir=lib&lastModified=1700000000&narHash=sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4%3D",
+                )
+                .unwrap(),
+            ),
+        );
+
+        registry.unpin_entry("nixpkgs").unwrap();
+        assert_eq!(
+            registry.entries().next().unwrap().to,
+            FlakeRef::Github(GitServiceRef::from_str("github:NixOS/nixpkgs?dir=lib").unwrap())
+        );
+
+        assert!(matches!(
+            registry.unpin_entry("missing"),
+            Err(RegistryError::EntryNotFound(id)) if id == "missing"
+        ));
+    }
+
+    #[test]
+    fn maps_refs() {
+        use std::str::FromStr;
+
+        use crate::flake_ref::file::TarballRef;
+        use crate::flake_ref::git_service::GitServiceRef;
+
+        let github = |url| FlakeRef::Github(GitServiceRef::from_str(url).unwrap());
+        let tarball =
+            FlakeRef::TarballHTTPS(TarballRef::from_str("https://example.com/r.tar.gz").unwrap());
+
+        let mut registry = Registry::from_iter([
+            RegistryEntry {
+                from: IndirectRef::new("nixpkgs".to_string(), Default::default()),
+                to: github("github:NixOS/nixpkgs/nixos-23.11"),
+                exact: true,
+            },
+            RegistryEntry {
+                from: IndirectRef::new("flox".to_string(), Default::default()),
+                to: github("github:flox/flox"),
+                exact: false,
+            },
+            RegistryEntry {
+                from: IndirectRef::new("tarball".to_string(), Default::default()),
+                to: tarball.clone(),
+                exact: false,
+            },
+        ]);
+
+        registry.map_refs(|to| match to {
+            FlakeRef::Github(_) => to.with_host("github.example.com"),
+            other => other,
+        });
+
+        let entries = registry
+            .entries()
+            .map(|entry| (entry.from.id.as_str(), entry.to.clone(), entry.exact))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [
+            (
+                "flox",
+                github("github:flox/flox?host=github.example.com"),
+                false
+            ),
+            (
+                "nixpkgs",
+                github("github:NixOS/nixpkgs/nixos-23.11?host=github.example.com"),
+                true
+            ),
+            ("tarball", tarball, false),
+        ]);
+    }
+
+    #[test]
+    fn entries_match_nix_format() {
+        use serde_json::json;
+
+        let exact = json!({
+            "from": { "id": "nixpkgs", "type": "indirect" },
+            "to": { "owner": "NixOS", "ref": "nixos-23.11", "repo": "nixpkgs", "type": "github" },
+            "exact": true,
+        });
+        let entry: RegistryEntry = serde_json::from_value(exact.clone()).unwrap();
+        assert_eq!(entry.from.id, "nixpkgs");
+        assert_eq!(entry.to.to_string(), "github:NixOS/nixpkgs/nixos-23.11");
+        assert!(entry.exact);
+        assert_eq!(serde_json::to_value(&entry).unwrap(), exact);
+
+        // nix omits `exact` unless it is set
+        let inexact = json!({
+            "from": { "id": "flox", "type": "indirect" },
+            "to": { "path": "/src/flox", "type": "path" },
+        });
+        let entry: RegistryEntry = serde_json::from_value(inexact.clone()).unwrap();
+        assert!(!entry.exact);
+        assert_eq!(serde_json::to_value(&entry).unwrap(), inexact);
+
+        let registry = json!({ "flakes": [inexact, exact], "version": 2 });
+        let parsed: Registry = serde_json::from_value(registry.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), registry);
+    }
+
+    #[test]
+    fn rejects_unknown_registry_version() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("registry.json");
+        std::fs::write(&path, r#"{"flakes": [], "version": 3}"#).unwrap();
+
+        assert!(matches!(
+            Registry::from_path(&path),
+            Err(RegistryError::UnsupportedVersion(3))
+        ));
+    }
 }
@@ -1,9 +1,15 @@
 use std::ffi::OsStr;
 use std::fmt::Display;
+use std::ops::Deref;
 use std::path::{Component, Path, PathBuf};
+use std::process::ExitStatus;
 use std::str::FromStr;
 
+use base64::prelude::{Engine, BASE64_STANDARD};
 use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 /// Respect [NIX_STORE_DIR](https://nixos.org/manual/nix/stable/command-ref/env-common.html#env-NIX_STORE_DIR)
@@ -17,14 +23,57 @@ pub static STORE_PREFIX: Lazy<PathBuf> = Lazy::new(|| {
     .to_path_buf()
 });
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct StorePath {
-    prefix: PathBuf,
-    basename: String,
-    package_path: Option<PathBuf>,
+/// The characters used by nix' base32 encoding (no `e`, `o`, `u` and `t`)
+static STORE_HASH: Lazy<Regex> = Lazy::new(|| Regex::new("^[0-9a-df-np-sv-z]{32}$").unwrap());
+
+/// The alphabet of nix' base32 encoding
+const NIX_BASE32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Encode bytes in nix' base32 encoding
+///
+/// <https://github.com/NixOS/nix/blob/2.17.0/src/libutil/hash.cc#L83-L107>
+fn nix_base32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let c = (bytes[i] >> j)
+                | bytes
+                    .get(i + 1)
+                    .map_or(0, |next| next.checked_shl(8 - j as u32).unwrap_or(0));
+            NIX_BASE32[(c & 0x1f) as usize] as char
+        })
+        .collect()
 }
 
+/// A path in the nix store, i.e. `<prefix>/<hash>-<name>[/<package path>]`
+///
+/// Derefs to the complete [Path].
+#[derive(Debug, PartialEq, Eq, Hash, Clone, DeserializeFromStr, SerializeDisplay)]
+pub struct StorePath(PathBuf);
+
 impl StorePath {
+    /// the `<prefix>/<hash>-<name>` ancestor of the path
+    ///
+    /// That is the path up to the first component that starts with a store hash,
+    /// or the entire path for unchecked paths without such component.
+    fn out_path_ref(&self) -> &Path {
+        let components = self.0.components().collect::<Vec<_>>();
+        let package_len = components
+            .iter()
+            .position(|component| {
+                component
+                    .as_os_str()
+                    .to_str()
+                    .and_then(|name| name.split_once('-'))
+                    .is_some_and(|(hash, _)| STORE_HASH.is_match(hash))
+            })
+            .map_or(0, |basename| components.len() - basename - 1);
+        self.0.ancestors().nth(package_len).unwrap()
+    }
+
     /// nix store prefix, checked against [STORE_PREFIX]
     ///
     /// ```
@@ -36,7 +85,7 @@ impl StorePath {
     /// assert_eq!(path.prefix(), STORE_PREFIX.as_path())
     /// ```
     pub fn prefix(&self) -> &Path {
-        &self.prefix
+        self.out_path_ref().parent().unwrap_or(Path::new(""))
     }
 
     /// the package name in the store
@@ -53,7 +102,38 @@ impl StorePath {
     /// );
     /// ```
     pub fn basename(&self) -> &str {
-        &self.basename
+        self.out_path_ref()
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default()
+    }
+
+    /// the hash part of the package name
+    ///
+    /// ```
+    /// # use runix::store_path::StorePath;
+    ///
+    /// let path = StorePath::from_path("/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10")
+    ///     .unwrap();
+    /// assert_eq!(path.hash(), "7rjqb838snvvxcmpvck1smfxhkwzqal5");
+    /// ```
+    pub fn hash(&self) -> &str {
+        self.basename()
+            .split_once('-')
+            .map_or(self.basename(), |(hash, _)| hash)
+    }
+
+    /// the name part of the package name
+    ///
+    /// ```
+    /// # use runix::store_path::StorePath;
+    ///
+    /// let path = StorePath::from_path("/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10")
+    ///     .unwrap();
+    /// assert_eq!(path.name(), "python3-3.10.10");
+    /// ```
+    pub fn name(&self) -> &str {
+        self.basename().split_once('-').map_or("", |(_, name)| name)
     }
 
     /// the package's path in the nix store
@@ -74,7 +154,7 @@ impl StorePath {
     /// assert_eq!(path.out_path(), Path::new(&out_path)); // !! without /bin/python
     /// ```
     pub fn out_path(&self) -> PathBuf {
-        self.prefix.join(&self.basename)
+        self.out_path_ref().to_path_buf()
     }
 
     /// path of a file or directory inside the package
@@ -95,19 +175,80 @@ impl StorePath {
     /// assert_eq!(path.package_path(), Some(Path::new("bin/python")));
     /// ```
     pub fn package_path(&self) -> Option<&Path> {
-        self.package_path.as_deref()
+        self.0
+            .strip_prefix(self.out_path_ref())
+            .ok()
+            .filter(|path| !path.as_os_str().is_empty())
     }
 
-    /// mutable reference to path of a file or directory inside the package
+    /// set or unset the path of a file or directory inside the package
     ///
     /// see also: [`StorePath::package_path`]
     ///
-    /// ```ignore
-    /// /nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10/bin/python
-    ///                                                            ^^^^^^^^^^^
     /// ```
-    pub fn package_path_mut(&mut self) -> &mut Option<PathBuf> {
-        &mut self.package_path
+    /// # use std::path::Path;
+    /// # use runix::store_path::StorePath;
+    ///
+    /// let mut path =
+    ///     StorePath::from_path("/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10")
+    ///         .unwrap();
+    /// path.set_package_path(Some("bin/python"));
+    /// assert_eq!(path.package_path(), Some(Path::new("bin/python")));
+    /// ```
+    pub fn set_package_path(&mut self, package_path: Option<impl AsRef<Path>>) {
+        let mut path = self.out_path();
+        if let Some(package_path) = package_path {
+            path.push(package_path);
+        }
+        self.0 = path;
+    }
+
+    /// The store path of a source tree with the given SRI `narHash`
+    ///
+    /// This is where nix puts fetched flake sources (named `source`),
+    /// see [`makeFixedOutputPath`](https://github.com/NixOS/nix/blob/2.17.0/src/libstore/store-api.cc#L209-L226).
+    /// Returns [None] unless `nar_hash` is a `sha256-<base64>` hash.
+    ///
+    /// ```
+    /// # use runix::store_path::StorePath;
+    /// let path = StorePath::from_nar_hash(
+    ///     "sha256-MTXmIYowHM1wyIYyqPdBLia5SjGnxETv0YkIbDsbkx4=",
+    ///     "source",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(
+    ///     path.to_string(),
+    ///     "/nix/store/083m43hjhry94cvfmqdv7kjpvsl3zzvi-source"
+    /// );
+    /// ```
+    pub fn from_nar_hash(nar_hash: &str, name: &str) -> Option<Self> {
+        let digest = BASE64_STANDARD
+            .decode(nar_hash.strip_prefix("sha256-")?)
+            .ok()?;
+        if digest.len() != 32 {
+            return None;
+        }
+
+        let hex = digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        let fingerprint = format!(
+            "source:sha256:{hex}:{store}:{name}",
+            store = STORE_PREFIX.to_string_lossy()
+        );
+
+        // "compress" the hash to 160 bits by xor-ing its tail onto its head
+        let mut compressed = [0u8; 20];
+        for (n, byte) in Sha256::digest(fingerprint).iter().enumerate() {
+            compressed[n % 20] ^= byte;
+        }
+
+        Some(Self::new_unchecked(
+            &*STORE_PREFIX,
+            format!("{}-{name}", nix_base32(&compressed)),
+            None::<&Path>,
+        ))
     }
 
     /// Combine components of store path into a native path type
@@ -122,11 +263,7 @@ impl StorePath {
     /// assert_eq!(StorePath::from_path(&path).unwrap().as_path(), path);
     /// ```
     pub fn as_path(&self) -> PathBuf {
-        if let Some(ref path) = self.package_path {
-            self.out_path().join(path)
-        } else {
-            self.out_path()
-        }
+        self.0.clone()
     }
 
     /// Try parsing a store path from a pathbuf
@@ -137,6 +274,8 @@ impl StorePath {
     /// * if the path's prefix does not equal the [STORE_PREFIX]
     /// * if the path contains '..' components
     /// * if the path only contains the [STORE_PREFIX]
+    /// * if the hash is not 32 characters of nix' base32 alphabet
+    /// * if the package name is missing after the hash
     /// ```
     /// # use runix::store_path::{StorePath, StorePathError, STORE_PREFIX};
     ///
@@ -161,6 +300,16 @@ impl StorePath {
     ///     StorePath::from_path("/nix/store/"),
     ///     Err(StorePathError::NoPackage(_))
     /// ));
+    ///
+    /// assert!(matches!(
+    ///     StorePath::from_path("/nix/store/not-a-hash-python3-3.10.10"),
+    ///     Err(StorePathError::InvalidHash(_))
+    /// ));
+    ///
+    /// assert!(matches!(
+    ///     StorePath::from_path("/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-"),
+    ///     Err(StorePathError::NoName(_))
+    /// ));
     /// ```
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, StorePathError> {
         Self::try_from(path.as_ref().to_path_buf())
@@ -175,12 +324,43 @@ impl StorePath {
         name: impl AsRef<str>,
         package_path: Option<impl AsRef<Path>>,
     ) -> Self {
-        StorePath {
-            prefix: prefix.as_ref().to_path_buf(),
-            basename: name.as_ref().to_string(),
-            package_path: package_path.map(|p| p.as_ref().to_path_buf()),
+        let mut path = prefix.as_ref().join(name.as_ref());
+        if let Some(package_path) = package_path {
+            path.push(package_path);
         }
+        StorePath(path)
     }
+
+    /// The closure of this path, i.e. the path and all paths it depends on,
+    /// directly or indirectly
+    ///
+    /// Runs `nix-store --query --requisites <path>` using `nix_store_bin`,
+    /// which lists the closure in no particular order.
+    /// The path has to be valid, i.e. present in the store.
+    pub async fn requisites(&self, nix_store_bin: &Path) -> Result<Vec<StorePath>, StorePathError> {
+        let output = tokio::process::Command::new(nix_store_bin)
+            .args(["--query", "--requisites"])
+            .arg(&self.0)
+            .output()
+            .await
+            .map_err(StorePathError::NixCall)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            return Err(StorePathError::NixQuery(output.status, stderr));
+        }
+
+        parse_path_lines(&output.stdout)
+    }
+}
+
+/// Parse the store paths printed by `nix-store --query`, one per line
+fn parse_path_lines(stdout: &[u8]) -> Result<Vec<StorePath>, StorePathError> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.trim().parse())
+        .collect()
 }
 
 impl TryFrom<PathBuf> for StorePath {
@@ -198,8 +378,7 @@ impl TryFrom<PathBuf> for StorePath {
             .as_path()
             .strip_prefix(&*STORE_PREFIX)
             .map_err(|_| StorePathError::NotAStorePath(value.clone()))?
-            .components()
-            .peekable();
+            .components();
 
         if !components
             .clone()
@@ -218,17 +397,16 @@ impl TryFrom<PathBuf> for StorePath {
             .to_string_lossy()
             .into_owned();
 
-        let mut path = StorePath {
-            prefix: STORE_PREFIX.to_path_buf(),
-            basename,
-            package_path: None,
-        };
-
-        if components.peek().is_some() {
-            let _ = path.package_path_mut().insert(components.collect());
+        match basename.split_once('-') {
+            Some((hash, _)) if !STORE_HASH.is_match(hash) => {
+                return Err(StorePathError::InvalidHash(value));
+            },
+            Some((_, "")) | None => return Err(StorePathError::NoName(value)),
+            _ => {},
         }
 
-        Ok(path)
+        // normalize away `.` components and trailing slashes
+        Ok(StorePath(value.components().collect()))
     }
 }
 
@@ -242,7 +420,21 @@ impl FromStr for StorePath {
 
 impl Display for StorePath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.as_path().to_string_lossy())
+        write!(f, "{}", self.0.to_string_lossy())
+    }
+}
+
+impl Deref for StorePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for StorePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
     }
 }
 
@@ -256,4 +448,69 @@ pub enum StorePathError {
     NotAStorePath(PathBuf),
     #[error("'{0}' is mising a package directory")]
     NoPackage(PathBuf),
+    #[error("'{0}' does not start with a valid store hash")]
+    InvalidHash(PathBuf),
+    #[error("'{0}' is missing a package name")]
+    NoName(PathBuf),
+    #[error("could not run nix: {0}")]
+    NixCall(std::io::Error),
+    #[error("nix build failed: {0}")]
+    NixBuild(Box<dyn std::error::Error + Send + Sync>),
+    #[error("nix build did not print an out path")]
+    NoOutPath,
+    #[error("nix-store --query failed [{0}]: {1}")]
+    NixQuery(ExitStatus, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_line::tests::write_script;
+
+    const REQUISITES: &str = "\
+/nix/store/9yn8qs8cgfgfwwxrz9wlcb0nq8krb4n8-libunistring-1.1
+/nix/store/vq3sdi8l15rzfl5zvmwpafrzis4sm6xf-glibc-2.37-8
+/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10
+";
+
+    #[test]
+    fn parses_requisites() {
+        let paths = parse_path_lines(REQUISITES.as_bytes()).unwrap();
+        assert_eq!(
+            paths.iter().map(StorePath::name).collect::<Vec<_>>(),
+            ["libunistring-1.1", "glibc-2.37-8", "python3-3.10.10"]
+        );
+
+        assert!(matches!(
+            parse_path_lines(b"/nix/store/not-a-hash-glibc\n"),
+            Err(StorePathError::InvalidHash(_))
+        ));
+        assert!(parse_path_lines(b"").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queries_requisites() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let nix_store_bin = tempdir.path().join("nix-store");
+        write_script(
+            &nix_store_bin,
+            &format!(
+                "[ \"$1 $2\" = '--query --requisites' ] || exit 1\ncat <<EOF\n{REQUISITES}EOF"
+            ),
+        );
+
+        let python =
+            StorePath::from_path("/nix/store/7rjqb838snvvxcmpvck1smfxhkwzqal5-python3-3.10.10")
+                .unwrap();
+        let closure = python.requisites(&nix_store_bin).await.unwrap();
+        assert_eq!(closure.len(), 3);
+        assert!(closure.contains(&python));
+
+        let failing = tempdir.path().join("failing");
+        write_script(&failing, "echo 'error: path is not valid' >&2\nexit 1");
+        assert!(matches!(
+            python.requisites(&failing).await,
+            Err(StorePathError::NixQuery(_, stderr)) if stderr.contains("not valid")
+        ));
+    }
 }
@@ -0,0 +1,263 @@
+//! Typed representation of nix store URIs as accepted by `--store`,
+//! `--eval-store`, `nix copy --from/--to` and the `substituters` setting.
+//!
+//! See <https://nixos.org/manual/nix/stable/command-ref/new-cli/nix3-help-stores.html>
+//! for the store types supported by nix.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use thiserror::Error;
+use url::{Position, Url};
+
+/// Characters that need to be escaped in the query of a keyword store URI
+/// (`daemon`, `auto`, `local`).
+///
+/// Unlike [serde_urlencoded] this leaves `/` untouched,
+/// so that `local?root=/tmp/x` renders as it was written.
+const QUERY_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'+')
+    .add(b'=')
+    .add(b'?');
+
+/// Store settings passed as query parameters, e.g. `?root=/tmp/x`
+///
+/// Parameters are kept in the order they were given.
+pub type StoreParams = Vec<(String, String)>;
+
+/// A nix store URI
+///
+/// ```
+/// # use runix::store_uri::StoreUri;
+/// let uri: StoreUri = "s3://bucket?region=eu-west-1".parse().unwrap();
+/// assert!(matches!(uri, StoreUri::S3(_)));
+/// assert_eq!(uri.to_string(), "s3://bucket?region=eu-west-1");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+pub enum StoreUri {
+    /// `auto`, the default store: the daemon if available, otherwise the local store
+    Auto(StoreParams),
+    /// `daemon`, the store managed by the nix daemon
+    Daemon(StoreParams),
+    /// `local`, direct access to the local store
+    Local(StoreParams),
+    /// `ssh://[user@]host`
+    Ssh(Url),
+    /// `ssh-ng://[user@]host`
+    SshNg(Url),
+    /// `s3://bucket`
+    S3(Url),
+    /// `file:///path/to/cache`
+    File(Url),
+    /// `http://cache`
+    Http(Url),
+    /// `https://cache`
+    Https(Url),
+    /// `/path/to/root`, a local store rooted at the given directory (a "chroot store"),
+    /// short for `local?root=/path/to/root`
+    Path(PathBuf),
+}
+
+impl Default for StoreUri {
+    fn default() -> Self {
+        StoreUri::Auto(Default::default())
+    }
+}
+
+impl StoreUri {
+    /// The name of the uri form, as used in error messages
+    pub fn form(&self) -> &'static str {
+        match self {
+            StoreUri::Auto(_) => "auto",
+            StoreUri::Daemon(_) => "daemon",
+            StoreUri::Local(_) => "local",
+            StoreUri::Ssh(_) => "ssh",
+            StoreUri::SshNg(_) => "ssh-ng",
+            StoreUri::S3(_) => "s3",
+            StoreUri::File(_) => "file",
+            StoreUri::Http(_) => "http",
+            StoreUri::Https(_) => "https",
+            StoreUri::Path(_) => "path",
+        }
+    }
+
+    /// The (decoded) store settings given as query parameters
+    pub fn params(&self) -> StoreParams {
+        match self {
+            StoreUri::Auto(params) | StoreUri::Daemon(params) | StoreUri::Local(params) => {
+                params.clone()
+            },
+            StoreUri::Ssh(url)
+            | StoreUri::SshNg(url)
+            | StoreUri::S3(url)
+            | StoreUri::File(url)
+            | StoreUri::Http(url)
+            | StoreUri::Https(url) => url
+                .query_pairs()
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect(),
+            StoreUri::Path(_) => Vec::new(),
+        }
+    }
+}
+
+impl FromStr for StoreUri {
+    type Err = StoreUriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(StoreUri::Path(PathBuf::from(s)));
+        }
+
+        let (keyword, query) = match s.split_once('?') {
+            Some((keyword, query)) => (keyword, Some(query)),
+            None => (s, None),
+        };
+
+        let keyword_store = match keyword {
+            "auto" => Some(StoreUri::Auto as fn(StoreParams) -> StoreUri),
+            "daemon" => Some(StoreUri::Daemon as fn(StoreParams) -> StoreUri),
+            "local" => Some(StoreUri::Local as fn(StoreParams) -> StoreUri),
+            _ => None,
+        };
+
+        if let Some(store) = keyword_store {
+            let params = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect();
+            return Ok(store(params));
+        }
+
+        let (form, store): (_, fn(Url) -> StoreUri) = match s.split_once("://") {
+            Some(("ssh", _)) => ("ssh", StoreUri::Ssh),
+            Some(("ssh-ng", _)) => ("ssh-ng", StoreUri::SshNg),
+            Some(("s3", _)) => ("s3", StoreUri::S3),
+            Some(("file", _)) => ("file", StoreUri::File),
+            Some(("http", _)) => ("http", StoreUri::Http),
+            Some(("https", _)) => ("https", StoreUri::Https),
+            _ => return Err(StoreUriError::Unknown(s.to_string())),
+        };
+
+        let url = Url::parse(s).map_err(|source| StoreUriError::InvalidUrl {
+            form,
+            uri: s.to_string(),
+            source,
+        })?;
+
+        Ok(store(url))
+    }
+}
+
+impl Display for StoreUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreUri::Auto(params) | StoreUri::Daemon(params) | StoreUri::Local(params) => {
+                write!(f, "{}", self.form())?;
+                for (n, (k, v)) in params.iter().enumerate() {
+                    write!(
+                        f,
+                        "{sep}{k}={v}",
+                        sep = if n == 0 { '?' } else { '&' },
+                        k = utf8_percent_encode(k, QUERY_ESCAPE),
+                        v = utf8_percent_encode(v, QUERY_ESCAPE),
+                    )?;
+                }
+                Ok(())
+            },
+            // `http(s)` urls are normalized to have at least `/` as path,
+            // nix does not distinguish `https://cache` from `https://cache/`
+            // so print the (shorter) form without the trailing `/`
+            StoreUri::Http(url) | StoreUri::Https(url) if url.path() == "/" => write!(
+                f,
+                "{}{}",
+                &url[..Position::BeforePath],
+                &url[Position::AfterPath..]
+            ),
+            StoreUri::Ssh(url)
+            | StoreUri::SshNg(url)
+            | StoreUri::S3(url)
+            | StoreUri::File(url)
+            | StoreUri::Http(url)
+            | StoreUri::Https(url) => write!(f, "{url}"),
+            StoreUri::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StoreUriError {
+    #[error("invalid {form} store URI '{uri}': {source}")]
+    InvalidUrl {
+        form: &'static str,
+        uri: String,
+        source: url::ParseError,
+    },
+    #[error("unknown store URI '{0}' (expected one of auto, daemon, local, ssh://, ssh-ng://, s3://, file://, http://, https:// or an absolute path)")]
+    Unknown(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(input: &str) {
+        let parsed = input
+            .parse::<StoreUri>()
+            .unwrap_or_else(|e| panic!("'{input}' should parse: {e}"));
+        assert_eq!(parsed.to_string(), input);
+    }
+
+    #[test]
+    fn roundtrips_all_forms() {
+        roundtrip("auto");
+        roundtrip("daemon");
+        roundtrip("local?root=/tmp/x");
+        roundtrip("ssh://host");
+        roundtrip("ssh://user@host?remote-store=/nix/store");
+        roundtrip("ssh-ng://");
+        roundtrip("ssh-ng://builder?compress=true");
+        roundtrip("s3://bucket?region=eu-west-1");
+        roundtrip("file:///cache");
+        roundtrip("http://cache");
+        roundtrip("https://cache");
+        roundtrip("https://cache?priority=30");
+        roundtrip("/custom/store");
+    }
+
+    #[test]
+    fn parses_params() {
+        let uri: StoreUri = "local?root=/tmp/x&state=/tmp/x%26y".parse().unwrap();
+        assert_eq!(
+            uri,
+            StoreUri::Local(vec![
+                ("root".to_string(), "/tmp/x".to_string()),
+                ("state".to_string(), "/tmp/x&y".to_string()),
+            ])
+        );
+        assert_eq!(uri.to_string(), "local?root=/tmp/x&state=/tmp/x%26y");
+
+        let uri: StoreUri = "s3://bucket?region=eu-west-1".parse().unwrap();
+        assert_eq!(uri.params(), vec![(
+            "region".to_string(),
+            "eu-west-1".to_string()
+        )]);
+    }
+
+    #[test]
+    fn errors_name_the_form() {
+        let err = "ssh://[::1".parse::<StoreUri>().unwrap_err();
+        assert!(matches!(err, StoreUriError::InvalidUrl { form: "ssh", .. }));
+        assert!(err.to_string().starts_with("invalid ssh store URI"));
+
+        let err = "ftp://cache".parse::<StoreUri>().unwrap_err();
+        assert!(matches!(err, StoreUriError::Unknown(_)));
+    }
+}
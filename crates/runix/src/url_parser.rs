@@ -1,16 +1,28 @@
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Output, Stdio};
 use std::str::FromStr;
 use std::string::FromUtf8Error;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
 
 use serde::de::Error;
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-
-use crate::flake_ref::lock::{InvalidRev, LastModified, NarHash, Rev, RevCount};
+use url::Url;
+
+use crate::flake_ref::lock::{
+    DirtyRev,
+    InvalidDirtyRev,
+    InvalidRev,
+    LastModified,
+    NarHash,
+    Rev,
+    RevCount,
+};
 use crate::flake_ref::protocol::WrappedUrlParseError;
-use crate::flake_ref::{ParseTimeError, Timestamp, TimestampDeserialize};
+use crate::flake_ref::{git_service, FlakeRef, ParseTimeError, Timestamp, TimestampDeserialize};
 
 pub static PARSER_UTIL_BIN_PATH: &str = env!("PARSER_UTIL_BIN");
 
@@ -26,13 +38,22 @@ pub enum UrlParseError {
     FailedSettingBinPath,
     #[error("could not get the path to the 'parser-util' binary, none provided and PARSER_UTIL_BIN not set")]
     BinPathNotSet,
+    // Inputs that are rejected without calling the parser
+    #[error("the flake reference is empty")]
+    EmptyInput,
+    #[error("the flake reference consists only of whitespace")]
+    WhitespaceOnly,
     // Errors trying to call the parser and read its output
     #[error("calling the parser failed")]
     ParserCall(#[from] std::io::Error),
+    #[error("the parser did not finish within {0:?}")]
+    Timeout(Duration),
     #[error("parser did not exit successfully: E{0}: '{1}'")]
     ParserError(ExitStatus, String),
     #[error("the parser did not return valid UTF-8")]
     Utf8(#[from] FromUtf8Error),
+    #[error("the parser printed {} bytes that are not valid UTF-8", .0.len())]
+    NonUtf8Output(Vec<u8>),
     // These are errors deserializing the JSON from `parser-util`
     #[error("protocol '{0}' is invalid for this flake type")]
     InvalidProtocol(String),
@@ -50,19 +71,28 @@ pub enum UrlParseError {
     MissingAttribute(&'static str),
     #[error("failed to resolve URL")]
     ResolutionFailed,
+    #[error("'flake:{0}' resolved to another indirect flake reference")]
+    UnresolvedIndirect(String),
     // Everything after this point is self-inflicted trying to strongly type what Nix gave us
     #[error("bad timestamp")]
     BadTimestamp(#[from] ParseTimeError),
     #[error("bad revision")]
     BadRevision(#[from] InvalidRev),
+    #[error("bad dirty revision")]
+    BadDirtyRevision(#[from] InvalidDirtyRev),
     #[error("unsupported protocol '{1}' for flake type '{0}'")]
     UnsupportedProtocol(String, String),
     #[error("unsupported service '{0}'")]
     UnsupportedService(String),
+    #[error("{first} conflicts with {second}, a ref or rev may only be given once")]
+    ConflictingRefRev { first: String, second: String },
     #[error("failed to parse URL")]
     URLParseError(#[from] WrappedUrlParseError),
     #[error("attribute '{0}' had unexpected type, expected '{1}' and found '{2}'")]
     AttributeType(&'static str, &'static str, Value),
+    #[cfg(feature = "ipfs")]
+    #[error(transparent)]
+    Ipfs(#[from] crate::flake_ref::ipfs::ParseIpfsRefError),
     #[error("{0}")]
     Other(String),
 }
@@ -472,22 +502,14 @@ impl ResolverFlag {
     }
 }
 
-/// Calls the `parser_util` binary with error handling
-fn call_bin(
-    bin_path: impl AsRef<Path>,
-    flag: ResolverFlag,
-    flake_ref: impl AsRef<str>,
-) -> Result<String, UrlParseError> {
-    let output = Command::new(bin_path.as_ref())
-        .arg(flag.as_flag())
-        .arg(flake_ref.as_ref())
-        .output()?;
+/// The stdout of a successful `parser_util` call
+fn parser_output(output: Output) -> Result<String, UrlParseError> {
     if !output.status.success() {
         let stderr = String::from_utf8(output.stderr)?;
         Err(UrlParseError::ParserError(output.status, stderr))
     } else {
-        let stdout = String::from_utf8(output.stdout)?;
-        Ok(stdout)
+        String::from_utf8(output.stdout)
+            .map_err(|err| UrlParseError::NonUtf8Output(err.into_bytes()))
     }
 }
 
@@ -498,11 +520,250 @@ pub fn resolve_flake_ref(
     flake_ref: impl AsRef<str>,
     bin_path: impl AsRef<Path>,
 ) -> Result<ResolvedFlakeRef, UrlParseError> {
-    let output = call_bin(bin_path, ResolverFlag::Resolve, &flake_ref)?;
-    // Type annotation needed here otherwise it thinks you're doing
-    // ResolvedFlakeRef::try_from::<()>(generic_parsed_url) for some reason
-    let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
-    ResolvedFlakeRef::try_from(generic_parsed_url)
+    FlakeRefParser::with_bin_path(bin_path).resolve(flake_ref.as_ref())
+}
+
+/// Resolves flake references, e.g. indirect ones through the flake registry
+///
+/// [ParserUtil] implements it by calling the `parser-util` binary,
+/// other implementations may resolve references without nix,
+/// cache resolutions or ask a remote service.
+///
+/// The reference to resolve is passed as a url (`flake:nixpkgs`)
+/// or as the JSON object of its attributes (`{"type":"indirect","id":"nixpkgs"}`),
+/// the form an [IndirectRef](crate::flake_ref::indirect::IndirectRef) is passed in.
+/// The result is the resolved reference with its attributes,
+/// i.e. the `resolvedRef` printed by `parser-util -r`.
+/// References that can not be resolved any further are returned as they are,
+/// including indirect ones missing in the registry.
+pub trait FlakeRefResolver {
+    fn resolve(&self, flake_ref: &str) -> Result<ParsedFlakeReference, UrlParseError>;
+}
+
+/// Resolves flake references using the `parser-util` binary at `bin_path`,
+/// see [resolve_flake_ref]
+///
+/// Other settings are taken from [FlakeRefParser::default],
+/// use a [FlakeRefParser] to configure them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParserUtil {
+    pub bin_path: PathBuf,
+}
+
+/// The binary of [FlakeRefParser::default]
+impl Default for ParserUtil {
+    fn default() -> Self {
+        ParserUtil {
+            bin_path: FlakeRefParser::default().bin_path,
+        }
+    }
+}
+
+impl FlakeRefResolver for ParserUtil {
+    fn resolve(&self, flake_ref: &str) -> Result<ParsedFlakeReference, UrlParseError> {
+        FlakeRefParser::with_bin_path(&self.bin_path)
+            .resolve(flake_ref)
+            .map(|resolved| resolved.resolved_ref)
+    }
+}
+
+/// The time [FlakeRefParser::default] allows `parser-util` to parse a flake reference
+pub const DEFAULT_PARSER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Configuration of runix read from the environment, see [RunixConfig::from_env]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunixConfig {
+    /// The `parser-util` binary, from `PARSER_UTIL_BIN`
+    pub parser_util_bin: Option<PathBuf>,
+    /// How long `parser-util` may take, from `RUNIX_TIMEOUT_SECS`
+    pub timeout: Option<Duration>,
+}
+
+impl RunixConfig {
+    /// Read the configuration from these environment variables:
+    ///
+    /// - `PARSER_UTIL_BIN`: the path to the `parser-util` binary,
+    ///   overriding the one runix was built with ([PARSER_UTIL_BIN_PATH])
+    /// - `RUNIX_TIMEOUT_SECS`: the whole number of seconds `parser-util` may take
+    ///   to parse a flake reference
+    ///
+    /// Variables that are unset or empty are left out of the configuration,
+    /// so are invalid values, which are logged.
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var_os(name))
+    }
+
+    /// Read the configuration from the variables `var` looks up
+    fn from_vars(var: impl Fn(&str) -> Option<OsString>) -> Self {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+
+        let timeout = var("RUNIX_TIMEOUT_SECS").and_then(|value| {
+            let secs = value.to_str().and_then(|secs| secs.parse().ok());
+            if secs.is_none() {
+                log::warn!("ignoring RUNIX_TIMEOUT_SECS={value:?}, expected whole seconds");
+            }
+            secs.map(Duration::from_secs)
+        });
+
+        RunixConfig {
+            parser_util_bin: var("PARSER_UTIL_BIN").map(PathBuf::from),
+            timeout,
+        }
+    }
+}
+
+/// Parses flake references into [FlakeRef]s using the `parser-util` binary
+///
+/// Holds the configuration of `parser-util` calls,
+/// so it can be set up once and reused for every reference to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlakeRefParser {
+    /// The `parser-util` binary
+    pub bin_path: PathBuf,
+    /// How long `parser-util` may take before it is killed
+    /// and parsing fails with [UrlParseError::Timeout]
+    pub timeout: Duration,
+    /// Variables set in the environment of `parser-util`,
+    /// in addition to the ones inherited from this process
+    pub env: HashMap<String, String>,
+}
+
+/// Configured by [RunixConfig::from_env], see there for the environment variables read
+///
+/// Falls back to the binary at [PARSER_UTIL_BIN_PATH], allowed [DEFAULT_PARSER_TIMEOUT].
+impl Default for FlakeRefParser {
+    fn default() -> Self {
+        RunixConfig::from_env().into()
+    }
+}
+
+/// Uses the defaults of [FlakeRefParser::default] for what is not configured
+impl From<RunixConfig> for FlakeRefParser {
+    fn from(config: RunixConfig) -> Self {
+        FlakeRefParser {
+            bin_path: config
+                .parser_util_bin
+                .unwrap_or_else(|| PARSER_UTIL_BIN_PATH.into()),
+            timeout: config.timeout.unwrap_or(DEFAULT_PARSER_TIMEOUT),
+            env: HashMap::new(),
+        }
+    }
+}
+
+impl FlakeRefParser {
+    /// Parses a flake reference
+    ///
+    /// Like nix, this accepts scheme-less indirect references such as `nixpkgs`.
+    /// To parse those without `parser-util`,
+    /// use [IndirectRef::from_str](crate::flake_ref::indirect::IndirectRef).
+    ///
+    /// Github and gitlab urls naming more than one ref or rev
+    /// (e.g. `github:owner/repo/main?rev=<rev>`) are rejected
+    /// with [UrlParseError::ConflictingRefRev] before calling `parser-util`.
+    /// So are empty urls ([UrlParseError::EmptyInput])
+    /// and urls of only whitespace ([UrlParseError::WhitespaceOnly]).
+    ///
+    /// With the `ipfs` feature, `ipfs://` and `ipns://` urls are parsed
+    /// without `parser-util`, which does not know them.
+    pub fn parse(&self, s: &str) -> Result<FlakeRef, UrlParseError> {
+        if s.is_empty() {
+            return Err(UrlParseError::EmptyInput);
+        }
+        if s.trim().is_empty() {
+            return Err(UrlParseError::WhitespaceOnly);
+        }
+
+        if let Ok(parsed) = Url::parse(s) {
+            if matches!(parsed.scheme(), "github" | "gitlab" | "sourcehut") {
+                if let Some((first, second)) = git_service::conflicting_ref_rev(&parsed) {
+                    return Err(UrlParseError::ConflictingRefRev { first, second });
+                }
+            }
+            #[cfg(feature = "ipfs")]
+            match parsed.scheme() {
+                "ipfs" => return Ok(FlakeRef::Ipfs(s.parse()?)),
+                "ipns" => return Ok(FlakeRef::Ipns(s.parse()?)),
+                _ => {},
+            }
+        }
+
+        let output = self.call(ResolverFlag::Installable, s)?;
+        let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
+        let parsed = InstallableFlakeRef::try_from(generic_parsed_url)?;
+        FlakeRef::from_parsed(&parsed.r#ref)
+    }
+
+    /// Resolves and parses a flake reference, see [resolve_flake_ref]
+    pub fn resolve(&self, flake_ref: &str) -> Result<ResolvedFlakeRef, UrlParseError> {
+        let output = self.call(ResolverFlag::Resolve, flake_ref)?;
+        let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
+        ResolvedFlakeRef::try_from(generic_parsed_url)
+    }
+
+    /// Parses and locks a flake reference, see [lock_flake_ref]
+    pub fn lock(&self, flake_ref: &str) -> Result<LockedFlakeRef, UrlParseError> {
+        let output = self.call(ResolverFlag::Lock, flake_ref)?;
+        let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
+        LockedFlakeRef::try_from(generic_parsed_url)
+    }
+
+    /// Resolves a flake reference to an installable, see [installable_flake_ref]
+    pub fn installable(&self, flake_ref: &str) -> Result<InstallableFlakeRef, UrlParseError> {
+        let output = self.call(ResolverFlag::Installable, flake_ref)?;
+        let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
+        InstallableFlakeRef::try_from(generic_parsed_url)
+    }
+
+    /// The default parser, calling the `parser-util` binary at `bin_path`
+    pub(crate) fn with_bin_path(bin_path: impl AsRef<Path>) -> Self {
+        FlakeRefParser {
+            bin_path: bin_path.as_ref().to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Calls `parser-util`, killing it once [Self::timeout] passed
+    fn call(&self, flag: ResolverFlag, flake_ref: &str) -> Result<String, UrlParseError> {
+        let child = Command::new(&self.bin_path)
+            .arg(flag.as_flag())
+            .arg(flake_ref)
+            .envs(&self.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // `wait_with_output` blocks until parser-util exited,
+        // unless it finishes first the timer kills it
+        let pid = child.id() as libc::pid_t;
+        let timeout = self.timeout;
+        let (finished, done) = mpsc::channel::<()>();
+        let timer = std::thread::spawn(move || {
+            let timed_out = done.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+            if timed_out {
+                // SAFETY: `kill` has no memory safety requirements
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+            timed_out
+        });
+
+        let output = child.wait_with_output();
+        drop(finished);
+        let timed_out = timer.join().expect("parser-util timer panicked");
+        let output = output?;
+
+        if timed_out && !output.status.success() {
+            return Err(UrlParseError::Timeout(self.timeout));
+        }
+        parser_output(output)
+    }
+}
+
+/// Resolves flake references with `parser-util`, with the configured timeout and environment
+impl FlakeRefResolver for FlakeRefParser {
+    fn resolve(&self, flake_ref: &str) -> Result<ParsedFlakeReference, UrlParseError> {
+        FlakeRefParser::resolve(self, flake_ref).map(|resolved| resolved.resolved_ref)
+    }
 }
 
 /// Parses and locks a flake reference.
@@ -510,11 +771,7 @@ pub fn lock_flake_ref(
     flake_ref: impl AsRef<str>,
     bin_path: impl AsRef<Path>,
 ) -> Result<LockedFlakeRef, UrlParseError> {
-    let output = call_bin(bin_path, ResolverFlag::Lock, &flake_ref)?;
-    // Type annotation needed here otherwise it thinks you're doing
-    // LockedFlakeRef::try_from::<()>(generic_parsed_url) for some reason
-    let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
-    LockedFlakeRef::try_from(generic_parsed_url)
+    FlakeRefParser::with_bin_path(bin_path).lock(flake_ref.as_ref())
 }
 
 /// Resolves a flake reference to an installable
@@ -522,11 +779,7 @@ pub fn installable_flake_ref(
     flake_ref: impl AsRef<str>,
     bin_path: impl AsRef<Path>,
 ) -> Result<InstallableFlakeRef, UrlParseError> {
-    let output = call_bin(bin_path, ResolverFlag::Installable, &flake_ref)?;
-    // Type annotation needed here otherwise it thinks you're doing
-    // InstallableFlakeRef::try_from::<()>(generic_parsed_url) for some reason
-    let generic_parsed_url: GenericParsedURL = serde_json::from_str(output.as_ref())?;
-    InstallableFlakeRef::try_from(generic_parsed_url)
+    FlakeRefParser::with_bin_path(bin_path).installable(flake_ref.as_ref())
 }
 
 /// Extracts the `narHash` flake attributes
@@ -552,6 +805,19 @@ pub(crate) fn extract_rev_attr(attrs: &Attrs) -> Result<Option<Rev>, UrlParseErr
     Ok(rev)
 }
 
+/// Extracts a `dirtyRev` or `dirtyShortRev` flake attribute
+pub(crate) fn extract_dirty_rev_attr(
+    attrs: &Attrs,
+    name: &'static str,
+) -> Result<Option<DirtyRev>, UrlParseError> {
+    let dirty_rev = match attrs.get(name) {
+        Some(Value::String(dirty_rev)) => Some(DirtyRev::from_str(dirty_rev)?),
+        Some(v) => return Err(UrlParseError::AttributeType(name, "String", v.clone())),
+        None => None,
+    };
+    Ok(dirty_rev)
+}
+
 /// Extracts the `dir` flake attribute
 pub(crate) fn extract_dir_attr(attrs: &Attrs) -> Result<Option<PathBuf>, UrlParseError> {
     let dir = match attrs.get("dir") {
@@ -696,7 +962,10 @@ pub(crate) fn extract_name_attr(attrs: &Attrs) -> Result<Option<String>, UrlPars
 
 #[cfg(test)]
 mod test {
+    use std::time::Instant;
+
     use super::*;
+    use crate::command_line::tests::write_script;
 
     const RESOLVED_JSON: &str = r#"
     {
@@ -753,6 +1022,95 @@ mod test {
         let _parsed = resolve_flake_ref("github:flox/flox", PARSER_UTIL_BIN_PATH).unwrap();
     }
 
+    #[test]
+    fn rejects_non_utf8_output() {
+        let (_tempdir, bin) = fake_parser_util("printf '{\"type\": \"\\377\"}'");
+
+        let err = resolve_flake_ref("github:flox/flox", &bin).unwrap_err();
+        let UrlParseError::NonUtf8Output(bytes) = err else {
+            panic!("expected non UTF-8 output, got {err:?}");
+        };
+        assert_eq!(bytes, b"{\"type\": \"\xff\"}");
+    }
+
+    /// A fake `parser-util` running `script`
+    fn fake_parser_util(script: &str) -> (tempfile::TempDir, PathBuf) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let bin = tempdir.path().join("parser-util");
+        write_script(&bin, script);
+        (tempdir, bin)
+    }
+
+    #[test]
+    fn flake_ref_parser_sets_env() {
+        let (_tempdir, bin) = fake_parser_util(
+            r#"cat <<EOF
+{
+  "input": "$2",
+  "attrPath": [],
+  "outputs": "default",
+  "ref": {
+    "attrs": { "type": "github", "owner": "$RUNIX_OWNER", "repo": "runix" },
+    "string": "github:$RUNIX_OWNER/runix"
+  }
+}
+EOF"#,
+        );
+        let parser = FlakeRefParser {
+            bin_path: bin,
+            env: HashMap::from([("RUNIX_OWNER".to_string(), "flox".to_string())]),
+            ..Default::default()
+        };
+
+        let flake_ref = parser.parse("github:flox/runix").unwrap();
+        assert_eq!(flake_ref.to_string(), "github:flox/runix");
+        assert!(matches!(
+            parser.parse(" ").unwrap_err(),
+            UrlParseError::WhitespaceOnly
+        ));
+    }
+
+    #[test]
+    fn flake_ref_parser_defaults_from_env() {
+        let vars = |vars: HashMap<&str, &str>| {
+            RunixConfig::from_vars(|name| vars.get(name).map(OsString::from))
+        };
+
+        let config = vars(HashMap::from([
+            ("PARSER_UTIL_BIN", "/opt/parser-util"),
+            ("RUNIX_TIMEOUT_SECS", "5"),
+        ]));
+        let parser = FlakeRefParser::from(config);
+        assert_eq!(parser.bin_path, Path::new("/opt/parser-util"));
+        assert_eq!(parser.timeout, Duration::from_secs(5));
+
+        let config = vars(HashMap::from([
+            ("PARSER_UTIL_BIN", ""),
+            ("RUNIX_TIMEOUT_SECS", "soon"),
+        ]));
+        assert_eq!(config, RunixConfig::default());
+        let parser = FlakeRefParser::from(config);
+        assert_eq!(parser.bin_path, Path::new(PARSER_UTIL_BIN_PATH));
+        assert_eq!(parser.timeout, DEFAULT_PARSER_TIMEOUT);
+    }
+
+    #[test]
+    fn flake_ref_parser_times_out() {
+        let (_tempdir, bin) = fake_parser_util("exec sleep 1000");
+        let parser = FlakeRefParser {
+            bin_path: bin,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let err = parser.parse("github:flox/runix").unwrap_err();
+        assert!(matches!(err, UrlParseError::Timeout(timeout) if timeout == parser.timeout));
+        let err = parser.resolve("flake:nixpkgs").unwrap_err();
+        assert!(matches!(err, UrlParseError::Timeout(timeout) if timeout == parser.timeout));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
     fn fix_test_bank_path(path: &str) -> String {
         let current_dir = std::env::current_dir().unwrap();
         let dir_str = current_dir.to_str().unwrap();